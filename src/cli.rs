@@ -0,0 +1,302 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::engine::backup::{Backup, TorrentBackupInfo};
+use crate::engine::control::{self, Request};
+use crate::engine::session::{DownloadStatus, TorrentDetails, TorrentStats};
+use crate::engine::settings::Settings;
+use crate::engine::{data_path, dht, parse_magnet, parse_torrent, priorities, saver};
+use crate::gui::get_readable_size;
+
+const USAGE: &str = "Usage: torrent-client [command]
+Without command the window is opened.
+Commands:
+  daemon                               run client without window
+  add <file|magnet> [--dest <folder>]  add torrent, current folder is default
+  list                                 list torrents
+  pause <hash>                         pause torrent
+  resume <hash>                        resume torrent
+  remove <hash> [--delete-data]        remove torrent, optionally with its files
+  info <hash>                          show torrent details
+  verify <hash>                        hash check downloaded data
+Hash can be shortened to any unique prefix.
+If client is running, commands are sent to it, otherwise saved session is changed.";
+
+// Running client answers list request at once
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Runs command given in arguments (without program name)
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    let Some(cmd) = args.first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    let args = &args[1..];
+    // control requests are signed with API token from settings
+    Settings::init();
+    // running client owns session, backup is changed only when nothing listens,
+    // any other failure means client is there and its session can't be touched
+    let running = match timeout(PROBE_TIMEOUT, control::send(&Request::List)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) if is_refused(&e) => false,
+        Ok(Err(e)) => return Err(e.context("Running client rejected request")),
+        Err(_) => anyhow::bail!("Running client didn't answer"),
+    };
+    if !running {
+        Backup::init()?;
+    }
+    match cmd.as_str() {
+        "add" => {
+            let Some(source) = args.first() else {
+                anyhow::bail!("add needs torrent file or magnet link");
+            };
+            let dest = match flag_value(args, "--dest") {
+                Some(dest) => dest,
+                None => std::env::current_dir()?.to_string_lossy().into_owned(),
+            };
+            // paths are resolved here, running client can have other working folder
+            let dest = std::fs::canonicalize(&dest)?.to_string_lossy().into_owned();
+            let source = if source.starts_with("magnet:") {
+                source.clone()
+            } else {
+                std::fs::canonicalize(source)?.to_string_lossy().into_owned()
+            };
+            let hash = if running {
+                let hash = control::send(&Request::Add { source, dest }).await?;
+                hash.as_str().unwrap_or_default().to_string()
+            } else {
+                add_to_backup(&source, &dest).await?
+            };
+            println!("Added {hash}");
+        }
+        "list" => {
+            let list: Vec<TorrentStats> = if running {
+                serde_json::from_value(control::send(&Request::List).await?)?
+            } else {
+                backups()
+                    .await?
+                    .iter()
+                    .map(|x| TorrentDetails::from_backup(x).stats)
+                    .collect()
+            };
+            print_list(&list);
+        }
+        "pause" | "resume" | "verify" | "info" => {
+            let hash = find_hash(args.first(), running).await?;
+            if running {
+                let req = match cmd.as_str() {
+                    "pause" => Request::Pause { hash },
+                    "resume" => Request::Resume { hash },
+                    "verify" => Request::Verify { hash },
+                    _ => Request::Info { hash },
+                };
+                let res = control::send(&req).await?;
+                if cmd == "info" {
+                    print_info(&serde_json::from_value(res)?);
+                }
+            } else {
+                let mut backup = load_backup(&hash).await?;
+                match cmd.as_str() {
+                    "pause" => {
+                        if let DownloadStatus::Downloading
+                        | DownloadStatus::Resuming
+                        | DownloadStatus::Seeding = backup.status
+                        {
+                            backup.status = DownloadStatus::Paused;
+                        }
+                    }
+                    "resume" => {
+                        if let DownloadStatus::Paused | DownloadStatus::Error(_) = backup.status {
+                            backup.status = DownloadStatus::Downloading;
+                        }
+                    }
+                    "verify" => {
+                        // parked bytes of skipped files are found by priorities
+                        priorities::torrent(&backup.torrent.info_hash)
+                            .set(backup.file_priorities.clone());
+                        saver::verify_backup(&mut backup).await;
+                        println!(
+                            "{}/{} pieces are verified",
+                            backup.pieces_done,
+                            backup.torrent.info.piece_hashes.len()
+                        );
+                    }
+                    _ => {
+                        print_info(&TorrentDetails::from_backup(&backup));
+                        return Ok(());
+                    }
+                }
+                Backup::global().backup_torrent(backup).await?;
+            }
+        }
+        "remove" => {
+            let hash = find_hash(args.first(), running).await?;
+            let delete_data = args.iter().any(|x| x == "--delete-data");
+            if running {
+                control::send(&Request::Remove { hash, delete_data }).await?;
+            } else {
+                let backup = load_backup(&hash).await?;
+                // torrent is kept if its data can't be deleted
+                if delete_data {
+                    saver::remove_data(&backup.save_path, &backup.torrent.info.name)?;
+                }
+                Backup::global().remove_torrent(&backup.torrent.info_hash).await?;
+            }
+        }
+        "help" | "--help" | "-h" => println!("{USAGE}"),
+        cmd => anyhow::bail!("Unknown command: {cmd}\n{USAGE}"),
+    }
+    Ok(())
+}
+
+fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|x| x.kind() == ErrorKind::ConnectionRefused)
+}
+
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let pos = args.iter().position(|x| x == flag)?;
+    args.get(pos + 1).cloned()
+}
+
+async fn backups() -> anyhow::Result<Vec<TorrentBackupInfo>> {
+    // no backup file means empty session
+    Ok(Backup::global().load_config().await.unwrap_or_default())
+}
+
+async fn load_backup(hash: &str) -> anyhow::Result<TorrentBackupInfo> {
+    Backup::global().load_backup(&hex::decode(hash)?).await
+}
+
+// Full info hash by its unique prefix
+async fn find_hash(prefix: Option<&String>, running: bool) -> anyhow::Result<String> {
+    let Some(prefix) = prefix else {
+        anyhow::bail!("Info hash is required");
+    };
+    let prefix = prefix.to_lowercase();
+    let hashes: Vec<String> = if running {
+        let list: Vec<TorrentStats> = serde_json::from_value(control::send(&Request::List).await?)?;
+        list.into_iter().map(|x| x.hash).collect()
+    } else {
+        backups()
+            .await?
+            .iter()
+            .map(|x| hex::encode(&x.torrent.info_hash))
+            .collect()
+    };
+    let found: Vec<String> = hashes.into_iter().filter(|x| x.starts_with(&prefix)).collect();
+    match found.len() {
+        0 => anyhow::bail!("No torrent with info hash {prefix}"),
+        1 => Ok(found[0].clone()),
+        _ => anyhow::bail!("Info hash {prefix} is ambiguous"),
+    }
+}
+
+// Existing data at destination is hash checked, so torrent continues from it
+async fn add_to_backup(source: &str, dest: &str) -> anyhow::Result<String> {
+    let torrent = if source.starts_with("magnet:") {
+        dht::spawn_dht();
+        let peer_id = {
+            use rand::distributions::{Alphanumeric, DistString};
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 20)
+        };
+        println!("Fetching torrent metadata from peers...");
+        parse_magnet(source, peer_id).await?
+    } else {
+        parse_torrent(source)?
+    };
+    if Backup::global().load_backup(&torrent.info_hash).await.is_ok() {
+        anyhow::bail!("Torrent is already added");
+    }
+    if !Path::new(dest).is_dir() {
+        anyhow::bail!("Destination folder {dest} doesn't exist");
+    }
+    let hash = hex::encode(&torrent.info_hash);
+    let mut backup = TorrentBackupInfo {
+        pieces_tasks: Default::default(),
+        chunks_tasks: Default::default(),
+        save_path: data_path(&torrent, dest),
+        torrent,
+        pieces_done: 0,
+        status: DownloadStatus::Downloading,
+        uploaded: 0,
+        seeding_secs: 0,
+        limits: Default::default(),
+        file_priorities: Vec::new(),
+        sequential: false,
+    };
+    saver::verify_backup(&mut backup).await;
+    Backup::global().backup_torrent(backup).await?;
+    Ok(hash)
+}
+
+fn status_name(status: &DownloadStatus) -> String {
+    match status {
+        DownloadStatus::Error(e) => format!("Error: {e}"),
+        status => format!("{status:?}"),
+    }
+}
+
+fn progress(stats: &TorrentStats) -> f64 {
+    if stats.pieces_total == 0 {
+        return 0.0;
+    }
+    stats.pieces_done as f64 / stats.pieces_total as f64 * 100.0
+}
+
+fn print_list(list: &[TorrentStats]) {
+    println!(
+        "{:<40}  {:<11}  {:>7}  {:>10}  {:>12}  {:>5}  Name",
+        "Hash", "Status", "Done", "Size", "Speed", "Peers"
+    );
+    for stats in list {
+        println!(
+            "{:<40}  {:<11}  {:>6.2}%  {:>10}  {:>12}  {:>5}  {}",
+            stats.hash,
+            status_name(&stats.status),
+            progress(stats),
+            get_readable_size(stats.size as usize, 2),
+            get_readable_size(stats.download_speed as usize, 2) + "/s",
+            stats.peers,
+            stats.name
+        );
+    }
+}
+
+fn print_info(details: &TorrentDetails) {
+    let stats = &details.stats;
+    println!("Name:       {}", stats.name);
+    println!("Hash:       {}", stats.hash);
+    println!("Status:     {}", status_name(&stats.status));
+    println!("Size:       {}", get_readable_size(stats.size as usize, 2));
+    println!(
+        "Progress:   {:.2}% ({}/{} pieces)",
+        progress(stats),
+        stats.pieces_done,
+        stats.pieces_total
+    );
+    println!("Speed:      {}/s", get_readable_size(stats.download_speed as usize, 2));
+    println!("Peers:      {}", stats.peers);
+    println!("Uploaded:   {}", get_readable_size(stats.total_uploaded as usize, 2));
+    println!("Saved to:   {}", stats.save_dir);
+    println!("Sequential: {}", details.sequential);
+    println!("Trackers:");
+    for tracker in &details.trackers {
+        match tracker.error {
+            Some(ref e) => println!("  {} (failed: {e})", tracker.url),
+            None => println!("  {}", tracker.url),
+        }
+    }
+    println!("Files:");
+    for file in &details.files {
+        println!(
+            "  {:>10}  {:<6}  {}",
+            get_readable_size(file.length as usize, 2),
+            file.priority.to_string(),
+            file.path
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep_until, timeout, Instant};
+
+use super::download::DataPiece;
+use super::events::{EngineEvent, EventSender};
+use super::logger::{log, LogLevel};
+use super::saver::TransferStats;
+use super::tracker::{AnnounceEvent, TrackerReq};
+use super::DownloadEvents;
+
+// Used if tracker sent nothing reasonable
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_INTERVAL_FLOOR: u64 = 30;
+// Failed announce is retried with doubling delay up to max
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+// Time given to deliver "stopped" to tracker
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const SCRAPE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Handle of per torrent announce task
+#[derive(Debug)]
+pub struct Announcer {
+    sender: mpsc::Sender<AnnounceEvent>,
+    handle: JoinHandle<()>,
+    scrape_handle: JoinHandle<()>,
+}
+
+impl Announcer {
+    // Announces "started" right away and then re-announces on tracker's interval,
+    // swarm statistics of every tracker are scraped and sent to UI
+    pub fn spawn(
+        mut req: TrackerReq,
+        stats: Arc<TransferStats>,
+        event_sender: mpsc::Sender<DownloadEvents>,
+        data_sender: mpsc::Sender<DataPiece>,
+        events: EventSender,
+    ) -> Self {
+        let scrape_handle = spawn_scraper(req.clone(), events.clone());
+        let (sender, mut receiver) = mpsc::channel(10);
+        let handle = tokio::spawn(async move {
+            let mut event = AnnounceEvent::Started;
+            let mut min_interval = DEFAULT_MIN_INTERVAL;
+            let mut fails = 0;
+            loop {
+                req.event = event;
+                req.downloaded = stats.downloaded.load(Ordering::Relaxed);
+                req.uploaded = stats.uploaded.load(Ordering::Relaxed);
+                req.left = stats.left.load(Ordering::Relaxed);
+                let res = req
+                    .announce(|tracker, res| {
+                        events.send(EngineEvent::TrackerAnnounce(tracker.clone(), res));
+                    })
+                    .await;
+                if event == AnnounceEvent::Stopped {
+                    log!(LogLevel::Info, "Sent stopped to tracker: {}", res.is_ok());
+                    break;
+                }
+                let last_announce = Instant::now();
+                let wait = match res {
+                    Ok(resp) => {
+                        fails = 0;
+                        event = AnnounceEvent::None;
+                        min_interval = resp
+                            .min_interval
+                            .map(|x| Duration::from_secs(x.max(MIN_INTERVAL_FLOOR as i64) as u64))
+                            .unwrap_or(DEFAULT_MIN_INTERVAL);
+                        log!(
+                            LogLevel::Info,
+                            "Announced, {} peer(s), interval {}",
+                            resp.peers.len(),
+                            resp.interval
+                        );
+                        req.connect_peers(resp.peers, event_sender.clone(), data_sender.clone());
+                        Duration::from_secs(resp.interval.max(MIN_INTERVAL_FLOOR as i64) as u64)
+                    }
+                    Err(e) => {
+                        // event is kept, so it will be sent with next attempt
+                        log!(LogLevel::Info, "Announce failed: {e}");
+                        fails += 1;
+                        (RETRY_DELAY * 2u32.pow(fails.min(10))).min(MAX_RETRY_DELAY)
+                    }
+                };
+
+                // waiting for interval to pass or for worker's request
+                let mut deadline = last_announce + wait;
+                loop {
+                    tokio::select! {
+                        _ = sleep_until(deadline) => break,
+                        msg = receiver.recv() => match msg.unwrap_or(AnnounceEvent::Stopped) {
+                            // worker needs peers, announcing as soon as tracker allows
+                            AnnounceEvent::None => {
+                                deadline = deadline.min(last_announce + min_interval);
+                            }
+                            new_event => {
+                                event = new_event;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Announcer {
+            sender,
+            handle,
+            scrape_handle,
+        }
+    }
+
+    pub async fn announce_now(&self) {
+        let _ = self.sender.send(AnnounceEvent::None).await;
+    }
+
+    pub async fn completed(&self) {
+        let _ = self.sender.send(AnnounceEvent::Completed).await;
+    }
+
+    pub async fn stop(self) {
+        self.scrape_handle.abort();
+        let _ = self.sender.send(AnnounceEvent::Stopped).await;
+        let mut handle = self.handle;
+        if timeout(STOP_TIMEOUT, &mut handle).await.is_err() {
+            handle.abort();
+        }
+    }
+}
+
+fn spawn_scraper(req: TrackerReq, events: EventSender) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let mut tasks = JoinSet::new();
+            for tracker in req.tracker_tiers().into_iter().flatten() {
+                let req = req.clone();
+                tasks.spawn(async move {
+                    let res = req.scrape(&tracker).await.map_err(|e| e.to_string());
+                    (tracker, res)
+                });
+            }
+            while let Some(res) = tasks.join_next().await {
+                if let Ok((tracker, res)) = res {
+                    events.send(EngineEvent::TrackerScrape(tracker, res));
+                }
+            }
+            tokio::time::sleep(SCRAPE_INTERVAL).await;
+        }
+    })
+}
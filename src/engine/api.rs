@@ -0,0 +1,276 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use super::http::{self, base64_decode, Request, Response};
+use super::logger::{log, LogLevel};
+use super::session::{DownloadStatus, SessionHandle};
+use super::settings::{self, Settings};
+use super::torrent::Torrent;
+
+// REST API for scripts and dashboards, every request needs
+// "Authorization: Bearer <token>" header with token from settings
+pub const API_PORT: u16 = 6684;
+
+// Browser UI, served at root of API server, it asks for token itself
+const WEB_UI: &str = include_str!("web/index.html");
+// Requests carry JSON, torrent files are sent base64 encoded in it
+const MAX_REQUEST_BODY: usize = 8 * 1024 * 1024;
+
+pub fn spawn_api_server(session: SessionHandle) -> JoinHandle<()> {
+    api_token();
+    tokio::spawn(async move {
+        let ip = if Settings::get().api_remote {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        };
+        let addr = SocketAddr::new(ip, API_PORT);
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to bind API server to {addr}: {e}");
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Serving control API on http://{addr}/api");
+        http::serve(listener, MAX_REQUEST_BODY, move |req| {
+            let session = session.clone();
+            async move {
+                if req.method == "GET" && (req.path == "/" || req.path == "/index.html") {
+                    return Response::new(200, WEB_UI.as_bytes().to_vec())
+                        .with_header("Content-Type", "text/html; charset=utf-8");
+                }
+                if !authorized(&req) {
+                    return error(401, "Missing or wrong API token")
+                        .with_header("WWW-Authenticate", "Bearer");
+                }
+                route(req, &session).await
+            }
+        })
+        .await;
+    })
+}
+
+// Token is generated on first start and kept in settings
+pub fn api_token() -> String {
+    let mut settings = Settings::get();
+    if let Some(ref token) = settings.api_token {
+        return token.clone();
+    }
+    let token = new_token();
+    settings.api_token = Some(token.clone());
+    Settings::set(settings);
+    token
+}
+
+pub fn new_token() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+fn authorized(req: &Request) -> bool {
+    req.header("authorization")
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(token_matches)
+}
+
+// Token can be changed in settings while servers run, so it's read every time
+pub fn token_matches(given: &str) -> bool {
+    let Some(current) = Settings::get().api_token else {
+        return false;
+    };
+    // comparison time doesn't depend on position of first mismatch
+    given.len() == current.len()
+        && given
+            .bytes()
+            .zip(current.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Torrent is given by source or by metainfo, default download folder is used without dest
+#[derive(Deserialize)]
+struct AddRequest {
+    // path of torrent file on client machine or magnet link
+    source: Option<String>,
+    // base64 contents of torrent file
+    metainfo: Option<String>,
+    dest: Option<String>,
+}
+
+async fn route(req: Request, session: &SessionHandle) -> Response {
+    let parts: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    let res = match (req.method.as_str(), parts.as_slice()) {
+        ("GET", ["api", "stats"]) => stats(session).await,
+        ("GET", ["api", "torrents"]) => session
+            .list()
+            .await
+            .and_then(|x| Ok(serde_json::to_value(x)?)),
+        ("POST", ["api", "torrents"]) => match serde_json::from_slice::<AddRequest>(&req.body) {
+            Ok(add) => add_torrent(session, add)
+                .await
+                .map(|hash| json!({ "hash": hash })),
+            Err(e) => return error(400, &format!("Invalid request body: {e}")),
+        },
+        ("GET", ["api", "torrents", hash]) => session
+            .info(hash.to_string())
+            .await
+            .and_then(|x| Ok(serde_json::to_value(x)?)),
+        ("DELETE", ["api", "torrents", hash]) => {
+            let delete_data = req.query.get("delete_data").is_some_and(|x| x == "true");
+            session.remove(hash.to_string(), delete_data).await.map(|_| json!(null))
+        }
+        ("POST", ["api", "torrents", hash, action]) => {
+            let hash = hash.to_string();
+            let res = match *action {
+                "pause" => session.pause(hash).await,
+                "resume" => session.resume(hash).await,
+                "verify" => session.verify(hash).await,
+                _ => return error(404, "Unknown action"),
+            };
+            res.map(|_| json!(null))
+        }
+        _ => return error(404, "Unknown endpoint"),
+    };
+    match res {
+        Ok(value) => Response::json(200, &value),
+        Err(e) if e.to_string().starts_with("No torrent") => error(404, &e.to_string()),
+        Err(e) => error(400, &e.to_string()),
+    }
+}
+
+async fn add_torrent(session: &SessionHandle, add: AddRequest) -> anyhow::Result<String> {
+    let dest = add
+        .dest
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(settings::default_download_dir);
+    match (add.metainfo, add.source) {
+        (Some(metainfo), _) => {
+            let torrent = Torrent::from_bytes(&base64_decode(&metainfo)?)?;
+            session.add(torrent, dest).await
+        }
+        (None, Some(source)) => session.add_source(&source, dest).await,
+        (None, None) => anyhow::bail!("Torrent source or metainfo is required"),
+    }
+}
+
+// Totals over all torrents
+async fn stats(session: &SessionHandle) -> anyhow::Result<serde_json::Value> {
+    let list = session.list().await?;
+    let count = |f: fn(&DownloadStatus) -> bool| list.iter().filter(|x| f(&x.status)).count();
+    Ok(json!({
+        "torrents": list.len(),
+        "downloading": count(|x| matches!(x, DownloadStatus::Downloading)),
+        "seeding": count(|x| matches!(x, DownloadStatus::Seeding)),
+        "paused": count(|x| matches!(x, DownloadStatus::Paused)),
+        "download_speed": list.iter().map(|x| x.download_speed).sum::<u64>(),
+        "peers": list.iter().map(|x| x.peers).sum::<usize>(),
+        "uploaded": list.iter().map(|x| x.uploaded).sum::<u64>(),
+    }))
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::json(status, &json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::super::http::Body;
+    use super::super::session::Session;
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: body.to_vec(),
+            peer_addr: "127.0.0.1:50000".parse().unwrap(),
+        }
+    }
+
+    // Empty session running its commands in background
+    fn session() -> SessionHandle {
+        let mut session = Session::default();
+        let handle = session.handle();
+        tokio::spawn(async move {
+            loop {
+                session.update();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+        handle
+    }
+
+    async fn call(session: &SessionHandle, req: Request) -> (u16, serde_json::Value) {
+        let res = route(req, session).await;
+        let Body::Bytes(body) = res.body else {
+            panic!("Streamed API response");
+        };
+        (res.status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn routes() {
+        let session = session();
+        let (status, body) = call(&session, request("GET", "/api/torrents", b"")).await;
+        assert_eq!((status, body), (200, json!([])));
+        let (status, body) = call(&session, request("GET", "/api/stats", b"")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["torrents"], 0);
+        assert_eq!(body["download_speed"], 0);
+
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        for (method, path) in [
+            ("GET", format!("/api/torrents/{hash}")),
+            ("DELETE", format!("/api/torrents/{hash}")),
+            ("POST", format!("/api/torrents/{hash}/pause")),
+        ] {
+            let (status, body) = call(&session, request(method, &path, b"")).await;
+            assert_eq!(status, 404, "{method} {path}");
+            assert!(body["error"].as_str().unwrap().starts_with("No torrent"));
+        }
+        let path = format!("/api/torrents/{hash}/start");
+        let (status, body) = call(&session, request("POST", &path, b"")).await;
+        assert_eq!((status, body), (404, json!({ "error": "Unknown action" })));
+        let (status, _) = call(&session, request("PUT", "/api/torrents", b"")).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn bad_add_requests() {
+        let session = session();
+        let (status, body) = call(&session, request("POST", "/api/torrents", b"{")).await;
+        assert_eq!(status, 400);
+        let error = body["error"].as_str().unwrap();
+        assert!(error.starts_with("Invalid request body"));
+
+        let req = request("POST", "/api/torrents", br#"{"dest": "/tmp"}"#);
+        let (status, body) = call(&session, req).await;
+        let error = json!({ "error": "Torrent source or metainfo is required" });
+        assert_eq!((status, body), (400, error));
+
+        let req = request("POST", "/api/torrents", br#"{"metainfo": "ZDU6"}"#);
+        let (status, _) = call(&session, req).await;
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn token_is_required() {
+        let mut req = request("GET", "/api/torrents", b"");
+        assert!(!authorized(&req));
+        let auth = ("authorization".to_string(), "Basic abc".to_string());
+        req.headers.extend([auth]);
+        assert!(!authorized(&req));
+        // empty token never matches
+        assert!(!token_matches(""));
+    }
+}
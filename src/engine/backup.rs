@@ -1,6 +1,12 @@
+use crate::engine::download::{ChunksTask, PieceTask};
 use crate::engine::logger::{log, LogLevel};
-use crate::gui::TorrentBackupInfo;
+use crate::engine::priorities::FilePriority;
+use crate::engine::session::DownloadStatus;
+use crate::engine::settings::RateLimits;
+use crate::engine::torrent::{Torrent, TorrentInfo};
 use dirs::data_local_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{Read, Seek, Write};
 use std::path::PathBuf;
@@ -10,6 +16,120 @@ use std::sync::Arc;
 
 static BACKUP: OnceCell<Backup> = OnceCell::new();
 const BACKUP_NAME: &str = "backup.bin";
+// Backup starts with magic and layout version, files of first release
+// have neither, they are read with old layout and rewritten in new one
+const BACKUP_MAGIC: &[u8; 4] = b"RTBK";
+const BACKUP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TorrentBackupInfo {
+    pub pieces_tasks: VecDeque<PieceTask>,
+    pub chunks_tasks: VecDeque<ChunksTask>,
+    pub torrent: Torrent,
+    pub save_path: String,
+    pub pieces_done: usize,
+    pub status: DownloadStatus,
+    // Bytes uploaded and seconds spent seeding in all sessions
+    pub uploaded: u64,
+    pub seeding_secs: u64,
+    // Speed limits of this torrent, applied together with global ones
+    pub limits: RateLimits,
+    // Priorities of torrent files, empty if all are normal
+    pub file_priorities: Vec<FilePriority>,
+    // Pieces are downloaded in order
+    pub sequential: bool,
+}
+
+// Layout of first release, without version header
+mod v0 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct TorrentBackupInfo {
+        pub pieces_tasks: VecDeque<PieceTask>,
+        pub chunks_tasks: VecDeque<ChunksTask>,
+        pub torrent: Torrent,
+        pub save_path: String,
+        pub pieces_done: usize,
+        pub status: DownloadStatus,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Torrent {
+        pub tracker_url: String,
+        // first tracker of every announce-list tier
+        pub tracker_urls: Option<Vec<String>>,
+        pub info: TorrentInfo,
+        pub info_hash: Vec<u8>,
+    }
+
+    #[derive(Deserialize)]
+    pub enum DownloadStatus {
+        Resuming,
+        Downloading,
+        Paused,
+        Finished,
+        Error(String),
+    }
+
+    impl From<TorrentBackupInfo> for super::TorrentBackupInfo {
+        fn from(old: TorrentBackupInfo) -> Self {
+            let tracker_tiers = match old.torrent.tracker_urls {
+                Some(urls) => urls.into_iter().map(|x| vec![x]).collect(),
+                None if !old.torrent.tracker_url.is_empty() => {
+                    vec![vec![old.torrent.tracker_url.clone()]]
+                }
+                None => Vec::new(),
+            };
+            let status = match old.status {
+                DownloadStatus::Resuming => super::DownloadStatus::Resuming,
+                DownloadStatus::Downloading => super::DownloadStatus::Downloading,
+                DownloadStatus::Paused => super::DownloadStatus::Paused,
+                DownloadStatus::Finished => super::DownloadStatus::Finished,
+                DownloadStatus::Error(e) => super::DownloadStatus::Error(e),
+            };
+            super::TorrentBackupInfo {
+                pieces_tasks: old.pieces_tasks,
+                chunks_tasks: old.chunks_tasks,
+                torrent: super::Torrent {
+                    tracker_url: old.torrent.tracker_url,
+                    tracker_tiers,
+                    info: old.torrent.info,
+                    info_hash: old.torrent.info_hash,
+                },
+                save_path: old.save_path,
+                pieces_done: old.pieces_done,
+                status,
+                uploaded: 0,
+                seeding_secs: 0,
+                limits: RateLimits::default(),
+                file_priorities: Vec::new(),
+                sequential: false,
+            }
+        }
+    }
+}
+
+fn encode_backups(backups: &Vec<TorrentBackupInfo>) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = BACKUP_MAGIC.to_vec();
+    bytes.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&bincode::serialize(backups)?);
+    Ok(bytes)
+}
+
+fn decode_backups(bytes: &[u8]) -> anyhow::Result<Vec<TorrentBackupInfo>> {
+    let Some(data) = bytes.strip_prefix(BACKUP_MAGIC.as_slice()) else {
+        let backups: Vec<v0::TorrentBackupInfo> = bincode::deserialize(bytes)?;
+        return Ok(backups.into_iter().map(Into::into).collect());
+    };
+    let Some((version, data)) = data.split_first_chunk::<4>() else {
+        anyhow::bail!("Backup without version");
+    };
+    match u32::from_le_bytes(*version) {
+        BACKUP_VERSION => Ok(bincode::deserialize(data)?),
+        version => anyhow::bail!("Unsupported backup version {version}"),
+    }
+}
 
 #[derive(Debug)]
 pub struct Backup {
@@ -17,9 +137,14 @@ pub struct Backup {
 }
 
 fn get_conf_path() -> anyhow::Result<PathBuf> {
+    data_file_path(BACKUP_NAME)
+}
+
+// Path of file stored in app data folder next to backup
+pub fn data_file_path(name: &str) -> anyhow::Result<PathBuf> {
     let config_dir = data_local_dir();
     if let Some(dir) = config_dir {
-        return Ok(dir.to_owned().join(BACKUP_NAME));
+        return Ok(dir.to_owned().join(name));
     }
     log!(LogLevel::Error, "Could not get config folder path");
     anyhow::bail!("Failed to get config folder path");
@@ -45,7 +170,7 @@ impl Backup {
         let mut file = File::open(path)?;
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
-        Ok(bincode::deserialize(&bytes)?)
+        decode_backups(&bytes)
     }
     
     pub async fn backup_torrent(&self, data: TorrentBackupInfo) -> anyhow::Result<()> {
@@ -59,7 +184,7 @@ impl Backup {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
     
-        let mut backups: Vec<TorrentBackupInfo> = bincode::deserialize(&bytes).unwrap_or_else(|_| {
+        let mut backups = decode_backups(&bytes).unwrap_or_else(|_| {
             log!(
                 LogLevel::Error,
                 "Backup file is damaged, it will be recreated"
@@ -82,7 +207,7 @@ impl Backup {
             backups.push(data);
         }
     
-        let bytes = bincode::serialize(&backups)?;
+        let bytes = encode_backups(&backups)?;
     
         file.seek(std::io::SeekFrom::Start(0))?;
         file.write_all(&bytes)?;
@@ -98,7 +223,7 @@ impl Backup {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
     
-        let backups: Vec<TorrentBackupInfo> = bincode::deserialize(&bytes)?;
+        let backups = decode_backups(&bytes)?;
     
         for backup in backups {
             if backup.torrent.info_hash == *info_hash {
@@ -120,7 +245,7 @@ impl Backup {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
     
-        let mut backups: Vec<TorrentBackupInfo> = bincode::deserialize(&bytes).unwrap_or_else(|_| {
+        let mut backups = decode_backups(&bytes).unwrap_or_else(|_| {
             log!(
                 LogLevel::Error,
                 "Backup file is damaged, it will be recreated"
@@ -139,7 +264,7 @@ impl Backup {
     
         if let Some(i) = delete_i {
             backups.remove(i);
-            let bytes = bincode::serialize(&backups)?;
+            let bytes = encode_backups(&backups)?;
     
             file.seek(std::io::SeekFrom::Start(0))?;
             file.write_all(&bytes)?;
@@ -149,3 +274,87 @@ impl Backup {
         Ok(())
     }   
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two torrents saved by first release: one with announce-list and
+    // finished, other with files and error status
+    const BACKUP_V0: [u8; 432] = [
+        2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        1, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        1, 0, 2, 0, 1, 12, 0, 0, 0, 0, 0, 0, 0, 104, 116, 116,
+        112, 58, 47, 47, 97, 47, 97, 110, 110, 1, 2, 0, 0, 0, 0, 0,
+        0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 104, 116, 116, 112, 58, 47,
+        47, 97, 47, 97, 110, 110, 10, 0, 0, 0, 0, 0, 0, 0, 117, 100,
+        112, 58, 47, 47, 98, 58, 56, 48, 64, 156, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 102, 0, 128, 0, 0, 0, 0,
+        0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0, 0, 0,
+        0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 20, 0, 0, 0, 0, 0, 0, 0, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 20, 0, 0, 0, 0, 0, 0, 0, 3, 3, 3, 3, 3, 3,
+        3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 2, 0,
+        0, 0, 0, 0, 0, 0, 47, 100, 1, 0, 0, 0, 0, 0, 0, 0,
+        3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 104, 116, 116, 112,
+        58, 47, 47, 99, 47, 97, 110, 110, 0, 5, 0, 0, 0, 0, 0, 0,
+        0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0,
+        0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 120, 47, 121, 1, 0, 0,
+        0, 0, 0, 0, 0, 103, 0, 64, 0, 0, 0, 0, 0, 0, 1, 0,
+        0, 0, 0, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 4, 4,
+        4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4,
+        4, 4, 20, 0, 0, 0, 0, 0, 0, 0, 5, 5, 5, 5, 5, 5,
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 2, 0,
+        0, 0, 0, 0, 0, 0, 47, 101, 0, 0, 0, 0, 0, 0, 0, 0,
+        4, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 100, 105, 115, 107,
+    ];
+
+    #[test]
+    fn first_release_backup_is_read() {
+        let backups = decode_backups(&BACKUP_V0).unwrap();
+        assert_eq!(backups.len(), 2);
+
+        let first = &backups[0];
+        assert_eq!(first.pieces_tasks[0].piece_i, 1);
+        assert_eq!(first.chunks_tasks[0].chunks, 1..2);
+        assert_eq!(
+            first.torrent.tracker_tiers,
+            vec![vec!["http://a/ann".to_string()], vec!["udp://b:80".to_string()]]
+        );
+        assert_eq!(first.torrent.info.piece_hashes, vec![vec![1; 20], vec![2; 20]]);
+        assert_eq!(first.torrent.info_hash, vec![3; 20]);
+        assert_eq!(first.save_path, "/d");
+        assert_eq!(first.pieces_done, 1);
+        assert!(matches!(first.status, DownloadStatus::Finished));
+        assert_eq!(first.uploaded, 0);
+        assert!(first.file_priorities.is_empty());
+
+        let second = &backups[1];
+        assert_eq!(second.torrent.tracker_tiers, vec![vec!["http://c/ann".to_string()]]);
+        assert_eq!(second.torrent.info.files.as_ref().unwrap()[0].path, "x/y");
+        assert!(matches!(second.status, DownloadStatus::Error(ref e) if e == "disk"));
+    }
+
+    #[test]
+    fn backup_round_trip() {
+        let mut backups = decode_backups(&BACKUP_V0).unwrap();
+        backups[0].status = DownloadStatus::Seeding;
+        backups[0].uploaded = 100;
+        backups[0].sequential = true;
+        let bytes = encode_backups(&backups).unwrap();
+        assert!(bytes.starts_with(BACKUP_MAGIC));
+
+        let backups = decode_backups(&bytes).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(matches!(backups[0].status, DownloadStatus::Seeding));
+        assert_eq!(backups[0].uploaded, 100);
+        assert!(backups[0].sequential);
+
+        let mut future = bytes.clone();
+        future[4] = 2;
+        assert!(decode_backups(&future).is_err());
+        assert!(decode_backups(BACKUP_MAGIC).is_err());
+    }
+}
@@ -16,6 +16,9 @@ pub enum BencodeValue {
 
 impl BencodeValue {
     pub fn decode_bencoded_value(mut encoded_value: &[u8]) -> anyhow::Result<(Self, usize)> {
+        if encoded_value.is_empty() {
+            anyhow::bail!("Invalid bencoded value, input is empty")
+        }
         match encoded_value[0] {
             x if x.is_ascii_digit() => {
                 let colon_index = if let Some(e) = encoded_value.iter().position(|b| *b == b':') {
@@ -24,11 +27,14 @@ impl BencodeValue {
                     anyhow::bail!("Invalid bencoded value, can't process it")
                 };
                 let number_string = &encoded_value[..colon_index];
-                let number = std::str::from_utf8(number_string)?.parse::<i64>()?;
-                let string = &encoded_value[colon_index + 1..colon_index + 1 + number as usize];
+                let number = std::str::from_utf8(number_string)?.parse::<usize>()?;
+                if colon_index + 1 + number > encoded_value.len() {
+                    anyhow::bail!("Invalid bencoded value, string is too short")
+                }
+                let string = &encoded_value[colon_index + 1..colon_index + 1 + number];
                 return Ok((
                     BencodeValue::Bytes(string.into()),
-                    colon_index + 1 + number as usize,
+                    colon_index + 1 + number,
                 ));
             }
             b'l' => {
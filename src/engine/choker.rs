@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use tokio::task::JoinHandle;
+
+use super::logger::{log, LogLevel};
+
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// Optimistic unchoke is rotated every third round (30 seconds)
+const OPTIMISTIC_ROUNDS: u32 = 3;
+// Peers we upload to, including optimistic one
+const UPLOAD_SLOTS: usize = 4;
+
+#[derive(Debug, Default)]
+struct PeerTransfer {
+    interested: bool,
+    // bytes of current round
+    downloaded: u64,
+    uploaded: u64,
+    // bytes of last finished round, used for ranking
+    last_downloaded: u64,
+    last_uploaded: u64,
+}
+
+#[derive(Debug, Default)]
+struct ChokerState {
+    peers: HashMap<SocketAddr, PeerTransfer>,
+    unchoked: HashSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+}
+
+// Tit-for-tat choker of one torrent: peers that give us the most get upload
+// slots (peers that take the most when seeding), one slot is given to random
+// peer so new peers get a chance. Peers apply decision with Peer::sync_choke
+#[derive(Debug, Default)]
+pub struct Choker {
+    state: Mutex<ChokerState>,
+    seeding: AtomicBool,
+}
+
+impl Choker {
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let choker = self.clone();
+        tokio::spawn(async move {
+            let mut round = 0;
+            loop {
+                tokio::time::sleep(CHOKE_INTERVAL).await;
+                round += 1;
+                choker.run_round(round % OPTIMISTIC_ROUNDS == 0);
+            }
+        })
+    }
+
+    pub fn set_seeding(&self, seeding: bool) {
+        self.seeding.store(seeding, Ordering::Relaxed);
+    }
+
+    pub fn set_interested(&self, addr: &SocketAddr, interested: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.entry(*addr).or_default().interested = interested;
+        if !interested {
+            return;
+        }
+        // free slots are given right away, not on the next round
+        if state.unchoked.len() < UPLOAD_SLOTS {
+            state.unchoked.insert(*addr);
+        }
+    }
+
+    pub fn add_downloaded(&self, addr: &SocketAddr, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.entry(*addr).or_default().downloaded += n;
+    }
+
+    pub fn add_uploaded(&self, addr: &SocketAddr, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.entry(*addr).or_default().uploaded += n;
+    }
+
+    pub fn is_unchoked(&self, addr: &SocketAddr) -> bool {
+        self.state.lock().unwrap().unchoked.contains(addr)
+    }
+
+    pub fn peer_removed(&self, addr: &SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.remove(addr);
+        state.unchoked.remove(addr);
+        if state.optimistic == Some(*addr) {
+            state.optimistic = None;
+        }
+    }
+
+    fn run_round(&self, rotate_optimistic: bool) {
+        let seeding = self.seeding.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        for peer in state.peers.values_mut() {
+            peer.last_downloaded = std::mem::take(&mut peer.downloaded);
+            peer.last_uploaded = std::mem::take(&mut peer.uploaded);
+        }
+
+        let mut interested: Vec<(SocketAddr, u64)> = state
+            .peers
+            .iter()
+            .filter(|(_, x)| x.interested)
+            .map(|(addr, x)| {
+                let rate = if seeding {
+                    x.last_uploaded
+                } else {
+                    x.last_downloaded
+                };
+                (*addr, rate)
+            })
+            .collect();
+        interested.sort_by(|a, b| b.1.cmp(&a.1));
+        let mut unchoked: HashSet<SocketAddr> = interested
+            .iter()
+            .take(UPLOAD_SLOTS - 1)
+            .map(|x| x.0)
+            .collect();
+
+        let keep_optimistic = state
+            .optimistic
+            .is_some_and(|x| state.peers.get(&x).is_some_and(|x| x.interested));
+        if rotate_optimistic || !keep_optimistic {
+            let candidates: Vec<SocketAddr> = interested
+                .iter()
+                .map(|x| x.0)
+                .filter(|x| !unchoked.contains(x))
+                .collect();
+            state.optimistic = candidates.choose(&mut rand::thread_rng()).copied();
+        }
+        if let Some(optimistic) = state.optimistic {
+            unchoked.insert(optimistic);
+        }
+        log!(
+            LogLevel::Debug,
+            "Choker round: {} interested, unchoked {:?}",
+            interested.len(),
+            unchoked
+        );
+        state.unchoked = unchoked;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    #[test]
+    fn free_slots_are_given_right_away() {
+        let choker = Choker::default();
+        for i in 0..6 {
+            choker.set_interested(&addr(i), true);
+        }
+        let unchoked: Vec<u8> = (0..6).filter(|i| choker.is_unchoked(&addr(*i))).collect();
+        assert_eq!(unchoked, vec![0, 1, 2, 3]);
+
+        choker.peer_removed(&addr(0));
+        assert!(!choker.is_unchoked(&addr(0)));
+        choker.set_interested(&addr(5), true);
+        assert!(choker.is_unchoked(&addr(5)));
+    }
+
+    #[test]
+    fn best_peers_are_unchoked() {
+        let choker = Choker::default();
+        for i in 0..8 {
+            choker.set_interested(&addr(i), true);
+            choker.add_downloaded(&addr(i), i as u64 * 1000);
+            // upload order is reversed
+            choker.add_uploaded(&addr(i), (8 - i) as u64 * 1000);
+        }
+        choker.set_interested(&addr(7), false);
+        choker.run_round(true);
+        for i in [4, 5, 6] {
+            assert!(choker.is_unchoked(&addr(i)));
+        }
+        assert!(!choker.is_unchoked(&addr(7)));
+        let state = choker.state.lock().unwrap();
+        assert_eq!(state.unchoked.len(), UPLOAD_SLOTS);
+        let optimistic = state.optimistic.unwrap();
+        assert!(state.unchoked.contains(&optimistic));
+        assert!(![4, 5, 6, 7].map(addr).contains(&optimistic));
+    }
+
+    #[test]
+    fn seeder_ranks_by_upload() {
+        let choker = Choker::default();
+        choker.set_seeding(true);
+        for i in 0..8 {
+            choker.set_interested(&addr(i), true);
+            choker.add_downloaded(&addr(i), i as u64 * 1000);
+            choker.add_uploaded(&addr(i), (8 - i) as u64 * 1000);
+        }
+        choker.run_round(true);
+        for i in [0, 1, 2] {
+            assert!(choker.is_unchoked(&addr(i)));
+        }
+        // counters of finished round are not used again
+        choker.run_round(false);
+        let state = choker.state.lock().unwrap();
+        assert!(state.peers.values().all(|x| x.last_uploaded == 0));
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use super::api;
+use super::logger::{log, LogLevel};
+use super::session::SessionHandle;
+use super::settings::Settings;
+
+// Running client (GUI or daemon) accepts control requests here, one JSON object per line
+pub const CONTROL_PORT: u16 = 6683;
+
+// Every line carries API token, so web pages posting to the port can't drive it
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope<R> {
+    token: String,
+    #[serde(flatten)]
+    request: R,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Request {
+    // source is path of torrent file or magnet link
+    Add { source: String, dest: String },
+    List,
+    Info { hash: String },
+    Pause { hash: String },
+    Resume { hash: String },
+    Verify { hash: String },
+    Remove {
+        hash: String,
+        #[serde(default)]
+        delete_data: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<anyhow::Result<serde_json::Value>> for Reply {
+    fn from(res: anyhow::Result<serde_json::Value>) -> Self {
+        match res {
+            Ok(result) => Reply {
+                ok: true,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => Reply {
+                ok: false,
+                result: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+pub fn spawn_control_server(session: SessionHandle) -> JoinHandle<()> {
+    api::api_token();
+    tokio::spawn(async move {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, CONTROL_PORT));
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to bind control socket {addr}: {e}");
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Accepting control requests on {addr}");
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log!(LogLevel::Error, "Failed to accept control client: {e}");
+                    continue;
+                }
+            };
+            let session = session.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(socket, session).await {
+                    log!(LogLevel::Debug, "Control client {addr} closed: {e}");
+                }
+            });
+        }
+    })
+}
+
+// Connection is closed after first invalid or unauthorized line
+async fn handle_client(socket: TcpStream, session: SessionHandle) -> anyhow::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let envelope = match serde_json::from_str::<Envelope<Request>>(&line) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                let msg = format!("Invalid request: {e}");
+                write_reply(&mut writer, Reply::from(Err(anyhow::anyhow!(msg.clone())))).await?;
+                anyhow::bail!(msg);
+            }
+        };
+        if !api::token_matches(&envelope.token) {
+            write_reply(&mut writer, Reply::from(Err(anyhow::anyhow!("Wrong API token")))).await?;
+            anyhow::bail!("Wrong API token");
+        }
+        let reply = Reply::from(execute(envelope.request, &session).await);
+        write_reply(&mut writer, reply).await?;
+    }
+    Ok(())
+}
+
+async fn write_reply(writer: &mut (impl AsyncWriteExt + Unpin), reply: Reply) -> anyhow::Result<()> {
+    let mut bytes = serde_json::to_vec(&reply)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn execute(req: Request, session: &SessionHandle) -> anyhow::Result<serde_json::Value> {
+    Ok(match req {
+        Request::Add { source, dest } => serde_json::json!(session.add_source(&source, dest).await?),
+        Request::List => serde_json::to_value(session.list().await?)?,
+        Request::Info { hash } => serde_json::to_value(session.info(hash).await?)?,
+        Request::Pause { hash } => {
+            session.pause(hash).await?;
+            serde_json::Value::Null
+        }
+        Request::Resume { hash } => {
+            session.resume(hash).await?;
+            serde_json::Value::Null
+        }
+        Request::Verify { hash } => {
+            session.verify(hash).await?;
+            serde_json::Value::Null
+        }
+        Request::Remove { hash, delete_data } => {
+            session.remove(hash, delete_data).await?;
+            serde_json::Value::Null
+        }
+    })
+}
+
+// Sends request to running client, fails if no client listens.
+// Token is taken from settings, so they have to be initialized
+pub async fn send(req: &Request) -> anyhow::Result<serde_json::Value> {
+    let socket = TcpStream::connect((Ipv4Addr::LOCALHOST, CONTROL_PORT)).await?;
+    let (reader, mut writer) = socket.into_split();
+    let envelope = Envelope {
+        token: Settings::get().api_token.unwrap_or_default(),
+        request: req,
+    };
+    let mut bytes = serde_json::to_vec(&envelope)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    let Some(line) = BufReader::new(reader).lines().next_line().await? else {
+        anyhow::bail!("Client closed control connection");
+    };
+    let reply: Reply = serde_json::from_str(&line)?;
+    match reply.error {
+        Some(e) if !reply.ok => anyhow::bail!(e),
+        _ => Ok(reply.result.unwrap_or_default()),
+    }
+}
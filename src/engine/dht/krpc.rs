@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use super::super::bencode::BencodeValue;
+use super::routing::Node;
+use super::NodeId;
+
+// KRPC error codes
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_UNKNOWN_METHOD: i64 = 204;
+
+#[derive(Debug)]
+pub enum KrpcMessage {
+    Query {
+        tid: Vec<u8>,
+        method: String,
+        args: BencodeValue,
+    },
+    Response {
+        tid: Vec<u8>,
+        values: BencodeValue,
+    },
+    Error {
+        tid: Vec<u8>,
+        code: i64,
+        msg: String,
+    },
+}
+
+impl KrpcMessage {
+    pub fn decode(buf: &[u8]) -> anyhow::Result<Self> {
+        let (msg, _) = BencodeValue::decode_bencoded_value(buf)?;
+        let BencodeValue::Bytes(ref tid) = msg["t"] else {
+            anyhow::bail!("KRPC message without transaction id");
+        };
+        let tid = tid.clone();
+        match msg["y"].to_lossy_string().as_str() {
+            "q" => Ok(KrpcMessage::Query {
+                tid,
+                method: msg["q"].to_lossy_string(),
+                args: msg["a"].clone(),
+            }),
+            "r" => Ok(KrpcMessage::Response {
+                tid,
+                values: msg["r"].clone(),
+            }),
+            "e" => {
+                // error list comes from network, it can be shorter than expected
+                let error = match msg["e"] {
+                    BencodeValue::List(ref list) => list.as_slice(),
+                    _ => &[],
+                };
+                let code = match error.first() {
+                    Some(BencodeValue::Num(code)) => *code,
+                    _ => ERROR_GENERIC,
+                };
+                Ok(KrpcMessage::Error {
+                    tid,
+                    code,
+                    msg: error.get(1).map(|x| x.to_lossy_string()).unwrap_or_default(),
+                })
+            }
+            _ => anyhow::bail!("Unknown KRPC message type"),
+        }
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut dict = HashMap::new();
+        match self {
+            KrpcMessage::Query { tid, method, args } => {
+                dict.insert("t".to_string(), BencodeValue::Bytes(tid.clone()));
+                dict.insert("y".to_string(), BencodeValue::Bytes(b"q".to_vec()));
+                dict.insert(
+                    "q".to_string(),
+                    BencodeValue::Bytes(method.as_bytes().to_vec()),
+                );
+                dict.insert("a".to_string(), args.clone());
+            }
+            KrpcMessage::Response { tid, values } => {
+                dict.insert("t".to_string(), BencodeValue::Bytes(tid.clone()));
+                dict.insert("y".to_string(), BencodeValue::Bytes(b"r".to_vec()));
+                dict.insert("r".to_string(), values.clone());
+            }
+            KrpcMessage::Error { tid, code, msg } => {
+                dict.insert("t".to_string(), BencodeValue::Bytes(tid.clone()));
+                dict.insert("y".to_string(), BencodeValue::Bytes(b"e".to_vec()));
+                dict.insert(
+                    "e".to_string(),
+                    BencodeValue::List(vec![
+                        BencodeValue::Num(*code),
+                        BencodeValue::Bytes(msg.as_bytes().to_vec()),
+                    ]),
+                );
+            }
+        }
+        let mut buf = Vec::new();
+        BencodeValue::Dict(dict).encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// Reading 20 byte value (node id, target or info hash) from arguments
+pub fn get_hash(dict: &BencodeValue, key: &str) -> Option<NodeId> {
+    let BencodeValue::Bytes(ref hash) = dict[key] else {
+        return None;
+    };
+    hash.as_slice().try_into().ok()
+}
+
+// Compact node info: 20 bytes of id + 6 bytes of IPv4 address
+pub fn encode_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for node in nodes {
+        if let SocketAddr::V4(addr) = node.addr {
+            buf.extend_from_slice(&node.id);
+            buf.extend_from_slice(&encode_peer(&addr));
+        }
+    }
+    buf
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(26)
+        .map(|x| {
+            let id: NodeId = x[..20].try_into().expect("Slice has length of 20");
+            Node::new(id, SocketAddr::V4(decode_peer(&x[20..])))
+        })
+        .collect()
+}
+
+pub fn encode_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut buf = addr.ip().octets().to_vec();
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+fn decode_peer(bytes: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    )
+}
+
+// "values" list of get_peers response
+pub fn decode_peers(values: &BencodeValue) -> Vec<SocketAddr> {
+    let mut res = Vec::new();
+    if let BencodeValue::List(list) = values {
+        for value in list {
+            if let BencodeValue::Bytes(bytes) = value {
+                if bytes.len() == 6 {
+                    res.push(SocketAddr::V4(decode_peer(bytes)));
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(values: &[(&str, BencodeValue)]) -> BencodeValue {
+        BencodeValue::Dict(
+            values
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    fn round_trip(msg: KrpcMessage) -> KrpcMessage {
+        KrpcMessage::decode(&msg.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let msg = KrpcMessage::Query {
+            tid: b"aa".to_vec(),
+            method: "get_peers".to_string(),
+            args: dict(&[
+                ("id", BencodeValue::Bytes(vec![1; 20])),
+                ("info_hash", BencodeValue::Bytes(vec![2; 20])),
+            ]),
+        };
+        let KrpcMessage::Query { tid, method, args } = round_trip(msg) else {
+            panic!("Query is decoded as other message");
+        };
+        assert_eq!(tid, b"aa");
+        assert_eq!(method, "get_peers");
+        assert_eq!(get_hash(&args, "id"), Some([1; 20]));
+        assert_eq!(get_hash(&args, "info_hash"), Some([2; 20]));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let msg = KrpcMessage::Response {
+            tid: b"bb".to_vec(),
+            values: dict(&[
+                ("id", BencodeValue::Bytes(vec![3; 20])),
+                ("token", BencodeValue::Bytes(b"secret".to_vec())),
+            ]),
+        };
+        let KrpcMessage::Response { tid, values } = round_trip(msg) else {
+            panic!("Response is decoded as other message");
+        };
+        assert_eq!(tid, b"bb");
+        assert_eq!(get_hash(&values, "id"), Some([3; 20]));
+        assert_eq!(values["token"].to_lossy_string(), "secret");
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let msg = KrpcMessage::Error {
+            tid: b"cc".to_vec(),
+            code: ERROR_PROTOCOL,
+            msg: "Bad token".to_string(),
+        };
+        let KrpcMessage::Error { tid, code, msg } = round_trip(msg) else {
+            panic!("Error is decoded as other message");
+        };
+        assert_eq!(tid, b"cc");
+        assert_eq!(code, ERROR_PROTOCOL);
+        assert_eq!(msg, "Bad token");
+    }
+
+    #[test]
+    fn short_error_list() {
+        let KrpcMessage::Error { code, msg, .. } = KrpcMessage::decode(b"d1:ele1:t2:aa1:y1:ee").unwrap()
+        else {
+            panic!("Error is decoded as other message");
+        };
+        assert_eq!(code, ERROR_GENERIC);
+        assert_eq!(msg, "");
+        assert!(KrpcMessage::decode(b"d1:ei5e1:t2:aa1:y1:ee").is_ok());
+        assert!(KrpcMessage::decode(b"d1:y1:qe").is_err());
+    }
+
+    #[test]
+    fn nodes_and_peers_round_trip() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let nodes = vec![Node::new([7; 20], SocketAddr::V4(addr))];
+        let decoded = decode_nodes(&encode_nodes(&nodes));
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].id, [7; 20]);
+        assert_eq!(decoded[0].addr, SocketAddr::V4(addr));
+
+        let values = BencodeValue::List(vec![
+            BencodeValue::Bytes(encode_peer(&addr)),
+            BencodeValue::Bytes(vec![1, 2, 3]),
+        ]);
+        assert_eq!(decode_peers(&values), vec![SocketAddr::V4(addr)]);
+    }
+}
@@ -0,0 +1,733 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+use super::backup;
+use super::bencode::BencodeValue;
+use super::download::DataPiece;
+use super::listener::LISTEN_PORT;
+use super::logger::{log, LogLevel};
+use super::peers::Peer;
+use super::torrent::Torrent;
+use super::DownloadEvents;
+use krpc::KrpcMessage;
+use routing::{distance, Node, RoutingTable, K};
+
+mod krpc;
+mod routing;
+
+pub type NodeId = [u8; 20];
+
+static DHT: OnceCell<Arc<Dht>> = OnceCell::new();
+
+const NODES_FILE: &str = "dht_nodes.bin";
+const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_LOOKUP_ROUNDS: usize = 8;
+// With less nodes than that table is bootstrapped again
+const MIN_NODES: usize = 16;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const SEARCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_IN_RESP: usize = 50;
+// Bounds of announced peers storage
+const MAX_STORED_HASHES: usize = 2000;
+const MAX_PEERS_PER_HASH: usize = 200;
+
+// Own id and known nodes, saved next to backup file
+#[derive(Debug, Serialize, Deserialize)]
+struct DhtState {
+    id: NodeId,
+    nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+#[derive(Debug)]
+struct TokenSecrets {
+    current: NodeId,
+    previous: NodeId,
+    rotated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct LookupResp {
+    token: Option<Vec<u8>>,
+    peers: Vec<SocketAddr>,
+    nodes: Vec<Node>,
+}
+
+// Mainline DHT node (BEP 5)
+#[derive(Debug)]
+pub struct Dht {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    // queries waiting for response, by transaction id
+    pending: Mutex<HashMap<Vec<u8>, oneshot::Sender<anyhow::Result<BencodeValue>>>>,
+    // peers announced to us, by info hash
+    storage: Mutex<HashMap<NodeId, HashMap<SocketAddr, Instant>>>,
+    secrets: Mutex<TokenSecrets>,
+    next_tid: AtomicU16,
+}
+
+impl Dht {
+    pub fn global() -> Option<&'static Arc<Dht>> {
+        DHT.get()
+    }
+
+    // Binding node to address and starting to answer queries,
+    // several nodes can run in one process (e.g. on loopback)
+    pub async fn bind(addr: SocketAddr, id: Option<NodeId>) -> anyhow::Result<Arc<Dht>> {
+        let socket = UdpSocket::bind(addr).await?;
+        let id = id.unwrap_or_else(rand::random);
+        let dht = Arc::new(Dht {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            storage: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(TokenSecrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            next_tid: AtomicU16::new(rand::random()),
+        });
+        tokio::spawn(dht.clone().receive_loop());
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn nodes_n(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    // Pinging given nodes and filling routing table with nodes close to us
+    pub async fn bootstrap(self: &Arc<Self>, addrs: &[SocketAddr]) {
+        let mut tasks = JoinSet::new();
+        for addr in addrs {
+            let dht = self.clone();
+            let addr = *addr;
+            tasks.spawn(async move { dht.ping(addr).await });
+        }
+        while tasks.join_next().await.is_some() {}
+        self.lookup(self.id, false).await;
+        log!(LogLevel::Info, "DHT bootstrapped, {} nodes", self.nodes_n());
+    }
+
+    // Searching peers of torrent, if port is given we announce ourselves
+    // to the closest nodes
+    pub async fn find_peers(
+        self: &Arc<Self>,
+        info_hash: &[u8],
+        port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let Ok(target) = NodeId::try_from(info_hash) else {
+            return Vec::new();
+        };
+        let (peers, tokens) = self.lookup(target, true).await;
+        if let Some(port) = port {
+            let mut tasks = JoinSet::new();
+            for (node, token) in tokens.into_iter().take(K) {
+                let dht = self.clone();
+                tasks
+                    .spawn(async move { dht.announce_peer(node.addr, &target, port, token).await });
+            }
+            while tasks.join_next().await.is_some() {}
+        }
+        peers
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        let resp = self.query(addr, "ping", HashMap::new()).await?;
+        krpc::get_hash(&resp, "id").ok_or(anyhow::anyhow!("Ping response without id"))
+    }
+
+    pub async fn find_node(&self, addr: SocketAddr, target: &NodeId) -> anyhow::Result<Vec<Node>> {
+        let mut args = HashMap::new();
+        args.insert("target".to_string(), BencodeValue::Bytes(target.to_vec()));
+        let resp = self.query(addr, "find_node", args).await?;
+        match resp["nodes"] {
+            BencodeValue::Bytes(ref nodes) => Ok(krpc::decode_nodes(nodes)),
+            _ => anyhow::bail!("find_node response without nodes"),
+        }
+    }
+
+    async fn get_peers(&self, addr: SocketAddr, info_hash: &NodeId) -> anyhow::Result<LookupResp> {
+        let mut args = HashMap::new();
+        args.insert(
+            "info_hash".to_string(),
+            BencodeValue::Bytes(info_hash.to_vec()),
+        );
+        let resp = self.query(addr, "get_peers", args).await?;
+        Ok(LookupResp {
+            token: match resp["token"] {
+                BencodeValue::Bytes(ref token) => Some(token.clone()),
+                _ => None,
+            },
+            peers: krpc::decode_peers(&resp["values"]),
+            nodes: match resp["nodes"] {
+                BencodeValue::Bytes(ref nodes) => krpc::decode_nodes(nodes),
+                _ => Vec::new(),
+            },
+        })
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: &NodeId,
+        port: u16,
+        token: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut args = HashMap::new();
+        args.insert(
+            "info_hash".to_string(),
+            BencodeValue::Bytes(info_hash.to_vec()),
+        );
+        args.insert("port".to_string(), BencodeValue::Num(port as i64));
+        args.insert("token".to_string(), BencodeValue::Bytes(token));
+        self.query(addr, "announce_peer", args).await?;
+        Ok(())
+    }
+
+    // Iterative search of nodes closest to target, returns found peers
+    // and tokens of nodes that answered get_peers sorted by distance
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        get_peers: bool,
+    ) -> (Vec<SocketAddr>, Vec<(Node, Vec<u8>)>) {
+        let mut shortlist = self.table.lock().unwrap().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+        let mut tokens = Vec::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let to_query: Vec<Node> = shortlist
+                .iter()
+                .filter(|x| !queried.contains(&x.id))
+                .cloned()
+                .collect();
+            if to_query.is_empty() {
+                break;
+            }
+            let mut tasks = JoinSet::new();
+            for node in to_query {
+                queried.insert(node.id);
+                let dht = self.clone();
+                tasks.spawn(async move {
+                    let resp = if get_peers {
+                        dht.get_peers(node.addr, &target).await
+                    } else {
+                        dht.find_node(node.addr, &target)
+                            .await
+                            .map(|nodes| LookupResp {
+                                nodes,
+                                ..Default::default()
+                            })
+                    };
+                    (node, resp)
+                });
+            }
+            while let Some(res) = tasks.join_next().await {
+                let Ok((node, resp)) = res else {
+                    continue;
+                };
+                match resp {
+                    Ok(resp) => {
+                        peers.extend(resp.peers);
+                        for new_node in resp.nodes {
+                            if new_node.id != self.id
+                                && !shortlist.iter().any(|x| x.id == new_node.id)
+                            {
+                                shortlist.push(new_node);
+                            }
+                        }
+                        if let Some(token) = resp.token {
+                            tokens.push((node, token));
+                        }
+                    }
+                    Err(e) => {
+                        log!(LogLevel::Debug, "DHT node {} failed: {e}", node.addr);
+                        self.table.lock().unwrap().remove(&node.id);
+                        shortlist.retain(|x| x.id != node.id);
+                    }
+                }
+            }
+            shortlist.sort_by_key(|x| distance(&x.id, &target));
+            shortlist.truncate(K);
+        }
+        tokens.sort_by_key(|(node, _)| distance(&node.id, &target));
+        (peers.into_iter().collect(), tokens)
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        mut args: HashMap<String, BencodeValue>,
+    ) -> anyhow::Result<BencodeValue> {
+        args.insert("id".to_string(), BencodeValue::Bytes(self.id.to_vec()));
+        let tid = self
+            .next_tid
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let msg = KrpcMessage::Query {
+            tid: tid.clone(),
+            method: method.to_string(),
+            args: BencodeValue::Dict(args),
+        }
+        .encode()?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(tid.clone(), sender);
+        if let Err(e) = self.socket.send_to(&msg, addr).await {
+            self.pending.lock().unwrap().remove(&tid);
+            return Err(e.into());
+        }
+        let res = timeout(QUERY_TIMEOUT, receiver).await;
+        self.pending.lock().unwrap().remove(&tid);
+        let resp = match res {
+            Ok(Ok(resp)) => resp?,
+            _ => anyhow::bail!("DHT query {method} to {addr} timed out"),
+        };
+        if let Some(id) = krpc::get_hash(&resp, "id") {
+            self.table.lock().unwrap().insert(Node::new(id, addr));
+        }
+        Ok(resp)
+    }
+
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buf = [0; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(e) => {
+                    log!(LogLevel::Debug, "DHT receive error: {e}");
+                    continue;
+                }
+            };
+            let msg = match KrpcMessage::decode(&buf[..len]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log!(LogLevel::Debug, "Invalid DHT message from {from}: {e}");
+                    continue;
+                }
+            };
+            match msg {
+                KrpcMessage::Query { tid, method, args } => {
+                    let resp = self.handle_query(from, tid, &method, &args);
+                    if let Ok(bytes) = resp.encode() {
+                        let _ = self.socket.send_to(&bytes, from).await;
+                    }
+                }
+                KrpcMessage::Response { tid, values } => {
+                    if let Some(sender) = self.pending.lock().unwrap().remove(&tid) {
+                        let _ = sender.send(Ok(values));
+                    }
+                }
+                KrpcMessage::Error { tid, code, msg } => {
+                    if let Some(sender) = self.pending.lock().unwrap().remove(&tid) {
+                        let _ = sender.send(Err(anyhow::anyhow!("DHT error {code}: {msg}")));
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_query(
+        &self,
+        from: SocketAddr,
+        tid: Vec<u8>,
+        method: &str,
+        args: &BencodeValue,
+    ) -> KrpcMessage {
+        let error = |code, msg: &str| KrpcMessage::Error {
+            tid: tid.clone(),
+            code,
+            msg: msg.to_string(),
+        };
+        let Some(id) = krpc::get_hash(args, "id") else {
+            return error(krpc::ERROR_PROTOCOL, "Missing id");
+        };
+        self.table.lock().unwrap().insert(Node::new(id, from));
+
+        let mut values = HashMap::new();
+        values.insert("id".to_string(), BencodeValue::Bytes(self.id.to_vec()));
+        match method {
+            "ping" => {}
+            "find_node" => {
+                let Some(target) = krpc::get_hash(args, "target") else {
+                    return error(krpc::ERROR_PROTOCOL, "Missing target");
+                };
+                let nodes = self.table.lock().unwrap().closest(&target, K);
+                values.insert(
+                    "nodes".to_string(),
+                    BencodeValue::Bytes(krpc::encode_nodes(&nodes)),
+                );
+            }
+            "get_peers" => {
+                let Some(info_hash) = krpc::get_hash(args, "info_hash") else {
+                    return error(krpc::ERROR_PROTOCOL, "Missing info_hash");
+                };
+                values.insert(
+                    "token".to_string(),
+                    BencodeValue::Bytes(self.make_token(&from, &self.secrets().current)),
+                );
+                let peers = self.stored_peers(&info_hash);
+                if peers.is_empty() {
+                    let nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                    values.insert(
+                        "nodes".to_string(),
+                        BencodeValue::Bytes(krpc::encode_nodes(&nodes)),
+                    );
+                } else {
+                    values.insert("values".to_string(), BencodeValue::List(peers));
+                }
+            }
+            "announce_peer" => {
+                let Some(info_hash) = krpc::get_hash(args, "info_hash") else {
+                    return error(krpc::ERROR_PROTOCOL, "Missing info_hash");
+                };
+                let BencodeValue::Bytes(ref token) = args["token"] else {
+                    return error(krpc::ERROR_PROTOCOL, "Missing token");
+                };
+                if !self.token_valid(&from, token) {
+                    return error(krpc::ERROR_PROTOCOL, "Bad token");
+                }
+                let port = match (&args["implied_port"], &args["port"]) {
+                    (BencodeValue::Num(1), _) => from.port(),
+                    (_, BencodeValue::Num(port @ 1..=65535)) => *port as u16,
+                    _ => return error(krpc::ERROR_PROTOCOL, "Missing port"),
+                };
+                if !self.store_peer(info_hash, SocketAddr::new(from.ip(), port)) {
+                    return error(krpc::ERROR_SERVER, "Storage is full");
+                }
+            }
+            _ => return error(krpc::ERROR_UNKNOWN_METHOD, "Method Unknown"),
+        }
+        KrpcMessage::Response {
+            tid,
+            values: BencodeValue::Dict(values),
+        }
+    }
+
+    // Oldest peer of full hash is replaced, new hash isn't stored when
+    // there are too many of them
+    fn store_peer(&self, info_hash: NodeId, addr: SocketAddr) -> bool {
+        let mut storage = self.storage.lock().unwrap();
+        if !storage.contains_key(&info_hash) && storage.len() >= MAX_STORED_HASHES {
+            prune_expired(&mut storage);
+            if storage.len() >= MAX_STORED_HASHES {
+                return false;
+            }
+        }
+        let peers = storage.entry(info_hash).or_default();
+        if !peers.contains_key(&addr) && peers.len() >= MAX_PEERS_PER_HASH {
+            let oldest = peers.iter().min_by_key(|(_, time)| **time).map(|(x, _)| *x);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, Instant::now());
+        true
+    }
+
+    // Called on refresh timer, hashes nobody asks for are cleaned too
+    fn prune_storage(&self) {
+        prune_expired(&mut self.storage.lock().unwrap());
+    }
+
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<BencodeValue> {
+        let mut storage = self.storage.lock().unwrap();
+        let Some(peers) = storage.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, time| time.elapsed() < PEER_TTL);
+        peers
+            .keys()
+            .filter_map(|x| match x {
+                SocketAddr::V4(addr) => Some(BencodeValue::Bytes(krpc::encode_peer(addr))),
+                SocketAddr::V6(_) => None,
+            })
+            .take(MAX_PEERS_IN_RESP)
+            .collect()
+    }
+
+    // Secrets are rotated, tokens made with previous one are still accepted
+    fn secrets(&self) -> MutexGuard<'_, TokenSecrets> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() > TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+            secrets.rotated = Instant::now();
+        }
+        secrets
+    }
+
+    fn make_token(&self, addr: &SocketAddr, secret: &NodeId) -> Vec<u8> {
+        let mut bytes = secret.to_vec();
+        match addr.ip() {
+            IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => bytes.extend_from_slice(&ip.octets()),
+        }
+        Torrent::bytes_hash(&bytes)
+    }
+
+    fn token_valid(&self, addr: &SocketAddr, token: &[u8]) -> bool {
+        let secrets = self.secrets();
+        self.make_token(addr, &secrets.current) == token
+            || self.make_token(addr, &secrets.previous) == token
+    }
+
+    pub fn save_state(&self) -> anyhow::Result<()> {
+        let state = DhtState {
+            id: self.id,
+            nodes: self
+                .table
+                .lock()
+                .unwrap()
+                .nodes()
+                .into_iter()
+                .map(|x| (x.id, x.addr))
+                .collect(),
+        };
+        let bytes = bincode::serialize(&state)?;
+        let mut file = File::create(backup::data_file_path(NODES_FILE)?)?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+fn load_state() -> anyhow::Result<DhtState> {
+    let mut file = File::open(backup::data_file_path(NODES_FILE)?)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+async fn bootstrap_addrs() -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for host in BOOTSTRAP_NODES {
+        match tokio::net::lookup_host(host).await {
+            Ok(res) => addrs.extend(res.filter(|x| x.is_ipv4())),
+            Err(e) => log!(LogLevel::Info, "Failed to resolve {host}: {e}"),
+        }
+    }
+    addrs
+}
+
+fn prune_expired(storage: &mut HashMap<NodeId, HashMap<SocketAddr, Instant>>) {
+    for peers in storage.values_mut() {
+        peers.retain(|_, time| time.elapsed() < PEER_TTL);
+    }
+    storage.retain(|_, peers| !peers.is_empty());
+}
+
+// Starting global DHT node on the same port number as peer listener
+pub fn spawn_dht() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let state = load_state().ok();
+        let addr = SocketAddr::from(([0, 0, 0, 0], LISTEN_PORT));
+        let dht = match Dht::bind(addr, state.as_ref().map(|x| x.id)).await {
+            Ok(dht) => dht,
+            Err(e) => {
+                log!(
+                    LogLevel::Error,
+                    "Failed to start DHT on port {LISTEN_PORT}: {e}"
+                );
+                return;
+            }
+        };
+        let _ = DHT.set(dht.clone());
+
+        let mut addrs: Vec<SocketAddr> = state
+            .map(|x| x.nodes.into_iter().map(|(_, addr)| addr).collect())
+            .unwrap_or_default();
+        loop {
+            if dht.nodes_n() < MIN_NODES {
+                addrs.extend(bootstrap_addrs().await);
+                dht.bootstrap(&addrs).await;
+                addrs.clear();
+            } else {
+                // refreshing buckets around own id
+                dht.lookup(dht.id, false).await;
+            }
+            dht.prune_storage();
+            if let Err(e) = dht.save_state() {
+                log!(LogLevel::Error, "Failed to save DHT nodes: {e}");
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    })
+}
+
+pub fn save_nodes() {
+    if let Some(dht) = Dht::global() {
+        if let Err(e) = dht.save_state() {
+            log!(LogLevel::Error, "Failed to save DHT nodes: {e}");
+        }
+    }
+}
+
+// Periodically asking DHT for peers of torrent and announcing ourselves,
+// connected peers are sent to worker as PeerAdd
+pub fn spawn_peer_search(
+    info_hash: Vec<u8>,
+    peer_id: String,
+    event_sender: Sender<DownloadEvents>,
+    data_sender: Sender<DataPiece>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let Some(dht) = Dht::global() else {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                continue;
+            };
+            let peers = dht.find_peers(&info_hash, Some(LISTEN_PORT)).await;
+            log!(LogLevel::Info, "DHT found {} peer(s)", peers.len());
+            for addr in peers {
+                let data_sender = data_sender.clone();
+                let event_sender = event_sender.clone();
+                let peer_id = peer_id.clone();
+                let info_hash = hex::encode(&info_hash);
+                tokio::spawn(async move {
+                    if let Ok(peer) = Peer::new(
+                        addr,
+                        data_sender,
+                        peer_id,
+                        info_hash,
+                        Duration::from_secs(2),
+                    )
+                    .await
+                    {
+                        log!(LogLevel::Info, "ok dht peer {:?}", peer.peer_addr);
+                        let _ = event_sender.send(DownloadEvents::PeerAdd(peer, true)).await;
+                    }
+                });
+            }
+            tokio::time::sleep(SEARCH_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES_N: usize = 8;
+
+    // Nodes on loopback, all bootstrapped from the first one
+    async fn spawn_nodes() -> Vec<Arc<Dht>> {
+        let mut nodes = Vec::new();
+        for _ in 0..NODES_N {
+            nodes.push(Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0)), None).await.unwrap());
+        }
+        let first = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            node.bootstrap(&[first]).await;
+        }
+        // first nodes learn about the ones bootstrapped after them
+        for node in &nodes {
+            node.lookup(node.id, false).await;
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn find_node_converges() {
+        let nodes = spawn_nodes().await;
+        for node in &nodes {
+            assert!(node.nodes_n() > 0);
+        }
+        let target = nodes[NODES_N - 1].id();
+        for node in &nodes[..NODES_N - 1] {
+            node.lookup(target, false).await;
+            let closest = node.table.lock().unwrap().closest(&target, 1);
+            assert_eq!(closest[0].id, target);
+        }
+    }
+
+    #[tokio::test]
+    async fn announced_peer_is_found() {
+        let nodes = spawn_nodes().await;
+        let info_hash: NodeId = rand::random();
+        nodes[1].find_peers(&info_hash, Some(7000)).await;
+        for node in &nodes[2..] {
+            let peers = node.find_peers(&info_hash, None).await;
+            assert!(peers.contains(&SocketAddr::from(([127, 0, 0, 1], 7000))));
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_is_bounded() {
+        let dht = Dht::bind(SocketAddr::from(([127, 0, 0, 1], 0)), None).await.unwrap();
+        let info_hash: NodeId = rand::random();
+        for port in 1..=MAX_PEERS_PER_HASH as u16 + 10 {
+            assert!(dht.store_peer(info_hash, SocketAddr::from(([10, 0, 0, 1], port))));
+        }
+        let peers = dht.storage.lock().unwrap()[&info_hash].clone();
+        assert_eq!(peers.len(), MAX_PEERS_PER_HASH);
+        // oldest ones were replaced
+        assert!(!peers.contains_key(&SocketAddr::from(([10, 0, 0, 1], 1))));
+
+        for _ in 1..MAX_STORED_HASHES {
+            assert!(dht.store_peer(rand::random(), SocketAddr::from(([10, 0, 0, 2], 1))));
+        }
+        assert!(!dht.store_peer(rand::random(), SocketAddr::from(([10, 0, 0, 2], 1))));
+        // known hash still gets peers
+        assert!(dht.store_peer(info_hash, SocketAddr::from(([10, 0, 0, 3], 1))));
+
+        // expired peers are pruned with their hashes
+        let Some(expired) = Instant::now().checked_sub(PEER_TTL) else {
+            return;
+        };
+        for peers in dht.storage.lock().unwrap().values_mut() {
+            peers.values_mut().for_each(|time| *time = expired);
+        }
+        dht.prune_storage();
+        assert!(dht.storage.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn announce_with_bad_token_is_rejected() {
+        let nodes = spawn_nodes().await;
+        let info_hash: NodeId = rand::random();
+        let addr = nodes[0].local_addr().unwrap();
+        let res = nodes[1]
+            .announce_peer(addr, &info_hash, 7001, b"bad token".to_vec())
+            .await;
+        assert!(res.is_err());
+        assert!(nodes[0].stored_peers(&info_hash).is_empty());
+
+        let token = nodes[1].get_peers(addr, &info_hash).await.unwrap().token.unwrap();
+        nodes[1].announce_peer(addr, &info_hash, 7001, token).await.unwrap();
+        assert_eq!(nodes[0].stored_peers(&info_hash).len(), 1);
+    }
+}
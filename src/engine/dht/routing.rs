@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::NodeId;
+
+// Bucket size
+pub const K: usize = 8;
+const ID_BITS: usize = 160;
+
+// Node that didn't answer for this time can be replaced with a new one
+const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn is_good(&self) -> bool {
+        self.last_seen.elapsed() < NODE_TIMEOUT
+    }
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut res = [0; 20];
+    for i in 0..20 {
+        res[i] = a[i] ^ b[i];
+    }
+    res
+}
+
+// Nodes are split into buckets by length of common prefix with own id
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable {
+            own_id,
+            buckets: vec![Vec::new(); ID_BITS],
+        }
+    }
+
+    fn bucket_i(&self, id: &NodeId) -> Option<usize> {
+        let dist = distance(&self.own_id, id);
+        let mut prefix = 0;
+        for byte in dist {
+            if byte == 0 {
+                prefix += 8;
+            } else {
+                prefix += byte.leading_zeros() as usize;
+                break;
+            }
+        }
+        if prefix >= ID_BITS {
+            // own id
+            None
+        } else {
+            Some(prefix)
+        }
+    }
+
+    // Adding node or refreshing it if it's already known
+    pub fn insert(&mut self, node: Node) {
+        let Some(bucket_i) = self.bucket_i(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[bucket_i];
+        if let Some(pos) = bucket.iter().position(|x| x.id == node.id) {
+            bucket[pos] = node;
+            return;
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(pos) = bucket.iter().position(|x| !x.is_good()) {
+            bucket[pos] = node;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(bucket_i) = self.bucket_i(id) {
+            self.buckets[bucket_i].retain(|x| x.id != *id);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+        nodes.sort_by_key(|x| distance(&x.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|x| x.len()).sum()
+    }
+}
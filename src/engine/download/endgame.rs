@@ -0,0 +1,56 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+// In endgame mode blocks that are still in flight are requested from several
+// peers at once. Downloaders record received blocks here, so the others
+// can cancel their requests of the same blocks
+#[derive(Debug)]
+pub struct Endgame {
+    active: AtomicBool,
+    // (piece index, chunk index)
+    received: Mutex<HashSet<(u16, u16)>>,
+    changed: watch::Sender<()>,
+}
+
+impl Endgame {
+    pub fn new() -> Self {
+        Endgame {
+            active: AtomicBool::new(false),
+            received: Mutex::new(HashSet::new()),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    pub fn activate(&self) {
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Notified every time any downloader receives block in endgame
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub fn chunk_received(&self, piece_i: u16, chunk_i: u16) {
+        if !self.is_active() {
+            return;
+        }
+        self.received.lock().unwrap().insert((piece_i, chunk_i));
+        self.changed.send_replace(());
+    }
+
+    pub fn is_received(&self, piece_i: u16, chunk_i: u16) -> bool {
+        self.received.lock().unwrap().contains(&(piece_i, chunk_i))
+    }
+
+    // Piece failed hash check and is downloaded again
+    pub fn forget_piece(&self, piece_i: u16) {
+        self.received.lock().unwrap().retain(|x| x.0 != piece_i);
+    }
+}
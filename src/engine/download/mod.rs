@@ -1,24 +1,41 @@
+pub mod endgame;
+pub mod picker;
+pub mod pipeline;
 pub mod tasks;
 
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::io::ErrorKind;
 use std::sync::Arc;
 use std::time::Duration;
 
 use tokio::sync::mpsc::Sender;
+use tokio::time::{sleep_until, timeout_at, Instant};
 
 use super::peers::{Peer, PeerMessage, PeerStatus};
 use super::torrent::Torrent;
 use super::DownloadEvents;
+use crate::engine::events::EngineEvent;
 use crate::engine::saver;
-use crate::gui::UiMsg;
 use crate::logger::{log, LogLevel};
+pub use endgame::Endgame;
+pub use picker::PiecePicker;
 pub use tasks::{ChunksTask, PieceTask};
 
+// Peer that has requests in flight but sends nothing for this time is dropped,
+// time spent waiting for rate limiter isn't counted
+const PIECE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Block of piece: piece index and chunk index
+type Block = (u16, u16);
+
+// Tasks are downloaded as one queue, so requests window stays full
+// when downloader moves to the next piece
 #[derive(Debug)]
 pub struct DownloadReq {
     pub torrent: Arc<Torrent>,
     pub peer: Peer,
-    pub task: ChunksTask,
+    pub tasks: Vec<ChunksTask>,
+    pub endgame: Arc<Endgame>,
 }
 pub struct DataPiece {
     pub buf: Vec<u8>,
@@ -27,11 +44,17 @@ pub struct DataPiece {
 }
 
 impl DownloadReq {
-    pub fn new(torrent: Arc<Torrent>, peer: Peer, task: ChunksTask) -> Self {
+    pub fn new(
+        torrent: Arc<Torrent>,
+        peer: Peer,
+        tasks: Vec<ChunksTask>,
+        endgame: Arc<Endgame>,
+    ) -> Self {
         DownloadReq {
             torrent,
             peer,
-            task,
+            tasks,
+            endgame,
         }
     }
 
@@ -39,7 +62,9 @@ impl DownloadReq {
         mut self,
         error_sender: Sender<DownloadEvents>,
     ) -> anyhow::Result<()> {
-        if let PeerStatus::NotConnected | PeerStatus::Choked = self.peer.status {
+        if let PeerStatus::NotConnected | PeerStatus::Handshaked | PeerStatus::Choked =
+            self.peer.status
+        {
             log!(LogLevel::Debug, "Peer is not ready for downloading yet");
             if let Err(e) = self.peer.connect(&self.torrent).await {
                 match e.downcast_ref::<std::io::Error>() {
@@ -83,88 +108,36 @@ impl DownloadReq {
                         let Some(save_info) = hashmap.get(&self.peer.info_hash) else {
                             anyhow::bail!("Peer: {} is removed", self.peer.peer_addr);
                         };
-                        let _ = save_info.ui_h.send_with_update(UiMsg::PeerDisconnect(self.peer.peer_addr.clone()));
+                        save_info.events.send(EngineEvent::PeerDisconnect(self.peer.peer_addr));
                         anyhow::bail!("Peer: {} is removed {e}", self.peer.peer_addr);
                     }
                 }
             };
         }
-        log!(
-            LogLevel::Debug,
-            "Downloading piece {}, chunks {:?}",
-            self.task.piece_i,
-            self.task.chunks
-        );
-        let mut begin = super::CHUNK_SIZE * self.task.chunks.start as u64;
-        for i in self.task.chunks.clone() {
-            let length = if i + 1 == self.task.chunks.end && self.task.includes_last_chunk {
-                if self.task.piece_i as usize == self.torrent.info.piece_hashes.len() - 1 {
-                    self.torrent.info.length
-                        - (self.torrent.info.piece_hashes.len() - 1) as u64
-                            * self.torrent.info.piece_length
-                        - i as u64 * super::CHUNK_SIZE
-                } else {
-                    self.torrent.info.piece_length - i as u64 * super::CHUNK_SIZE
-                }
-            } else {
-                super::CHUNK_SIZE
-            };
-            let mut buf = Vec::new();
-            buf.extend_from_slice(&(self.task.piece_i as u32).to_be_bytes());
-            buf.extend_from_slice(&(begin as u32).to_be_bytes());
-            buf.extend_from_slice(&(length as u32).to_be_bytes());
-            if let Err(e) = self.peer.send_message(&PeerMessage::Request(buf)).await {
-                log!(
-                    LogLevel::Error,
-                    "Failed to request download: {}, peers addr: {}",
-                    e,
-                    self.peer.peer_addr
-                );
-                match e.downcast_ref::<std::io::Error>() {
-                    Some(e) => {
-                        if let ErrorKind::BrokenPipe | ErrorKind::NotConnected = e.kind() {
-                            self.peer
-                                .reconnect(&self.torrent, Duration::from_secs(2))
-                                .await?;
-                            log!(LogLevel::Debug, "Reconnected to peer");
-                        } else {
-                            error_sender
-                                .send(DownloadEvents::PeerAdd(self.peer, false))
-                                .await?;
-                            anyhow::bail!("Peer error: {}", e)
-                        }
-                    }
-                    None => {
-                        error_sender
-                            .send(DownloadEvents::PeerAdd(self.peer, false))
-                            .await?;
-                        anyhow::bail!("Peer error: {}", e);
-                    }
-                }
-                error_sender
-                    .send(DownloadEvents::ChunksFail(self.task.clone()))
-                    .await?;
-                return Ok(());
-            }
-            begin += super::CHUNK_SIZE;
-            // tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        if let Err(e) = self.peer.send_extension_updates().await {
+            log!(
+                LogLevel::Debug,
+                "Failed to send extension messages: {e}, peer {}",
+                self.peer.peer_addr
+            );
         }
-        log!(LogLevel::Debug, "Sended all requests");
-        if let Err(e) = self
-            .peer
-            .wait_for_msg(
-                &PeerMessage::Piece(Vec::new()),
-                (self.task.chunks.end - self.task.chunks.start) as u32,
-                Some(Duration::from_secs(10)),
-            )
-            .await
-        {
+        log!(LogLevel::Debug, "Downloading tasks {:?}", self.tasks);
+        // blocks that aren't received yet
+        let mut remaining: BTreeSet<Block> = self
+            .tasks
+            .iter()
+            .flat_map(|x| x.chunks.clone().map(|chunk_i| (x.piece_i, chunk_i)))
+            .collect();
+        if let Err(e) = self.pipeline(&mut remaining).await {
             log!(
                 LogLevel::Error,
                 "Failed to download: {}, peer: {}",
                 e,
                 self.peer.peer_addr
             );
+            for task in self.remaining_tasks(&remaining) {
+                error_sender.send(DownloadEvents::ChunksFail(task)).await?;
+            }
             if let Some(e) = e.downcast_ref::<std::io::Error>() {
                 log!(LogLevel::Debug, "error:kind : {}", e.kind());
                 if let ErrorKind::BrokenPipe
@@ -173,15 +146,17 @@ impl DownloadReq {
                 | ErrorKind::ConnectionRefused
                 | ErrorKind::ConnectionReset = e.kind()
                 {
-                    self.peer
+                    // failed chunks are already returned, peer is just dropped
+                    if let Err(e) = self
+                        .peer
                         .reconnect(&self.torrent, Duration::from_secs(2))
-                        .await?;
+                        .await
+                    {
+                        log!(LogLevel::Debug, "Failed to reconnect: {e}");
+                        return Ok(());
+                    }
                 }
             }
-
-            error_sender
-                .send(DownloadEvents::ChunksFail(self.task.clone()))
-                .await?;
         } else {
             log!(LogLevel::Debug, "downloaded");
         }
@@ -190,4 +165,163 @@ impl DownloadReq {
             .await?;
         Ok(())
     }
+
+    // Keeping up to window size requests in flight, next request is sent
+    // as soon as any piece arrives. Pieces can come in any order
+    async fn pipeline(&mut self, remaining: &mut BTreeSet<Block>) -> anyhow::Result<()> {
+        let mut endgame_changes = self.endgame.subscribe();
+        let mut to_request: VecDeque<Block> = remaining.iter().copied().collect();
+        // (piece index, begin) -> block of sent requests
+        let mut outstanding: HashMap<(u32, u32), Block> = HashMap::new();
+        self.peer.request_window.restart_sample();
+        loop {
+            self.cancel_received(remaining, &mut to_request, &mut outstanding)
+                .await?;
+            while outstanding.len() < self.peer.request_window.size() {
+                let Some(block) = to_request.pop_front() else {
+                    break;
+                };
+                let msg = PeerMessage::Request(self.block_payload(block));
+                self.peer.send_message(&msg).await?;
+                outstanding.insert(Self::block_key(block), block);
+            }
+            if outstanding.is_empty() {
+                return Ok(());
+            }
+
+            // while waiting for data other peers can deliver our blocks in endgame
+            let deadline = Instant::now() + PIECE_TIMEOUT;
+            loop {
+                tokio::select! {
+                    res = self.peer.socket.readable() => {
+                        res?;
+                        break;
+                    }
+                    Ok(_) = endgame_changes.changed() => {
+                        self.cancel_received(remaining, &mut to_request, &mut outstanding)
+                            .await?;
+                        if outstanding.is_empty() && to_request.is_empty() {
+                            return Ok(());
+                        }
+                    }
+                    _ = sleep_until(deadline) => {
+                        self.peer.request_window.on_timeout();
+                        anyhow::bail!("Timeout Error!!!");
+                    }
+                }
+            }
+            // data is there already, delay of speed limit isn't peer's fault
+            self.peer.limiter.download_ready().await;
+            let deadline = Instant::now() + PIECE_TIMEOUT;
+            let msg = match timeout_at(deadline, self.peer.receive_message()).await {
+                Ok(msg) => msg?,
+                Err(_) => {
+                    self.peer.request_window.on_timeout();
+                    anyhow::bail!("Timeout Error!!!");
+                }
+            };
+            if let PeerMessage::Piece(ref buf) = msg {
+                if buf.len() < 8 {
+                    anyhow::bail!("Invalid piece message");
+                }
+                let piece_i = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                let begin = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                let Some(block) = outstanding.remove(&(piece_i, begin)) else {
+                    // could be cancelled too late, saver ignores duplicates anyway
+                    log!(
+                        LogLevel::Debug,
+                        "Unrequested piece {piece_i}, begin {begin}, peer {}",
+                        self.peer.peer_addr
+                    );
+                    continue;
+                };
+                remaining.remove(&block);
+                self.peer
+                    .request_window
+                    .on_received((buf.len() - 8) as u64);
+                self.endgame.chunk_received(block.0, block.1);
+                self.peer.block_received(buf).await?;
+                continue;
+            }
+            self.peer.handle_message(msg).await?;
+        }
+    }
+
+    // Dropping blocks other downloaders already received in endgame,
+    // requests that are already sent are cancelled
+    async fn cancel_received(
+        &mut self,
+        remaining: &mut BTreeSet<Block>,
+        to_request: &mut VecDeque<Block>,
+        outstanding: &mut HashMap<(u32, u32), Block>,
+    ) -> anyhow::Result<()> {
+        if !self.endgame.is_active() {
+            return Ok(());
+        }
+        remaining.retain(|&(piece_i, chunk_i)| !self.endgame.is_received(piece_i, chunk_i));
+        to_request.retain(|x| remaining.contains(x));
+        let cancelled: Vec<Block> = outstanding
+            .values()
+            .filter(|x| !remaining.contains(x))
+            .copied()
+            .collect();
+        for block in cancelled {
+            outstanding.remove(&Self::block_key(block));
+            let msg = PeerMessage::Cancel(self.block_payload(block));
+            self.peer.send_message(&msg).await?;
+            log!(
+                LogLevel::Debug,
+                "Cancelled piece {}, chunk {}, peer {}",
+                block.0,
+                block.1,
+                self.peer.peer_addr
+            );
+        }
+        Ok(())
+    }
+
+    // Piece index and begin, as they come in piece message
+    fn block_key((piece_i, chunk_i): Block) -> (u32, u32) {
+        (piece_i as u32, (chunk_i as u64 * super::CHUNK_SIZE) as u32)
+    }
+
+    // Index, begin and length of block, as used in request and cancel
+    fn block_payload(&self, block: Block) -> Vec<u8> {
+        let (piece_i, begin) = Self::block_key(block);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&piece_i.to_be_bytes());
+        buf.extend_from_slice(&begin.to_be_bytes());
+        buf.extend_from_slice(&(self.chunk_length(block) as u32).to_be_bytes());
+        buf
+    }
+
+    fn chunk_length(&self, (piece_i, chunk_i): Block) -> u64 {
+        let piece_length = self.torrent.get_piece_length(piece_i as usize);
+        (piece_length - chunk_i as u64 * super::CHUNK_SIZE).min(super::CHUNK_SIZE)
+    }
+
+    // Not received blocks grouped into continuous tasks
+    fn remaining_tasks(&self, remaining: &BTreeSet<Block>) -> Vec<ChunksTask> {
+        let mut tasks: Vec<ChunksTask> = Vec::new();
+        for &(piece_i, chunk_i) in remaining {
+            match tasks.last_mut() {
+                Some(task) if task.piece_i == piece_i && task.chunks.end == chunk_i => {
+                    task.chunks.end += 1
+                }
+                _ => tasks.push(ChunksTask {
+                    piece_i,
+                    chunks: chunk_i..chunk_i + 1,
+                    includes_last_chunk: false,
+                }),
+            }
+        }
+        for task in &mut tasks {
+            task.includes_last_chunk = self.tasks.iter().any(|x| {
+                x.piece_i == task.piece_i
+                    && x.includes_last_chunk
+                    && x.chunks.end == task.chunks.end
+            });
+        }
+        tasks
+    }
 }
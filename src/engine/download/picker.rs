@@ -0,0 +1,406 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use rand::seq::SliceRandom;
+
+use super::super::peers::{bitfield_has, Peer};
+use super::super::priorities::FilePriority;
+use super::super::torrent::PieceBitmap;
+use super::tasks::{ChunksTask, PieceTask};
+
+// Pieces picked in random order before switching to rarest first,
+// so there is something to share with other peers as soon as possible
+const RANDOM_FIRST_PIECES: usize = 4;
+// How many peers can download same block in endgame
+const MAX_ENDGAME_COPIES: usize = 3;
+// In sequential mode pieces closest to playback position are picked in order,
+// others are picked as usual, so peers that don't have them are still used
+const SEQUENTIAL_WINDOW: u16 = 20;
+
+// Number of known peers having each piece. Updated from bitfields peers
+// bring back to worker and from Have messages as soon as peer reads them
+#[derive(Debug, Default)]
+pub struct Availability(Mutex<AvailabilityState>);
+
+#[derive(Debug, Default)]
+struct AvailabilityState {
+    counts: Vec<u16>,
+    // last bitfield seen from peer, to apply only the difference
+    peer_pieces: HashMap<SocketAddr, Vec<u8>>,
+}
+
+impl Availability {
+    pub fn new(pieces_n: usize) -> Self {
+        Availability(Mutex::new(AvailabilityState {
+            counts: vec![0; pieces_n],
+            peer_pieces: HashMap::new(),
+        }))
+    }
+
+    pub fn counts(&self) -> Vec<u16> {
+        self.0.lock().unwrap().counts.clone()
+    }
+
+    pub fn peer_updated(&self, addr: &SocketAddr, bitfield: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+        let old = state.peer_pieces.remove(addr).unwrap_or_default();
+        for (i, n) in state.counts.iter_mut().enumerate() {
+            match (bitfield_has(&old, i), bitfield_has(bitfield, i)) {
+                (false, true) => *n += 1,
+                (true, false) => *n = n.saturating_sub(1),
+                _ => {}
+            }
+        }
+        state.peer_pieces.insert(*addr, bitfield.to_vec());
+    }
+
+    pub fn peer_removed(&self, addr: &SocketAddr) {
+        let mut state = self.0.lock().unwrap();
+        let Some(old) = state.peer_pieces.remove(addr) else {
+            return;
+        };
+        for (i, n) in state.counts.iter_mut().enumerate() {
+            if bitfield_has(&old, i) {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+
+    // Peers not known yet are counted from their bitfield later
+    pub fn have(&self, addr: &SocketAddr, piece_i: usize) {
+        let mut state = self.0.lock().unwrap();
+        if piece_i >= state.counts.len() {
+            return;
+        }
+        let Some(bitfield) = state.peer_pieces.get_mut(addr) else {
+            return;
+        };
+        if bitfield_has(bitfield, piece_i) {
+            return;
+        }
+        if bitfield.len() <= piece_i / 8 {
+            bitfield.resize(piece_i / 8 + 1, 0);
+        }
+        bitfield[piece_i / 8] |= 0x80 >> (piece_i % 8);
+        state.counts[piece_i] += 1;
+    }
+}
+
+// Decides which piece is downloaded next, rarest pieces by availability first
+#[derive(Debug)]
+pub struct PiecePicker {
+    availability: Arc<Availability>,
+    // pieces that were started by picker
+    picked_n: usize,
+    // from file priorities, skipped pieces are never picked
+    priorities: Vec<FilePriority>,
+    // pieces of urgent byte ranges go before any others
+    urgent: Vec<bool>,
+    sequential: bool,
+}
+
+impl PiecePicker {
+    pub fn new(pieces_n: usize) -> Self {
+        PiecePicker {
+            availability: Arc::new(Availability::new(pieces_n)),
+            picked_n: 0,
+            priorities: vec![FilePriority::Normal; pieces_n],
+            urgent: vec![false; pieces_n],
+            sequential: false,
+        }
+    }
+
+    pub fn set_urgent(&mut self, urgent: Vec<bool>) {
+        self.urgent = urgent;
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+    }
+
+    fn is_wanted(&self, piece_i: u16) -> bool {
+        self.priorities[piece_i as usize] != FilePriority::Skip
+    }
+
+    // Some not skipped piece is still waiting to be requested
+    pub fn has_wanted(
+        &self,
+        pieces_tasks: &VecDeque<PieceTask>,
+        chunks_tasks: &VecDeque<ChunksTask>,
+    ) -> bool {
+        pieces_tasks.iter().any(|x| self.is_wanted(x.piece_i))
+            || chunks_tasks.iter().any(|x| self.is_wanted(x.piece_i))
+    }
+
+    // Every not skipped piece is downloaded and verified
+    pub fn selection_done(&self, bitmap: &PieceBitmap) -> bool {
+        (0..self.priorities.len()).all(|i| !self.is_wanted(i as u16) || bitmap.has(i))
+    }
+
+    // Shared with peers, so they count Have messages they receive
+    pub fn availability(&self) -> Arc<Availability> {
+        self.availability.clone()
+    }
+
+    pub fn peer_updated(&mut self, peer: &Peer) {
+        if let Some(ref bitfield) = peer.peer_bitfield {
+            self.availability.peer_updated(&peer.peer_addr, bitfield);
+        }
+    }
+
+    pub fn peer_removed(&mut self, addr: &SocketAddr) {
+        self.availability.peer_removed(addr);
+    }
+
+    // Task peer can download: returned chunks go first, then urgent pieces in order,
+    // then pieces of highest priority peer has: in sequential mode ones from
+    // window in order, otherwise already started ones, then random or rarest
+    pub fn next_task(
+        &mut self,
+        peer: &Peer,
+        pieces_tasks: &mut VecDeque<PieceTask>,
+        chunks_tasks: &mut VecDeque<ChunksTask>,
+    ) -> Option<ChunksTask> {
+        if let Some(pos) = chunks_tasks
+            .iter()
+            .position(|x| self.is_wanted(x.piece_i) && peer.have_piece(x.piece_i as usize))
+        {
+            return chunks_tasks.remove(pos);
+        }
+
+        let priority = |i: &usize| self.priorities[pieces_tasks[*i].piece_i as usize];
+        let position = |i: &usize| pieces_tasks[*i].piece_i;
+        let wanted: Vec<usize> = (0..pieces_tasks.len())
+            .filter(|&i| !pieces_tasks[i].is_assigned())
+            .filter(|&i| self.is_wanted(pieces_tasks[i].piece_i))
+            .collect();
+        // window starts at first piece that isn't requested yet, so it moves
+        // forward as pieces are downloaded
+        let window_start = wanted.iter().map(position).min()?;
+        let mut candidates: Vec<usize> = wanted
+            .into_iter()
+            .filter(|&i| peer.have_piece(pieces_tasks[i].piece_i as usize))
+            .collect();
+        let urgent = candidates
+            .iter()
+            .copied()
+            .filter(|i| self.urgent[position(i) as usize])
+            .min_by_key(position);
+        let top = candidates.iter().map(priority).max()?;
+        candidates.retain(|i| priority(i) == top);
+        let in_window = candidates
+            .iter()
+            .copied()
+            .filter(|i| self.sequential && position(i) < window_start + SEQUENTIAL_WINDOW)
+            .min_by_key(position);
+        let started: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|&i| pieces_tasks[i].chunks_done > 0)
+            .collect();
+        let task_i = if let Some(i) = urgent.or(in_window) {
+            i
+        } else if !started.is_empty() {
+            self.rarest(&started, pieces_tasks)?
+        } else if self.picked_n < RANDOM_FIRST_PIECES {
+            *candidates.choose(&mut rand::thread_rng())?
+        } else {
+            self.rarest(&candidates, pieces_tasks)?
+        };
+
+        let piece_task = &mut pieces_tasks[task_i];
+        if piece_task.chunks_done == 0 {
+            self.picked_n += 1;
+        }
+        let task = piece_task.next_chunks();
+        if piece_task.is_assigned() {
+            pieces_tasks.remove(task_i);
+        }
+        Some(task)
+    }
+
+    // When every block is already requested, blocks in flight are requested
+    // again from other peers, task with fewest copies goes first
+    pub fn endgame_task(&self, peer: &Peer, in_flight: &[&ChunksTask]) -> Option<ChunksTask> {
+        in_flight
+            .iter()
+            .filter(|x| peer.have_piece(x.piece_i as usize))
+            .map(|x| (*x, in_flight.iter().filter(|y| *y == x).count()))
+            .filter(|(_, copies)| *copies < MAX_ENDGAME_COPIES)
+            .min_by_key(|(_, copies)| *copies)
+            .map(|(x, _)| x.clone())
+    }
+
+    // Ties are broken randomly, so peers don't race for the same piece
+    fn rarest(&self, candidates: &[usize], pieces_tasks: &VecDeque<PieceTask>) -> Option<usize> {
+        let counts = self.availability.counts();
+        let availability = |i: &usize| counts[pieces_tasks[*i].piece_i as usize];
+        let min = candidates.iter().map(availability).min()?;
+        let rarest: Vec<usize> = candidates
+            .iter()
+            .copied()
+            .filter(|i| availability(i) == min)
+            .collect();
+        rarest.choose(&mut rand::thread_rng()).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    use super::super::super::peers::PeerMessage;
+    use super::*;
+
+    const PIECES_N: usize = 4;
+
+    // Peer on loopback socket, address is only used as key
+    async fn peer(id: u8, pieces: &[usize]) -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let addr = SocketAddr::from(([10, 0, 0, id], 6881));
+        let mut peer = Peer::from_socket(
+            socket,
+            addr,
+            mpsc::channel(1).0,
+            String::new(),
+            String::new(),
+        );
+        let mut bitfield = vec![0; PIECES_N.div_ceil(8)];
+        for i in pieces {
+            bitfield[i / 8] |= 0x80 >> (i % 8);
+        }
+        peer.peer_bitfield = Some(bitfield);
+        peer
+    }
+
+    fn pieces_tasks() -> VecDeque<PieceTask> {
+        (0..PIECES_N as u16)
+            .map(|piece_i| PieceTask {
+                piece_i,
+                total_chunks: 1,
+                chunks_done: 0,
+            })
+            .collect()
+    }
+
+    fn task(piece_i: u16) -> ChunksTask {
+        ChunksTask {
+            piece_i,
+            chunks: 0..1,
+            includes_last_chunk: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn rarest_piece_goes_first() {
+        let mut picker = PiecePicker::new(PIECES_N);
+        // availability of pieces: 3, 3, 2, 1
+        let seed = peer(1, &[0, 1, 2, 3]).await;
+        picker.peer_updated(&seed);
+        picker.peer_updated(&peer(2, &[0, 1, 2]).await);
+        picker.peer_updated(&peer(3, &[0, 1]).await);
+        // random first pieces are already picked
+        picker.picked_n = RANDOM_FIRST_PIECES;
+
+        let mut pieces_tasks = pieces_tasks();
+        let mut chunks_tasks = VecDeque::new();
+        let mut next = || picker.next_task(&seed, &mut pieces_tasks, &mut chunks_tasks);
+        assert_eq!(next().unwrap().piece_i, 3);
+        assert_eq!(next().unwrap().piece_i, 2);
+        let mut rest = vec![next().unwrap().piece_i, next().unwrap().piece_i];
+        rest.sort();
+        assert_eq!(rest, vec![0, 1]);
+        assert!(next().is_none());
+    }
+
+    #[tokio::test]
+    async fn availability_follows_peers() {
+        let mut picker = PiecePicker::new(PIECES_N);
+        let mut first = peer(1, &[0, 1]).await;
+        picker.peer_updated(&first);
+        picker.peer_updated(&peer(2, &[1]).await);
+        assert_eq!(picker.availability.counts(), vec![1, 2, 0, 0]);
+
+        // only difference with previous bitfield is applied
+        first.peer_bitfield = Some(vec![0b0011_0000]);
+        picker.peer_updated(&first);
+        assert_eq!(picker.availability.counts(), vec![0, 1, 1, 1]);
+        picker.peer_removed(&first.peer_addr);
+        assert_eq!(picker.availability.counts(), vec![0, 1, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn have_messages_are_counted() {
+        let mut picker = PiecePicker::new(PIECES_N);
+        let mut first = peer(1, &[0]).await;
+        first.availability = picker.availability();
+        picker.peer_updated(&first);
+        first.handle_message(PeerMessage::Have(2)).await.unwrap();
+        first.handle_message(PeerMessage::Have(2)).await.unwrap();
+        assert_eq!(picker.availability.counts(), vec![1, 0, 1, 0]);
+
+        // peer brings back the same bitfield, nothing is counted twice
+        picker.peer_updated(&first);
+        assert_eq!(picker.availability.counts(), vec![1, 0, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn endgame_copies_are_limited() {
+        let picker = PiecePicker::new(PIECES_N);
+        let seed = peer(1, &[0, 1, 2, 3]).await;
+        let (a, b) = (task(0), task(1));
+
+        // task with fewest copies goes first
+        let in_flight = [&a, &a, &b];
+        assert_eq!(picker.endgame_task(&seed, &in_flight), Some(b.clone()));
+
+        // nothing is requested more than MAX_ENDGAME_COPIES times
+        let in_flight = vec![&a; MAX_ENDGAME_COPIES];
+        assert_eq!(picker.endgame_task(&seed, &in_flight), None);
+
+        // peer gets only blocks of pieces it has
+        let partial = peer(2, &[0]).await;
+        assert_eq!(picker.endgame_task(&partial, &[&b]), None);
+    }
+
+    #[tokio::test]
+    async fn sequential_pieces_are_in_order() {
+        let mut picker = PiecePicker::new(PIECES_N);
+        let seed = peer(1, &[0, 1, 2, 3]).await;
+        picker.peer_updated(&seed);
+        picker.set_sequential(true);
+
+        let mut pieces_tasks = pieces_tasks();
+        let mut chunks_tasks = VecDeque::new();
+        let mut next = || picker.next_task(&seed, &mut pieces_tasks, &mut chunks_tasks);
+        let order: Vec<u16> = std::iter::from_fn(|| next().map(|x| x.piece_i)).collect();
+        assert_eq!(order, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn urgent_pieces_go_first() {
+        use FilePriority::*;
+        let mut picker = PiecePicker::new(PIECES_N);
+        let seed = peer(1, &[0, 1, 2, 3]).await;
+        picker.peer_updated(&seed);
+        picker.set_sequential(true);
+        picker.set_priorities(vec![Skip, High, Low, Low]);
+        picker.set_urgent(vec![false, false, true, true]);
+
+        let mut pieces_tasks = pieces_tasks();
+        let mut chunks_tasks = VecDeque::new();
+        let mut next = || picker.next_task(&seed, &mut pieces_tasks, &mut chunks_tasks);
+        let order: Vec<u16> = std::iter::from_fn(|| next().map(|x| x.piece_i)).collect();
+        // skipped piece is never picked
+        assert_eq!(order, vec![2, 3, 1]);
+    }
+}
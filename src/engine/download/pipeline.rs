@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::super::settings::Settings;
+use super::tasks::CHUNK_SIZE;
+
+// Bounds of outstanding requests number per peer, kept in settings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowLimits {
+    pub min: usize,
+    pub max: usize,
+    // window is sized to keep peer busy for this many seconds at its measured rate
+    pub queue_secs: f64,
+}
+
+impl Default for WindowLimits {
+    fn default() -> Self {
+        WindowLimits {
+            min: 2,
+            max: 250,
+            queue_secs: 3.0,
+        }
+    }
+}
+
+// Rate is sampled at least this long
+const SAMPLE_TIME: Duration = Duration::from_secs(1);
+const INITIAL_WINDOW: usize = 4;
+
+// Number of requests kept in flight to one peer, adapts to peer's download rate
+#[derive(Debug)]
+pub struct RequestWindow {
+    limits: WindowLimits,
+    size: usize,
+    // smoothed rate, bytes per second
+    rate: f64,
+    sample_bytes: u64,
+    sample_start: Instant,
+}
+
+impl RequestWindow {
+    pub fn new(limits: WindowLimits) -> Self {
+        // settings file can have any numbers
+        let min = limits.min.max(1);
+        let limits = WindowLimits {
+            min,
+            max: limits.max.max(min),
+            queue_secs: limits.queue_secs.max(0.0),
+        };
+        RequestWindow {
+            limits,
+            size: INITIAL_WINDOW.clamp(limits.min, limits.max),
+            rate: 0.0,
+            sample_bytes: 0,
+            sample_start: Instant::now(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Called when requests are sent after pause, so idle time isn't counted in rate
+    pub fn restart_sample(&mut self) {
+        self.sample_bytes = 0;
+        self.sample_start = Instant::now();
+    }
+
+    pub fn on_received(&mut self, bytes: u64) {
+        self.sample_bytes += bytes;
+        let elapsed = self.sample_start.elapsed();
+        if elapsed < SAMPLE_TIME {
+            return;
+        }
+        let sample = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            self.rate * 0.7 + sample * 0.3
+        };
+        let wanted = (self.rate * self.limits.queue_secs / CHUNK_SIZE as f64).ceil();
+        self.size = (wanted as usize).clamp(self.limits.min, self.limits.max);
+        self.restart_sample();
+    }
+
+    // Peer didn't answer in time, it's likely overloaded
+    pub fn on_timeout(&mut self) {
+        self.size = (self.size / 2).max(self.limits.min);
+        self.restart_sample();
+    }
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        RequestWindow::new(Settings::get().request_window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Window as if bytes were received during last two seconds
+    fn receive(window: &mut RequestWindow, bytes: u64) {
+        window.sample_start = Instant::now() - Duration::from_secs(2);
+        window.on_received(bytes);
+    }
+
+    #[test]
+    fn window_grows_with_rate() {
+        let mut window = RequestWindow::new(WindowLimits::default());
+        assert_eq!(window.size(), INITIAL_WINDOW);
+
+        // sample isn't taken before SAMPLE_TIME
+        window.on_received(1 << 20);
+        assert_eq!(window.size(), INITIAL_WINDOW);
+
+        // 512 KiB/s for 3 seconds is 96 blocks
+        window.restart_sample();
+        receive(&mut window, 1 << 20);
+        assert_eq!(window.size(), 96);
+
+        receive(&mut window, 1 << 30);
+        assert_eq!(window.size(), WindowLimits::default().max);
+    }
+
+    #[test]
+    fn window_halves_on_timeout() {
+        let mut window = RequestWindow::new(WindowLimits::default());
+        receive(&mut window, 1 << 20);
+        window.on_timeout();
+        assert_eq!(window.size(), 48);
+        window.on_timeout();
+        assert_eq!(window.size(), 24);
+        for _ in 0..10 {
+            window.on_timeout();
+        }
+        assert_eq!(window.size(), WindowLimits::default().min);
+    }
+
+    #[test]
+    fn limits_are_sanitized() {
+        let window = RequestWindow::new(WindowLimits {
+            min: 0,
+            max: 0,
+            queue_secs: -1.0,
+        });
+        assert_eq!(window.limits.min, 1);
+        assert_eq!(window.limits.max, 1);
+        assert_eq!(window.limits.queue_secs, 0.0);
+        assert_eq!(window.size(), 1);
+    }
+}
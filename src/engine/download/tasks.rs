@@ -7,7 +7,6 @@ use serde::{Deserialize, Serialize};
 use super::super::torrent::Torrent;
 
 const CHUNKS_PER_TASK: u16 = 60;
-pub const MAX_CHUNKS_TASKS: usize = 100;
 pub const CHUNK_SIZE: u64 = 16384;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,7 +16,7 @@ pub struct PieceTask {
     pub chunks_done: u16,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChunksTask {
     pub piece_i: u16,
     pub chunks: Range<u16>,
@@ -48,33 +47,20 @@ pub fn get_piece_tasks(torrent: Arc<Torrent>, pieces_done: Vec<usize>) -> VecDeq
     pieces_tasks
 }
 
-pub fn add_chunks_tasks(
-    pieces_tasks: &mut VecDeque<PieceTask>,
-    chunks_tasks: &mut VecDeque<ChunksTask>,
-    chunks_to_add: usize,
-) {
-    for _ in 0..chunks_to_add {
-        if pieces_tasks.is_empty() {
-            break;
-        }
-        let mut task = pieces_tasks.get_mut(0).expect("We checked it's not empty");
-        if task.chunks_done >= task.total_chunks {
-            let _ = pieces_tasks.pop_front();
-            if pieces_tasks.is_empty() {
-                break;
-            }
-            task = pieces_tasks.get_mut(0).expect("We checked it's not empty");
-        }
-        let chunks_up_border = if (task.chunks_done + CHUNKS_PER_TASK) > task.total_chunks {
-            task.total_chunks
-        } else {
-            task.chunks_done + CHUNKS_PER_TASK
+impl PieceTask {
+    // Splitting next range of not yet requested chunks off the piece
+    pub fn next_chunks(&mut self) -> ChunksTask {
+        let chunks_up_border = (self.chunks_done + CHUNKS_PER_TASK).min(self.total_chunks);
+        let task = ChunksTask {
+            piece_i: self.piece_i,
+            chunks: self.chunks_done..chunks_up_border,
+            includes_last_chunk: chunks_up_border == self.total_chunks,
         };
-        chunks_tasks.push_back(ChunksTask {
-            piece_i: task.piece_i,
-            chunks: task.chunks_done..chunks_up_border,
-            includes_last_chunk: chunks_up_border == task.total_chunks,
-        });
-        task.chunks_done = chunks_up_border;
+        self.chunks_done = chunks_up_border;
+        task
+    }
+
+    pub fn is_assigned(&self) -> bool {
+        self.chunks_done >= self.total_chunks
     }
 }
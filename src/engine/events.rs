@@ -0,0 +1,145 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::broadcast::{Receiver, Sender};
+
+use super::tracker::{AnnounceInfo, ScrapeInfo};
+
+// Messages on channel of torrent worker: tasks of worker report progress with them,
+// session reads them and controls worker with Pause, Stop and ForceOff
+#[derive(Clone, Debug)]
+pub enum EngineEvent {
+    HashCheckFinished,
+
+    PeerDiscovered(SocketAddr),
+
+    PeerDisconnect(SocketAddr),
+
+    // Whole torrent or all not skipped files are downloaded
+    TorrentFinished,
+    // Seeding torrent downloads again, skipped files were selected
+    DownloadResumed,
+    PieceDone(u16),
+    ForceOff,
+
+    // String with error text
+    TorrentErr(String),
+
+    // Pieces downloaded in total
+    Pause(u16),
+
+    // Pieces done
+    Stop(u16),
+
+    // Peer and bytes uploaded to it
+    DataUploaded(SocketAddr, u64),
+
+    // Tracker url and its announce result (error is failure reason)
+    TrackerAnnounce(String, Result<AnnounceInfo, String>),
+
+    // Tracker url and its scrape result
+    TrackerScrape(String, Result<ScrapeInfo, String>),
+
+    // Totals of seeding torrent, sent periodically
+    Seeding { uploaded: u64, seconds: u64 },
+}
+
+// Frontend of engine (window, tests, ...), gets every event sent by torrent workers.
+// Called from worker tasks, so it should only wake frontend up or copy event
+pub trait EngineEvents: Send + Sync {
+    fn on_event(&self, info_hash: &[u8], event: &EngineEvent);
+}
+
+// Sending side of worker channel, its tasks emit events with it
+#[derive(Clone)]
+pub struct EventSender {
+    info_hash: Arc<Vec<u8>>,
+    sender: Sender<EngineEvent>,
+    subscribers: Arc<Vec<Arc<dyn EngineEvents>>>,
+}
+
+impl fmt::Debug for EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSender")
+            .field("info_hash", &hex::encode(self.info_hash.as_slice()))
+            .field("subscribers", &self.subscribers.len())
+            .finish()
+    }
+}
+
+impl EventSender {
+    pub fn new(
+        info_hash: Vec<u8>,
+        sender: Sender<EngineEvent>,
+        subscribers: Vec<Arc<dyn EngineEvents>>,
+    ) -> Self {
+        EventSender {
+            info_hash: Arc::new(info_hash),
+            sender,
+            subscribers: Arc::new(subscribers),
+        }
+    }
+
+    // Subscribers get event even when nobody reads the channel
+    // (e.g. worker already stopped), so send error is ignored
+    pub fn send(&self, event: EngineEvent) {
+        for subscriber in self.subscribers.iter() {
+            subscriber.on_event(&self.info_hash, &event);
+        }
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<EngineEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    // Subscriber keeping every event it got
+    #[derive(Default)]
+    pub struct EventLog {
+        pub events: Mutex<Vec<EngineEvent>>,
+    }
+
+    impl EventLog {
+        pub fn has(&self, f: impl Fn(&EngineEvent) -> bool) -> bool {
+            self.events.lock().unwrap().iter().any(f)
+        }
+    }
+
+    impl EngineEvents for EventLog {
+        fn on_event(&self, _: &[u8], event: &EngineEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn subscriber_gets_event_without_receivers() {
+        let (sender, receiver) = broadcast::channel(8);
+        drop(receiver);
+        let log = Arc::new(EventLog::default());
+        let events = EventSender::new(vec![1; 20], sender, vec![log.clone()]);
+        events.send(EngineEvent::PieceDone(3));
+        assert!(log.has(|x| matches!(x, EngineEvent::PieceDone(3))));
+    }
+
+    #[test]
+    fn channel_and_subscribers_get_event() {
+        let (sender, _) = broadcast::channel(8);
+        let log = Arc::new(EventLog::default());
+        let events = EventSender::new(vec![1; 20], sender, vec![log.clone()]);
+        let mut receiver = events.subscribe();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        events.send(EngineEvent::DataUploaded(peer, 10));
+        assert!(matches!(receiver.try_recv(), Ok(EngineEvent::DataUploaded(_, 10))));
+        assert!(log.has(|x| matches!(x, EngineEvent::DataUploaded(_, 10))));
+    }
+}
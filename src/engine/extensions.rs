@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::bencode::BencodeValue;
+use super::listener::LISTEN_PORT;
+
+// Client name sent in extended handshake
+const CLIENT_VERSION: &str = "MkTorrent 0.1";
+
+// Extension built on top of extension protocol (BEP 10).
+// One handler instance is shared between all peers of the registry,
+// so per peer state should be keyed by peer address
+pub trait ExtensionHandler: Send + Sync {
+    // Name used in "m" dictionary of extended handshake, e.g. "ut_metadata"
+    fn name(&self) -> &'static str;
+
+    // Adding handler specific fields to our extended handshake
+    fn extend_handshake(&self, _handshake: &mut HashMap<String, BencodeValue>) {}
+
+    // Called when remote side sent extended handshake
+    fn on_handshake(&self, _peer_addr: &SocketAddr, _handshake: &BencodeValue) {}
+
+    // Handling message of this extension, returned payload is sent back to peer
+    fn on_message(&self, peer_addr: &SocketAddr, payload: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+
+    // Message handler wants to send to peer by its own (e.g. periodic updates)
+    fn pending_message(&self, _peer_addr: &SocketAddr) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Arc<dyn ExtensionHandler>>,
+}
+
+impl std::fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.handlers.iter().map(|x| x.name()))
+            .finish()
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, handler: Arc<dyn ExtensionHandler>) {
+        if self.local_id(handler.name()).is_none() {
+            self.handlers.push(handler);
+        }
+    }
+
+    // Extended message ids are assigned by registration order, 0 is handshake
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.handlers
+            .iter()
+            .position(|x| x.name() == name)
+            .map(|i| i as u8 + 1)
+    }
+
+    pub fn handler(&self, id: u8) -> Option<&Arc<dyn ExtensionHandler>> {
+        if id == 0 {
+            return None;
+        }
+        self.handlers.get(id as usize - 1)
+    }
+
+    pub fn handlers(&self) -> &[Arc<dyn ExtensionHandler>] {
+        &self.handlers
+    }
+
+    pub fn handshake_payload(&self) -> anyhow::Result<Vec<u8>> {
+        let mut m = HashMap::new();
+        for (i, handler) in self.handlers.iter().enumerate() {
+            m.insert(handler.name().to_string(), BencodeValue::Num(i as i64 + 1));
+        }
+        let mut handshake = HashMap::new();
+        handshake.insert("m".to_string(), BencodeValue::Dict(m));
+        handshake.insert(
+            "v".to_string(),
+            BencodeValue::Bytes(CLIENT_VERSION.as_bytes().to_vec()),
+        );
+        handshake.insert("p".to_string(), BencodeValue::Num(LISTEN_PORT as i64));
+        for handler in &self.handlers {
+            handler.extend_handshake(&mut handshake);
+        }
+        let mut buf = Vec::new();
+        BencodeValue::Dict(handshake).encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// Reading extension ids peer assigned to extensions ("m" dictionary)
+pub fn parse_remote_ids(handshake: &BencodeValue) -> HashMap<String, u8> {
+    let mut res = HashMap::new();
+    if let BencodeValue::Dict(m) = &handshake["m"] {
+        for (name, id) in m {
+            // id 0 means extension is disabled
+            if let BencodeValue::Num(id @ 1..=255) = id {
+                res.insert(name.clone(), *id as u8);
+            }
+        }
+    }
+    res
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use super::logger::{log, LogLevel};
+
+// Connection without new request for this time is closed
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEADERS: usize = 100;
+// Request line and every header are read up to this length
+const MAX_LINE: usize = 8 * 1024;
+// Request line and headers together
+const MAX_HEAD: usize = 32 * 1024;
+
+// Minimal HTTP/1.1 server, enough for local endpoints of the client
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    // path without query, percent decoded
+    pub path: String,
+    pub query: HashMap<String, String>,
+    // names are lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub peer_addr: SocketAddr,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|x| x.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    // Content-Length is known ahead, chunks are written as they come
+    Stream {
+        length: u64,
+        chunks: mpsc::Receiver<anyhow::Result<Vec<u8>>>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(body),
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Response::new(status, text.as_bytes().to_vec())
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+
+    pub fn json(status: u16, value: &serde_json::Value) -> Self {
+        Response::new(status, value.to_string().into_bytes())
+            .with_header("Content-Type", "application/json")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// Serving connections until listener fails, handler is called for every request.
+// Requests with body longer than max_body are rejected before it's read
+pub async fn serve<H, F>(listener: TcpListener, max_body: usize, handler: H)
+where
+    H: Fn(Request) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Response> + Send,
+{
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to accept http connection: {e}");
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, addr, max_body, handler).await {
+                log!(LogLevel::Debug, "Http connection {addr} closed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection<H, F>(
+    socket: TcpStream,
+    addr: SocketAddr,
+    max_body: usize,
+    handler: H,
+) -> anyhow::Result<()>
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let mut socket = BufReader::new(socket);
+    loop {
+        let Ok(req) = timeout(KEEP_ALIVE_TIMEOUT, read_request(&mut socket, addr, max_body)).await else {
+            return Ok(());
+        };
+        let Some(req) = req? else {
+            return Ok(());
+        };
+        let keep_alive = !req
+            .header("connection")
+            .is_some_and(|x| x.eq_ignore_ascii_case("close"));
+        let head_only = req.method == "HEAD";
+        let res = handler(req).await;
+        write_response(socket.get_mut(), res, head_only).await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// None if connection is closed before request
+async fn read_request(
+    socket: &mut BufReader<TcpStream>,
+    peer_addr: SocketAddr,
+    max_body: usize,
+) -> anyhow::Result<Option<Request>> {
+    let mut line = String::new();
+    let mut head_left = MAX_HEAD;
+    if read_line(socket, &mut line, &mut head_left).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Invalid request line");
+    };
+    let method = method.to_string();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path);
+    let query = parse_query(query);
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if read_line(socket, &mut line, &mut head_left).await? == 0 {
+            anyhow::bail!("Connection closed in headers");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            anyhow::bail!("Too many headers");
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(x) => x.parse()?,
+        None => 0,
+    };
+    if length > max_body {
+        anyhow::bail!("Body is too big");
+    }
+    let mut body = vec![0; length];
+    socket.read_exact(&mut body).await?;
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+        peer_addr,
+    }))
+}
+
+// Reading line without letting client grow it without end,
+// head_left is what remains of request head limit
+async fn read_line(
+    socket: &mut BufReader<TcpStream>,
+    line: &mut String,
+    head_left: &mut usize,
+) -> anyhow::Result<usize> {
+    let limit = MAX_LINE.min(*head_left);
+    let n = (&mut *socket).take(limit as u64).read_line(line).await?;
+    if n == limit && !line.ends_with('\n') {
+        anyhow::bail!("Request head is too long");
+    }
+    *head_left -= n;
+    Ok(n)
+}
+
+async fn write_response(socket: &mut TcpStream, res: Response, head_only: bool) -> anyhow::Result<()> {
+    let length = match res.body {
+        Body::Bytes(ref bytes) => bytes.len() as u64,
+        Body::Stream { length, .. } => length,
+    };
+    let mut head = format!("HTTP/1.1 {} {}\r\n", res.status, reason(res.status));
+    for (name, value) in &res.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("Content-Length: {length}\r\n\r\n"));
+    socket.write_all(head.as_bytes()).await?;
+    if head_only {
+        return Ok(());
+    }
+    match res.body {
+        Body::Bytes(bytes) => socket.write_all(&bytes).await?,
+        Body::Stream { mut chunks, .. } => {
+            let mut written = 0;
+            while let Some(chunk) = chunks.recv().await {
+                let chunk = chunk?;
+                written += chunk.len() as u64;
+                socket.write_all(&chunk).await?;
+            }
+            // connection can't be reused if body is shorter than promised
+            if written != length {
+                anyhow::bail!("Stream ended after {written} of {length} bytes");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        302 => "Found",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let (name, value) = x.split_once('=').unwrap_or((x, ""));
+            (
+                percent_decode(&name.replace('+', " ")),
+                percent_decode(&value.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+pub fn percent_decode(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = src
+                .get(i + 1..i + 3)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+            {
+                res.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+// Standard alphabet, padding and whitespace are ignored
+pub fn base64_decode(input: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' | b'\r' | b'\n' | b' ' => continue,
+            _ => anyhow::bail!("invalid base64"),
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    // single character can't make a byte
+    if bits >= 6 {
+        anyhow::bail!("invalid base64 length");
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reading request written by client to local connection
+    async fn read(raw: Vec<u8>, max_body: usize) -> anyhow::Result<Option<Request>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        // client can be blocked by rejected data, so it writes in background
+        tokio::spawn(async move { client.write_all(&raw).await });
+        read_request(&mut BufReader::new(socket), addr, max_body).await
+    }
+
+    #[tokio::test]
+    async fn request_is_read() {
+        let raw = b"POST /a%20b?x=1 HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi".to_vec();
+        let req = read(raw, 2).await.unwrap().unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/a b");
+        assert_eq!(req.query["x"], "1");
+        assert_eq!(req.header("content-length"), Some("2"));
+        assert_eq!(req.body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn head_is_limited() {
+        let mut raw = b"GET /".to_vec();
+        raw.extend(vec![b'a'; MAX_LINE]);
+        assert!(read(raw, 0).await.is_err());
+
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..MAX_HEADERS - 1 {
+            raw.extend(format!("X-{i}: {}\r\n", "a".repeat(MAX_LINE / 2)).as_bytes());
+        }
+        assert!(read(raw, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn big_body_is_rejected() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc".to_vec();
+        assert!(read(raw, 2).await.is_err());
+    }
+
+    #[test]
+    fn query_is_decoded() {
+        let query = parse_query("a=1&b=x+y%21&&c&d=%zz&=e");
+        assert_eq!(query["a"], "1");
+        assert_eq!(query["b"], "x y!");
+        assert_eq!(query["c"], "");
+        assert_eq!(query["d"], "%zz");
+        assert_eq!(query[""], "e");
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn malformed_percent_is_kept() {
+        assert_eq!(percent_decode("%C3%A9"), "é");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%g0%%41"), "%g0%A");
+        // invalid UTF-8 isn't an error
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+        // multibyte character after percent doesn't break slicing
+        assert_eq!(percent_decode("%é"), "%é");
+    }
+
+    #[test]
+    fn base64_is_decoded() {
+        assert_eq!(base64_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64_decode("aGVs\nbG8").unwrap(), b"hello");
+        assert_eq!(base64_decode("-_8=").unwrap(), vec![0xfb, 0xff]);
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert!(base64_decode("aGV*").is_err());
+        assert!(base64_decode("aGVsb").is_err());
+        assert!(base64_decode("é").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+use super::logger::{log, LogLevel};
+use super::peers::{self, Peer};
+use super::saver;
+use super::DownloadEvents;
+
+// Port announced to trackers, incoming peers connect here
+pub const LISTEN_PORT: u16 = 6681;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(4);
+
+// Accepts incoming peer connections and routes them to running torrents
+pub fn spawn_listener(peer_id: String) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // IPv6 socket is bound first, on dual-stack systems it accepts IPv4 too
+        // and binding IPv4 socket after it fails, which is fine
+        let mut listeners = Vec::new();
+        for addr in [
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, LISTEN_PORT)),
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, LISTEN_PORT)),
+        ] {
+            match TcpListener::bind(addr).await {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => log!(LogLevel::Info, "Failed to bind {addr}: {e}"),
+            }
+        }
+        if listeners.is_empty() {
+            log!(LogLevel::Error, "Failed to bind port {LISTEN_PORT}");
+            return;
+        }
+        log!(LogLevel::Info, "Listening for peers on port {LISTEN_PORT}");
+        let mut tasks = JoinSet::new();
+        for listener in listeners {
+            tasks.spawn(accept_loop(listener, peer_id.clone()));
+        }
+        while tasks.join_next().await.is_some() {}
+    })
+}
+
+async fn accept_loop(listener: TcpListener, peer_id: String) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to accept peer: {e}");
+                continue;
+            }
+        };
+        let addr = peers::canonical_addr(addr);
+        let peer_id = peer_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_incoming(socket, addr, peer_id).await {
+                log!(LogLevel::Debug, "Incoming peer {addr} rejected: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_incoming(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    peer_id: String,
+) -> anyhow::Result<()> {
+    let mut handshake = [0; 68];
+    match timeout(HANDSHAKE_TIMEOUT, socket.read_exact(&mut handshake)).await {
+        Ok(res) => res?,
+        Err(_) => anyhow::bail!("Handshake timeout"),
+    };
+    if handshake[0] != 19 || &handshake[1..20] != b"BitTorrent protocol" {
+        anyhow::bail!("Invalid handshake");
+    }
+    let info_hash = handshake[28..48].to_vec();
+
+    // Looking for running torrent with same info hash
+    let (event_sender, data_sender) = {
+        let Some(save_info) = saver::SAVE_INFO.get() else {
+            anyhow::bail!("Saver is not initialised");
+        };
+        let hashmap = save_info.read().await;
+        let Some(save_info) = hashmap.get(&hex::encode(&info_hash)) else {
+            anyhow::bail!("Torrent {} is not active", hex::encode(&info_hash));
+        };
+        (save_info.event_sender.clone(), save_info.data_sender.clone())
+    };
+
+    let mut peer = Peer::from_socket(
+        socket,
+        addr,
+        data_sender,
+        peer_id,
+        hex::encode(&info_hash),
+    );
+    peer.accept_handshake(&handshake).await?;
+    log!(LogLevel::Info, "Incoming peer {addr}");
+    event_sender.send(DownloadEvents::PeerAdd(peer, true)).await?;
+    Ok(())
+}
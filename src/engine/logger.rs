@@ -10,10 +10,12 @@ pub struct Logger {
     log_path: String,
 }
 
+// Messages before logger is initialized (e.g. in unit tests) are dropped
 macro_rules! log {
     ($a:expr,$($b:tt)*) => {{
-        crate::logger::Logger::global()
-            .add_log($a, format!($($b)*).as_str());
+        if let Some(logger) = crate::logger::Logger::try_global() {
+            logger.add_log($a, format!($($b)*).as_str());
+        }
     }
     };
 }
@@ -35,8 +37,8 @@ impl std::fmt::Display for LogLevel {
 }
 
 impl Logger {
-    pub fn global() -> &'static Logger {
-        LOGGER_INSTANCE.get().expect("logger is not initialized")
+    pub fn try_global() -> Option<&'static Logger> {
+        LOGGER_INSTANCE.get()
     }
 
     pub fn init() -> Result<(), anyhow::Error> {
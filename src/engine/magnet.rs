@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use super::dht::Dht;
+use super::logger::{log, LogLevel};
+use super::metadata;
+use super::torrent::Torrent;
+use super::tracker::TrackerReq;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_PEERS_AT_ONCE: usize = 30;
+const DHT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Vec<u8>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let Some(query) = uri.trim().strip_prefix(MAGNET_PREFIX) else {
+            anyhow::bail!("Not a magnet link");
+        };
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => {
+                    if !trackers.contains(&value) {
+                        trackers.push(value)
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(info_hash) = info_hash else {
+            anyhow::bail!("Magnet link has no btih info hash");
+        };
+        Ok(MagnetLink {
+            info_hash,
+            name,
+            trackers,
+        })
+    }
+
+    // Asking trackers and DHT for peers and downloading info dictionary from them
+    pub async fn resolve(&self, peer_id: String) -> anyhow::Result<Torrent> {
+        match timeout(RESOLVE_TIMEOUT, self.fetch_info(peer_id)).await {
+            Ok(res) => {
+                let info = res?;
+                Torrent::from_metadata(&info, self.trackers.clone())
+            }
+            Err(_) => anyhow::bail!("Failed to fetch metadata: timeout"),
+        }
+    }
+
+    async fn fetch_info(&self, peer_id: String) -> anyhow::Result<Vec<u8>> {
+        if self.trackers.is_empty() && Dht::global().is_none() {
+            anyhow::bail!("Magnet link has no trackers and DHT is not running");
+        }
+        // size is not known before metadata is fetched, left is
+        // non zero so trackers don't treat us as a seeder
+        let tracker_req = TrackerReq::new(self.info_hash.clone(), peer_id.clone(), 1);
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(1);
+
+        let mut handles = Vec::new();
+        for tracker in &self.trackers {
+            let tracker = tracker.clone();
+            let tracker_req = tracker_req.clone();
+            let sender = sender.clone();
+            let info_hash = self.info_hash.clone();
+            let peer_id = peer_id.clone();
+            handles.push(tokio::spawn(async move {
+                let resp = match tracker_req.send(&tracker).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        log!(LogLevel::Info, "Failed tracker: {tracker} {e}");
+                        return;
+                    }
+                };
+                fetch_from_peers(resp.peers, &info_hash, peer_id, sender).await;
+            }));
+        }
+        // DHT is asked in any case, it's the only source for trackerless links
+        {
+            let sender = sender.clone();
+            let info_hash = self.info_hash.clone();
+            let peer_id = peer_id.clone();
+            handles.push(tokio::spawn(async move {
+                loop {
+                    let peers = match Dht::global() {
+                        Some(dht) => dht.find_peers(&info_hash, None).await,
+                        None => Vec::new(),
+                    };
+                    fetch_from_peers(peers, &info_hash, peer_id.clone(), sender.clone()).await;
+                    // DHT may be still bootstrapping
+                    tokio::time::sleep(DHT_RETRY_INTERVAL).await;
+                }
+            }));
+        }
+        drop(sender);
+
+        let res = receiver.recv().await;
+        for handle in handles {
+            handle.abort();
+        }
+        match res {
+            Some(info) => Ok(info),
+            None => anyhow::bail!("No peer shared torrent metadata"),
+        }
+    }
+}
+
+async fn fetch_from_peers(
+    peers: Vec<SocketAddr>,
+    info_hash: &[u8],
+    peer_id: String,
+    sender: mpsc::Sender<Vec<u8>>,
+) {
+    for peers in peers.chunks(MAX_PEERS_AT_ONCE) {
+        let mut tasks = Vec::new();
+        for addr in peers {
+            let addr = *addr;
+            let info_hash = info_hash.to_vec();
+            let peer_id = peer_id.clone();
+            let sender = sender.clone();
+            tasks.push(tokio::spawn(async move {
+                match metadata::fetch_from_peer(addr, &info_hash, peer_id).await {
+                    Ok(info) => {
+                        let _ = sender.send(info).await;
+                    }
+                    Err(e) => {
+                        log!(LogLevel::Debug, "Metadata from {addr} failed: {e}")
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+// Info hash can be 40 hex characters or 32 base32 characters
+fn decode_info_hash(hash: &str) -> anyhow::Result<Vec<u8>> {
+    match hash.len() {
+        40 => Ok(hex::decode(hash)?),
+        32 => {
+            let mut res = Vec::with_capacity(20);
+            let mut buf: u64 = 0;
+            let mut bits = 0;
+            for c in hash.to_ascii_uppercase().bytes() {
+                let val = match c {
+                    b'A'..=b'Z' => c - b'A',
+                    b'2'..=b'7' => c - b'2' + 26,
+                    _ => anyhow::bail!("Invalid base32 info hash"),
+                };
+                buf = (buf << 5) | val as u64;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    res.push((buf >> bits) as u8);
+                }
+            }
+            Ok(res)
+        }
+        _ => anyhow::bail!("Invalid info hash length"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_HEX: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn hex_magnet() {
+        let link = MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789ABCDEF0123456789ABCDEF01234567&dn=Some+movie%21\
+             &tr=udp%3A%2F%2Ftracker.example%3A80&tr=http%3A%2F%2Fa%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A80&x.pe=10.0.0.1%3A6881",
+        )
+        .unwrap();
+        assert_eq!(hex::encode(&link.info_hash), HASH_HEX);
+        assert_eq!(link.name.as_deref(), Some("Some movie!"));
+        assert_eq!(
+            link.trackers,
+            vec!["udp://tracker.example:80", "http://a/announce"]
+        );
+    }
+
+    #[test]
+    fn base32_magnet() {
+        let link =
+            MagnetLink::parse("magnet:?xt=urn:btih:aeruKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(hex::encode(&link.info_hash), HASH_HEX);
+        assert_eq!(link.name, None);
+        assert!(link.trackers.is_empty());
+    }
+
+    #[test]
+    fn invalid_magnets() {
+        for uri in [
+            "http://example.com",
+            "magnet:?dn=name",
+            "magnet:?xt=urn:sha1:0123456789abcdef0123456789abcdef01234567",
+            "magnet:?xt=urn:btih:0123",
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef0123456z",
+            "magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRL1",
+        ] {
+            assert!(MagnetLink::parse(uri).is_err(), "{uri} is accepted");
+        }
+    }
+}
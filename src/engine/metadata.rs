@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use super::bencode::BencodeValue;
+use super::extensions::{ExtensionHandler, ExtensionRegistry};
+use super::logger::{log, LogLevel};
+use super::peers::{Peer, PeerMessage};
+use super::torrent::Torrent;
+
+// Metadata is transferred in 16KiB pieces (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16384;
+const MAX_METADATA_SIZE: usize = 10 * 1024 * 1024;
+
+const EXTENSION_NAME: &str = "ut_metadata";
+const MSG_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Debug, Default)]
+struct MetadataDownload {
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+    rejected: bool,
+}
+
+// ut_metadata extension, collects info dictionary pieces sent by peers
+#[derive(Debug, Default)]
+pub struct UtMetadata {
+    downloads: Mutex<HashMap<SocketAddr, MetadataDownload>>,
+}
+
+impl UtMetadata {
+    // Number of metadata pieces, known after peer's extended handshake
+    pub fn pieces_n(&self, peer_addr: &SocketAddr) -> Option<usize> {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.get(peer_addr).map(|x| x.pieces.len())
+    }
+
+    pub fn is_rejected(&self, peer_addr: &SocketAddr) -> bool {
+        let downloads = self.downloads.lock().unwrap();
+        downloads.get(peer_addr).is_some_and(|x| x.rejected)
+    }
+
+    // Returns whole metadata once all pieces are received
+    pub fn take_metadata(&self, peer_addr: &SocketAddr) -> Option<Vec<u8>> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads.get(peer_addr)?;
+        if download.pieces.iter().any(|x| x.is_none()) {
+            return None;
+        }
+        let download = downloads.remove(peer_addr)?;
+        let mut metadata = Vec::with_capacity(download.size);
+        for piece in download.pieces.into_iter().flatten() {
+            metadata.extend_from_slice(&piece);
+        }
+        Some(metadata)
+    }
+
+    pub fn request_msg(piece_i: usize) -> anyhow::Result<Vec<u8>> {
+        let mut req = HashMap::new();
+        req.insert("msg_type".to_string(), BencodeValue::Num(MSG_REQUEST));
+        req.insert("piece".to_string(), BencodeValue::Num(piece_i as i64));
+        let mut buf = Vec::new();
+        BencodeValue::Dict(req).encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl ExtensionHandler for UtMetadata {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_handshake(&self, peer_addr: &SocketAddr, handshake: &BencodeValue) {
+        let BencodeValue::Num(size) = handshake["metadata_size"] else {
+            return;
+        };
+        if size <= 0 || size as usize > MAX_METADATA_SIZE {
+            return;
+        }
+        let size = size as usize;
+        let pieces_n = (size as f64 / METADATA_PIECE_SIZE as f64).ceil() as usize;
+        self.downloads.lock().unwrap().insert(
+            *peer_addr,
+            MetadataDownload {
+                size,
+                pieces: vec![None; pieces_n],
+                rejected: false,
+            },
+        );
+    }
+
+    fn on_message(&self, peer_addr: &SocketAddr, payload: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let (msg, dict_len) = BencodeValue::decode_bencoded_value(payload)?;
+        let BencodeValue::Num(piece_i) = msg["piece"] else {
+            anyhow::bail!("ut_metadata message without piece");
+        };
+        match msg["msg_type"] {
+            BencodeValue::Num(MSG_REQUEST) => {
+                // we don't share metadata
+                let mut reject = HashMap::new();
+                reject.insert("msg_type".to_string(), BencodeValue::Num(MSG_REJECT));
+                reject.insert("piece".to_string(), BencodeValue::Num(piece_i));
+                let mut buf = Vec::new();
+                BencodeValue::Dict(reject).encode(&mut buf)?;
+                Ok(Some(buf))
+            }
+            BencodeValue::Num(MSG_DATA) => {
+                let mut downloads = self.downloads.lock().unwrap();
+                let Some(download) = downloads.get_mut(peer_addr) else {
+                    return Ok(None);
+                };
+                let piece_i = piece_i as usize;
+                if piece_i >= download.pieces.len() {
+                    anyhow::bail!("Invalid metadata piece index");
+                }
+                let data = &payload[dict_len..];
+                let begin = piece_i * METADATA_PIECE_SIZE;
+                let end = (begin + METADATA_PIECE_SIZE).min(download.size);
+                if data.len() != end - begin {
+                    anyhow::bail!("Invalid metadata piece size");
+                }
+                download.pieces[piece_i] = Some(data.to_vec());
+                Ok(None)
+            }
+            BencodeValue::Num(MSG_REJECT) => {
+                let mut downloads = self.downloads.lock().unwrap();
+                if let Some(download) = downloads.get_mut(peer_addr) {
+                    download.rejected = true;
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// Downloading info dictionary from one peer using ut_metadata extension
+pub async fn fetch_from_peer(
+    addr: SocketAddr,
+    info_hash: &[u8],
+    peer_id: String,
+) -> anyhow::Result<Vec<u8>> {
+    // pieces are never requested, so data receiver is not needed
+    let (data_sender, _) = mpsc::channel(1);
+    let mut peer = Peer::new(
+        addr,
+        data_sender,
+        peer_id,
+        hex::encode(info_hash),
+        Duration::from_secs(2),
+    )
+    .await?;
+    let ut_metadata = Arc::new(UtMetadata::default());
+    let mut registry = ExtensionRegistry::default();
+    registry.register(ut_metadata.clone());
+    peer.extensions = Arc::new(registry);
+
+    peer.exchange_handshake(info_hash, Duration::from_secs(4))
+        .await?;
+    if !peer.supports_extensions {
+        anyhow::bail!("Peer doesn't support extension protocol");
+    }
+    peer.send_extended_handshake().await?;
+
+    // waiting for peer's extended handshake
+    while peer.remote_extensions.is_none() {
+        if let msg @ PeerMessage::Extended(_, _) = receive(&mut peer).await? {
+            peer.handle_message(msg).await?;
+        }
+    }
+    let peer_addr = peer.peer_addr;
+    let Some(pieces_n) = ut_metadata.pieces_n(&peer_addr) else {
+        anyhow::bail!("Peer didn't send metadata size");
+    };
+    if !peer.supports_extension(EXTENSION_NAME) {
+        anyhow::bail!("Peer doesn't support ut_metadata");
+    }
+
+    for piece_i in 0..pieces_n {
+        peer.send_extension(EXTENSION_NAME, UtMetadata::request_msg(piece_i)?)
+            .await?;
+    }
+    let metadata = loop {
+        if let msg @ PeerMessage::Extended(_, _) = receive(&mut peer).await? {
+            peer.handle_message(msg).await?;
+        }
+        if ut_metadata.is_rejected(&peer_addr) {
+            anyhow::bail!("Peer rejected metadata request");
+        }
+        if let Some(metadata) = ut_metadata.take_metadata(&peer_addr) {
+            break metadata;
+        }
+    };
+
+    if Torrent::bytes_hash(&metadata) != info_hash {
+        anyhow::bail!("Metadata hash didn't match");
+    }
+    log!(LogLevel::Info, "Got metadata from {addr}");
+    Ok(metadata)
+}
+
+async fn receive(peer: &mut Peer) -> anyhow::Result<PeerMessage> {
+    match timeout(MSG_TIMEOUT, peer.receive_message()).await {
+        Ok(res) => res,
+        Err(_) => anyhow::bail!("Timeout Error!!!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(size: i64) -> BencodeValue {
+        BencodeValue::Dict(HashMap::from([(
+            "metadata_size".to_string(),
+            BencodeValue::Num(size),
+        )]))
+    }
+
+    fn data_msg(piece_i: usize, data: &[u8]) -> Vec<u8> {
+        let mut buf = format!("d8:msg_typei1e5:piecei{piece_i}e10:total_sizei20000ee").into_bytes();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn metadata_is_assembled() {
+        let metadata = UtMetadata::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        metadata.on_handshake(&peer, &handshake(20000));
+        assert_eq!(metadata.pieces_n(&peer), Some(2));
+
+        let reply = metadata.on_message(&peer, &data_msg(1, &[2; 3616])).unwrap();
+        assert!(reply.is_none());
+        assert!(metadata.take_metadata(&peer).is_none());
+        assert!(metadata.on_message(&peer, &data_msg(0, &[1; 100])).is_err());
+        assert!(metadata.on_message(&peer, &data_msg(2, &[1; 100])).is_err());
+        let piece = [1; METADATA_PIECE_SIZE];
+        metadata.on_message(&peer, &data_msg(0, &piece)).unwrap();
+
+        let info = metadata.take_metadata(&peer).unwrap();
+        assert_eq!(info.len(), 20000);
+        assert_eq!(info[METADATA_PIECE_SIZE - 1], 1);
+        assert_eq!(info[METADATA_PIECE_SIZE], 2);
+        assert_eq!(metadata.pieces_n(&peer), None);
+    }
+
+    #[test]
+    fn bad_sizes_are_ignored() {
+        let metadata = UtMetadata::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        metadata.on_handshake(&peer, &handshake(0));
+        metadata.on_handshake(&peer, &handshake(MAX_METADATA_SIZE as i64 + 1));
+        assert_eq!(metadata.pieces_n(&peer), None);
+    }
+
+    #[test]
+    fn requests_are_rejected() {
+        let metadata = UtMetadata::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let reply = metadata.on_message(&peer, b"d8:msg_typei0e5:piecei3ee").unwrap();
+        assert_eq!(reply.unwrap(), b"d8:msg_typei2e5:piecei3ee");
+
+        metadata.on_handshake(&peer, &handshake(100));
+        assert!(!metadata.is_rejected(&peer));
+        metadata.on_message(&peer, b"d8:msg_typei2e5:piecei0ee").unwrap();
+        assert!(metadata.is_rejected(&peer));
+    }
+
+    #[test]
+    fn request_message() {
+        assert_eq!(UtMetadata::request_msg(1).unwrap(), b"d8:msg_typei0e5:piecei1ee");
+    }
+}
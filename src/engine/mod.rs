@@ -1,32 +1,62 @@
-use crate::engine::backup::Backup;
-use crate::gui::{DownloadStatus, TorrentBackupInfo, UiHandle, UiMsg};
-use std::collections::VecDeque;
+use crate::engine::backup::{Backup, TorrentBackupInfo};
+use crate::engine::session::DownloadStatus;
+use crate::engine::events::{EngineEvent, EventSender};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
 use tokio::sync::{mpsc, Semaphore};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-use download::tasks::{ChunksTask, PieceTask, MAX_CHUNKS_TASKS};
-use download::DownloadReq;
+use announcer::Announcer;
+use choker::Choker;
+use download::tasks::{ChunksTask, PieceTask};
+use download::{DownloadReq, Endgame, PiecePicker};
+use extensions::ExtensionRegistry;
+use pex::PeerExchange;
 use peers::Peer;
+use ratelimit::PeerLimiter;
+use seeder::{SeedEnd, Seeder, ShareTotals};
 use torrent::Torrent;
 use tracker::TrackerReq;
 
 use self::download::tasks::CHUNK_SIZE;
 use self::download::DataPiece;
+use self::saver::TransferStats;
 use crate::engine::torrent::PieceBitmap;
 use crate::logger::{log, LogLevel};
 
+mod announcer;
+pub mod api;
 pub mod backup;
 mod bencode;
+mod choker;
+pub mod control;
+pub mod dht;
 pub mod download;
+pub mod events;
+pub mod extensions;
+mod http;
+pub mod listener;
 pub mod logger;
+pub mod magnet;
+mod metadata;
 mod peers;
+mod pex;
+pub mod priorities;
+pub mod ratelimit;
 pub mod saver;
+mod seeder;
+pub mod session;
+pub mod settings;
+pub mod streamer;
 pub mod torrent;
-mod tracker;
+pub mod tracker;
+pub mod transmission;
 
 #[derive(Debug)]
 pub enum DownloadEvents {
@@ -41,11 +71,16 @@ pub fn parse_torrent(torrent_path: &str) -> anyhow::Result<Torrent> {
     Ok(Torrent::new(torrent_path)?)
 }
 
+pub async fn parse_magnet(uri: &str, peer_id: String) -> anyhow::Result<Torrent> {
+    let magnet = magnet::MagnetLink::parse(uri)?;
+    magnet.resolve(peer_id).await
+}
+
 #[derive(Debug)]
 struct DownloaderInfo {
     handle: Option<JoinHandle<()>>,
-    task: ChunksTask,
-    peer_addr: String,
+    tasks: Vec<ChunksTask>,
+    peer_addr: SocketAddr,
 }
 
 // Represents peer lifecycle in peers array
@@ -66,18 +101,36 @@ pub enum TorrentInfo {
     Backup(TorrentBackupInfo),
 }
 
+// Where torrent data is saved if user picked dest: folder named as torrent
+// inside it, or dest itself if it isn't a folder
+pub fn data_path(torrent: &Torrent, dest: &str) -> String {
+    if Path::new(dest).is_dir() {
+        Path::new(dest).join(&torrent.info.name).to_string_lossy().into_owned()
+    } else {
+        dest.to_string()
+    }
+}
+
+// path is data path of torrent, it's ignored for backup
 pub async fn download_torrent(
     torrent_info: TorrentInfo,
     path: &str,
-    ui_handle: UiHandle,
+    events: EventSender,
     peer_id: String,
 ) -> anyhow::Result<()> {
-    let mut ui_reader = ui_handle.ui_sender.clone().subscribe();
+    let mut event_reader = events.subscribe();
 
     let torrent = match torrent_info {
         TorrentInfo::Torrent(ref torrent) => torrent.clone(),
         TorrentInfo::Backup(ref backup) => backup.torrent.clone(),
     };
+    let totals = match torrent_info {
+        TorrentInfo::Torrent(_) => ShareTotals::default(),
+        TorrentInfo::Backup(ref backup) => ShareTotals {
+            uploaded: backup.uploaded,
+            seeding_secs: backup.seeding_secs,
+        },
+    };
 
     if torrent.info.piece_hashes.len() > u16::MAX as usize {
         anyhow::bail!("Too many pieces");
@@ -87,40 +140,52 @@ pub async fn download_torrent(
     }
 
     let save_path = if let TorrentInfo::Backup(ref backup) = torrent_info {
-        Path::new(&backup.save_path).to_path_buf()
+        backup.save_path.clone()
     } else {
-        if Path::new(path).is_dir() {
-            Path::new(path).join(&torrent.info.name)
-        } else {
-            Path::new(path).to_path_buf()
-        }
+        path.to_string()
     };
-
-    let save_path = save_path.to_str().unwrap();
-    let tracker_req = TrackerReq::init(&torrent, peer_id);
+    let save_path = save_path.as_str();
+    let tracker_req = TrackerReq::init(&torrent, peer_id.clone());
 
     let torrent = Arc::new(torrent);
+    // Torrent with tracker order learned while announcing, used for backups
+    let backup_torrent = || Torrent {
+        tracker_tiers: tracker_req.tracker_tiers(),
+        ..Arc::as_ref(&torrent).clone()
+    };
     let (send_status, mut get_status) = mpsc::channel(270);
     let (send_data, get_data) = mpsc::channel::<DataPiece>(50);
 
-    // Requesting peers from trackers and sending through channel mg PeerAdd
-    let mut peer_discovery_handles = tracker_req
-        .discover_peers(torrent.clone(), send_status.clone(), send_data.clone())
-        .await;
+    // Extensions peers of this torrent support, attached to every peer
+    let pex = Arc::new(PeerExchange::new(
+        hex::encode(&torrent.info_hash),
+        peer_id.clone(),
+        send_status.clone(),
+        send_data.clone(),
+    ));
+    let mut extensions = ExtensionRegistry::default();
+    extensions.register(pex.clone());
+    let extensions = Arc::new(extensions);
+
+    let dht_handle = dht::spawn_peer_search(
+        torrent.info_hash.clone(),
+        peer_id,
+        send_status.clone(),
+        send_data.clone(),
+    );
+
+    let choker = Arc::new(Choker::default());
+    let choker_handle = choker.spawn();
+    // torrent's own speed limits, GUI changes them while torrent runs
+    let limiter = ratelimit::torrent(&torrent.info_hash);
 
     let mut peers: Vec<DownloaderPeer> = Vec::new();
     let pieces_done = if let TorrentInfo::Torrent(_) = torrent_info {
-        Some(saver::find_downloaded_pieces(torrent.clone(), save_path, ui_handle.clone()).await)
+        Some(saver::find_downloaded_pieces(torrent.clone(), save_path, events.clone()).await)
     } else {
         None
     };
-    let _ = ui_handle.send_with_update(UiMsg::HashCheckFinished);
-
-    let pieces_done_n = if let TorrentInfo::Backup(ref backup) = torrent_info {
-        backup.pieces_done
-    } else {
-        pieces_done.as_ref().unwrap().len()
-    };
+    events.send(EngineEvent::HashCheckFinished);
 
     let mut pieces_tasks;
     let mut chunks_tasks;
@@ -130,50 +195,81 @@ pub async fn download_torrent(
             pieces_tasks =
                 download::tasks::get_piece_tasks(torrent.clone(), pieces_done.clone().unwrap());
             chunks_tasks = VecDeque::new();
-            download::tasks::add_chunks_tasks(
-                &mut pieces_tasks,
-                &mut chunks_tasks,
-                MAX_CHUNKS_TASKS - 1,
-            );
-            log!(LogLevel::Debug, "\n{:?}\n{:?}", pieces_tasks, chunks_tasks);
+            log!(LogLevel::Debug, "\n{:?}", pieces_tasks);
         }
         TorrentInfo::Backup(ref info) => {
             pieces_tasks = info.pieces_tasks.clone();
+            // fully requested pieces could be kept by older versions
+            pieces_tasks.retain(|x| !x.is_assigned());
             chunks_tasks = info.chunks_tasks.clone();
-            let len: usize = chunks_tasks.len();
-            if MAX_CHUNKS_TASKS as i32 - chunks_tasks.len() as i32 > 0 {
-                download::tasks::add_chunks_tasks(
-                    &mut pieces_tasks,
-                    &mut chunks_tasks,
-                    MAX_CHUNKS_TASKS - len,
-                );
-            }
         }
     }
+    // chunks tasks are made from pieces_tasks on demand, by peer's pieces
+    let mut picker = PiecePicker::new(torrent.info.piece_hashes.len());
+    // skipped pieces stay in tasks, picker doesn't give them to peers
+    let file_priorities = priorities::torrent(&torrent.info_hash);
+    let mut priorities_version = file_priorities.version();
+    picker.set_priorities(priorities::piece_priorities(&torrent, &file_priorities.get()));
+    picker.set_urgent(priorities::urgent_pieces(&torrent, &file_priorities.urgent()));
+    picker.set_sequential(file_priorities.is_sequential());
+    let endgame = Arc::new(Endgame::new());
+    // bytes of pieces that aren't downloaded yet
+    let left = pieces_tasks
+        .iter()
+        .map(|x| x.piece_i)
+        .chain(chunks_tasks.iter().map(|x| x.piece_i))
+        .collect::<HashSet<u16>>()
+        .into_iter()
+        .map(|x| torrent.get_piece_length(x as usize))
+        .sum();
+    let stats = Arc::new(TransferStats::new(left));
+    let uploaded_total = || totals.uploaded + stats.uploaded.load(Ordering::Relaxed);
+    // grows only when seeding, can happen several times if files are selected later
+    let mut seeding_secs = totals.seeding_secs;
+    // Worker state saved on Pause and Stop, torrent is resumed from it later
+    let backup_info = |pieces_tasks: VecDeque<PieceTask>,
+                       chunks_tasks: VecDeque<ChunksTask>,
+                       pieces_done: usize,
+                       status: DownloadStatus,
+                       share: ShareTotals| TorrentBackupInfo {
+        pieces_tasks,
+        chunks_tasks,
+        torrent: backup_torrent(),
+        save_path: save_path.to_string(),
+        pieces_done,
+        status,
+        uploaded: share.uploaded,
+        seeding_secs: share.seeding_secs,
+        limits: limiter.limits(),
+        file_priorities: file_priorities.get(),
+        sequential: file_priorities.is_sequential(),
+    };
+
     let saver_cancel = CancellationToken::new();
-    let saver_task = saver::spawn_saver(
+    let mut saver_task = saver::spawn_saver(
         save_path.to_string(),
         torrent.clone(),
         get_data,
+        send_data.clone(),
         send_status.clone(),
-        pieces_done_n,
-        ui_handle.clone(),
+        // backup restores verified pieces in saver
+        pieces_done.clone().unwrap_or_default(),
+        events.clone(),
         if let TorrentInfo::Backup(backup) = torrent_info {
             Some(backup)
         } else {
             None
         },
+        stats.clone(),
         saver_cancel.clone(),
     )
     .await;
 
-    if pieces_tasks.is_empty() && chunks_tasks.is_empty() {
+    // complete torrent goes straight to seeding
+    let mut finished = pieces_tasks.is_empty() && chunks_tasks.is_empty();
+    if finished {
         log!(LogLevel::Info, "Done");
-        for handle in peer_discovery_handles {
-            handle.abort();
-        }
-        ui_handle.send_with_update(UiMsg::TorrentFinished).unwrap();
-        return Ok(());
+        events.send(EngineEvent::TorrentFinished);
     }
 
     let mut bitmap = PieceBitmap::new(torrent.info.piece_hashes.len());
@@ -184,358 +280,525 @@ pub async fn download_torrent(
         }
     }
 
-    while let Ok(msg) = ui_reader.try_recv() {
-        match msg {
-            UiMsg::ForceOff => {
-                log!(LogLevel::Debug, "Gor off msg, shutting down..");
-                saver_cancel.cancel();
-                let _ = saver_task.await;
-                log!(LogLevel::Info, "Saver finished");
-                return Ok(());
-            }
-            ref msg @ UiMsg::Stop(done) | ref msg @ UiMsg::Pause(done) => {
-                log!(LogLevel::Debug, "Gor pause msg, shutting down..");
-                Backup::global()
-                    .backup_torrent(TorrentBackupInfo {
-                        pieces_tasks,
-                        chunks_tasks,
-                        torrent: Arc::as_ref(&torrent).clone(),
-                        save_path: save_path.to_string(),
-                        pieces_done: 0,
-                        status: if let UiMsg::Pause(_) = msg {
-                            DownloadStatus::Paused
-                        } else {
-                            DownloadStatus::Downloading
-                        },
-                    })
-                    .await?;
-                saver_cancel.cancel();
-                let _ = saver_task.await;
-                log!(LogLevel::Info, "Saver finished");
-                return Ok(());
-            }
-            _ => {}
+    if let Some(msg) = next_stop_event(&mut event_reader) {
+        log!(LogLevel::Debug, "Got {msg:?} before download, shutting down..");
+        if let Some((pieces_done, status)) = backup_status(&msg, finished) {
+            let share = ShareTotals {
+                uploaded: uploaded_total(),
+                seeding_secs,
+            };
+            let info = backup_info(pieces_tasks, chunks_tasks, pieces_done, status, share);
+            Backup::global().backup_torrent(info).await?;
         }
+        dht_handle.abort();
+        choker_handle.abort();
+        saver_cancel.cancel();
+        let _ = saver_task.await;
+        log!(LogLevel::Info, "Saver finished");
+        return Ok(());
     }
 
+    // Announcing to trackers while worker is alive, peers are sent through PeerAdd
+    let announcer = Announcer::spawn(
+        tracker_req.clone(),
+        stats.clone(),
+        send_status.clone(),
+        send_data.clone(),
+        events.clone(),
+    );
+
     let semaphore = Arc::new(Semaphore::new(50));
     let mut wait_for_channel_msg = false;
     loop {
-        // checking that saver task is alive
-        if saver_task.is_finished() {
-            if let Ok(res) = saver_task.await {
-                res?;
+        while !finished {
+            // checking that saver task is alive
+            if saver_task.is_finished() {
+                if let Ok(res) = (&mut saver_task).await {
+                    res?;
+                }
+                break;
             }
-            break;
-        }
 
-        // if no free peers found, waiting for any message for some time,
-        // if none appeared, searching for peers again
-        let download_status = if wait_for_channel_msg {
-            let output;
-            let time = tokio::time::sleep(Duration::from_secs(20));
-            tokio::select! {
-                _ = time => {
-                    log!(LogLevel::Info, "Started peer search");
-                    peer_discovery_handles = tracker_req.discover_peers(torrent.clone(), send_status.clone(), send_data.clone()).await;
-                    continue;
-                }
-                res = get_status.recv() => {
-                    output = res;
+            if file_priorities.version() != priorities_version {
+                priorities_version = file_priorities.version();
+                log!(LogLevel::Info, "Piece priorities changed");
+                let files = file_priorities.get();
+                picker.set_priorities(priorities::piece_priorities(&torrent, &files));
+                let urgent = file_priorities.urgent();
+                picker.set_urgent(priorities::urgent_pieces(&torrent, &urgent));
+                picker.set_sequential(file_priorities.is_sequential());
+            }
+
+            // if no free peers found, waiting for any message for some time,
+            // if none appeared, searching for peers again
+            let mut stop = None;
+            let download_status = if wait_for_channel_msg {
+                let time = tokio::time::sleep(Duration::from_secs(20));
+                tokio::select! {
+                    _ = time => {
+                        log!(LogLevel::Info, "Started peer search");
+                        announcer.announce_now().await;
+                        continue;
+                    }
+                    res = get_status.recv() => res,
+                    res = event_reader.recv() => match res {
+                        Ok(msg) if is_stop_event(&msg) => {
+                            stop = Some(msg);
+                            None
+                        }
+                        Err(RecvError::Lagged(n)) => {
+                            log!(LogLevel::Error, "Worker skipped {n} events");
+                            continue;
+                        }
+                        _ => continue,
+                    },
                 }
-                msg @ Ok(UiMsg::ForceOff | UiMsg::Pause(_) | UiMsg::Stop(_)) = ui_reader.recv() => {
-                    let msg = msg.unwrap();
-                    for peer in peers {
-                        if let DownloaderPeer::Busy(DownloaderInfo {
-                            handle: Some(handle),
-                            task,
-                            ..
-                        }) = peer
-                        {
-                            log!(LogLevel::Fatal, "Returned chunk task: {:?}", task);
+            } else {
+                stop = next_stop_event(&mut event_reader);
+                get_status.try_recv().ok()
+            };
+            if let Some(msg) = stop {
+                log!(LogLevel::Debug, "Got {msg:?}, shutting down worker");
+                for peer in peers.drain(..) {
+                    if let DownloaderPeer::Busy(DownloaderInfo {
+                        handle: Some(handle),
+                        tasks,
+                        ..
+                    }) = peer
+                    {
+                        log!(LogLevel::Debug, "Returned chunk tasks: {:?}", tasks);
+                        for task in tasks.into_iter().rev() {
                             chunks_tasks.push_front(task);
-                            handle.abort();
                         }
+                        handle.abort();
                     }
-                    match msg {
-                        UiMsg::ForceOff => {
-                            log!(LogLevel::Debug, "Gor off msg, shutting down..");
-                        },
-                        ref msg @ UiMsg::Stop(done) | ref msg @ UiMsg::Pause(done) => {
-                            log!(LogLevel::Debug, "Gor pause msg, shutting down..");
-                            Backup::global().backup_torrent(
-                                TorrentBackupInfo {
-                                    pieces_tasks,
-                                    chunks_tasks,
-                                    torrent: Arc::as_ref(&torrent).clone(),
-                                    save_path: save_path.to_string(),
-                                    pieces_done: done as usize,
-                                    status: if let UiMsg::Pause(_) = msg {
-                                        DownloadStatus::Paused
-                                    } else {
-                                        DownloadStatus::Downloading
-                                    }
-                                },
-                            ).await?;
-                        },
-                        _ => {}
-                    }
-                    saver_cancel.cancel();
-                    let _ = saver_task.await;
-                    log!(LogLevel::Info, "Saver finished");
-                    break;
                 }
+                if let Some((pieces_done, status)) = backup_status(&msg, finished) {
+                    let share = ShareTotals {
+                        uploaded: uploaded_total(),
+                        seeding_secs,
+                    };
+                    let info = backup_info(
+                        std::mem::take(&mut pieces_tasks),
+                        std::mem::take(&mut chunks_tasks),
+                        pieces_done,
+                        status,
+                        share,
+                    );
+                    Backup::global().backup_torrent(info).await?;
+                }
+                saver_cancel.cancel();
+                let _ = (&mut saver_task).await;
+                log!(LogLevel::Info, "Saver finished");
+                break;
             }
-            output
-        } else {
-            let ui_msg = ui_reader.try_recv();
-            if let Ok(msg) = ui_msg {
-                match msg {
-                    UiMsg::ForceOff => {
-                        log!(LogLevel::Debug, "Gor off msg, shutting down..");
-                        for peer in peers {
-                            if let DownloaderPeer::Busy(DownloaderInfo {
-                                handle: Some(handle),
-                                ..
-                            }) = peer
-                            {
-                                handle.abort();
-                            }
-                        }
+            if let Some(download_status) = download_status {
+                match download_status {
+                    DownloadEvents::PieceComplete(n) => {
+                        bitmap.add(n);
+                    }
+                    DownloadEvents::Finished => {
+                        finished = true;
+                        announcer.completed().await;
+                        events.send(EngineEvent::TorrentFinished);
                         break;
                     }
-                    ref msg @ UiMsg::Stop(done) | ref msg @ UiMsg::Pause(done) => {
-                        log!(LogLevel::Debug, "Shutting down worker");
-                        for peer in peers {
+                    DownloadEvents::InvalidHash(piece_i) => {
+                        endgame.forget_piece(piece_i as u16);
+                        let total_chunks =
+                            (torrent.info.piece_length as f64 / CHUNK_SIZE as f64).ceil() as u64;
+                        pieces_tasks.push_front(PieceTask {
+                            piece_i: piece_i as u16,
+                            chunks_done: 0,
+                            total_chunks: if piece_i as usize == (torrent.info.piece_hashes.len() - 1) {
+                                ((torrent.info.length
+                                    - (torrent.info.piece_hashes.len() - 1) as u64
+                                        * torrent.info.piece_length)
+                                    as f64
+                                    / CHUNK_SIZE as f64)
+                                    .ceil() as u64
+                            } else {
+                                total_chunks
+                            } as u16,
+                        })
+                    }
+                    DownloadEvents::ChunksFail(chunk) => {
+                        log!(LogLevel::Debug, "chunk failed: {:?}", chunk);
+                        chunks_tasks.push_front(chunk);
+                    }
+                    DownloadEvents::PeerAdd(mut peer, discovered) => {
+                        // skipping if peer exists already
+                        if discovered {
+                            peer.own_bitfield = bitmap.clone();
+                            peer.extensions = extensions.clone();
+                            peer.choker = choker.clone();
+                            peer.availability = picker.availability();
+                            peer.limiter = PeerLimiter::new(limiter.clone());
+                            events.send(EngineEvent::PeerDiscovered(peer.peer_addr));
+                        }
+                        if discovered
+                            && peers
+                                .iter()
+                                .position(|x| match x {
+                                    DownloaderPeer::Busy(info) => info.peer_addr == peer.peer_addr,
+                                    DownloaderPeer::Free(arr_peer) => {
+                                        peer.peer_addr == arr_peer.peer_addr
+                                    }
+                                    _ => false,
+                                })
+                                .is_some()
+                        {
+                            log!(LogLevel::Info, "Continue:^(");
+                            continue;
+                        }
+
+                        wait_for_channel_msg = false;
+
+                        // Checking did peer task finished or not
+                        for el in &mut peers {
                             if let DownloaderPeer::Busy(DownloaderInfo {
                                 handle: Some(handle),
-                                task,
                                 ..
-                            }) = peer
+                            }) = el
                             {
-                                log!(LogLevel::Fatal, "Returned chunk task: {:?}", task);
-                                chunks_tasks.push_front(task);
-                                handle.abort();
+                                if handle.is_finished() {
+                                    if let DownloaderPeer::Busy(info) =
+                                        std::mem::replace(el, DownloaderPeer::Finished)
+                                    {
+                                        pex.peer_dropped(&info.peer_addr);
+                                        picker.peer_removed(&info.peer_addr);
+                                        choker.peer_removed(&info.peer_addr);
+                                    }
+                                }
                             }
                         }
-                        Backup::global()
-                            .backup_torrent(TorrentBackupInfo {
-                                pieces_tasks,
-                                chunks_tasks,
-                                torrent: Arc::as_ref(&torrent).clone(),
-                                save_path: save_path.to_string(),
-                                pieces_done: done as usize,
-                                status: if let UiMsg::Pause(_) = msg {
-                                    DownloadStatus::Paused
-                                } else {
-                                    DownloadStatus::Downloading
-                                },
-                            })
-                            .await?;
-                        saver_cancel.cancel();
-                        let _ = saver_task.await;
-                        log!(LogLevel::Info, "Saver is gone");
-                        break;
+
+                        let pos = peers.iter().position(|x| {
+                            if let DownloaderPeer::Finished = x {
+                                true
+                            } else {
+                                false
+                            }
+                        });
+                        if !discovered {
+                            let _ = peer.sync_bitmaps(&bitmap).await;
+                        }
+                        pex.peer_connected(&peer.peer_addr);
+                        picker.peer_updated(&peer);
+                        if let Some(i) = pos {
+                            peers[i] = DownloaderPeer::Free(peer);
+                        } else {
+                            peers.push(DownloaderPeer::Free(peer))
+                        }
                     }
-                    _ => {}
                 }
-            };
-            let download_status = get_status.try_recv();
-            if let Ok(val) = download_status {
-                Some(val)
-            } else {
-                None
             }
-        };
-        if let Some(download_status) = download_status {
-            match download_status {
-                DownloadEvents::PieceComplete(n) => {
-                    bitmap.add(n);
-                }
-                DownloadEvents::Finished => {
-                    ui_handle.send_with_update(UiMsg::TorrentFinished).unwrap();
-                    break;
-                }
-                DownloadEvents::InvalidHash(piece_i) => {
-                    let total_chunks =
-                        (torrent.info.piece_length as f64 / CHUNK_SIZE as f64).ceil() as u64;
-                    pieces_tasks.push_front(PieceTask {
-                        piece_i: piece_i as u16,
-                        chunks_done: 0,
-                        total_chunks: if piece_i as usize == (torrent.info.piece_hashes.len() - 1) {
-                            ((torrent.info.length
-                                - (torrent.info.piece_hashes.len() - 1) as u64
-                                    * torrent.info.piece_length)
-                                as f64
-                                / CHUNK_SIZE as f64)
-                                .ceil() as u64
-                        } else {
-                            total_chunks
-                        } as u16,
-                    })
-                }
-                DownloadEvents::ChunksFail(chunk) => {
-                    log!(LogLevel::Debug, "chunk failed: {:?}", chunk);
-                    chunks_tasks.push_front(chunk);
-                }
-                DownloadEvents::PeerAdd(mut peer, discovered) => {
-                    // skipping if peer exists already
-                    if discovered {
-                        peer.own_bitfield = bitmap.clone();
-                        ui_handle
-                            .send_with_update(UiMsg::PeerDiscovered(peer.peer_addr.clone()))?;
-                    }
-                    if discovered
-                        && peers
-                            .iter()
-                            .position(|x| match x {
-                                DownloaderPeer::Busy(info) => info.peer_addr == peer.peer_addr,
-                                DownloaderPeer::Free(arr_peer) => {
-                                    peer.peer_addr == arr_peer.peer_addr
-                                }
-                                _ => false,
-                            })
-                            .is_some()
-                    {
-                        log!(LogLevel::Info, "Continue:^(");
-                        continue;
+
+            log!(LogLevel::Debug, "Got to task assignment");
+            let has_tasks = picker.has_wanted(&pieces_tasks, &chunks_tasks);
+            // tasks that are being downloaded, used in endgame
+            let in_flight: Vec<ChunksTask> = peers
+                .iter()
+                .filter_map(|x| match x {
+                    DownloaderPeer::Busy(DownloaderInfo {
+                        handle: Some(handle),
+                        tasks,
+                        ..
+                    }) if !handle.is_finished() => Some(tasks.clone()),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            if has_tasks || !in_flight.is_empty() {
+                let send_status = send_status.clone();
+                let mut free_poses = Vec::with_capacity(peers.len());
+                for (i, peer) in peers.iter().enumerate() {
+                    if let DownloaderPeer::Free(_) = peer {
+                        free_poses.push(i);
                     }
+                }
 
-                    wait_for_channel_msg = false;
+                if free_poses.is_empty() {
+                    log!(LogLevel::Debug, "No free peers, skipping iteration");
+                    wait_for_channel_msg = true;
+                    continue;
+                }
 
-                    // Checking did peer task finished or not
-                    for el in &mut peers {
-                        if let DownloaderPeer::Busy(DownloaderInfo {
-                            handle: Some(handle),
-                            ..
-                        }) = el
-                        {
-                            if handle.is_finished() {
-                                let _ = std::mem::replace(el, DownloaderPeer::Finished);
-                            }
-                        }
-                    }
+                let continue_permit = semaphore.clone().try_acquire_owned();
+                let continue_permit = if let Ok(p) = continue_permit {
+                    p
+                } else {
+                    continue;
+                };
 
-                    let pos = peers.iter().position(|x| {
-                        if let DownloaderPeer::Finished = x {
-                            true
+                let (peer_i, mut tasks) = {
+                    let mut ok_task = None;
+                    for i in &free_poses {
+                        let DownloaderPeer::Free(ref peer) = peers[*i] else {
+                            continue;
+                        };
+                        let task = if has_tasks {
+                            picker.next_task(peer, &mut pieces_tasks, &mut chunks_tasks)
                         } else {
-                            false
+                            if !endgame.is_active() {
+                                log!(LogLevel::Info, "All blocks are requested, entering endgame");
+                                endgame.activate();
+                            }
+                            picker.endgame_task(peer, &in_flight.iter().collect::<Vec<_>>())
+                        };
+                        if let Some(task) = task {
+                            ok_task = Some((*i, vec![task]));
+                            break;
                         }
-                    });
-                    if !discovered {
-                        let _ = peer.sync_bitmaps(&bitmap).await;
                     }
-                    if let Some(i) = pos {
-                        peers[i] = DownloaderPeer::Free(peer);
+                    if let Some(res) = ok_task {
+                        res
                     } else {
-                        peers.push(DownloaderPeer::Free(peer))
+                        log!(
+                            LogLevel::Debug,
+                            "No free peers have needed pieces, skipping iteration"
+                        );
+                        wait_for_channel_msg = true;
+                        continue;
+                    }
+                };
+
+                let DownloaderPeer::Free(peer) = &peers[peer_i] else {
+                    panic!("Not possible")
+                };
+                // peer gets enough blocks to fill its request window, possibly
+                // from several pieces, so requests don't stop between pieces
+                if has_tasks {
+                    let mut chunks_n: usize = tasks.iter().map(|x| x.chunks.len()).sum();
+                    while chunks_n < peer.request_window.size() {
+                        let Some(task) =
+                            picker.next_task(peer, &mut pieces_tasks, &mut chunks_tasks)
+                        else {
+                            break;
+                        };
+                        chunks_n += task.chunks.len();
+                        tasks.push(task);
                     }
                 }
+                let peer_addr = peer.peer_addr;
+                let DownloaderPeer::Free(peer) = std::mem::replace(
+                    &mut peers[peer_i],
+                    DownloaderPeer::Busy(DownloaderInfo {
+                        handle: None,
+                        tasks: tasks.clone(),
+                        peer_addr,
+                    }),
+                ) else {
+                    panic!("not possible, we checked it")
+                };
+                let downloader = DownloadReq::new(torrent.clone(), peer, tasks, endgame.clone());
+                let handle = tokio::spawn(async move {
+                    log!(
+                        LogLevel::Debug,
+                        "Peer {}, Curr tasks: {:?}",
+                        downloader.peer.peer_addr,
+                        downloader.tasks
+                    );
+                    let tasks = downloader.tasks.clone();
+                    let addr = downloader.peer.peer_addr;
+                    let send_status2 = send_status.clone();
+                    drop(continue_permit);
+                    if let Err(e) = downloader.request_data(send_status).await {
+                        log!(
+                            LogLevel::Error,
+                            "Request data error: {}, peer addr {}",
+                            e,
+                            addr
+                        );
+                        for task in tasks {
+                            send_status2
+                                .send(DownloadEvents::ChunksFail(task))
+                                .await
+                                .unwrap();
+                        }
+                    };
+                });
+                if let DownloaderPeer::Busy(ref mut info) = peers[peer_i] {
+                    info.handle = Some(handle);
+                }
+            } else if !(pieces_tasks.is_empty() && chunks_tasks.is_empty())
+                && picker.selection_done(&bitmap)
+            {
+                // only pieces of skipped files are left
+                log!(LogLevel::Info, "Selected files are downloaded");
+                finished = true;
+                events.send(EngineEvent::TorrentFinished);
+            } else {
+                wait_for_channel_msg = true;
+                continue;
             }
         }
 
-        if chunks_tasks.len() < MAX_CHUNKS_TASKS {
-            download::tasks::add_chunks_tasks(&mut pieces_tasks, &mut chunks_tasks, 1);
+        if !finished {
+            break;
         }
-        log!(LogLevel::Debug, "Got to task assignment");
-        if !chunks_tasks.is_empty() {
-            let send_status = send_status.clone();
-            let mut free_poses = Vec::with_capacity(peers.len());
-            for (i, peer) in peers.iter().enumerate() {
-                if let DownloaderPeer::Free(_) = peer {
-                    free_poses.push(i);
-                }
+        // complete torrent, bitmap could be empty if it started finished
+        if pieces_tasks.is_empty() && chunks_tasks.is_empty() {
+            for i in 0..torrent.info.piece_hashes.len() {
+                bitmap.add(i);
             }
-
-            if free_poses.is_empty() {
-                log!(LogLevel::Debug, "No free peers, skipping iteration");
-                wait_for_channel_msg = true;
+        }
+        // peers that are downloading now come later through PeerAdd
+        let free_peers = peers
+            .drain(..)
+            .filter_map(|x| match x {
+                DownloaderPeer::Free(peer) => Some(peer),
+                _ => None,
+            })
+            .collect();
+        let seeder = Seeder {
+            torrent: torrent.clone(),
+            events: events.clone(),
+            stats: stats.clone(),
+            choker: choker.clone(),
+            extensions: extensions.clone(),
+            pex: pex.clone(),
+            limiter: limiter.clone(),
+            totals: ShareTotals {
+                uploaded: totals.uploaded,
+                seeding_secs,
+            },
+        };
+        let end = seeder
+            .run(free_peers, bitmap.clone(), &mut get_status, &mut event_reader)
+            .await;
+        let (status, share) = match end {
+            SeedEnd::SelectionChanged(share) => {
+                seeding_secs = share.seeding_secs;
+                finished = false;
+                events.send(EngineEvent::DownloadResumed);
+                announcer.announce_now().await;
                 continue;
             }
+            SeedEnd::TargetReached(share) => (Some(DownloadStatus::Finished), share),
+            SeedEnd::Stopped(EngineEvent::Pause(_), share) => (Some(DownloadStatus::Paused), share),
+            SeedEnd::Stopped(EngineEvent::Stop(_), share) => (Some(DownloadStatus::Seeding), share),
+            SeedEnd::Stopped(_, share) => (None, share),
+        };
+        if let Some(status) = status {
+            // tasks of skipped pieces are kept
+            let pieces_done = (0..torrent.info.piece_hashes.len())
+                .filter(|i| bitmap.has(*i))
+                .count();
+            let info = backup_info(
+                std::mem::take(&mut pieces_tasks),
+                std::mem::take(&mut chunks_tasks),
+                pieces_done,
+                status,
+                share,
+            );
+            Backup::global().backup_torrent(info).await?;
+        }
+        saver_cancel.cancel();
+        let _ = (&mut saver_task).await;
+        break;
+    }
 
-            let continue_permit = semaphore.clone().try_acquire_owned();
-            let continue_permit = if let Ok(p) = continue_permit {
-                p
-            } else {
-                continue;
-            };
+    announcer.stop().await;
+    dht_handle.abort();
+    choker_handle.abort();
+    Ok(())
+}
 
-            let task = chunks_tasks.pop_front().unwrap();
-            let peer_i = {
-                let mut ok_peer_i = None;
-                for i in &free_poses {
-                    let DownloaderPeer::Free(ref mut peer) = peers[*i] else {
-                        continue;
-                    };
-                    if peer.have_piece(task.piece_i as usize) {
-                        ok_peer_i = Some(*i);
-                        break;
-                    }
-                }
-                if let Some(i) = ok_peer_i {
-                    i
-                } else {
-                    log!(
-                        LogLevel::Debug,
-                        "No peers that have this piece, skipping iteration"
-                    );
-                    wait_for_channel_msg = true;
-                    chunks_tasks.push_front(task);
-                    continue;
-                }
-            };
+// Events session controls worker with
+fn is_stop_event(msg: &EngineEvent) -> bool {
+    matches!(
+        msg,
+        EngineEvent::ForceOff | EngineEvent::Pause(_) | EngineEvent::Stop(_)
+    )
+}
 
-            let DownloaderPeer::Free(peer) = &peers[peer_i] else {
-                panic!("Not possible")
-            };
-            let peer_addr = peer.peer_addr.clone();
-            let DownloaderPeer::Free(peer) = std::mem::replace(
-                &mut peers[peer_i],
-                DownloaderPeer::Busy(DownloaderInfo {
-                    handle: None,
-                    task: task.clone(),
-                    peer_addr,
-                }),
-            ) else {
-                panic!("not possible, we checked it")
-            };
-            let downloader = DownloadReq::new(torrent.clone(), peer, task);
-            let handle = tokio::spawn(async move {
-                log!(
-                    LogLevel::Debug,
-                    "Peer {}, Curr task: {:?}",
-                    downloader.peer.peer_addr,
-                    downloader.task
-                );
-                let task = downloader.task.clone();
-                let addr = downloader.peer.peer_addr.clone();
-                let send_status2 = send_status.clone();
-                drop(continue_permit);
-                if let Err(e) = downloader.request_data(send_status).await {
-                    log!(
-                        LogLevel::Error,
-                        "Request data error: {}, peer addr {}",
-                        e,
-                        addr
-                    );
-                    send_status2
-                        .send(DownloadEvents::ChunksFail(task))
-                        .await
-                        .unwrap();
-                };
-            });
-            if let DownloaderPeer::Busy(ref mut info) = peers[peer_i] {
-                info.handle = Some(handle);
+// Reading all queued events without waiting, other events are dropped
+fn next_stop_event(event_reader: &mut broadcast::Receiver<EngineEvent>) -> Option<EngineEvent> {
+    loop {
+        match event_reader.try_recv() {
+            Ok(msg) if is_stop_event(&msg) => return Some(msg),
+            Ok(_) => {}
+            Err(TryRecvError::Lagged(n)) => {
+                log!(LogLevel::Error, "Worker skipped {n} events");
             }
-        } else {
-            wait_for_channel_msg = true;
-            continue;
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
         }
     }
+}
 
-    for handle in peer_discovery_handles {
-        handle.abort();
+// Pieces done and status saved to backup, ForceOff isn't backed up
+fn backup_status(msg: &EngineEvent, finished: bool) -> Option<(usize, DownloadStatus)> {
+    match *msg {
+        EngineEvent::Pause(done) => Some((done as usize, DownloadStatus::Paused)),
+        EngineEvent::Stop(done) if finished => Some((done as usize, DownloadStatus::Seeding)),
+        EngineEvent::Stop(done) => Some((done as usize, DownloadStatus::Downloading)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::sync::{broadcast, RwLock};
+
+    use super::events::tests::EventLog;
+    use super::*;
+
+    const DATA_LEN: usize = 40_000;
+    const PIECE_LEN: usize = 16_384;
+
+    // Single file torrent with its data already on disk
+    fn complete_torrent(dir: &std::path::Path) -> (Torrent, String) {
+        let data: Vec<u8> = (0..DATA_LEN).map(|x| (x % 251) as u8).collect();
+        let mut pieces = Vec::new();
+        for piece in data.chunks(PIECE_LEN) {
+            pieces.extend(Torrent::bytes_hash(&piece.to_vec()));
+        }
+        let announce = "http://127.0.0.1:1/announce";
+        let mut meta = format!("d8:announce{}:{announce}4:infod", announce.len()).into_bytes();
+        meta.extend(format!("6:lengthi{DATA_LEN}e4:name8:data.bin").as_bytes());
+        meta.extend(format!("12:piece lengthi{PIECE_LEN}e6:pieces{}:", pieces.len()).as_bytes());
+        meta.extend(pieces);
+        meta.extend(b"ee");
+        let path = dir.join("data.bin");
+        std::fs::write(&path, data).unwrap();
+        let torrent = Torrent::from_bytes(&meta).unwrap();
+        (torrent, path.to_string_lossy().into_owned())
+    }
+
+    #[tokio::test]
+    async fn worker_events_reach_subscriber() {
+        let _ = saver::SAVE_INFO.set(RwLock::new(HashMap::new()));
+        let dir = std::env::temp_dir().join(format!("events-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (torrent, path) = complete_torrent(&dir);
+        let pieces_n = torrent.info.piece_hashes.len();
+
+        let (sender, _) = broadcast::channel(100);
+        let log = Arc::new(EventLog::default());
+        let events = EventSender::new(torrent.info_hash.clone(), sender.clone(), vec![log.clone()]);
+        let worker = tokio::spawn(async move {
+            let peer_id = "-TS0001-000000000000".to_string();
+            download_torrent(TorrentInfo::Torrent(torrent), &path, events, peer_id).await
+        });
+
+        let finished = async {
+            while !log.has(|x| matches!(x, EngineEvent::TorrentFinished)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), finished).await.unwrap();
+        assert!(log.has(|x| matches!(x, EngineEvent::HashCheckFinished)));
+        for i in 0..pieces_n {
+            assert!(log.has(|x| matches!(x, EngineEvent::PieceDone(n) if *n as usize == i)));
+        }
+
+        sender.send(EngineEvent::ForceOff).unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(10), worker).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap().unwrap();
     }
-    Ok(())
 }
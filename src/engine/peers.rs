@@ -1,25 +1,39 @@
 use async_recursion::async_recursion;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
 use tokio::time::timeout;
 
+use super::bencode::BencodeValue;
+use super::choker::Choker;
+use super::download::picker::Availability;
+use super::download::pipeline::RequestWindow;
 use super::download::DataPiece;
+use super::events::EngineEvent;
+use super::extensions::{self, ExtensionRegistry};
 use super::logger::{log, LogLevel};
+use super::ratelimit::PeerLimiter;
 use super::torrent::Torrent;
 use crate::engine::download::tasks::CHUNK_SIZE;
 use crate::engine::saver;
 use crate::engine::torrent::PieceBitmap;
-use crate::gui::UiMsg;
 
 const MAX_INTERESTED_ATTEMPTS: u8 = 3;
+// Longest accepted message other than piece and bitfield, mostly extended ones
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// Reserved bit in handshake marking extension protocol (BEP 10) support
+const EXTENSION_BIT: u8 = 0x10;
 
 #[derive(Debug)]
 pub struct Peer {
     pub peer_id: Option<String>,
     pub my_peer_id: String,
-    pub peer_addr: String,
+    pub peer_addr: SocketAddr,
     // can be shorter than it should
     // in case received have request instead of bitfield msg
     pub peer_bitfield: Option<Vec<u8>>,
@@ -28,6 +42,20 @@ pub struct Peer {
     pub status: PeerStatus,
     pub socket: TcpStream,
     pub info_hash: String,
+    // Remote side set extension protocol bit in handshake
+    pub supports_extensions: bool,
+    pub extensions: Arc<ExtensionRegistry>,
+    // Extension name -> id assigned by remote side, None before extended handshake
+    pub remote_extensions: Option<HashMap<String, u8>>,
+    // Outstanding requests limit, kept between download tasks
+    pub request_window: RequestWindow,
+    pub choker: Arc<Choker>,
+    // Piece availability of worker's picker, Have messages are counted in it
+    pub availability: Arc<Availability>,
+    // We don't upload to peer while choking it
+    pub am_choking: bool,
+    // Torrent and global speed limits applied to socket reads and writes
+    pub limiter: PeerLimiter,
 }
 
 #[derive(Debug, PartialEq)]
@@ -41,6 +69,8 @@ pub enum PeerMessage {
     Request(Vec<u8>),
     Piece(Vec<u8>),
     Cancel(Vec<u8>),
+    // Extension protocol message, extended message id + payload
+    Extended(u8, Vec<u8>),
     KeepAlive,
 }
 
@@ -57,6 +87,7 @@ impl std::fmt::Display for PeerMessage {
             PeerMessage::Bitfield(_) => "bitfield",
             PeerMessage::Unchoke => "unckoke",
             PeerMessage::NotInterested => "not-interested",
+            PeerMessage::Extended(_, _) => "extended",
         };
         write!(f, "{}", msg)
     }
@@ -65,13 +96,15 @@ impl std::fmt::Display for PeerMessage {
 #[derive(Debug)]
 pub enum PeerStatus {
     NotConnected,
+    // Handshake is done by remote side (incoming connection)
+    Handshaked,
     Choked,
     Unchoked,
 }
 
 impl Peer {
     pub async fn new(
-        addr: &str,
+        addr: SocketAddr,
         data_sender: Sender<DataPiece>,
         peer_id: String,
         info_hash: String,
@@ -83,7 +116,7 @@ impl Peer {
         };
         Ok(Peer {
             peer_id: None,
-            peer_addr: addr.to_owned(),
+            peer_addr: addr,
             my_peer_id: peer_id,
             socket,
             info_hash,
@@ -91,10 +124,47 @@ impl Peer {
             peer_bitfield: None,
             own_bitfield: PieceBitmap::new(1),
             status: PeerStatus::NotConnected,
+            supports_extensions: false,
+            extensions: Arc::new(ExtensionRegistry::default()),
+            remote_extensions: None,
+            request_window: RequestWindow::default(),
+            choker: Arc::new(Choker::default()),
+            availability: Arc::new(Availability::default()),
+            am_choking: true,
+            limiter: PeerLimiter::default(),
         })
     }
+
+    pub fn from_socket(
+        socket: TcpStream,
+        addr: SocketAddr,
+        data_sender: Sender<DataPiece>,
+        peer_id: String,
+        info_hash: String,
+    ) -> Peer {
+        Peer {
+            peer_id: None,
+            peer_addr: addr,
+            my_peer_id: peer_id,
+            socket,
+            info_hash,
+            data_sender,
+            peer_bitfield: None,
+            own_bitfield: PieceBitmap::new(1),
+            status: PeerStatus::NotConnected,
+            supports_extensions: false,
+            extensions: Arc::new(ExtensionRegistry::default()),
+            remote_extensions: None,
+            request_window: RequestWindow::default(),
+            choker: Arc::new(Choker::default()),
+            availability: Arc::new(Availability::default()),
+            am_choking: true,
+            limiter: PeerLimiter::default(),
+        }
+    }
+
     pub async fn reconnect(&mut self, torrent: &Torrent, dur: Duration) -> anyhow::Result<()> {
-        self.socket = match timeout(dur, TcpStream::connect(&self.peer_addr)).await {
+        self.socket = match timeout(dur, TcpStream::connect(self.peer_addr)).await {
             Ok(res) => res?,
             Err(_) => anyhow::bail!("Connection timeout"),
         };
@@ -107,13 +177,19 @@ impl Peer {
     pub async fn connect(&mut self, torrent: &Torrent) -> anyhow::Result<()> {
         match self.status {
             PeerStatus::Unchoked => Ok(()),
-            PeerStatus::NotConnected => {
+            PeerStatus::NotConnected | PeerStatus::Handshaked => {
                 log!(
                     LogLevel::Debug,
                     "Connecting to peer: {}",
                     self.socket.peer_addr()?
                 );
-                self.peer_id = Some(self.handshake(torrent, Duration::from_secs(4)).await?);
+                if let PeerStatus::NotConnected = self.status {
+                    self.peer_id = Some(self.handshake(torrent, Duration::from_secs(4)).await?);
+                } else {
+                    self.send_message(&PeerMessage::Bitfield(self.own_bitfield.bitmap.clone()))
+                        .await?;
+                    self.send_extended_handshake().await?;
+                }
                 let mut timeout = 1;
                 let mut attempts_n = 0;
                 while attempts_n < MAX_INTERESTED_ATTEMPTS {
@@ -175,112 +251,172 @@ impl Peer {
                 msg = self.receive_message().await?;
             }
             let msg_str = msg.to_string();
-            match msg {
-                PeerMessage::Bitfield(buf) => self.peer_bitfield = Some(buf),
-                PeerMessage::Have(n) => {
-                    if let Some(ref mut bitfield) = self.peer_bitfield {
-                        let bitfield_i = n / 8;
-                        if bitfield_i as usize <= bitfield.len() {
-                            let bit_n = n as i32 % 8;
-                            let mut mask = 128;
-                            for _ in 0..(bit_n - 1) {
-                                mask >>= 1;
-                            }
-                            bitfield[bitfield_i as usize] |= mask;
-                        }
-                    } else {
-                        let bitfield_i = n / 8;
-                        let bit_n = n as i32 % 8;
-                        let mut mask = 128;
-                        for _ in 0..(bit_n - 1) {
-                            mask >>= 1;
-                        }
-                        let mut bitfield = vec![0u8; (bitfield_i + 1) as usize];
-                        bitfield[bitfield_i as usize] |= mask;
-                        self.peer_bitfield = Some(bitfield);
+            self.handle_message(msg).await?;
+            if msg_str == *target_msg.to_string() {
+                n += 1;
+                if n >= msg_appear_n {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Sending choke or unchoke if choker changed its decision about peer
+    pub async fn sync_choke(&mut self) -> anyhow::Result<()> {
+        let unchoke = self.choker.is_unchoked(&self.peer_addr);
+        if unchoke == self.am_choking {
+            let msg = if unchoke {
+                PeerMessage::Unchoke
+            } else {
+                PeerMessage::Choke
+            };
+            self.send_message(&msg).await?;
+            self.am_choking = !unchoke;
+        }
+        Ok(())
+    }
+
+    // Updating peer state according to message, answering requests
+    pub async fn handle_message(&mut self, msg: PeerMessage) -> anyhow::Result<()> {
+        self.sync_choke().await?;
+        match msg {
+            PeerMessage::Bitfield(buf) => self.peer_bitfield = Some(buf),
+            PeerMessage::Have(n) => {
+                let byte_i = n as usize / 8;
+                // piece index can't exceed u16, see download_torrent
+                if byte_i <= u16::MAX as usize / 8 {
+                    let bitfield = self.peer_bitfield.get_or_insert_with(Vec::new);
+                    if bitfield.len() <= byte_i {
+                        bitfield.resize(byte_i + 1, 0);
                     }
+                    bitfield[byte_i] |= 0x80 >> (n % 8);
+                    self.availability.have(&self.peer_addr, n as usize);
                 }
-                PeerMessage::Unchoke => self.status = PeerStatus::Unchoked,
-                PeerMessage::Piece(buf) => {
-                    let piece_i = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-                    let begin = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                    self.data_sender
-                        .send(DataPiece {
-                            buf: buf[8..].to_vec(),
-                            piece_i: piece_i as u64,
-                            begin: begin as u64,
-                        })
-                        .await?;
+            }
+            PeerMessage::Unchoke => self.status = PeerStatus::Unchoked,
+            // requested blocks are taken by downloader, see block_received
+            PeerMessage::Piece(_) => {
+                log!(LogLevel::Debug, "Unrequested piece from peer {}", self.peer_addr);
+            }
+            PeerMessage::Choke => {
+                self.status = PeerStatus::Choked;
+                log!(LogLevel::Error, "peer {} choked", self.peer_addr);
+                anyhow::bail!("Peer choked");
+            }
+            PeerMessage::Interested => {
+                self.choker.set_interested(&self.peer_addr, true);
+                self.sync_choke().await?;
+            }
+            PeerMessage::NotInterested => {
+                self.choker.set_interested(&self.peer_addr, false);
+            }
+            // requests of choked peer are discarded
+            PeerMessage::Request(_) if self.am_choking => {}
+            PeerMessage::Request(buf) => {
+                log!(LogLevel::Debug, "Got request msg!!!");
+                if buf.len() != 12 {
+                    anyhow::bail!("Invalid request message");
                 }
-                PeerMessage::Choke => {
-                    self.status = PeerStatus::Choked;
-                    log!(LogLevel::Error, "peer {} choked", self.peer_addr);
-                    anyhow::bail!("Peer choked");
+                let piece_i = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                let begin = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                let length = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+                let Some(save_info) = saver::SAVE_INFO.get() else {
+                    return Ok(());
+                };
+                // guard isn't held while data is read and sent, upload waits for limiter
+                let (torrent, save_path, size_progression, verified, stats, events) = {
+                    let hashmap = save_info.read().await;
+                    let Some(save_info) = hashmap.get(&self.info_hash) else {
+                        return Ok(());
+                    };
+                    (
+                        save_info.torrent.clone(),
+                        save_info.save_path.clone(),
+                        save_info.size_progression.clone(),
+                        save_info.verified.clone(),
+                        save_info.stats.clone(),
+                        save_info.events.clone(),
+                    )
+                };
+                // only verified data is served, block has to be inside the piece
+                if length == 0
+                    || length as u64 > CHUNK_SIZE
+                    || piece_i as usize >= torrent.info.piece_hashes.len()
+                    || begin as u64 + length as u64 > torrent.get_piece_length(piece_i as usize)
+                {
+                    anyhow::bail!("Invalid request: piece {piece_i}, begin {begin}, length {length}");
                 }
-                PeerMessage::Interested => {
-                    self.send_message(&PeerMessage::Unchoke).await?;
+                if !verified.has(piece_i as usize) {
+                    log!(LogLevel::Debug, "Peer {} requested missing piece {piece_i}", self.peer_addr);
+                    return Ok(());
                 }
-                PeerMessage::Request(buf) => {
-                    log!(LogLevel::Debug, "Got request msg!!!");
-                    if buf.len() > 11 {
-                        let piece_i = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-                        let begin = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                        let length = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
-                        if length as u64 <= CHUNK_SIZE {
-                            let Some(save_info) = saver::SAVE_INFO.get() else {
-                                continue;
-                            };
-                            let hashmap = save_info.read().await;
-                            let Some(save_info) = hashmap.get(&self.info_hash)
-                            else {
-                                continue;
-                            };
-                            let mut buf = Vec::new();
-                            if let Some(size_progression) = &save_info.size_progression {
-                                if let Ok(_) = saver::read_piece_from_files(
-                                    &save_info.save_path,
-                                    &save_info.torrent.clone(),
-                                    piece_i as u64,
-                                    begin as u64,
-                                    length as u64,
-                                    &mut buf,
-                                    size_progression,
-                                ) {
-
-                                }
-                            } else {
-                                use std::fs::File;
-                                use std::io::{Seek, Read};
-                                let Ok(mut file) = File::options()
-                                    .read(true)
-                                    .write(true)
-                                    .create(false)
-                                    .open(&save_info.save_path) else {continue};
-                                buf = vec![0u8; length as usize];
-                                let Ok(_) = file.seek(std::io::SeekFrom::Start(begin as u64)) else {continue};
-                                let Ok(_) = file.read_exact(&mut buf) else {continue};
-                            }
-                            let mut req = Vec::new();
-                            req.extend_from_slice(&piece_i.to_be_bytes());
-                            req.extend_from_slice(&begin.to_be_bytes());
-                            req.extend_from_slice(&buf);
-
-                            let _ = self.send_message(&PeerMessage::Piece(req)).await;
-                            let _ = save_info.ui_h.send_with_update(UiMsg::DataUploaded(length as u64));
-                            log!(LogLevel::Debug, "Data sent");
-                        }
+                let mut buf = Vec::new();
+                // offset of requested block in torrent data
+                let addr = piece_i as u64 * torrent.info.piece_length + begin as u64;
+                if let Some(ref size_progression) = size_progression {
+                    if let Err(e) = saver::read_piece_from_files(
+                        &save_path,
+                        &torrent,
+                        addr,
+                        length as u64,
+                        &mut buf,
+                        size_progression,
+                    ) {
+                        log!(LogLevel::Error, "Failed to read requested data: {e}");
+                        return Ok(());
                     }
+                } else {
+                    use std::fs::File;
+                    use std::io::{Seek, Read};
+                    let Ok(mut file) = File::options()
+                        .read(true)
+                        .write(true)
+                        .create(false)
+                        .open(&save_path) else {return Ok(())};
+                    buf = vec![0u8; length as usize];
+                    let Ok(_) = file.seek(std::io::SeekFrom::Start(addr)) else {return Ok(())};
+                    let Ok(_) = file.read_exact(&mut buf) else {return Ok(())};
                 }
-                PeerMessage::KeepAlive => {}
-                _ => {}
+                let mut req = Vec::new();
+                req.extend_from_slice(&piece_i.to_be_bytes());
+                req.extend_from_slice(&begin.to_be_bytes());
+                req.extend_from_slice(&buf);
+
+                // upload is counted only when block really went to peer
+                self.send_message(&PeerMessage::Piece(req)).await?;
+                stats.add_uploaded(length as u64);
+                self.choker.add_uploaded(&self.peer_addr, length as u64);
+                events.send(EngineEvent::DataUploaded(self.peer_addr, length as u64));
+                log!(LogLevel::Debug, "Data sent");
             }
-            if msg_str == *target_msg.to_string() {
-                n += 1;
-                if n >= msg_appear_n {
-                    break;
+            PeerMessage::Extended(id, payload) => {
+                if let Err(e) = self.handle_extended(id, payload).await {
+                    log!(LogLevel::Debug, "Extension error, peer {}: {e}", self.peer_addr);
                 }
             }
+            PeerMessage::KeepAlive => {}
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Passing block of piece message to saver, only ones that were requested
+    pub async fn block_received(&self, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.len() < 8 {
+            anyhow::bail!("Invalid piece message");
         }
+        self.choker
+            .add_downloaded(&self.peer_addr, (buf.len() - 8) as u64);
+        let piece_i = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let begin = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        self.data_sender
+            .send(DataPiece {
+                buf: buf[8..].to_vec(),
+                piece_i: piece_i as u64,
+                begin: begin as u64,
+            })
+            .await?;
         Ok(())
     }
 
@@ -291,50 +427,71 @@ impl Peer {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&1u32.to_be_bytes());
                 buf.extend_from_slice(&2u8.to_be_bytes());
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
             }
             PeerMessage::Request(req) => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&((1 + req.len()) as u32).to_be_bytes());
                 buf.extend_from_slice(&6u8.to_be_bytes());
                 buf.extend_from_slice(req);
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
+            }
+            PeerMessage::Cancel(req) => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(&((1 + req.len()) as u32).to_be_bytes());
+                buf.extend_from_slice(&8u8.to_be_bytes());
+                buf.extend_from_slice(req);
+                self.write(&buf).await?;
+            }
+            PeerMessage::NotInterested => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(&1u32.to_be_bytes());
+                buf.extend_from_slice(&3u8.to_be_bytes());
+                self.write(&buf).await?;
             }
             PeerMessage::Choke => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&1u32.to_be_bytes());
                 buf.extend_from_slice(&0u8.to_be_bytes());
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
             }
             PeerMessage::Unchoke => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&1u32.to_be_bytes());
                 buf.extend_from_slice(&1u8.to_be_bytes());
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
             }
             PeerMessage::KeepAlive => {
-                self.socket.write_all(&0u32.to_be_bytes()).await?;
+                self.write(&0u32.to_be_bytes()).await?;
             }
             PeerMessage::Bitfield(bitfield) => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&((1 + bitfield.len()) as u32).to_be_bytes());
                 buf.extend_from_slice(&5u8.to_be_bytes());
                 buf.extend_from_slice(bitfield);
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
             }
             PeerMessage::Have(i) => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&1u32.to_be_bytes());
                 buf.extend_from_slice(&4u8.to_be_bytes());
                 buf.extend_from_slice(&(*i).to_be_bytes());
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
             },
             PeerMessage::Piece(req) => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&((1 + req.len()) as u32).to_be_bytes());
                 buf.extend_from_slice(&7u8.to_be_bytes());
                 buf.extend_from_slice(req);
-                self.socket.write_all(&buf).await?;
+                self.write(&buf).await?;
+            }
+            PeerMessage::Extended(id, payload) => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(&((2 + payload.len()) as u32).to_be_bytes());
+                buf.extend_from_slice(&20u8.to_be_bytes());
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+                self.write(&buf).await?;
             }
             _ => {
                 panic!("Unimplemented msg to send: {}", msg)
@@ -343,54 +500,86 @@ impl Peer {
         Ok(())
     }
 
-    async fn receive_message(&mut self) -> anyhow::Result<PeerMessage> {
-        let mut data = [0; 5]; // length + msg type
+    async fn write(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.limiter.upload(buf.len() as u64).await;
+        self.socket.write_all(buf).await?;
+        Ok(())
+    }
+
+    pub async fn receive_message(&mut self) -> anyhow::Result<PeerMessage> {
+        self.limiter.download_ready().await;
+        let mut data = [0; 4]; // length
         self.socket.read_exact(&mut data).await?;
-        let data_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let data_len = u32::from_be_bytes(data) as usize;
         if data_len == 0 {
+            self.limiter.downloaded(4);
             return Ok(PeerMessage::KeepAlive);
         }
-        match data[4] {
-            0 => Ok(PeerMessage::Choke),
-            1 => Ok(PeerMessage::Unchoke),
-            2 => Ok(PeerMessage::Interested),
-            // 3 => Ok(PeerMessage::NotInterested),
-            4 => {
-                let mut data = [0; 4];
-                self.socket.read_exact(&mut data).await?;
-                Ok(PeerMessage::Have(u32::from_be_bytes([
-                    data[0], data[1], data[2], data[3],
-                ])))
-            }
-            5 => {
-                let mut data = vec![0; data_len as usize - 1];
-                self.socket.read_exact(&mut data).await?;
-                Ok(PeerMessage::Bitfield(data))
-            }
-            6 => {
-                let mut data = vec![0; data_len as usize - 1];
-                self.socket.read_exact(&mut data).await?;
-                Ok(PeerMessage::Request(data))
+        let mut msg_type = [0; 1];
+        self.socket.read_exact(&mut msg_type).await?;
+        // length comes from peer, it's checked before anything is allocated
+        let payload_len = data_len - 1;
+        let max_len = match msg_type[0] {
+            5 => self.max_bitfield_len(),
+            7 => 8 + CHUNK_SIZE as usize,
+            _ => MAX_MESSAGE_LEN,
+        };
+        if payload_len > max_len {
+            anyhow::bail!("Message {} is too long: {data_len}", msg_type[0]);
+        }
+        let exact_len = match msg_type[0] {
+            4 => Some(4),
+            6 | 8 => Some(12),
+            _ => None,
+        };
+        if exact_len.is_some_and(|x| x != payload_len) {
+            anyhow::bail!("Message {} has invalid length: {data_len}", msg_type[0]);
+        }
+        let data = self.read_payload(payload_len).await?;
+        // only bytes that were really read are counted
+        self.limiter.downloaded(4 + data_len as u64);
+        let msg = match msg_type[0] {
+            // payload of messages without one is discarded, so stream stays in sync
+            0 => PeerMessage::Choke,
+            1 => PeerMessage::Unchoke,
+            2 => PeerMessage::Interested,
+            3 => PeerMessage::NotInterested,
+            4 => PeerMessage::Have(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            5 => PeerMessage::Bitfield(data),
+            6 => PeerMessage::Request(data),
+            7 => PeerMessage::Piece(data),
+            8 => PeerMessage::Cancel(data),
+            20 => {
+                let Some((&id, payload)) = data.split_first() else {
+                    anyhow::bail!("Invalid extended message");
+                };
+                PeerMessage::Extended(id, payload.to_vec())
             }
+            // payload of unsupported message is skipped
+            _ => PeerMessage::KeepAlive,
+        };
+        Ok(msg)
+    }
 
-            7 => {
-                let mut data = vec![0; data_len as usize - 1];
-                self.socket.read_exact(&mut data).await?;
-                Ok(PeerMessage::Piece(data))
-            }
-            _ => Ok(PeerMessage::KeepAlive),
+    async fn read_payload(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.socket.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    // One bit per piece. Until torrent is known (metadata exchange) own bitfield
+    // is a placeholder of one piece, then only u16 piece index limit applies
+    fn max_bitfield_len(&self) -> usize {
+        if self.own_bitfield.bitmap.len() > 1 {
+            self.own_bitfield.bitmap.len()
+        } else {
+            (u16::MAX as usize + 1) / 8
         }
     }
 
     pub fn have_piece(&self, piece_i: usize) -> bool {
         if let Some(ref bitfield) = self.peer_bitfield {
-            let bitfield_i = piece_i / 8;
-            let bit_i = piece_i as i32 % 8;
-            let mut mask = 128;
-            for _ in 0..(bit_i - 1) {
-                mask >>= 1;
-            }
-            mask & bitfield[bitfield_i] == mask
+            bitfield_has(bitfield, piece_i)
         } else {
             // cause in the start bitfield is not inited
             true
@@ -398,20 +587,254 @@ impl Peer {
     }
 
     async fn handshake(&mut self, torrent: &Torrent, time: Duration) -> anyhow::Result<String> {
-        let mut msg = Vec::new();
-        msg.push(b"\x13"[0]); // 0x13 = 19
-        msg.extend_from_slice(b"BitTorrent protocol");
-        msg.extend_from_slice(&[0; 8]);
-        msg.extend_from_slice(&torrent.info_hash);
-        msg.extend_from_slice(&self.my_peer_id.as_bytes());
+        let peer_id = self.exchange_handshake(&torrent.info_hash, time).await?;
+        self.send_message(&PeerMessage::Bitfield(self.own_bitfield.bitmap.clone()))
+            .await?;
+        self.send_extended_handshake().await?;
+        Ok(peer_id)
+    }
+
+    // Sends handshake and reads answer, without sending bitfield
+    pub async fn exchange_handshake(
+        &mut self,
+        info_hash: &[u8],
+        time: Duration,
+    ) -> anyhow::Result<String> {
+        let msg = self.handshake_msg(info_hash);
         self.socket.write_all(&msg).await?;
         log!(LogLevel::Debug, "Sended handskake");
         let mut response = [0; 68];
-        let _ = timeout(time, self.socket.read_exact(&mut response)).await?;
+        timeout(time, self.socket.read_exact(&mut response)).await??;
         log!(LogLevel::Debug, "Received answer handskake");
+        if &response[28..48] != info_hash {
+            anyhow::bail!("Info hash in handshake didn't match");
+        }
+        self.supports_extensions = response[25] & EXTENSION_BIT != 0;
         let peer_id = &response[response.len() - 20..response.len()];
-        self.send_message(&PeerMessage::Bitfield(self.own_bitfield.bitmap.clone()))
-            .await?;
         Ok(hex::encode(peer_id))
     }
+
+    fn handshake_msg(&self, info_hash: &[u8]) -> Vec<u8> {
+        let mut reserved = [0; 8];
+        reserved[5] |= EXTENSION_BIT;
+        let mut msg = Vec::new();
+        msg.push(b"\x13"[0]); // 0x13 = 19
+        msg.extend_from_slice(b"BitTorrent protocol");
+        msg.extend_from_slice(&reserved);
+        msg.extend_from_slice(info_hash);
+        msg.extend_from_slice(self.my_peer_id.as_bytes());
+        msg
+    }
+
+    // Answering handshake of incoming connection, bitfield is sent
+    // later in connect, when own bitfield is known
+    pub async fn accept_handshake(&mut self, remote_handshake: &[u8; 68]) -> anyhow::Result<()> {
+        let msg = self.handshake_msg(&remote_handshake[28..48]);
+        self.socket.write_all(&msg).await?;
+        self.supports_extensions = remote_handshake[25] & EXTENSION_BIT != 0;
+        self.peer_id = Some(hex::encode(&remote_handshake[48..68]));
+        self.status = PeerStatus::Handshaked;
+        Ok(())
+    }
+
+    pub async fn send_extended_handshake(&mut self) -> anyhow::Result<()> {
+        if !self.supports_extensions {
+            return Ok(());
+        }
+        let payload = self.extensions.handshake_payload()?;
+        self.send_message(&PeerMessage::Extended(0, payload)).await
+    }
+
+    // Sending message of extension, using id peer assigned to it
+    pub async fn send_extension(&mut self, name: &str, payload: Vec<u8>) -> anyhow::Result<()> {
+        let Some(id) = self
+            .remote_extensions
+            .as_ref()
+            .and_then(|ids| ids.get(name).copied())
+        else {
+            anyhow::bail!("Peer doesn't support {name}");
+        };
+        self.send_message(&PeerMessage::Extended(id, payload)).await
+    }
+
+    // Sending messages extension handlers have for this peer
+    pub async fn send_extension_updates(&mut self) -> anyhow::Result<()> {
+        let handlers = self.extensions.handlers().to_vec();
+        for handler in handlers {
+            if !self.supports_extension(handler.name()) {
+                continue;
+            }
+            if let Some(msg) = handler.pending_message(&self.peer_addr) {
+                self.send_extension(handler.name(), msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.remote_extensions
+            .as_ref()
+            .is_some_and(|ids| ids.contains_key(name))
+    }
+
+    async fn handle_extended(&mut self, id: u8, payload: Vec<u8>) -> anyhow::Result<()> {
+        if id == 0 {
+            let (handshake, _) = BencodeValue::decode_bencoded_value(&payload)?;
+            self.remote_extensions = Some(extensions::parse_remote_ids(&handshake));
+            for handler in self.extensions.handlers() {
+                handler.on_handshake(&self.peer_addr, &handshake);
+            }
+            return Ok(());
+        }
+        let Some(handler) = self.extensions.handler(id).cloned() else {
+            anyhow::bail!("Unknown extended message id {id}");
+        };
+        if let Some(reply) = handler.on_message(&self.peer_addr, &payload)? {
+            self.send_extension(handler.name(), reply).await?;
+        }
+        Ok(())
+    }
+}
+
+// First piece is the high bit of the first byte
+pub fn bitfield_has(bitfield: &[u8], piece_i: usize) -> bool {
+    bitfield
+        .get(piece_i / 8)
+        .is_some_and(|byte| byte & (0x80 >> (piece_i % 8)) != 0)
+}
+
+// Compact peer format: 4 or 16 bytes of IP followed by 2 bytes of port
+pub fn encode_compact(peers: &[SocketAddr]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for peer in peers {
+        match peer {
+            SocketAddr::V4(addr) => buf.extend_from_slice(&addr.ip().octets()),
+            SocketAddr::V6(addr) => buf.extend_from_slice(&addr.ip().octets()),
+        }
+        buf.extend_from_slice(&peer.port().to_be_bytes());
+    }
+    buf
+}
+
+pub fn decode_compact_v4(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(6)
+        .map(|x| {
+            let ip: [u8; 4] = x[..4].try_into().expect("Slice has length of 4");
+            SocketAddr::from((ip, u16::from_be_bytes([x[4], x[5]])))
+        })
+        .collect()
+}
+
+pub fn decode_compact_v6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(18)
+        .map(|x| {
+            let ip: [u8; 16] = x[..16].try_into().expect("Slice has length of 16");
+            SocketAddr::from((ip, u16::from_be_bytes([x[16], x[17]])))
+        })
+        .collect()
+}
+
+// IPv4 peers accepted on dual-stack socket have IPv4-mapped IPv6 address
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    // Peer reading from socket, other end is returned to write raw messages
+    async fn peer_pair() -> (Peer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (remote, _) = listener.accept().await.unwrap();
+        let peer = Peer::from_socket(
+            socket,
+            addr,
+            mpsc::channel(1).0,
+            String::new(),
+            String::new(),
+        );
+        (peer, remote)
+    }
+
+    fn message(len: u32, id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = len.to_be_bytes().to_vec();
+        buf.push(id);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[tokio::test]
+    async fn huge_length_is_rejected() {
+        for id in [5, 7, 20, 100] {
+            let (mut peer, mut remote) = peer_pair().await;
+            remote.write_all(&message(u32::MAX, id, &[])).await.unwrap();
+            assert!(peer.receive_message().await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn fixed_lengths_are_checked() {
+        let (mut peer, mut remote) = peer_pair().await;
+        // extra payload of choke is discarded, next message is read as usual
+        remote.write_all(&message(3, 0, &[1, 2])).await.unwrap();
+        remote.write_all(&message(5, 4, &[0, 0, 0, 7])).await.unwrap();
+        remote.write_all(&message(3, 30, &[1, 2])).await.unwrap();
+        remote.write_all(&message(4, 20, &[1, 2, 3])).await.unwrap();
+        assert_eq!(peer.receive_message().await.unwrap(), PeerMessage::Choke);
+        assert_eq!(peer.receive_message().await.unwrap(), PeerMessage::Have(7));
+        assert_eq!(peer.receive_message().await.unwrap(), PeerMessage::KeepAlive);
+        assert_eq!(
+            peer.receive_message().await.unwrap(),
+            PeerMessage::Extended(1, vec![2, 3])
+        );
+
+        remote.write_all(&message(6, 4, &[0; 5])).await.unwrap();
+        assert!(peer.receive_message().await.is_err());
+        let (mut peer, mut remote) = peer_pair().await;
+        remote.write_all(&message(12, 6, &[0; 11])).await.unwrap();
+        assert!(peer.receive_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bitfield_is_limited_by_pieces() {
+        let (mut peer, mut remote) = peer_pair().await;
+        peer.own_bitfield = PieceBitmap::new(20);
+        remote.write_all(&message(4, 5, &[0; 3])).await.unwrap();
+        remote.write_all(&message(5, 5, &[0; 4])).await.unwrap();
+        assert_eq!(peer.receive_message().await.unwrap(), PeerMessage::Bitfield(vec![0; 3]));
+        assert!(peer.receive_message().await.is_err());
+    }
+
+    #[test]
+    fn compact_addresses() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let buf = encode_compact(&[v4, v6]);
+        assert_eq!(buf[..6], *b"\x0a\x00\x00\x01\x1a\xe1");
+        assert_eq!(
+            buf[6..],
+            *b"\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\xc8\xd5"
+        );
+        assert_eq!(decode_compact_v4(&buf[..6]), vec![v4]);
+        assert_eq!(decode_compact_v6(&buf[6..]), vec![v6]);
+        // trailing partial entry is ignored
+        assert_eq!(decode_compact_v4(&buf[..10]), vec![v4]);
+        assert!(decode_compact_v6(&buf[6..20]).is_empty());
+    }
+
+    #[test]
+    fn mapped_address_is_canonical() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(canonical_addr(mapped), "10.0.0.1:6881".parse().unwrap());
+        assert_eq!(canonical_addr(v6), v6);
+    }
 }
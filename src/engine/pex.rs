@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::Sender;
+
+use super::bencode::BencodeValue;
+use super::download::DataPiece;
+use super::extensions::ExtensionHandler;
+use super::logger::{log, LogLevel};
+use super::peers::{self, Peer};
+use super::DownloadEvents;
+
+const EXTENSION_NAME: &str = "ut_pex";
+
+// Peers shouldn't get pex messages more often than once a minute
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_PEX_PEERS: usize = 50;
+// Addresses kept to not connect to same peer twice
+const MAX_KNOWN_PEERS: usize = 2000;
+
+#[derive(Debug)]
+struct SentInfo {
+    time: Instant,
+    peers: HashSet<SocketAddr>,
+}
+
+// Peer exchange (ut_pex) - sharing addresses of connected peers,
+// peers learned from others are sent to worker as PeerAdd
+#[derive(Debug)]
+pub struct PeerExchange {
+    info_hash: String,
+    peer_id: String,
+    event_sender: Sender<DownloadEvents>,
+    data_sender: Sender<DataPiece>,
+    // peers connected to worker right now
+    swarm: Mutex<HashSet<SocketAddr>>,
+    // all peers we connected or tried to connect to
+    known: Mutex<HashSet<SocketAddr>>,
+    // what was sent to each peer last time
+    sent: Mutex<HashMap<SocketAddr, SentInfo>>,
+    // ports peers listen on ("p" of extended handshake), incoming
+    // connections come from other port
+    listen_ports: Mutex<HashMap<SocketAddr, u16>>,
+}
+
+impl PeerExchange {
+    pub fn new(
+        info_hash: String,
+        peer_id: String,
+        event_sender: Sender<DownloadEvents>,
+        data_sender: Sender<DataPiece>,
+    ) -> Self {
+        PeerExchange {
+            info_hash,
+            peer_id,
+            event_sender,
+            data_sender,
+            swarm: Mutex::new(HashSet::new()),
+            known: Mutex::new(HashSet::new()),
+            sent: Mutex::new(HashMap::new()),
+            listen_ports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn peer_connected(&self, peer_addr: &SocketAddr) {
+        self.known.lock().unwrap().insert(*peer_addr);
+        self.swarm.lock().unwrap().insert(*peer_addr);
+    }
+
+    pub fn peer_dropped(&self, peer_addr: &SocketAddr) {
+        self.swarm.lock().unwrap().remove(peer_addr);
+        self.sent.lock().unwrap().remove(peer_addr);
+        self.listen_ports.lock().unwrap().remove(peer_addr);
+    }
+
+    // Address other peers can connect to
+    fn listen_addr(&self, peer_addr: &SocketAddr) -> SocketAddr {
+        let mut addr = *peer_addr;
+        if let Some(port) = self.listen_ports.lock().unwrap().get(peer_addr) {
+            addr.set_port(*port);
+        }
+        addr
+    }
+
+    fn connect_to(&self, addr: SocketAddr) {
+        {
+            let mut known = self.known.lock().unwrap();
+            if known.contains(&addr) {
+                return;
+            }
+            if known.len() >= MAX_KNOWN_PEERS {
+                // failed and dropped peers are forgotten, connected ones are kept
+                let swarm = self.swarm.lock().unwrap();
+                known.retain(|x| swarm.contains(x));
+                if known.len() >= MAX_KNOWN_PEERS {
+                    return;
+                }
+            }
+            known.insert(addr);
+        }
+        let data_sender = self.data_sender.clone();
+        let event_sender = self.event_sender.clone();
+        let peer_id = self.peer_id.clone();
+        let info_hash = self.info_hash.clone();
+        tokio::spawn(async move {
+            if let Ok(peer) =
+                Peer::new(addr, data_sender, peer_id, info_hash, Duration::from_secs(2)).await
+            {
+                log!(LogLevel::Info, "ok pex peer {:?}", peer.peer_addr);
+                let _ = event_sender.send(DownloadEvents::PeerAdd(peer, true)).await;
+            }
+        });
+    }
+}
+
+impl ExtensionHandler for PeerExchange {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_handshake(&self, peer_addr: &SocketAddr, handshake: &BencodeValue) {
+        if let BencodeValue::Num(port @ 1..=65535) = handshake["p"] {
+            self.listen_ports
+                .lock()
+                .unwrap()
+                .insert(*peer_addr, port as u16);
+        }
+    }
+
+    fn on_message(
+        &self,
+        peer_addr: &SocketAddr,
+        payload: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (msg, _) = BencodeValue::decode_bencoded_value(payload)?;
+        let mut peers = Vec::new();
+        if let BencodeValue::Bytes(ref added) = msg["added"] {
+            peers.extend(peers::decode_compact_v4(added));
+        }
+        if let BencodeValue::Bytes(ref added) = msg["added6"] {
+            peers.extend(peers::decode_compact_v6(added));
+        }
+        log!(LogLevel::Debug, "Got {} pex peer(s) from {peer_addr}", peers.len());
+        // BEP 11 allows 50 added peers per message, the rest is ignored
+        peers.truncate(MAX_PEX_PEERS);
+        for addr in peers {
+            self.connect_to(addr);
+        }
+        Ok(None)
+    }
+
+    fn pending_message(&self, peer_addr: &SocketAddr) -> Option<Vec<u8>> {
+        let swarm: HashSet<SocketAddr> = self
+            .swarm
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| *x != peer_addr)
+            .map(|x| self.listen_addr(x))
+            .collect();
+        let mut sent = self.sent.lock().unwrap();
+        let prev = sent.get(peer_addr);
+        if prev.is_some_and(|x| x.time.elapsed() < PEX_INTERVAL) {
+            return None;
+        }
+        let empty = HashSet::new();
+        let prev_peers = prev.map(|x| &x.peers).unwrap_or(&empty);
+        let added: Vec<&SocketAddr> = swarm
+            .iter()
+            .filter(|x| !prev_peers.contains(*x))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        let dropped: Vec<&SocketAddr> = prev_peers
+            .iter()
+            .filter(|x| !swarm.contains(*x))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        // IPv4 and IPv6 peers are sent in separate fields
+        let mut msg = HashMap::new();
+        for (suffix, is_v6) in [("", false), ("6", true)] {
+            let added: Vec<SocketAddr> =
+                added.iter().filter(|x| x.is_ipv6() == is_v6).map(|x| **x).collect();
+            let dropped: Vec<SocketAddr> =
+                dropped.iter().filter(|x| x.is_ipv6() == is_v6).map(|x| **x).collect();
+            msg.insert(
+                format!("added{suffix}.f"),
+                BencodeValue::Bytes(vec![0; added.len()]),
+            );
+            msg.insert(
+                format!("added{suffix}"),
+                BencodeValue::Bytes(peers::encode_compact(&added)),
+            );
+            msg.insert(
+                format!("dropped{suffix}"),
+                BencodeValue::Bytes(peers::encode_compact(&dropped)),
+            );
+        }
+        let mut buf = Vec::new();
+        BencodeValue::Dict(msg).encode(&mut buf).ok()?;
+
+        let mut peers: HashSet<SocketAddr> = prev_peers
+            .iter()
+            .filter(|x| swarm.contains(*x))
+            .cloned()
+            .collect();
+        peers.extend(added.into_iter().cloned());
+        sent.insert(
+            *peer_addr,
+            SentInfo {
+                time: Instant::now(),
+                peers,
+            },
+        );
+        Some(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn listen_port_is_shared() {
+        let (event_sender, _) = mpsc::channel(1);
+        let (data_sender, _) = mpsc::channel(1);
+        let pex = PeerExchange::new(String::new(), String::new(), event_sender, data_sender);
+        let receiver: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let incoming: SocketAddr = "10.0.0.2:50123".parse().unwrap();
+        pex.peer_connected(&receiver);
+        pex.peer_connected(&incoming);
+        let handshake = BencodeValue::Dict(HashMap::from([(
+            "p".to_string(),
+            BencodeValue::Num(6882),
+        )]));
+        pex.on_handshake(&incoming, &handshake);
+
+        let msg = pex.pending_message(&receiver).unwrap();
+        let (msg, _) = BencodeValue::decode_bencoded_value(&msg).unwrap();
+        let BencodeValue::Bytes(ref added) = msg["added"] else {
+            panic!("No added peers");
+        };
+        let added = peers::decode_compact_v4(added);
+        assert_eq!(added, vec!["10.0.0.2:6882".parse::<SocketAddr>().unwrap()]);
+        // nothing new until interval passes
+        assert!(pex.pending_message(&receiver).is_none());
+    }
+
+    #[tokio::test]
+    async fn ipv6_peers_are_exchanged() {
+        let (event_sender, _) = mpsc::channel(1);
+        let (data_sender, _) = mpsc::channel(1);
+        let pex = PeerExchange::new(String::new(), String::new(), event_sender, data_sender);
+        let receiver: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        pex.peer_connected(&receiver);
+        pex.peer_connected(&v6);
+
+        let msg = pex.pending_message(&receiver).unwrap();
+        let (msg, _) = BencodeValue::decode_bencoded_value(&msg).unwrap();
+        assert!(matches!(msg["added"], BencodeValue::Bytes(ref x) if x.is_empty()));
+        let BencodeValue::Bytes(ref added) = msg["added6"] else {
+            panic!("No added IPv6 peers");
+        };
+        assert_eq!(peers::decode_compact_v6(added), vec![v6]);
+    }
+
+    #[tokio::test]
+    async fn added_peers_are_parsed() {
+        let (event_sender, _) = mpsc::channel(1);
+        let (data_sender, _) = mpsc::channel(1);
+        let pex = PeerExchange::new(String::new(), String::new(), event_sender, data_sender);
+        let sender: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let msg = b"d5:added6:\x7f\x00\x00\x01\x00\x017:added.f1:\x006:added618:\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x01e";
+        assert_eq!(pex.on_message(&sender, msg).unwrap(), None);
+        let known = pex.known.lock().unwrap();
+        assert!(known.contains(&"127.0.0.1:1".parse().unwrap()));
+        assert!(known.contains(&"[::1]:1".parse().unwrap()));
+        assert_eq!(known.len(), 2);
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::torrent::Torrent;
+
+// Per torrent file priorities, keyed by hex info hash
+static TORRENTS: Lazy<Mutex<HashMap<String, Arc<FilePriorities>>>> = Lazy::new(Default::default);
+
+// Bytes at start and end of file players read first to probe container
+const PROBE_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FilePriority {
+    // file isn't downloaded and isn't created on disk
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    pub const ALL: [FilePriority; 4] = [
+        FilePriority::Skip,
+        FilePriority::Low,
+        FilePriority::Normal,
+        FilePriority::High,
+    ];
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            FilePriority::Skip => "Skip",
+            FilePriority::Low => "Low",
+            FilePriority::Normal => "Normal",
+            FilePriority::High => "High",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Priorities of torrent files, set by GUI and read by worker and saver.
+// Empty list means every file has normal priority
+#[derive(Debug, Default)]
+pub struct FilePriorities {
+    files: Mutex<Vec<FilePriority>>,
+    // pieces are downloaded in order, for playback while downloading
+    sequential: AtomicBool,
+    // byte ranges of torrent data downloaded before anything else
+    urgent: Mutex<Vec<Range<u64>>>,
+    // increased on every change, so worker can notice it
+    version: AtomicU64,
+}
+
+impl FilePriorities {
+    pub fn get(&self) -> Vec<FilePriority> {
+        self.files.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, files: Vec<FilePriority>) {
+        *self.files.lock().unwrap() = files;
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_sequential(&self, sequential: bool) {
+        self.sequential.store(sequential, Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential.load(Ordering::Relaxed)
+    }
+
+    pub fn add_urgent(&self, range: Range<u64>) {
+        self.urgent.lock().unwrap().push(range);
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Removes one range equal to given one
+    pub fn remove_urgent(&self, range: &Range<u64>) {
+        let mut urgent = self.urgent.lock().unwrap();
+        if let Some(pos) = urgent.iter().position(|x| x == range) {
+            urgent.remove(pos);
+        }
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn urgent(&self) -> Vec<Range<u64>> {
+        self.urgent.lock().unwrap().clone()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped_files(&self) -> HashSet<usize> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| **x == FilePriority::Skip)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+pub fn torrent(info_hash: &[u8]) -> Arc<FilePriorities> {
+    TORRENTS
+        .lock()
+        .unwrap()
+        .entry(hex::encode(info_hash))
+        .or_default()
+        .clone()
+}
+
+pub fn remove_torrent(info_hash: &[u8]) {
+    TORRENTS.lock().unwrap().remove(&hex::encode(info_hash));
+}
+
+// Piece gets the highest priority of files it overlaps,
+// so piece is skipped only if all its files are skipped
+pub fn piece_priorities(torrent: &Torrent, files: &[FilePriority]) -> Vec<FilePriority> {
+    let pieces_n = torrent.info.piece_hashes.len();
+    let Some(ref torrent_files) = torrent.info.files else {
+        return vec![FilePriority::Normal; pieces_n];
+    };
+    if files.len() != torrent_files.len() {
+        return vec![FilePriority::Normal; pieces_n];
+    }
+    let mut res = vec![FilePriority::Skip; pieces_n];
+    let mut start = 0;
+    for (file, priority) in torrent_files.iter().zip(files) {
+        let end = start + file.length;
+        if file.length > 0 {
+            let first = (start / torrent.info.piece_length) as usize;
+            let last = ((end - 1) / torrent.info.piece_length) as usize;
+            for piece in res.iter_mut().take(last + 1).skip(first) {
+                *piece = (*piece).max(*priority);
+            }
+        }
+        start = end;
+    }
+    res
+}
+
+// Pieces overlapping any of byte ranges
+pub fn urgent_pieces(torrent: &Torrent, ranges: &[Range<u64>]) -> Vec<bool> {
+    let mut res = vec![false; torrent.info.piece_hashes.len()];
+    for range in ranges.iter().filter(|x| !x.is_empty()) {
+        let first = (range.start / torrent.info.piece_length) as usize;
+        let last = ((range.end - 1) / torrent.info.piece_length) as usize;
+        for piece in res.iter_mut().take(last + 1).skip(first) {
+            *piece = true;
+        }
+    }
+    res
+}
+
+// Head and tail of file, as ranges of torrent data
+pub fn file_ends(torrent: &Torrent, file_i: usize) -> Vec<Range<u64>> {
+    let (start, length) = match torrent.info.files {
+        Some(ref files) => (
+            files.iter().take(file_i).map(|x| x.length).sum(),
+            files[file_i].length,
+        ),
+        None => (0, torrent.info.length),
+    };
+    let end = start + length;
+    vec![
+        start..(start + PROBE_BYTES).min(end),
+        end.saturating_sub(PROBE_BYTES).max(start)..end,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Torrent with 16 KiB pieces and files of given lengths
+    fn torrent(lengths: &[u64]) -> Torrent {
+        let mut info = b"d5:filesl".to_vec();
+        for (i, length) in lengths.iter().enumerate() {
+            info.extend(format!("d6:lengthi{length}e4:pathl1:{i}ee").as_bytes());
+        }
+        let total: u64 = lengths.iter().sum();
+        let pieces_n = total.div_ceil(16384) as usize;
+        info.extend(b"e4:name6:folder12:piece lengthi16384e");
+        info.extend(format!("6:pieces{}:", pieces_n * 20).as_bytes());
+        info.extend(vec![0; pieces_n * 20]);
+        info.extend(b"e");
+        Torrent::from_metadata(&info, Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn piece_gets_highest_priority() {
+        use FilePriority::*;
+        // pieces: 0..16384, 16384..32768, 32768..40000
+        let torrent = torrent(&[10, 20000, 0, 12758, 7232]);
+        let res = piece_priorities(&torrent, &[Skip, Low, High, Skip, Skip]);
+        assert_eq!(res, vec![Low, Low, Skip]);
+        let res = piece_priorities(&torrent, &[High, Skip, Skip, Skip, Normal]);
+        assert_eq!(res, vec![High, Skip, Normal]);
+        // wrong length is ignored
+        let res = piece_priorities(&torrent, &[Skip]);
+        assert_eq!(res, vec![Normal; 3]);
+    }
+
+    #[test]
+    fn skipped_files() {
+        use FilePriority::*;
+        let priorities = FilePriorities::default();
+        let version = priorities.version();
+        priorities.set(vec![Skip, Normal, Skip, High]);
+        assert_eq!(priorities.skipped_files(), HashSet::from([0, 2]));
+        assert!(priorities.version() > version);
+    }
+
+    #[test]
+    fn urgent_ranges_to_pieces() {
+        let torrent = torrent(&[40000]);
+        let res = urgent_pieces(&torrent, &[0..1, 20000..20000]);
+        assert_eq!(res, vec![true, false, false]);
+        let res = urgent_pieces(&torrent, &[16383..16385, 39999..40000]);
+        assert_eq!(res, vec![true, true, true]);
+    }
+
+    #[test]
+    fn ends_of_file() {
+        const MIB: u64 = 1024 * 1024;
+        let torrent = torrent(&[10, 10 * MIB]);
+        assert_eq!(file_ends(&torrent, 0), vec![0..10, 0..10]);
+        assert_eq!(
+            file_ends(&torrent, 1),
+            vec![10..10 + PROBE_BYTES, 10 + 6 * MIB..10 + 10 * MIB]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Timelike;
+use once_cell::sync::Lazy;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::settings::{RateLimits, Settings};
+
+static GLOBAL: Lazy<RateLimiter> = Lazy::new(RateLimiter::default);
+// Per torrent limiters, keyed by hex info hash
+static TORRENTS: Lazy<Mutex<HashMap<String, Arc<RateLimiter>>>> = Lazy::new(Default::default);
+
+// Scheduled profile is checked this often
+const SCHEDULE_CHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+// Token bucket, rate is in bytes per second, 0 means unlimited.
+// Tokens can go below zero, then following consumers wait for the debt too
+#[derive(Debug)]
+pub struct Bucket {
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            rate: AtomicU64::new(0),
+            state: Mutex::new(BucketState {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Bucket {
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    // Taking n tokens, returns time to wait until debt is paid
+    fn take(&self, n: u64) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now - state.last;
+        state.last = now;
+        // burst is limited to one second of traffic
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+        state.tokens -= n as f64;
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-state.tokens / rate as f64)
+    }
+
+    pub async fn consume(&self, n: u64) {
+        tokio::time::sleep(self.take(n)).await;
+    }
+
+    // Waiting for previous debt only, amount is taken after data is read
+    pub async fn ready(&self) {
+        tokio::time::sleep(self.take(0)).await;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: Mutex<RateLimits>,
+    download: Bucket,
+    upload: Bucket,
+}
+
+impl RateLimiter {
+    pub fn set(&self, limits: RateLimits) {
+        self.download.set_rate(limits.download.unwrap_or(0) * 1024);
+        self.upload.set_rate(limits.upload.unwrap_or(0) * 1024);
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits.lock().unwrap().clone()
+    }
+}
+
+pub fn torrent(info_hash: &[u8]) -> Arc<RateLimiter> {
+    TORRENTS
+        .lock()
+        .unwrap()
+        .entry(hex::encode(info_hash))
+        .or_default()
+        .clone()
+}
+
+pub fn remove_torrent(info_hash: &[u8]) {
+    TORRENTS.lock().unwrap().remove(&hex::encode(info_hash));
+}
+
+// Limits of one peer connection: torrent's own limits and global ones
+#[derive(Debug, Clone, Default)]
+pub struct PeerLimiter {
+    torrent: Option<Arc<RateLimiter>>,
+}
+
+impl PeerLimiter {
+    pub fn new(torrent: Arc<RateLimiter>) -> Self {
+        PeerLimiter {
+            torrent: Some(torrent),
+        }
+    }
+
+    // Called before reading message, nothing is read if it's cancelled
+    pub async fn download_ready(&self) {
+        if let Some(ref torrent) = self.torrent {
+            torrent.download.ready().await;
+        }
+        GLOBAL.download.ready().await;
+    }
+
+    pub fn downloaded(&self, n: u64) {
+        if let Some(ref torrent) = self.torrent {
+            torrent.download.take(n);
+        }
+        GLOBAL.download.take(n);
+    }
+
+    pub async fn upload(&self, n: u64) {
+        if let Some(ref torrent) = self.torrent {
+            torrent.upload.consume(n).await;
+        }
+        GLOBAL.upload.consume(n).await;
+    }
+}
+
+// Applying global limits from settings, alternative profile is used when
+// it's switched on manually or current hour is inside its schedule
+pub fn apply_settings() {
+    let settings = Settings::get();
+    let alt_active = settings.alt_enabled
+        || settings.alt_schedule.as_ref().is_some_and(|schedule| {
+            schedule.contains(chrono::Local::now().hour() as u8)
+        });
+    GLOBAL.set(if alt_active {
+        settings.alt_limits
+    } else {
+        settings.limits
+    });
+}
+
+pub fn spawn_scheduler() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            apply_settings();
+            tokio::time::sleep(SCHEDULE_CHECK).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1000;
+
+    fn bucket() -> Bucket {
+        let bucket = Bucket::default();
+        bucket.set_rate(RATE);
+        bucket
+    }
+
+    // Moving last refill back, as if time has passed
+    fn wait(bucket: &Bucket, time: Duration) {
+        bucket.state.lock().unwrap().last -= time;
+    }
+
+    fn near(a: Duration, b: Duration) -> bool {
+        a.abs_diff(b) < Duration::from_millis(50)
+    }
+
+    #[test]
+    fn unlimited_never_waits() {
+        let bucket = Bucket::default();
+        assert_eq!(bucket.take(u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn debt_is_waited() {
+        let bucket = bucket();
+        assert!(near(bucket.take(2 * RATE), Duration::from_secs(2)));
+        // next consumer waits for previous debt too
+        assert!(near(bucket.take(0), Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn tokens_refill() {
+        let bucket = bucket();
+        bucket.take(RATE);
+        wait(&bucket, Duration::from_millis(500));
+        assert!(near(bucket.take(0), Duration::from_millis(500)));
+        wait(&bucket, Duration::from_millis(500));
+        assert!(near(bucket.take(0), Duration::ZERO));
+    }
+
+    #[test]
+    fn burst_is_one_second() {
+        let bucket = bucket();
+        wait(&bucket, Duration::from_secs(10));
+        assert_eq!(bucket.take(RATE), Duration::ZERO);
+        assert!(near(bucket.take(RATE), Duration::from_secs(1)));
+    }
+}
@@ -1,40 +1,122 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio_util::sync::CancellationToken;
 
 use once_cell::sync::OnceCell;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Receiver;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, Notify, RwLock};
 use tokio::task::JoinHandle;
-use tokio::time::timeout;
+use tokio::time::{interval, timeout};
 
-use crate::gui::TorrentBackupInfo;
-use crate::gui::{UiHandle, UiMsg};
-
-use super::download::tasks::CHUNK_SIZE;
+use super::backup::TorrentBackupInfo;
+use super::download::tasks::{get_piece_tasks, CHUNK_SIZE};
 use super::download::DataPiece;
+use super::events::{EngineEvent, EventSender};
 use super::logger::{log, LogLevel};
+use super::priorities::{self, FilePriorities};
+use super::torrent::{is_plain_name, PieceBitmap};
 use super::{DownloadEvents, Torrent};
 
+// Bytes of skipped files from pieces shared with wanted files,
+// kept at their offset in torrent data (sparse file)
+const PART_FILE: &str = ".parts";
+// Changes of file priorities are applied to data on disk this often
+const PRIORITIES_CHECK: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 struct PieceChunksBitmap {
     bitmap: Vec<u8>,
     last_chunk_mask: u8,
 }
 
+// Transfer counters of current session, reported to trackers
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    pub left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        TransferStats {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_downloaded(&self, n: u64) {
+        self.downloaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, n: u64) {
+        self.uploaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn piece_verified(&self, piece_length: u64) {
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some(x.saturating_sub(piece_length))
+            });
+    }
+}
+
+// Pieces that passed hash check, readers of torrent data wait on it
+#[derive(Debug)]
+pub struct VerifiedPieces {
+    bitmap: Mutex<PieceBitmap>,
+    notify: Notify,
+}
+
+impl VerifiedPieces {
+    fn new(pieces_n: usize) -> Self {
+        VerifiedPieces {
+            bitmap: Mutex::new(PieceBitmap::new(pieces_n)),
+            notify: Notify::new(),
+        }
+    }
+
+    fn add(&self, piece_i: usize) {
+        self.bitmap.lock().unwrap().add(piece_i);
+        self.notify.notify_waiters();
+    }
+
+    pub fn has(&self, piece_i: usize) -> bool {
+        self.bitmap.lock().unwrap().has(piece_i)
+    }
+
+    pub async fn wait(&self, piece_i: usize) {
+        loop {
+            // registered before check, so notification between them isn't lost
+            let notified = self.notify.notified();
+            if self.has(piece_i) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SaveInfo {
     pub save_path: String,
     pub torrent: Arc<Torrent>,
     pub size_progression: Option<Vec<u64>>,
-    pub ui_h: UiHandle,
+    pub events: EventSender,
+    pub event_sender: mpsc::Sender<DownloadEvents>,
+    pub data_sender: mpsc::Sender<DataPiece>,
+    pub stats: Arc<TransferStats>,
+    pub verified: Arc<VerifiedPieces>,
 }
 
 pub static SAVE_INFO: OnceCell<RwLock<HashMap<String, SaveInfo>>> = OnceCell::new();
@@ -111,32 +193,27 @@ impl PieceChunksBitmap {
     }
 }
 
-fn bin_search(value: u64, arr: &[u64], l: usize, r: usize) -> usize {
-    if value == 0 {
-        return 1;
-    }
-    if l >= r {
-        return l;
-    }
-    let m = l + (r - l) / 2;
-    if arr[m] == value {
-        return m;
-    }
-    if arr[m] < value {
-        bin_search(value, arr, m + 1, r)
-    } else {
-        bin_search(value, arr, l, m)
+// Chunk comes from peer, so piece index and offset are checked before
+// they are used to index hashes and chunk bitmaps
+fn chunk_in_range(torrent: &Torrent, data: &DataPiece) -> bool {
+    if data.piece_i >= torrent.info.piece_hashes.len() as u64 || data.begin % CHUNK_SIZE != 0 {
+        return false;
     }
+    let piece_length = torrent.get_piece_length(data.piece_i as usize);
+    data.begin < piece_length
+        && data.buf.len() as u64 == CHUNK_SIZE.min(piece_length - data.begin)
 }
 
 pub async fn spawn_saver(
     src_path: String,
     torrent: Arc<Torrent>,
     mut get_data: Receiver<DataPiece>,
+    send_data: mpsc::Sender<DataPiece>,
     send_status: mpsc::Sender<DownloadEvents>,
-    pieces_done: usize,
-    ui_handle: UiHandle,
+    pieces_done: Vec<usize>,
+    events: EventSender,
     backup: Option<TorrentBackupInfo>,
+    stats: Arc<TransferStats>,
     cancel_token: CancellationToken,
 ) -> JoinHandle<anyhow::Result<()>> {
     // variable containing increasing array starting from 0;
@@ -153,13 +230,22 @@ pub async fn spawn_saver(
         None
     };
 
+    let verified = Arc::new(VerifiedPieces::new(torrent.info.piece_hashes.len()));
+    for i in &pieces_done {
+        verified.add(*i);
+    }
+
     SAVE_INFO.get().unwrap().write().await.insert(
         hex::encode(torrent.info_hash.clone()),
         SaveInfo {
             save_path: src_path.clone(),
             torrent: torrent.clone(),
             size_progression: files_lengthes.clone(),
-            ui_h: ui_handle.clone(),
+            events: events.clone(),
+            event_sender: send_status.clone(),
+            data_sender: send_data,
+            stats: stats.clone(),
+            verified: verified.clone(),
         },
     );
 
@@ -167,7 +253,7 @@ pub async fn spawn_saver(
     // notify about finishing donwload
     tokio::spawn(async move {
         let mut pieces_chunks: HashMap<u64, PieceChunksBitmap> = HashMap::new();
-        let mut pieces_finished = pieces_done;
+        let mut pieces_finished = pieces_done.len();
         if let Some(backup) = backup {
             for p_task in backup.pieces_tasks {
                 pieces_chunks.insert(
@@ -199,6 +285,7 @@ pub async fn spawn_saver(
 
             for i in 0..torrent.info.piece_hashes.len() {
                 if pieces_chunks.keys().position(|x| *x == i as u64).is_none() {
+                    verified.add(i);
                     send_status
                         .send(DownloadEvents::PieceComplete(i))
                         .await
@@ -209,24 +296,69 @@ pub async fn spawn_saver(
             pieces_finished = torrent.info.piece_hashes.len() - pieces_chunks.keys().len();
         }
 
+        let file_priorities = priorities::torrent(&torrent.info_hash);
+        // skipped files, synced with file priorities before every write
+        let mut skipped = HashSet::new();
+        let mut priorities_version = None;
+        let mut priorities_check = interval(PRIORITIES_CHECK);
         loop {
             tokio::select! {
                 _ = cancel_token.cancelled() => {
                     break;
                 },
+                _ = priorities_check.tick() => {
+                    sync_priorities(
+                        &src_path,
+                        &torrent,
+                        &files_lengthes,
+                        &file_priorities,
+                        &mut priorities_version,
+                        &mut skipped,
+                    );
+                }
                 data = get_data.recv() => {
                     let Some(data) = data else {
                         break
                     };
+                    sync_priorities(
+                        &src_path,
+                        &torrent,
+                        &files_lengthes,
+                        &file_priorities,
+                        &mut priorities_version,
+                        &mut skipped,
+                    );
+                    if !chunk_in_range(&torrent, &data) {
+                        log!(
+                            LogLevel::Error,
+                            "Saver: invalid chunk {}.., length {} of piece {}",
+                            data.begin,
+                            data.buf.len(),
+                            data.piece_i
+                        );
+                        continue;
+                    }
+                    // verified data is never overwritten, pieces restored from
+                    // backup have no chunks bitmap
+                    if verified.has(data.piece_i as usize) {
+                        log!(
+                            LogLevel::Debug,
+                            "Saver: chunk {}.. of verified piece {}",
+                            data.begin,
+                            data.piece_i
+                        );
+                        continue;
+                    }
                     if pieces_chunks.contains_key(&data.piece_i)
                 && pieces_chunks
                     .get(&data.piece_i)
                     .unwrap()
                     .chunk_exist(data.begin as usize)
                     {
+                        // same block can be requested from several peers in endgame
                         log!(
-                            LogLevel::Error,
-                            "Saver: Chunk {}.. of piece {} is already saved!!!",
+                            LogLevel::Debug,
+                            "Saver: duplicate chunk {}.. of piece {}",
                             data.begin,
                             data.piece_i
                         );
@@ -234,29 +366,7 @@ pub async fn spawn_saver(
                     }
                     let addr = data.piece_i * torrent.info.piece_length + data.begin;
                     if let Some(ref size_progression) = files_lengthes {
-                        let file_i_l = bin_search(addr, &size_progression, 0, size_progression.len());
-                        let r_addr = addr + data.buf.len() as u64;
-                        let file_i_r = bin_search(r_addr, &size_progression, 0, size_progression.len());
-                        if file_i_l == file_i_r {
-                            // data if from one file
-                            save_piece_to_file(
-                                &torrent,
-                                &src_path,
-                                file_i_l - 1,
-                                &data.buf,
-                                addr - size_progression[file_i_l - 1],
-                            )?;
-                        } else {
-                            // data is from different files
-                            save_data_to_files(
-                                &src_path,
-                                torrent.clone(),
-                                &data,
-                                &size_progression,
-                                file_i_l,
-                                file_i_r,
-                            )?;
-                        }
+                        save_data(&src_path, &torrent, size_progression, &skipped, addr, &data.buf)?;
                     } else {
                         // one file case
                         let mut file = File::options()
@@ -269,6 +379,8 @@ pub async fn spawn_saver(
                         file.write_all(&data.buf).unwrap();
                     };
 
+                    stats.add_downloaded(data.buf.len() as u64);
+
                     // adding chunk to bitmap
                     if let std::collections::hash_map::Entry::Vacant(e) = pieces_chunks.entry(data.piece_i)
                     {
@@ -287,7 +399,6 @@ pub async fn spawn_saver(
                             read_piece_from_files(
                                 &src_path,
                                 &torrent,
-                                data.piece_i,
                                 addr,
                                 piece_length,
                                 &mut piece_buf,
@@ -313,11 +424,9 @@ pub async fn spawn_saver(
                                 .unwrap();
                             *chunks_bitmap = PieceChunksBitmap::new(&torrent, data.piece_i as usize);
                         } else {
-                            if let Err(e) =
-                                ui_handle.send_with_update(UiMsg::PieceDone(data.piece_i as u16))
-                            {
-                                log!(LogLevel::Error, "Failed to PieceDone msg");
-                            }
+                            stats.piece_verified(piece_length);
+                            verified.add(data.piece_i as usize);
+                            events.send(EngineEvent::PieceDone(data.piece_i as u16));
                             send_status
                                 .send(DownloadEvents::PieceComplete(data.piece_i as usize))
                                 .await
@@ -332,7 +441,8 @@ pub async fn spawn_saver(
                             if pieces_finished == torrent.info.piece_hashes.len() {
                                 log!(LogLevel::Info, "Whole file downloaded and verified");
                                 send_status.send(DownloadEvents::Finished).await.unwrap();
-                                break;
+                                // saver keeps running while seeding, so peers can read data
+                                // and incoming connections find torrent, until cancelled
                             }
                         }
                     }
@@ -356,133 +466,199 @@ pub async fn spawn_saver(
     })
 }
 
-fn save_data_to_files(
+// Parts of files covered by range of torrent data: (file index, offset in file, length)
+fn file_segments(size_progression: &[u64], addr: u64, length: u64) -> Vec<(usize, u64, u64)> {
+    let mut res = Vec::new();
+    let end = addr + length;
+    let mut file_i = size_progression.partition_point(|&x| x <= addr).saturating_sub(1);
+    while file_i + 1 < size_progression.len() && size_progression[file_i] < end {
+        let l = addr.max(size_progression[file_i]);
+        let r = end.min(size_progression[file_i + 1]);
+        if l < r {
+            res.push((file_i, l - size_progression[file_i], r - l));
+        }
+        file_i += 1;
+    }
+    res
+}
+
+fn file_path(src_path: &str, torrent: &Torrent, file_i: usize) -> PathBuf {
+    Path::new(src_path).join(&torrent.info.files.as_ref().unwrap()[file_i].path)
+}
+
+// Skipped file that isn't on disk yet keeps its bytes in part file,
+// files that already exist are written even if skipped later
+fn is_parked(src_path: &str, torrent: &Torrent, file_i: usize, skipped: &HashSet<usize>) -> bool {
+    skipped.contains(&file_i) && !file_path(src_path, torrent, file_i).exists()
+}
+
+fn save_data(
     src_path: &String,
-    torrent: Arc<Torrent>,
-    data: &DataPiece,
-    size_progression: &Vec<u64>,
-    file_i_l: usize,
-    file_i_r: usize,
+    torrent: &Arc<Torrent>,
+    size_progression: &[u64],
+    skipped: &HashSet<usize>,
+    addr: u64,
+    buf: &[u8],
 ) -> anyhow::Result<()> {
-    let mut addr = data.piece_i * torrent.info.piece_length + data.begin;
-    let mut bytes_saved = 0;
-    save_piece_to_file(
-        &torrent,
-        &src_path,
-        file_i_l - 1,
-        &data.buf[bytes_saved..(bytes_saved + {
-            let mut border_r = torrent.info.files.as_ref().unwrap()[file_i_l - 1].length as usize;
-            border_r = border_r - (addr - size_progression[file_i_l - 1]) as usize;
-            if (bytes_saved + border_r) > data.buf.len() {
-                data.buf.len() - bytes_saved
-            } else {
-                border_r
-            }
-        })],
-        addr - size_progression[file_i_l - 1],
-    )?;
-    // println!("{} {}", torrent.info.files.as_ref().unwrap()[file_i].length as usize, size_progression[file_i] as usize, size_progression[file_i] as usize);
-    bytes_saved += torrent.info.files.as_ref().unwrap()[file_i_l - 1].length as usize
-        - (addr as usize - size_progression[file_i_l - 1] as usize);
-    addr = 0;
-    for file_i in file_i_l..=(file_i_r - 2) {
-        save_piece_to_file(
-            &torrent,
-            &src_path,
-            file_i,
-            &data.buf[bytes_saved..(bytes_saved + {
-                let border_r = torrent.info.files.as_ref().unwrap()[file_i].length as usize;
-                if (bytes_saved + border_r) > data.buf.len() {
-                    data.buf.len() - bytes_saved
-                } else {
-                    border_r
-                }
-            })],
-            addr,
-        )?;
-        // println!("{} {}", torrent.info.files.as_ref().unwrap()[file_i].length as usize, size_progression[file_i] as usize, size_progression[file_i] as usize);
-        bytes_saved += torrent.info.files.as_ref().unwrap()[file_i].length as usize;
-        //addr = 0;
+    let mut saved = 0;
+    for (file_i, offset, length) in file_segments(size_progression, addr, buf.len() as u64) {
+        let data = &buf[saved..saved + length as usize];
+        if is_parked(src_path, torrent, file_i, skipped) {
+            let path = Path::new(src_path);
+            std::fs::create_dir_all(path)?;
+            let mut file = File::options()
+                .write(true)
+                .create(true)
+                .open(path.join(PART_FILE))?;
+            file.seek(std::io::SeekFrom::Start(addr + saved as u64))?;
+            file.write_all(data)?;
+        } else {
+            save_piece_to_file(torrent, src_path, file_i, data, offset)?;
+        }
+        saved += length as usize;
     }
-    save_piece_to_file(
-        &torrent,
-        &src_path,
-        file_i_r - 1,
-        &data.buf[bytes_saved..(bytes_saved + {
-            let border_r = torrent.info.files.as_ref().unwrap()[file_i_r - 1].length as usize;
-            if (bytes_saved + border_r) > data.buf.len() {
-                data.buf.len() - bytes_saved
-            } else {
-                border_r
+    Ok(())
+}
+
+fn sync_priorities(
+    src_path: &String,
+    torrent: &Arc<Torrent>,
+    size_progression: &Option<Vec<u64>>,
+    file_priorities: &FilePriorities,
+    version: &mut Option<u64>,
+    skipped: &mut HashSet<usize>,
+) {
+    let Some(size_progression) = size_progression else {
+        return;
+    };
+    if *version == Some(file_priorities.version()) {
+        return;
+    }
+    *version = Some(file_priorities.version());
+    *skipped = file_priorities.skipped_files();
+    if let Err(e) = unpark_files(src_path, torrent, size_progression, skipped) {
+        log!(LogLevel::Error, "Failed to move data out of part file: {e}");
+    }
+}
+
+// Wanted files that aren't on disk get their bytes from part file,
+// holes of part file are not copied, so files stay sparse
+fn unpark_files(
+    src_path: &String,
+    torrent: &Arc<Torrent>,
+    size_progression: &[u64],
+    skipped: &HashSet<usize>,
+) -> anyhow::Result<()> {
+    let Ok(mut part) = File::open(Path::new(src_path).join(PART_FILE)) else {
+        return Ok(());
+    };
+    let part_len = part.metadata()?.len();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    for file_i in 0..size_progression.len() - 1 {
+        if skipped.contains(&file_i) || file_path(src_path, torrent, file_i).exists() {
+            continue;
+        }
+        let start = size_progression[file_i];
+        let end = size_progression[file_i + 1].min(part_len);
+        let mut pos = start;
+        while pos < end {
+            let n = (end - pos).min(CHUNK_SIZE) as usize;
+            part.seek(std::io::SeekFrom::Start(pos))?;
+            part.read_exact(&mut buf[..n])?;
+            if buf[..n].iter().any(|x| *x != 0) {
+                save_piece_to_file(torrent, src_path, file_i, &buf[..n], pos - start)?;
             }
-        })],
-        0,
-    )?;
+            pos += n as u64;
+        }
+        if pos > start {
+            log!(LogLevel::Info, "File {file_i} is moved out of part file");
+        }
+    }
     Ok(())
 }
 
 pub fn read_piece_from_files(
     src_path: &String,
     torrent: &Arc<Torrent>,
-    piece_i: u64,
     addr: u64,
     length_to_read: u64,
-    mut piece_buf: &mut Vec<u8>,
+    piece_buf: &mut Vec<u8>,
     size_progression: &Vec<u64>,
 ) -> anyhow::Result<()> {
-    let file_i_l = bin_search(addr, &size_progression, 0, size_progression.len());
-    let file_i_r = bin_search(
-        addr + length_to_read,
-        &size_progression,
-        0,
-        size_progression.len(),
-    );
-    if file_i_l == file_i_r {
-        let mut path = Path::new(&src_path);
-        let new_path = path.join(&torrent.info.files.as_ref().unwrap()[file_i_l - 1].path);
-        path = &new_path;
-        let mut file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
-        file.seek(std::io::SeekFrom::Start(
-            addr - size_progression[file_i_l - 1],
-        ))?;
-        *piece_buf = vec![0u8; length_to_read as usize];
-        file.read_exact(&mut piece_buf)?;
+    let skipped = priorities::torrent(&torrent.info_hash).skipped_files();
+    *piece_buf = vec![0u8; length_to_read as usize];
+    let mut readed_bytes = 0;
+    for (file_i, offset, length) in file_segments(size_progression, addr, length_to_read) {
+        let (path, offset) = if is_parked(src_path, torrent, file_i, &skipped) {
+            (Path::new(src_path).join(PART_FILE), addr + readed_bytes as u64)
+        } else {
+            (file_path(src_path, torrent, file_i), offset)
+        };
+        let mut file = File::options().read(true).open(path)?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        file.read_exact(&mut piece_buf[readed_bytes..readed_bytes + length as usize])?;
+        readed_bytes += length as usize;
+    }
+    Ok(())
+}
+
+// Reads range of torrent data, from single file or from files it spans
+pub fn read_data(
+    src_path: &String,
+    torrent: &Arc<Torrent>,
+    size_progression: &Option<Vec<u64>>,
+    addr: u64,
+    length: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(size_progression) = size_progression {
+        read_piece_from_files(src_path, torrent, addr, length, &mut buf, size_progression)?;
     } else {
-        *piece_buf = Vec::new();
-        let mut readed_bytes = 0;
-        let mut path = Path::new(&src_path);
-        let new_path = path.join(&torrent.info.files.as_ref().unwrap()[file_i_l - 1].path);
-        path = &new_path;
-        let mut file = File::options().read(true).write(true).open(path)?;
-        file.seek(std::io::SeekFrom::Start(
-            addr - size_progression[file_i_l - 1],
-        ))?;
-        let bytes_n = file.read_to_end(&mut piece_buf)?;
-        readed_bytes += bytes_n;
-        for file_i in file_i_l..=(file_i_r - 2) {
-            let mut path = Path::new(&src_path);
-            let new_path = path.join(&torrent.info.files.as_ref().unwrap()[file_i].path);
-            path = &new_path;
-            let mut file = File::options().read(true).write(true).open(&path)?;
-            let mut buf = Vec::new();
-            let bytes_n = file.read_to_end(&mut buf)?;
-            readed_bytes += bytes_n;
-            piece_buf.extend_from_slice(&buf[..bytes_n]);
-        }
-        let mut path = Path::new(&src_path);
-        let new_path = path.join(&torrent.info.files.as_ref().unwrap()[file_i_r - 1].path);
-        path = &new_path;
-        let mut file = File::options().read(true).write(true).open(path)?;
-        let piece_length = torrent.get_piece_length(piece_i as usize);
-        let mut buf = vec![0u8; piece_length as usize - readed_bytes];
-        if !buf.is_empty() {
-            file.read_exact(&mut buf)?;
-            piece_buf.extend_from_slice(&buf);
-        }
+        let mut file = File::options().read(true).open(src_path)?;
+        buf = vec![0u8; length as usize];
+        file.seek(std::io::SeekFrom::Start(addr))?;
+        file.read_exact(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+// Hash checks data on disk and rebuilds download state of backup from it
+pub async fn verify_backup(backup: &mut TorrentBackupInfo) {
+    let (sender, _) = broadcast::channel(1024);
+    let events = EventSender::new(backup.torrent.info_hash.clone(), sender, Vec::new());
+    let torrent = Arc::new(backup.torrent.clone());
+    let pieces_done = find_downloaded_pieces(torrent.clone(), &backup.save_path, events).await;
+    backup.pieces_done = pieces_done.len();
+    backup.pieces_tasks = get_piece_tasks(torrent, pieces_done);
+    backup.chunks_tasks.clear();
+}
+
+// Removes downloaded files of torrent, part file is inside torrent folder.
+// Torrent name comes from peers, so data path has to be strictly inside folder it was saved to
+pub fn remove_data(save_path: &str, name: &str) -> anyhow::Result<()> {
+    let path = Path::new(save_path);
+    if !path.exists() {
+        return Ok(());
+    }
+    if !is_plain_name(name) {
+        anyhow::bail!("Refusing to delete {save_path}, torrent name {name:?} isn't a file name");
+    }
+    let (Some(parent), Some(Component::Normal(_))) = (path.parent(), path.components().last())
+    else {
+        anyhow::bail!("Refusing to delete {save_path}, it isn't inside a folder");
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".").canonicalize()?
+    } else {
+        parent.canonicalize()?
+    };
+    if path.canonicalize()?.parent() != Some(parent.as_path()) {
+        anyhow::bail!("Refusing to delete {save_path}, it's outside of {}", parent.display());
+    }
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else if path.exists() {
+        std::fs::remove_file(path)?;
     }
     Ok(())
 }
@@ -490,9 +666,9 @@ pub fn read_piece_from_files(
 pub async fn find_downloaded_pieces(
     torrent: Arc<Torrent>,
     src_path: &str,
-    ui_handle: UiHandle,
+    events: EventSender,
 ) -> Vec<usize> {
-    let mut ui_receiver = ui_handle.ui_sender.subscribe();
+    let mut event_receiver = events.subscribe();
     let mut downloaded_pieces = Vec::new();
     let mut pieces_processed = 0;
     let mut pieces_done = 0;
@@ -516,7 +692,6 @@ pub async fn find_downloaded_pieces(
                 if let Err(e) = read_piece_from_files(
                     &src_path.to_string(),
                     &torrent,
-                    i as u64,
                     addr,
                     piece_length,
                     &mut piece_buf,
@@ -532,15 +707,13 @@ pub async fn find_downloaded_pieces(
                     );
                 } else {
                     let sender = sender.clone();
-                    let ui_handle = ui_handle.clone();
+                    let events = events.clone();
                     let torrent = torrent.clone();
                     tokio::task::spawn_blocking(move || {
                         let hash = Torrent::bytes_hash(&piece_buf);
                         if hash == torrent.info.piece_hashes[i] {
                             sender.try_send((i, true)).unwrap();
-                            if let Err(e) = ui_handle.send_with_update(UiMsg::PieceDone(i as u16)) {
-                                log!(LogLevel::Error, "Failed to send PieceDone msg");
-                            }
+                            events.send(EngineEvent::PieceDone(i as u16));
                             log!(LogLevel::Info, "Piece {} is already downloaded", i);
                         } else {
                             sender.try_send((i, false)).unwrap();
@@ -559,7 +732,7 @@ pub async fn find_downloaded_pieces(
             let mut file = File::options().read(true).open(src_path).unwrap();
 
             for i in 0..pieces_i {
-                if let Ok(UiMsg::Pause(_) | UiMsg::Stop(_)) = ui_receiver.try_recv() {
+                if let Ok(EngineEvent::Pause(_) | EngineEvent::Stop(_)) = event_receiver.try_recv() {
                     return Vec::new();
                 }
                 let piece_length = torrent.get_piece_length(i);
@@ -582,15 +755,13 @@ pub async fn find_downloaded_pieces(
                 }
                 {
                     let sender = sender.clone();
-                    let ui_handle = ui_handle.clone();
+                    let events = events.clone();
                     let torrent = torrent.clone();
                     tokio::task::spawn_blocking(move || {
                         let hash = Torrent::bytes_hash(&piece_buf);
                         if hash == torrent.info.piece_hashes[i] {
                             sender.try_send((i, true)).unwrap();
-                            if let Err(e) = ui_handle.send_with_update(UiMsg::PieceDone(i as u16)) {
-                                log!(LogLevel::Error, "Failed to PieceDone msg");
-                            }
+                            events.send(EngineEvent::PieceDone(i as u16));
                             log!(LogLevel::Info, "Piece {} is already downloaded", i);
                         } else {
                             sender.try_send((i, false)).unwrap();
@@ -601,7 +772,7 @@ pub async fn find_downloaded_pieces(
         }
 
         while pieces_done < pieces_processed {
-            if let Ok(UiMsg::Pause(_) | UiMsg::Stop(_)) = ui_receiver.try_recv() {
+            if let Ok(EngineEvent::Pause(_) | EngineEvent::Stop(_)) = event_receiver.try_recv() {
                 break;
             }
             if let Ok(Some((i, have))) =
@@ -646,3 +817,56 @@ fn save_piece_to_file(
     file.set_len(torrent.info.files.as_ref().unwrap()[file_i].length)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::priorities::FilePriority::{Normal, Skip};
+    use super::*;
+
+    #[test]
+    fn segments_of_files() {
+        let size_progression = [0, 10, 30010, 30010, 50010];
+        assert_eq!(
+            file_segments(&size_progression, 5, 20),
+            vec![(0, 5, 5), (1, 0, 15)]
+        );
+        // empty file is skipped
+        assert_eq!(
+            file_segments(&size_progression, 30000, 20),
+            vec![(1, 29990, 10), (3, 0, 10)]
+        );
+        assert!(file_segments(&size_progression, 50010, 5).is_empty());
+    }
+
+    #[test]
+    fn skipped_file_is_parked() {
+        let dir = std::env::temp_dir().join(format!("part-test-{}", std::process::id()));
+        let src_path = dir.to_string_lossy().into_owned();
+        let mut info = b"d5:filesld6:lengthi10e4:pathl1:aeed6:lengthi30000e4:pathl1:bee".to_vec();
+        info.extend(b"d6:lengthi20000e4:pathl1:ceee4:name4:part12:piece lengthi16384e6:pieces80:");
+        info.extend([0; 80]);
+        info.extend(b"e");
+        let torrent = Arc::new(Torrent::from_metadata(&info, Vec::new()).unwrap());
+        let size_progression = vec![0, 10, 30010, 50010];
+        let progression = Some(size_progression.clone());
+        let data: Vec<u8> = (0..50010).map(|x| (x % 251 + 1) as u8).collect();
+
+        let priorities = priorities::torrent(&torrent.info_hash);
+        priorities.set(vec![Normal, Skip, Normal]);
+        let skipped = priorities.skipped_files();
+        save_data(&src_path, &torrent, &size_progression, &skipped, 0, &data).unwrap();
+        assert!(!dir.join("b").exists());
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), data[30010..]);
+        let read = read_data(&src_path, &torrent, &progression, 5, 30010);
+        assert_eq!(read.unwrap(), data[5..30015]);
+
+        priorities.set(Vec::new());
+        unpark_files(&src_path, &torrent, &size_progression, &HashSet::new()).unwrap();
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), data[10..30010]);
+        let read = read_data(&src_path, &torrent, &progression, 0, 50010);
+        assert_eq!(read.unwrap(), data);
+
+        priorities::remove_torrent(&torrent.info_hash);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout, Instant};
+
+use super::choker::Choker;
+use super::events::{EngineEvent, EventSender};
+use super::extensions::ExtensionRegistry;
+use super::logger::{log, LogLevel};
+use super::peers::{bitfield_has, Peer, PeerMessage, PeerStatus};
+use super::pex::PeerExchange;
+use super::priorities;
+use super::ratelimit::{PeerLimiter, RateLimiter};
+use super::saver::TransferStats;
+use super::settings::Settings;
+use super::torrent::{PieceBitmap, Torrent};
+use super::DownloadEvents;
+
+// How often targets are checked and UI is updated
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Choke decisions are applied to idle peers this often
+const CHOKE_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+// Peer that sent nothing for this time is disconnected
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bytes uploaded and time spent seeding, summed over sessions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShareTotals {
+    pub uploaded: u64,
+    pub seeding_secs: u64,
+}
+
+#[derive(Debug)]
+pub enum SeedEnd {
+    // Ratio or time target is reached
+    TargetReached(ShareTotals),
+    // User stopped torrent, message is the one that stopped it
+    Stopped(EngineEvent, ShareTotals),
+    // Skipped file became wanted, torrent is downloaded again
+    SelectionChanged(ShareTotals),
+}
+
+pub struct Seeder {
+    pub torrent: Arc<Torrent>,
+    pub events: EventSender,
+    pub stats: Arc<TransferStats>,
+    pub choker: Arc<Choker>,
+    pub extensions: Arc<ExtensionRegistry>,
+    pub pex: Arc<PeerExchange>,
+    pub limiter: Arc<RateLimiter>,
+    pub totals: ShareTotals,
+}
+
+impl Seeder {
+    // Serving connected and incoming peers until target is reached or torrent is stopped,
+    // bitmap is not full if some files are skipped
+    pub async fn run(
+        self,
+        peers: Vec<Peer>,
+        bitmap: PieceBitmap,
+        events: &mut mpsc::Receiver<DownloadEvents>,
+        event_reader: &mut broadcast::Receiver<EngineEvent>,
+    ) -> SeedEnd {
+        log!(LogLevel::Info, "Seeding {}", self.torrent.info.name);
+        self.choker.set_seeding(true);
+        let bitmap = Arc::new(bitmap);
+        let started = Instant::now();
+        let file_priorities = priorities::torrent(&self.torrent.info_hash);
+        let mut priorities_version = file_priorities.version();
+
+        let mut known: HashSet<SocketAddr> = HashSet::new();
+        let mut tasks = JoinSet::new();
+        for peer in peers {
+            self.serve(peer, &bitmap, &mut known, &mut tasks);
+        }
+
+        let mut check = interval(CHECK_INTERVAL);
+        loop {
+            let totals = ShareTotals {
+                uploaded: self.totals.uploaded + self.stats.uploaded.load(Ordering::Relaxed),
+                seeding_secs: self.totals.seeding_secs + started.elapsed().as_secs(),
+            };
+            tokio::select! {
+                Some(event) = events.recv() => {
+                    if let DownloadEvents::PeerAdd(mut peer, discovered) = event {
+                        if discovered {
+                            peer.extensions = self.extensions.clone();
+                            peer.choker = self.choker.clone();
+                            peer.limiter = PeerLimiter::new(self.limiter.clone());
+                            self.events
+                                .send(EngineEvent::PeerDiscovered(peer.peer_addr));
+                        }
+                        self.serve(peer, &bitmap, &mut known, &mut tasks);
+                    }
+                }
+                Some(res) = tasks.join_next() => {
+                    if let Ok(addr) = res {
+                        known.remove(&addr);
+                        self.choker.peer_removed(&addr);
+                        self.pex.peer_dropped(&addr);
+                        self.events.send(EngineEvent::PeerDisconnect(addr));
+                    }
+                }
+                msg = event_reader.recv() => {
+                    if let Ok(msg @ (EngineEvent::ForceOff | EngineEvent::Pause(_) | EngineEvent::Stop(_))) = msg {
+                        tasks.abort_all();
+                        return SeedEnd::Stopped(msg, totals);
+                    }
+                }
+                _ = check.tick() => {
+                    self.events.send(EngineEvent::Seeding {
+                        uploaded: totals.uploaded,
+                        seconds: totals.seeding_secs,
+                    });
+                    if self.target_reached(&totals) {
+                        log!(
+                            LogLevel::Info,
+                            "Seeding target reached, uploaded {} in total",
+                            totals.uploaded
+                        );
+                        tasks.abort_all();
+                        return SeedEnd::TargetReached(totals);
+                    }
+                    if file_priorities.version() != priorities_version {
+                        priorities_version = file_priorities.version();
+                        let pieces =
+                            priorities::piece_priorities(&self.torrent, &file_priorities.get());
+                        let missing = pieces
+                            .iter()
+                            .enumerate()
+                            .any(|(i, x)| *x != priorities::FilePriority::Skip && !bitmap.has(i));
+                        if missing {
+                            log!(LogLevel::Info, "New files are selected, downloading again");
+                            tasks.abort_all();
+                            self.choker.set_seeding(false);
+                            return SeedEnd::SelectionChanged(totals);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn target_reached(&self, totals: &ShareTotals) -> bool {
+        let settings = Settings::get();
+        let ratio = totals.uploaded as f64 / self.torrent.info.length as f64;
+        settings.seed_ratio.is_some_and(|x| ratio >= x)
+            || settings
+                .seed_time
+                .is_some_and(|x| totals.seeding_secs >= x * 60)
+    }
+
+    fn serve(
+        &self,
+        peer: Peer,
+        bitmap: &Arc<PieceBitmap>,
+        known: &mut HashSet<SocketAddr>,
+        tasks: &mut JoinSet<SocketAddr>,
+    ) {
+        if !known.insert(peer.peer_addr) {
+            return;
+        }
+        self.pex.peer_connected(&peer.peer_addr);
+        let torrent = self.torrent.clone();
+        let bitmap = bitmap.clone();
+        tasks.spawn(async move {
+            let addr = peer.peer_addr;
+            if let Err(e) = serve_peer(peer, &torrent, &bitmap).await {
+                log!(LogLevel::Debug, "Stopped seeding to {addr}: {e}");
+            }
+            addr
+        });
+    }
+}
+
+// Answering peer's requests, choke decisions are applied even if peer is silent
+async fn serve_peer(mut peer: Peer, torrent: &Torrent, bitmap: &PieceBitmap) -> anyhow::Result<()> {
+    match peer.status {
+        PeerStatus::NotConnected => {
+            peer.own_bitfield = bitmap.clone();
+            peer.exchange_handshake(&torrent.info_hash, Duration::from_secs(4))
+                .await?;
+            peer.send_message(&PeerMessage::Bitfield(bitmap.bitmap.clone()))
+                .await?;
+            peer.send_extended_handshake().await?;
+        }
+        PeerStatus::Handshaked => {
+            peer.own_bitfield = bitmap.clone();
+            peer.send_message(&PeerMessage::Bitfield(bitmap.bitmap.clone()))
+                .await?;
+            peer.send_extended_handshake().await?;
+        }
+        PeerStatus::Choked | PeerStatus::Unchoked => {
+            // peer was used for downloading, telling about pieces it doesn't know of
+            if peer.own_bitfield.bitmap.len() == bitmap.bitmap.len() {
+                for i in peer.own_bitfield.diff(bitmap) {
+                    peer.send_message(&PeerMessage::Have(i as u32)).await?;
+                    peer.own_bitfield.add(i);
+                }
+            }
+            peer.send_message(&PeerMessage::NotInterested).await?;
+        }
+    }
+
+    let mut sync = interval(CHOKE_SYNC_INTERVAL);
+    let mut last_received = Instant::now();
+    let mut last_keep_alive = Instant::now();
+    loop {
+        tokio::select! {
+            res = peer.socket.readable() => {
+                res?;
+                let msg = timeout(MESSAGE_TIMEOUT, peer.receive_message()).await??;
+                last_received = Instant::now();
+                let pieces_changed = matches!(msg, PeerMessage::Bitfield(_) | PeerMessage::Have(_));
+                match msg {
+                    // we don't download anything, remote choke doesn't matter
+                    PeerMessage::Choke => peer.status = PeerStatus::Choked,
+                    msg => peer.handle_message(msg).await?,
+                }
+                if pieces_changed && is_seed(&peer, torrent.info.piece_hashes.len()) {
+                    anyhow::bail!("Peer is seed too");
+                }
+            }
+            _ = sync.tick() => {
+                if last_received.elapsed() > PEER_IDLE_TIMEOUT {
+                    anyhow::bail!("Peer is idle");
+                }
+                peer.sync_choke().await?;
+                // pex messages are sent here too, peers aren't downloaded from
+                peer.send_extension_updates().await?;
+                if last_keep_alive.elapsed() > KEEP_ALIVE_INTERVAL {
+                    peer.send_message(&PeerMessage::KeepAlive).await?;
+                    last_keep_alive = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+fn is_seed(peer: &Peer, pieces_n: usize) -> bool {
+    peer.peer_bitfield
+        .as_ref()
+        .is_some_and(|x| (0..pieces_n).all(|i| bitfield_has(x, i)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn pex_is_sent_while_seeding() {
+        let info = b"d6:lengthi10e4:name1:a12:piece lengthi16384e6:pieces20:\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00e";
+        let torrent = Torrent::from_metadata(info, Vec::new()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let (event_sender, _) = mpsc::channel(1);
+        let (data_sender, _) = mpsc::channel(1);
+        let pex = Arc::new(PeerExchange::new(
+            String::new(),
+            String::new(),
+            event_sender,
+            data_sender.clone(),
+        ));
+        pex.peer_connected(&addr);
+        pex.peer_connected(&"10.0.0.2:6881".parse().unwrap());
+        let mut extensions = ExtensionRegistry::default();
+        extensions.register(pex);
+
+        let mut peer = Peer::from_socket(socket, addr, data_sender, String::new(), String::new());
+        peer.status = PeerStatus::Unchoked;
+        peer.extensions = Arc::new(extensions);
+        peer.remote_extensions = Some(HashMap::from([("ut_pex".to_string(), 3)]));
+        let mut bitmap = PieceBitmap::new(1);
+        bitmap.add(0);
+        peer.own_bitfield = bitmap.clone();
+        tokio::spawn(async move { serve_peer(peer, &torrent, &bitmap).await });
+
+        // messages before it are not interested and choke ones
+        let pex_received = async {
+            loop {
+                let len = remote.read_u32().await.unwrap() as usize;
+                let mut buf = vec![0; len];
+                remote.read_exact(&mut buf).await.unwrap();
+                if buf.starts_with(&[20, 3]) {
+                    break;
+                }
+            }
+        };
+        timeout(Duration::from_secs(2), pex_received).await.unwrap();
+    }
+}
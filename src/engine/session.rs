@@ -0,0 +1,982 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::TryRecvError, Receiver, Sender};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use super::backup::{Backup, TorrentBackupInfo};
+use super::events::{EngineEvent, EngineEvents, EventSender};
+use super::{api, control, transmission};
+use super::logger::{log, LogLevel};
+use super::priorities::{self, FilePriority};
+use super::ratelimit;
+use super::saver;
+use super::settings::Settings;
+use super::torrent::Torrent;
+use super::tracker::{AnnounceInfo, ScrapeInfo};
+use super::{
+    data_path, dht, download_torrent, listener, parse_magnet, parse_torrent, streamer, TorrentInfo,
+};
+
+const PIECES_TO_TIME_MEASURE: u8 = 5;
+// Upload speed is averaged over blocks sent in last seconds
+const UPLOAD_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum DownloadStatus {
+    Resuming,
+    Downloading,
+    Paused,
+    Finished,
+    Error(String),
+    // Download is complete, data is uploaded to peers.
+    // New variants go last, bincode stores variant index in backup
+    Seeding,
+}
+
+pub struct WorkerInfo {
+    handle: JoinHandle<anyhow::Result<()>>,
+    sender: Sender<EngineEvent>,
+    receiver: Receiver<EngineEvent>,
+}
+
+pub struct TimeStamp {
+    pub time: Instant,
+    pub pieces_n: u32,
+}
+
+pub struct TorrentDownload {
+    pub status: DownloadStatus,
+    worker_info: Option<WorkerInfo>,
+    pub peers: Vec<SocketAddr>,
+    pub torrent: Torrent,
+    pub pieces_done: u32,
+    pub last_timestamp: Option<TimeStamp>,
+    // milliseconds per piece
+    pub download_speed: Option<u16>,
+    // file or folder with torrent data
+    pub save_dir: String,
+    pub uploaded: u64,
+    // uploaded bytes and seeding time over all sessions
+    pub total_uploaded: u64,
+    pub seeding_secs: u64,
+    pub trackers: Vec<TrackerView>,
+    // blocks sent to peers: time, peer and size
+    recent_uploads: VecDeque<(Instant, SocketAddr, u64)>,
+}
+
+// Last announce and scrape results of tracker
+pub struct TrackerView {
+    pub url: String,
+    pub announce: Option<Result<AnnounceInfo, String>>,
+    pub scrape: Option<Result<ScrapeInfo, String>>,
+}
+
+// Snapshot of torrent state for control clients
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TorrentStats {
+    pub hash: String,
+    pub name: String,
+    pub status: DownloadStatus,
+    pub size: u64,
+    pub pieces_done: u32,
+    pub pieces_total: usize,
+    // bytes per second
+    pub download_speed: u64,
+    pub upload_speed: u64,
+    // bytes uploaded in this session and in all sessions
+    pub uploaded: u64,
+    pub total_uploaded: u64,
+    pub peers: usize,
+    // peers that got data from us recently
+    pub uploading_peers: usize,
+    pub save_dir: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileStats {
+    pub path: String,
+    pub length: u64,
+    pub priority: FilePriority,
+}
+
+// Last announce of tracker, fields are None until it is contacted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrackerStats {
+    pub url: String,
+    pub error: Option<String>,
+    pub peers: Option<usize>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+}
+
+// Stats with files, trackers and peers of torrent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TorrentDetails {
+    #[serde(flatten)]
+    pub stats: TorrentStats,
+    pub piece_length: u64,
+    pub files: Vec<FileStats>,
+    pub trackers: Vec<TrackerStats>,
+    pub peer_addrs: Vec<String>,
+    pub sequential: bool,
+}
+
+impl TorrentDetails {
+    fn new(stats: TorrentStats, torrent: &Torrent, priorities: &[FilePriority], sequential: bool) -> Self {
+        let files = match torrent.info.files {
+            Some(ref files) => files
+                .iter()
+                .enumerate()
+                .map(|(i, x)| FileStats {
+                    path: x.path.clone(),
+                    length: x.length,
+                    priority: priorities.get(i).copied().unwrap_or_default(),
+                })
+                .collect(),
+            None => vec![FileStats {
+                path: torrent.info.name.clone(),
+                length: torrent.info.length,
+                priority: FilePriority::Normal,
+            }],
+        };
+        let trackers = torrent
+            .tracker_tiers
+            .iter()
+            .flatten()
+            .map(|url| TrackerStats {
+                url: url.clone(),
+                error: None,
+                peers: None,
+                seeders: None,
+                leechers: None,
+            })
+            .collect();
+        TorrentDetails {
+            stats,
+            piece_length: torrent.info.piece_length,
+            files,
+            trackers,
+            peer_addrs: Vec::new(),
+            sequential,
+        }
+    }
+
+    // Saved state of torrent, when no client is running
+    pub fn from_backup(backup: &TorrentBackupInfo) -> Self {
+        let stats = TorrentStats {
+            hash: hex::encode(&backup.torrent.info_hash),
+            name: backup.torrent.info.name.clone(),
+            status: backup.status.clone(),
+            size: backup.torrent.info.length,
+            pieces_done: backup.pieces_done as u32,
+            pieces_total: backup.torrent.info.piece_hashes.len(),
+            download_speed: 0,
+            upload_speed: 0,
+            uploaded: 0,
+            total_uploaded: backup.uploaded,
+            peers: 0,
+            uploading_peers: 0,
+            save_dir: backup.save_path.clone(),
+        };
+        TorrentDetails::new(stats, &backup.torrent, &backup.file_priorities, backup.sequential)
+    }
+}
+
+impl TorrentDownload {
+    fn tracker_view(&mut self, url: String) -> &mut TrackerView {
+        let pos = self.trackers.iter().position(|x| x.url == url);
+        let i = match pos {
+            Some(i) => i,
+            None => {
+                self.trackers.push(TrackerView {
+                    url,
+                    announce: None,
+                    scrape: None,
+                });
+                self.trackers.len() - 1
+            }
+        };
+        &mut self.trackers[i]
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.worker_info.is_some()
+    }
+
+    // Bytes per second, 0 if torrent isn't downloading
+    pub fn speed(&self) -> u64 {
+        match (&self.status, self.download_speed) {
+            (DownloadStatus::Downloading, Some(ms)) if ms != 0 => {
+                self.torrent.info.piece_length * 1_000 / ms as u64
+            }
+            _ => 0,
+        }
+    }
+
+    fn add_upload(&mut self, peer: SocketAddr, n: u64) {
+        self.uploaded += n;
+        let now = Instant::now();
+        while self
+            .recent_uploads
+            .front()
+            .is_some_and(|x| now.duration_since(x.0) > UPLOAD_RATE_WINDOW)
+        {
+            self.recent_uploads.pop_front();
+        }
+        self.recent_uploads.push_back((now, peer, n));
+    }
+
+    fn recent_uploads(&self) -> impl Iterator<Item = &(Instant, SocketAddr, u64)> {
+        self.recent_uploads
+            .iter()
+            .filter(|x| x.0.elapsed() <= UPLOAD_RATE_WINDOW)
+    }
+
+    // Bytes per second sent to peers
+    pub fn upload_speed(&self) -> u64 {
+        self.recent_uploads().map(|x| x.2).sum::<u64>() / UPLOAD_RATE_WINDOW.as_secs()
+    }
+
+    pub fn uploading_peers(&self) -> usize {
+        let peers: HashSet<SocketAddr> = self.recent_uploads().map(|x| x.1).collect();
+        peers.len()
+    }
+
+    pub fn stats(&self) -> TorrentStats {
+        TorrentStats {
+            hash: hex::encode(&self.torrent.info_hash),
+            name: self.torrent.info.name.clone(),
+            status: self.status.clone(),
+            size: self.torrent.info.length,
+            pieces_done: self.pieces_done,
+            pieces_total: self.torrent.info.piece_hashes.len(),
+            download_speed: self.speed(),
+            upload_speed: self.upload_speed(),
+            uploaded: self.uploaded,
+            total_uploaded: self.total_uploaded,
+            peers: self.peers.len(),
+            uploading_peers: self.uploading_peers(),
+            save_dir: self.save_dir.clone(),
+        }
+    }
+
+    pub fn details(&self) -> TorrentDetails {
+        let file_priorities = priorities::torrent(&self.torrent.info_hash);
+        let mut details = TorrentDetails::new(
+            self.stats(),
+            &self.torrent,
+            &file_priorities.get(),
+            file_priorities.is_sequential(),
+        );
+        for tracker in details.trackers.iter_mut() {
+            let Some(view) = self.trackers.iter().find(|x| x.url == tracker.url) else {
+                continue;
+            };
+            match &view.announce {
+                Some(Ok(info)) => {
+                    tracker.peers = Some(info.peers_n);
+                    tracker.seeders = info.complete;
+                    tracker.leechers = info.incomplete;
+                }
+                Some(Err(e)) => tracker.error = Some(e.clone()),
+                None => {}
+            }
+            if let Some(Ok(info)) = &view.scrape {
+                tracker.seeders = Some(info.complete);
+                tracker.leechers = Some(info.incomplete);
+            }
+        }
+        details.peer_addrs = self.peers.iter().map(|x| x.to_string()).collect();
+        details
+    }
+}
+
+// Commands of control clients, executed by session owner on update
+pub enum Command {
+    Add {
+        torrent: Torrent,
+        dest: String,
+        reply: oneshot::Sender<anyhow::Result<String>>,
+    },
+    List(oneshot::Sender<Vec<TorrentStats>>),
+    Info(String, oneshot::Sender<anyhow::Result<TorrentDetails>>),
+    Pause(String, oneshot::Sender<anyhow::Result<()>>),
+    Resume(String, oneshot::Sender<anyhow::Result<()>>),
+    Verify(String, oneshot::Sender<anyhow::Result<()>>),
+    Remove {
+        hash: String,
+        delete_data: bool,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+// Cloneable way to drive session from other tasks
+#[derive(Clone, Debug)]
+pub struct SessionHandle {
+    sender: mpsc::UnboundedSender<Command>,
+    pub peer_id: String,
+}
+
+impl SessionHandle {
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> anyhow::Result<T> {
+        let (reply, receiver) = oneshot::channel();
+        if self.sender.send(command(reply)).is_err() {
+            anyhow::bail!("Session is closed");
+        }
+        Ok(receiver.await?)
+    }
+
+    // Returns hex info hash of added torrent
+    pub async fn add(&self, torrent: Torrent, dest: String) -> anyhow::Result<String> {
+        self.request(|reply| Command::Add {
+            torrent,
+            dest,
+            reply,
+        })
+        .await?
+    }
+
+    // source is path of torrent file or magnet link
+    pub async fn add_source(&self, source: &str, dest: String) -> anyhow::Result<String> {
+        let torrent = if source.starts_with("magnet:") {
+            parse_magnet(source, self.peer_id.clone()).await?
+        } else {
+            parse_torrent(source)?
+        };
+        self.add(torrent, dest).await
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<TorrentStats>> {
+        self.request(Command::List).await
+    }
+
+    pub async fn info(&self, hash: String) -> anyhow::Result<TorrentDetails> {
+        self.request(|reply| Command::Info(hash, reply)).await?
+    }
+
+    pub async fn pause(&self, hash: String) -> anyhow::Result<()> {
+        self.request(|reply| Command::Pause(hash, reply)).await?
+    }
+
+    pub async fn resume(&self, hash: String) -> anyhow::Result<()> {
+        self.request(|reply| Command::Resume(hash, reply)).await?
+    }
+
+    pub async fn verify(&self, hash: String) -> anyhow::Result<()> {
+        self.request(|reply| Command::Verify(hash, reply)).await?
+    }
+
+    pub async fn remove(&self, hash: String, delete_data: bool) -> anyhow::Result<()> {
+        self.request(|reply| Command::Remove {
+            hash,
+            delete_data,
+            reply,
+        })
+        .await?
+    }
+}
+
+// Torrents of the client and their workers, GUI and daemon are frontends of it
+pub struct Session {
+    pub torrents: Vec<TorrentDownload>,
+    pub peer_id: String,
+    // frontends told about events of workers
+    subscribers: Vec<Arc<dyn EngineEvents>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    handle: SessionHandle,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        use rand::distributions::{Alphanumeric, DistString};
+        let (sender, commands) = mpsc::unbounded_channel();
+        let peer_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
+        Session {
+            torrents: Vec::new(),
+            peer_id: peer_id.clone(),
+            subscribers: Vec::new(),
+            commands,
+            handle: SessionHandle { sender, peer_id },
+        }
+    }
+}
+
+impl Session {
+    pub fn handle(&self) -> SessionHandle {
+        self.handle.clone()
+    }
+
+    // Subscribers added before start get events of restored torrents too
+    pub fn subscribe(&mut self, subscriber: Arc<dyn EngineEvents>) {
+        self.subscribers.push(subscriber);
+    }
+
+    // Starts engine services and restores torrents from backup
+    pub fn start(&mut self) {
+        saver::init_saver_globals();
+        Settings::init();
+        ratelimit::spawn_scheduler();
+        Backup::init().expect("Saver does not work");
+        listener::spawn_listener(self.peer_id.clone());
+        streamer::spawn_stream_server();
+        control::spawn_control_server(self.handle());
+        api::spawn_api_server(self.handle());
+        transmission::spawn_rpc_server(self.handle());
+        dht::spawn_dht();
+        match async_std::task::block_on(Backup::global().load_config()) {
+            Ok(backups) => {
+                for backup in backups {
+                    ratelimit::torrent(&backup.torrent.info_hash).set(backup.limits.clone());
+                    let file_priorities = priorities::torrent(&backup.torrent.info_hash);
+                    file_priorities.set(backup.file_priorities.clone());
+                    file_priorities.set_sequential(backup.sequential);
+                    self.torrents.push(TorrentDownload {
+                        peers: Vec::new(),
+                        status: backup.status.clone(),
+                        worker_info: None,
+                        torrent: backup.torrent.clone(),
+                        pieces_done: backup.pieces_done as u32,
+                        save_dir: backup.save_path.clone(),
+                        last_timestamp: None,
+                        download_speed: None,
+                        uploaded: 0,
+                        total_uploaded: backup.uploaded,
+                        seeding_secs: backup.seeding_secs,
+                        trackers: Vec::new(),
+                        recent_uploads: VecDeque::new(),
+                    });
+                    log!(LogLevel::Info, "done: {}", backup.pieces_done);
+                    if let DownloadStatus::Downloading | DownloadStatus::Seeding = backup.status {
+                        self.start_download(TorrentInfo::Backup(backup), "");
+                    }
+                }
+            }
+            Err(e) => log!(LogLevel::Error, "Failed to open backup file: {e}"),
+        }
+    }
+
+    pub fn find(&self, hash: &str) -> Option<usize> {
+        let hash = hash.to_lowercase();
+        self.torrents
+            .iter()
+            .position(|x| hex::encode(&x.torrent.info_hash) == hash)
+    }
+
+    // New torrent is saved to dest_dir, known torrents keep their data path
+    pub fn start_download(&mut self, torrent_info: TorrentInfo, dest_dir: &str) {
+        let torrent = match &torrent_info {
+            TorrentInfo::Torrent(torrent) => torrent.clone(),
+            TorrentInfo::Backup(backup) => backup.torrent.clone(),
+        };
+
+        let (sender, receiver) = broadcast::channel(20_000);
+        let folder = match torrent_info {
+            TorrentInfo::Backup(ref backup) => backup.save_path.clone(),
+
+            TorrentInfo::Torrent(ref torrent) => {
+                let pos = self
+                    .torrents
+                    .iter()
+                    .position(|x| x.torrent.info_hash == torrent.info_hash);
+                if let Some(i) = pos {
+                    self.torrents[i].save_dir.clone()
+                } else {
+                    data_path(torrent, dest_dir)
+                }
+            }
+        };
+        let handle = {
+            let folder = folder.clone();
+            let name = torrent.info.name.clone();
+            let sender = sender.clone();
+            let peer_id = self.peer_id.clone();
+            let info_hash = torrent.info_hash.clone();
+            let subscribers = self.subscribers.clone();
+            tokio::spawn(async move {
+                log!(LogLevel::Info, "Strating torrent downloading: {name}");
+                let events = EventSender::new(info_hash, sender, subscribers);
+                let res = download_torrent(torrent_info, &folder, events.clone(), peer_id).await;
+                if let Err(ref e) = res {
+                    log!(LogLevel::Fatal, "Failed to download torrent: {e}");
+                    events.send(EngineEvent::TorrentErr(e.to_string()));
+                }
+                log!(LogLevel::Info, "{} download finished", name);
+                res
+            })
+        };
+        let info = WorkerInfo {
+            handle,
+            sender,
+            receiver,
+        };
+
+        let torrent_i = self
+            .torrents
+            .iter()
+            .position(|x| x.torrent.info_hash == torrent.info_hash);
+
+        if torrent_i.is_none() {
+            self.torrents.push(TorrentDownload {
+                peers: Vec::new(),
+                torrent,
+                status: DownloadStatus::Resuming,
+                worker_info: Some(info),
+                pieces_done: 0,
+                download_speed: None,
+                save_dir: folder,
+                last_timestamp: None,
+                uploaded: 0,
+                total_uploaded: 0,
+                seeding_secs: 0,
+                trackers: Vec::new(),
+                recent_uploads: VecDeque::new(),
+            });
+        } else {
+            let i = torrent_i.unwrap();
+            self.torrents[i].worker_info = Some(info);
+            self.torrents[i].last_timestamp = None;
+            self.torrents[i].status = DownloadStatus::Resuming;
+        }
+    }
+
+    pub fn pause_torrent(&mut self, i: usize) {
+        self.torrents[i].peers.clear();
+        self.torrents[i].recent_uploads.clear();
+        let worker_info = self.torrents[i].worker_info.take();
+        if let Some(info) = worker_info {
+            log!(LogLevel::Info, "Sended pause msg!!!");
+            let _ = info
+                .sender
+                .send(EngineEvent::Pause(self.torrents[i].pieces_done as u16));
+            log!(LogLevel::Info, "Finished: sended pause msg!!!");
+            self.torrents[i].status = DownloadStatus::Paused;
+
+            async_std::task::block_on(async move {
+                let _ = info.handle.await;
+            });
+            log!(LogLevel::Debug, "Finished block on handle");
+        }
+    }
+
+    pub fn resume_torrent(&mut self, i: usize) {
+        log!(LogLevel::Debug, "Resuming torrent");
+        let backup = async_std::task::block_on(
+            Backup::global().load_backup(&self.torrents[i].torrent.info_hash),
+        );
+        log!(LogLevel::Debug, "Backup data loaded successfully");
+        self.torrents[i].status = DownloadStatus::Resuming;
+        self.torrents[i].download_speed = None;
+        self.torrents[i].last_timestamp = None;
+        if let Ok(backup) = backup {
+            if backup.pieces_done != 0 {
+                self.start_download(TorrentInfo::Backup(backup), "");
+                return;
+            }
+        }
+        self.torrents[i].pieces_done = 0;
+        self.start_download(TorrentInfo::Torrent(self.torrents[i].torrent.clone()), "");
+    }
+
+    // Hash checks data on disk again, torrent is downloading after it
+    pub fn verify_torrent(&mut self, i: usize) {
+        self.pause_torrent(i);
+        self.torrents[i].pieces_done = 0;
+        self.torrents[i].download_speed = None;
+        self.start_download(TorrentInfo::Torrent(self.torrents[i].torrent.clone()), "");
+    }
+
+    pub fn delete_torrent(&mut self, i: usize, delete_data: bool) {
+        if let Some(info) = self.torrents[i].worker_info.take() {
+            let _ = info.sender.send(EngineEvent::ForceOff);
+            if delete_data {
+                // worker must not write files while they are removed
+                async_std::task::block_on(async move {
+                    let _ = info.handle.await;
+                });
+            }
+        }
+        if delete_data {
+            if let Err(e) = saver::remove_data(
+                &self.torrents[i].save_dir,
+                &self.torrents[i].torrent.info.name,
+            ) {
+                log!(LogLevel::Error, "Failed to remove torrent data: {e}");
+            }
+        }
+        async_std::task::block_on(
+            Backup::global().remove_torrent(&self.torrents[i].torrent.info_hash),
+        )
+        .unwrap();
+        ratelimit::remove_torrent(&self.torrents[i].torrent.info_hash);
+        priorities::remove_torrent(&self.torrents[i].torrent.info_hash);
+        self.torrents.remove(i);
+    }
+
+    // Called periodically by frontend: runs control commands
+    // and applies messages of workers to torrents state
+    pub fn update(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            self.run_command(command);
+        }
+        for t_i in 0..self.torrents.len() {
+            self.torrent_updates(t_i);
+        }
+    }
+
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::Add {
+                torrent,
+                dest,
+                reply,
+            } => {
+                let hash = hex::encode(&torrent.info_hash);
+                let res = if self.find(&hash).is_some() {
+                    Err(anyhow::anyhow!("Torrent is already added"))
+                } else {
+                    self.start_download(TorrentInfo::Torrent(torrent), &dest);
+                    Ok(hash)
+                };
+                let _ = reply.send(res);
+            }
+            Command::List(reply) => {
+                let _ = reply.send(self.torrents.iter().map(|x| x.stats()).collect());
+            }
+            Command::Info(hash, reply) => {
+                let res = self.by_hash(&hash).map(|i| self.torrents[i].details());
+                let _ = reply.send(res);
+            }
+            Command::Pause(hash, reply) => {
+                let res = self.by_hash(&hash).map(|i| {
+                    if self.torrents[i].is_active() {
+                        self.pause_torrent(i);
+                    }
+                });
+                let _ = reply.send(res);
+            }
+            Command::Resume(hash, reply) => {
+                let res = self.by_hash(&hash).map(|i| {
+                    if let DownloadStatus::Paused | DownloadStatus::Error(_) =
+                        self.torrents[i].status
+                    {
+                        self.resume_torrent(i);
+                    }
+                });
+                let _ = reply.send(res);
+            }
+            Command::Verify(hash, reply) => {
+                let res = self.by_hash(&hash).map(|i| self.verify_torrent(i));
+                let _ = reply.send(res);
+            }
+            Command::Remove {
+                hash,
+                delete_data,
+                reply,
+            } => {
+                let res = self
+                    .by_hash(&hash)
+                    .map(|i| self.delete_torrent(i, delete_data));
+                let _ = reply.send(res);
+            }
+        }
+    }
+
+    fn by_hash(&self, hash: &str) -> anyhow::Result<usize> {
+        self.find(hash)
+            .ok_or_else(|| anyhow::anyhow!("No torrent with info hash {hash}"))
+    }
+
+    fn torrent_updates(&mut self, t_i: usize) {
+        let Some(t_info) = &self.torrents[t_i].worker_info else {
+            return;
+        };
+        // worker ends by itself when seeding target is reached or on error
+        if t_info.handle.is_finished() {
+            let info = self.torrents[t_i].worker_info.take().unwrap();
+            let torrent = &mut self.torrents[t_i];
+            torrent.peers.clear();
+            match async_std::task::block_on(info.handle) {
+                Ok(Ok(())) => {
+                    torrent.pieces_done = torrent.torrent.info.piece_hashes.len() as u32;
+                    torrent.status = DownloadStatus::Finished;
+                }
+                Ok(Err(e)) => torrent.status = DownloadStatus::Error(e.to_string()),
+                Err(e) => torrent.status = DownloadStatus::Error(format!("Worker failed: {e}")),
+            }
+            return;
+        }
+
+        if let DownloadStatus::Downloading | DownloadStatus::Resuming | DownloadStatus::Seeding =
+            self.torrents[t_i].status
+        {
+            let mut done_piece = false;
+            // events are taken out first, handlers below can restart the worker
+            let mut events = Vec::new();
+            let receiver = &mut self.torrents[t_i].worker_info.as_mut().unwrap().receiver;
+            loop {
+                match receiver.try_recv() {
+                    Ok(msg) => events.push(msg),
+                    Err(TryRecvError::Lagged(n)) => {
+                        log!(LogLevel::Error, "Session skipped {n} worker events");
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+            for msg in events {
+                // pieces found by hash check don't count for download speed,
+                // other events are handled same way as when downloading
+                if let DownloadStatus::Resuming = self.torrents[t_i].status {
+                    match msg {
+                        EngineEvent::PieceDone(_) => {
+                            self.torrents[t_i].pieces_done += 1;
+                            continue;
+                        }
+                        EngineEvent::HashCheckFinished => {
+                            self.torrents[t_i].status = DownloadStatus::Downloading;
+                            self.torrents[t_i].last_timestamp = Some(TimeStamp {
+                                time: Instant::now(),
+                                pieces_n: 0,
+                            });
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                match msg {
+                    EngineEvent::PieceDone(_) => {
+                        done_piece = true;
+                        self.torrents[t_i].pieces_done += 1;
+                        log!(
+                            LogLevel::Info,
+                            "Donwloaded: {}",
+                            self.torrents[t_i].pieces_done
+                        );
+                        if self.torrents[t_i].last_timestamp.is_some() {
+                            let info = self.torrents[t_i].last_timestamp.as_ref().unwrap();
+                            let pieces_done_from_timestamp =
+                                self.torrents[t_i].pieces_done - info.pieces_n;
+                            if pieces_done_from_timestamp >= PIECES_TO_TIME_MEASURE as u32 {
+                                let time_per_piece = info.time.elapsed().as_millis()
+                                    / PIECES_TO_TIME_MEASURE as u128;
+                                if self.torrents[t_i].download_speed.is_none() {
+                                    if time_per_piece != 0 {
+                                        self.torrents[t_i].download_speed =
+                                            Some(time_per_piece as u16);
+                                    }
+                                    continue;
+                                }
+                                log!(
+                                    LogLevel::Debug,
+                                    "Piece download time is {}ms  for torrent {}",
+                                    time_per_piece,
+                                    self.torrents[t_i].torrent.info.name
+                                );
+                                log!(
+                                    LogLevel::Info,
+                                    "curr speed: {time_per_piece}, result: {}",
+                                    self.torrents[t_i].download_speed.unwrap()
+                                );
+
+                                if time_per_piece as f64
+                                    / self.torrents[t_i].download_speed.unwrap() as f64
+                                    >= 3.0
+                                {
+                                    log!(LogLevel::Debug, "Restart by speed slow down");
+                                    self.pause_torrent(t_i);
+                                    self.resume_torrent(t_i);
+                                    // rest of events came from stopped worker
+                                    return;
+                                }
+
+                                self.torrents[t_i].last_timestamp = Some(TimeStamp {
+                                    time: Instant::now(),
+                                    pieces_n: self.torrents[t_i].pieces_done,
+                                });
+                                self.torrents[t_i].download_speed = if time_per_piece != 0 {
+                                    Some(time_per_piece as u16)
+                                } else {
+                                    None
+                                };
+                            }
+                        } else {
+                            self.torrents[t_i].last_timestamp = Some(TimeStamp {
+                                time: Instant::now(),
+                                pieces_n: self.torrents[t_i].pieces_done,
+                            });
+                        }
+
+                        if self.torrents[t_i].pieces_done
+                            == self.torrents[t_i].torrent.info.piece_hashes.len() as u32
+                        {
+                            self.torrents[t_i].status = DownloadStatus::Seeding;
+                        }
+                    }
+                    EngineEvent::DataUploaded(peer, n) => {
+                        self.torrents[t_i].add_upload(peer, n);
+                    }
+                    EngineEvent::TrackerAnnounce(tracker, res) => {
+                        self.torrents[t_i].tracker_view(tracker).announce = Some(res);
+                    }
+                    EngineEvent::TrackerScrape(tracker, res) => {
+                        self.torrents[t_i].tracker_view(tracker).scrape = Some(res);
+                    }
+                    EngineEvent::TorrentFinished => {
+                        self.torrents[t_i].status = DownloadStatus::Seeding;
+                        // with skipped files pieces are counted by PieceDone
+                        let info_hash = &self.torrents[t_i].torrent.info_hash;
+                        if priorities::torrent(info_hash).skipped_files().is_empty() {
+                            self.torrents[t_i].pieces_done =
+                                self.torrents[t_i].torrent.info.piece_hashes.len() as u32;
+                        }
+                    }
+                    EngineEvent::DownloadResumed => {
+                        self.torrents[t_i].status = DownloadStatus::Downloading;
+                        self.torrents[t_i].last_timestamp = None;
+                    }
+                    EngineEvent::Seeding { uploaded, seconds } => {
+                        self.torrents[t_i].total_uploaded = uploaded;
+                        self.torrents[t_i].seeding_secs = seconds;
+                    }
+                    EngineEvent::TorrentErr(msg) => {
+                        self.torrents[t_i].status = DownloadStatus::Error(msg)
+                    }
+                    EngineEvent::PeerDiscovered(peer) => {
+                        if self.torrents[t_i]
+                            .peers
+                            .iter()
+                            .position(|x| *x == peer)
+                            .is_none()
+                        {
+                            self.torrents[t_i].peers.push(peer);
+                        }
+                    }
+                    EngineEvent::PeerDisconnect(peer) => {
+                        if let Some(index) =
+                            self.torrents[t_i].peers.iter().position(|x| *x == peer)
+                        {
+                            self.torrents[t_i].peers.remove(index);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if !done_piece {
+                if let DownloadStatus::Seeding = self.torrents[t_i].status {
+                    return;
+                }
+                if self.torrents[t_i].last_timestamp.is_some() {
+                    let info = self.torrents[t_i].last_timestamp.as_ref().unwrap();
+                    let piece_time =
+                        info.time.elapsed().as_millis() / PIECES_TO_TIME_MEASURE as u128;
+
+
+                    if info.time.elapsed().as_millis() > 30_000
+                        && piece_time as f64 >= self.torrents[t_i].download_speed.unwrap_or(0) as f64 * 1.35
+                    {
+                        log!(LogLevel::Debug, "Restart by 30s timeout");
+                        self.pause_torrent(t_i);
+                        self.resume_torrent(t_i)
+                    }
+                }
+            }
+        }
+    }
+
+    // Stops workers, they back up their progress, and saves state of idle torrents
+    pub fn shutdown(&mut self) {
+        dht::save_nodes();
+        for q_torrent in &self.torrents {
+            match &q_torrent.status {
+                DownloadStatus::Downloading | DownloadStatus::Resuming | DownloadStatus::Seeding => {
+                    if let Some(info) = &q_torrent.worker_info {
+                        let _ = info
+                            .sender
+                            .send(EngineEvent::Stop(q_torrent.pieces_done as u16));
+                    }
+                }
+                DownloadStatus::Paused => {
+                    // Data is already backed up, only limits and priorities could change since
+                    let info_hash = &q_torrent.torrent.info_hash;
+                    let limits = ratelimit::torrent(info_hash).limits();
+                    let file_priorities = priorities::torrent(info_hash).get();
+                    let sequential = priorities::torrent(info_hash).is_sequential();
+                    if let Ok(mut backup) =
+                        async_std::task::block_on(Backup::global().load_backup(info_hash))
+                    {
+                        if backup.limits != limits
+                            || backup.file_priorities != file_priorities
+                            || backup.sequential != sequential
+                        {
+                            backup.limits = limits;
+                            backup.file_priorities = file_priorities;
+                            backup.sequential = sequential;
+                            async_std::task::block_on(Backup::global().backup_torrent(backup))
+                                .unwrap();
+                        }
+                    }
+                }
+                status => {
+                    async_std::task::block_on(Backup::global().backup_torrent(TorrentBackupInfo {
+                        pieces_tasks: VecDeque::new(),
+                        chunks_tasks: VecDeque::new(),
+                        torrent: q_torrent.torrent.clone(),
+                        save_path: q_torrent.save_dir.clone(),
+                        pieces_done: q_torrent.pieces_done as usize,
+                        status: status.clone(),
+                        uploaded: q_torrent.total_uploaded,
+                        seeding_secs: q_torrent.seeding_secs,
+                        limits: ratelimit::torrent(&q_torrent.torrent.info_hash).limits(),
+                        file_priorities: priorities::torrent(&q_torrent.torrent.info_hash).get(),
+                        sequential: priorities::torrent(&q_torrent.torrent.info_hash)
+                            .is_sequential(),
+                    }))
+                    .unwrap();
+                }
+            }
+        }
+
+        for q_torrent in std::mem::take(&mut self.torrents) {
+            match q_torrent.status {
+                DownloadStatus::Downloading | DownloadStatus::Resuming | DownloadStatus::Seeding => {
+                    if let Some(info) = q_torrent.worker_info {
+                        async_std::task::block_on(info.handle).unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Daemon drives session on its own, there is no frame loop
+pub async fn run_headless(mut session: Session) {
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    loop {
+        tokio::select! {
+            _ = tick.tick() => session.update(),
+            _ = shutdown_signal() => break,
+        }
+    }
+    log!(LogLevel::Info, "Shutting down, saving session");
+    session.shutdown();
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,131 @@
+use std::fs;
+use std::sync::RwLock;
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use super::backup::data_file_path;
+use super::download::pipeline::WindowLimits;
+use super::logger::{log, LogLevel};
+
+static SETTINGS: OnceCell<RwLock<Settings>> = OnceCell::new();
+const SETTINGS_NAME: &str = "settings.json";
+
+// User settings, stored as json next to backup
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Seeding stops when uploaded / torrent size reaches it
+    pub seed_ratio: Option<f64>,
+    // Seeding stops after this many minutes of seeding
+    pub seed_time: Option<u64>,
+    // Global speed limits
+    pub limits: RateLimits,
+    // Alternative limits, used when enabled or inside schedule
+    pub alt_limits: RateLimits,
+    pub alt_enabled: bool,
+    pub alt_schedule: Option<Schedule>,
+    // Bearer token of control API, generated on first start
+    pub api_token: Option<String>,
+    // API and web UI listen on all interfaces instead of localhost, applied on restart
+    pub api_remote: bool,
+    // Requests kept in flight to one peer, used by newly connected peers
+    pub request_window: WindowLimits,
+}
+
+// Speed limits in KiB/s, None is unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+// Hours of day, from is inclusive and to is exclusive, wraps over midnight
+// if from is bigger than to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub from_hour: u8,
+    pub to_hour: u8,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            from_hour: 8,
+            to_hour: 18,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.from_hour <= self.to_hour {
+            (self.from_hour..self.to_hour).contains(&hour)
+        } else {
+            hour >= self.from_hour || hour < self.to_hour
+        }
+    }
+}
+
+impl Settings {
+    pub fn init() {
+        let settings = Settings::load().unwrap_or_else(|e| {
+            log!(LogLevel::Info, "Using default settings: {e}");
+            Settings::default()
+        });
+        SETTINGS
+            .set(RwLock::new(settings))
+            .expect("Failed to init settings");
+    }
+
+    // Copy of current settings
+    pub fn get() -> Settings {
+        SETTINGS
+            .get()
+            .map(|x| x.read().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set(settings: Settings) {
+        if let Err(e) = settings.save() {
+            log!(LogLevel::Error, "Failed to save settings: {e}");
+        }
+        if let Some(current) = SETTINGS.get() {
+            *current.write().unwrap() = settings;
+        }
+    }
+
+    fn load() -> anyhow::Result<Settings> {
+        let bytes = fs::read(data_file_path(SETTINGS_NAME)?)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec_pretty(self)?;
+        fs::write(data_file_path(SETTINGS_NAME)?, bytes)?;
+        Ok(())
+    }
+}
+
+// Where torrents go if control client doesn't pick folder
+pub fn default_download_dir() -> String {
+    dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ".".to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Default settings with "test-token" API token, nothing is read from disk
+    pub fn init() {
+        let settings = Settings {
+            api_token: Some("test-token".to_string()),
+            ..Default::default()
+        };
+        let _ = SETTINGS.set(RwLock::new(settings));
+    }
+}
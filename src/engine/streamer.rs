@@ -0,0 +1,297 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::http::{self, Body, Request, Response};
+use super::logger::{log, LogLevel};
+use super::priorities::{self, FilePriorities, FilePriority};
+use super::saver::{self, VerifiedPieces};
+use super::Torrent;
+
+// Files of running torrents are served at http://127.0.0.1:STREAM_PORT/<info hash>/<file index>
+pub const STREAM_PORT: u16 = 6682;
+
+// Bytes ahead of reading position that are downloaded before anything else
+const READAHEAD: u64 = 8 * 1024 * 1024;
+// Largest chunk read from disk at once
+const READ_BLOCK: u64 = 256 * 1024;
+
+// Serves torrent files to local media players while they download
+pub fn spawn_stream_server() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, STREAM_PORT));
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to bind stream server to {addr}: {e}");
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Streaming torrent files on http://{addr}");
+        // only GET and HEAD requests are served, they have no body
+        http::serve(listener, 0, handle_request).await;
+    })
+}
+
+// What is needed to read torrent data after saver info lock is released
+struct DataSource {
+    save_path: String,
+    torrent: Arc<Torrent>,
+    size_progression: Option<Vec<u64>>,
+    verified: Arc<VerifiedPieces>,
+}
+
+async fn handle_request(req: Request) -> Response {
+    if req.method != "GET" && req.method != "HEAD" {
+        return Response::text(405, "Only GET and HEAD are supported");
+    }
+    let mut parts = req.path.trim_matches('/').split('/');
+    let (Some(info_hash), Some(file_i), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Response::text(404, "Expected /<info hash>/<file index>");
+    };
+    let Ok(file_i) = file_i.parse::<usize>() else {
+        return Response::text(404, "Invalid file index");
+    };
+    let info_hash = info_hash.to_lowercase();
+    let Some(source) = data_source(&info_hash).await else {
+        return Response::text(404, "Torrent isn't running");
+    };
+    let Some((file_start, file_length, name)) = file_span(&source.torrent, file_i) else {
+        return Response::text(404, "No such file in torrent");
+    };
+
+    let (status, range) = match req.header("range") {
+        Some(value) => match parse_range(value, file_length) {
+            Some(range) => (206, range),
+            None => {
+                return Response::text(416, "Invalid range")
+                    .with_header("Content-Range", &format!("bytes */{file_length}"));
+            }
+        },
+        None => (200, 0..file_length),
+    };
+
+    let mut res = Response {
+        status,
+        headers: Vec::new(),
+        body: Body::Bytes(Vec::new()),
+    }
+    .with_header("Accept-Ranges", "bytes")
+    .with_header("Content-Type", content_type(&name));
+    if status == 206 {
+        res = res.with_header(
+            "Content-Range",
+            &format!("bytes {}-{}/{file_length}", range.start, range.end - 1),
+        );
+    }
+    if req.method == "HEAD" || range.is_empty() {
+        res.body = Body::Stream {
+            length: range.end - range.start,
+            chunks: mpsc::channel(1).1,
+        };
+        return res;
+    }
+
+    let file_priorities = priorities::torrent(&source.torrent.info_hash);
+    unskip_file(&file_priorities, file_i);
+    log!(
+        LogLevel::Info,
+        "Streaming bytes {}..{} of file {file_i} of {info_hash}",
+        range.start,
+        range.end
+    );
+    let (sender, chunks) = mpsc::channel(4);
+    let length = range.end - range.start;
+    let data_range = file_start + range.start..file_start + range.end;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sender.closed() => {},
+            res = stream_data(&source, &file_priorities, data_range, &sender) => {
+                if let Err(e) = res {
+                    log!(LogLevel::Error, "Failed to stream torrent data: {e}");
+                    let _ = sender.send(Err(e)).await;
+                }
+            }
+        }
+    });
+    res.body = Body::Stream { length, chunks };
+    res
+}
+
+async fn data_source(info_hash: &str) -> Option<DataSource> {
+    let save_info = saver::SAVE_INFO.get()?.read().await;
+    let save_info = save_info.get(info_hash)?;
+    Some(DataSource {
+        save_path: save_info.save_path.clone(),
+        torrent: save_info.torrent.clone(),
+        size_progression: save_info.size_progression.clone(),
+        verified: save_info.verified.clone(),
+    })
+}
+
+// Offset of file in torrent data, its length and name
+fn file_span(torrent: &Torrent, file_i: usize) -> Option<(u64, u64, String)> {
+    match torrent.info.files {
+        Some(ref files) => {
+            let file = files.get(file_i)?;
+            let start = files.iter().take(file_i).map(|x| x.length).sum();
+            Some((start, file.length, file.path.clone()))
+        }
+        None if file_i == 0 => Some((0, torrent.info.length, torrent.info.name.clone())),
+        None => None,
+    }
+}
+
+// Single range of "bytes=start-end", "bytes=start-" or "bytes=-suffix" form,
+// range that can't be satisfied gives None
+fn parse_range(value: &str, length: u64) -> Option<Range<u64>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    let (start, end) = spec.split(',').next()?.trim().split_once('-')?;
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        length.saturating_sub(suffix)..length
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            length
+        } else {
+            end.parse::<u64>().ok()?.saturating_add(1).min(length)
+        };
+        start..end
+    };
+    if range.start >= length || range.is_empty() {
+        return None;
+    }
+    Some(range)
+}
+
+fn content_type(name: &str) -> &'static str {
+    let ext = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "srt" | "txt" => "text/plain; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+// Skipped file would never be downloaded, so it's switched back to normal
+fn unskip_file(file_priorities: &FilePriorities, file_i: usize) {
+    let mut files = file_priorities.get();
+    if files.get(file_i) == Some(&FilePriority::Skip) {
+        files[file_i] = FilePriority::Normal;
+        file_priorities.set(files);
+    }
+}
+
+// Urgent range of torrent data, removed when stream ends
+struct UrgentRange<'a> {
+    file_priorities: &'a FilePriorities,
+    range: Option<Range<u64>>,
+}
+
+impl UrgentRange<'_> {
+    fn set(&mut self, range: Range<u64>) {
+        if self.range.as_ref() == Some(&range) {
+            return;
+        }
+        if let Some(old) = self.range.take() {
+            self.file_priorities.remove_urgent(&old);
+        }
+        self.file_priorities.add_urgent(range.clone());
+        self.range = Some(range);
+    }
+}
+
+impl Drop for UrgentRange<'_> {
+    fn drop(&mut self) {
+        if let Some(range) = self.range.take() {
+            self.file_priorities.remove_urgent(&range);
+        }
+    }
+}
+
+// Sends range of torrent data piece by piece, each piece is sent only after
+// saver verified it; window ahead of reading position is downloaded first
+async fn stream_data(
+    source: &DataSource,
+    file_priorities: &FilePriorities,
+    range: Range<u64>,
+    sender: &mpsc::Sender<anyhow::Result<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    let piece_length = source.torrent.info.piece_length;
+    let mut urgent = UrgentRange {
+        file_priorities,
+        range: None,
+    };
+    let mut pos = range.start;
+    while pos < range.end {
+        let piece_i = (pos / piece_length) as usize;
+        // window starts at piece boundary, so it changes once per piece
+        let window_start = (piece_i as u64 * piece_length).max(range.start);
+        urgent.set(window_start..(window_start + READAHEAD).min(range.end));
+        source.verified.wait(piece_i).await;
+        let end = range
+            .end
+            .min((piece_i as u64 + 1) * piece_length)
+            .min(pos + READ_BLOCK);
+        let buf = saver::read_data(
+            &source.save_path,
+            &source.torrent,
+            &source.size_progression,
+            pos,
+            end - pos,
+        )?;
+        if sender.send(Ok(buf)).await.is_err() {
+            break;
+        }
+        pos = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+        // open-ended and suffix ranges
+        assert_eq!(parse_range("bytes=900-", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(0..1000));
+        // end is clamped to length
+        assert_eq!(parse_range("bytes=500-5000", 1000), Some(500..1000));
+        assert_eq!(parse_range(&format!("bytes=0-{}", u64::MAX), 1000), Some(0..1000));
+        // only first of several ranges is served
+        assert_eq!(parse_range("bytes=0-9, 20-29", 1000), Some(0..10));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=2000-3000", 1000), None);
+        assert_eq!(parse_range("bytes=50-10", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=10", 1000), None);
+    }
+}
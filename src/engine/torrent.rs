@@ -1,7 +1,8 @@
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
@@ -29,6 +30,10 @@ impl PieceBitmap {
         }
         self.bitmap[cell_i] |= mask;
     }
+    pub fn has(&self, piece_i: usize) -> bool {
+        if piece_i >= self.pieces_n {return false}
+        self.bitmap[piece_i / 8] & (0b1000_0000 >> (piece_i % 8)) != 0
+    }
     pub fn diff(&self, b2: &PieceBitmap) -> Vec<usize> {
         let mut res = Vec::new();
         if self.bitmap.len() != b2.bitmap.len() {
@@ -37,12 +42,13 @@ impl PieceBitmap {
         for i in 0..self.bitmap.len() {
             if self.bitmap[i] != b2.bitmap[i] {
                 let mut bits = self.bitmap[i] ^ b2.bitmap[i];
+                // first piece is the high bit
                 for j in 0..8 {
-                    if i * 8 + j > self.pieces_n {break}
-                    if bits & 0b0000_0001 != 0 {
+                    if i * 8 + j >= self.pieces_n {break}
+                    if bits & 0b1000_0000 != 0 {
                         res.push(i * 8 + j);
                     }
-                    bits >>= 1;
+                    bits <<= 1;
                 }
             }
         }
@@ -51,6 +57,15 @@ impl PieceBitmap {
 }
 
 
+// Single normal path component: not empty, not "." or "..", without separators or root
+pub fn is_plain_name(name: &str) -> bool {
+    !name.contains(['/', '\\', '\0'])
+        && matches!(
+            Path::new(name).components().collect::<Vec<_>>().as_slice(),
+            [Component::Normal(x)] if *x == name
+        )
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TorrentFile {
     pub length: u64,
@@ -60,7 +75,8 @@ pub struct TorrentFile {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Torrent {
     pub tracker_url: String,
-    pub tracker_urls: Option<Vec<String>>,
+    // announce-list tiers (BEP 12), order inside tier is updated while announcing
+    pub tracker_tiers: Vec<Vec<String>>,
     pub info: TorrentInfo,
     pub info_hash: Vec<u8>,
 }
@@ -78,46 +94,138 @@ pub struct TorrentInfo {
 impl Torrent {
     pub fn new(path: &str) -> anyhow::Result<Self> {
         log!(LogLevel::Debug, "Parsing torrent file");
-        let parsed_file = Torrent::parse_torrent_file(path)?;
-        let length = if let BencodeValue::Num(n) = parsed_file["info"]["length"] {
+        Torrent::from_bytes(&Torrent::read_torrent_file(path)?)
+    }
+
+    // Contents of torrent file, e.g. uploaded by control clients
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let parsed_file = BencodeValue::decode_bencoded_value(bytes)?.0;
+        let torrent_info = Torrent::parse_info(&parsed_file["info"])?;
+
+        let mut tracker_tiers = Vec::new();
+        if let BencodeValue::List(ref be_tiers) = parsed_file["announce-list"] {
+            for be_tier in be_tiers {
+                let BencodeValue::List(be_trackers) = be_tier else {
+                    continue;
+                };
+                let tier: Vec<String> = be_trackers
+                    .iter()
+                    .filter_map(|x| match x {
+                        BencodeValue::Bytes(url) if !url.is_empty() => {
+                            Some(String::from_utf8_lossy(url).into_owned())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                if !tier.is_empty() {
+                    log!(LogLevel::Info, "Tracker tier: {:?}", tier);
+                    tracker_tiers.push(tier);
+                }
+            }
+        }
+        let tracker_url = parsed_file["announce"].to_lossy_string();
+        // announce is used only if there is no announce-list
+        let has_announce =
+            matches!(parsed_file["announce"], BencodeValue::Bytes(ref x) if !x.is_empty());
+        if tracker_tiers.is_empty() && has_announce {
+            tracker_tiers.push(vec![tracker_url.clone()]);
+        }
+        Torrent::shuffle_tiers(&mut tracker_tiers);
+
+        Ok(Torrent {
+            tracker_url,
+            tracker_tiers,
+            info: torrent_info,
+            info_hash: Torrent::bencode_hash(&parsed_file["info"])?,
+        })
+    }
+
+    // Building torrent from info dictionary received from peers (magnet links)
+    pub fn from_metadata(info_bytes: &[u8], trackers: Vec<String>) -> anyhow::Result<Self> {
+        let (info, _) = BencodeValue::decode_bencoded_value(info_bytes)?;
+        let torrent_info = Torrent::parse_info(&info)?;
+        let tracker_url = trackers.first().cloned().unwrap_or_default();
+        // all trackers of magnet link are treated as one tier
+        let mut tracker_tiers = if trackers.is_empty() {
+            Vec::new()
+        } else {
+            vec![trackers]
+        };
+        Torrent::shuffle_tiers(&mut tracker_tiers);
+        Ok(Torrent {
+            tracker_url,
+            tracker_tiers,
+            info: torrent_info,
+            info_hash: Torrent::bytes_hash(&info_bytes.to_vec()),
+        })
+    }
+
+    // Trackers inside tier are shuffled once when torrent is added
+    fn shuffle_tiers(tiers: &mut Vec<Vec<String>>) {
+        let mut rng = rand::thread_rng();
+        for tier in tiers {
+            tier.shuffle(&mut rng);
+        }
+    }
+
+    fn parse_info(info: &BencodeValue) -> anyhow::Result<TorrentInfo> {
+        let BencodeValue::Bytes(_) = info["name"] else {
+            anyhow::bail!("Torrent without name");
+        };
+        // name is file or folder created inside download folder
+        let name = info["name"].to_lossy_string();
+        if !is_plain_name(&name) {
+            anyhow::bail!("Invalid torrent name {name:?}");
+        }
+        let length = if let BencodeValue::Num(n) = info["length"] {
             Some(n as u64)
         } else {
             None
         };
         let mut length = length.unwrap_or(0);
-        let files = if let BencodeValue::List(list) = &parsed_file["info"]["files"] {
+        let files = if let BencodeValue::List(list) = &info["files"] {
             let mut res = Vec::new();
             for el in list {
                 let BencodeValue::Dict(dict) = el else {
                     anyhow::bail!("wrong torrent file structure");
                 };
-                let BencodeValue::Num(len) = dict["length"] else {
+                let Some(BencodeValue::Num(len)) = dict.get("length") else {
                     anyhow::bail!("wrong torrent file structure");
                 };
-                let BencodeValue::List(ref path) = dict["path"] else {
+                let Some(BencodeValue::List(path)) = dict.get("path") else {
                     anyhow::bail!("wrong torrent file structure");
                 };
 
+                // pieces come from torrent author or peers, they must not leave torrent folder
+                if path.is_empty() {
+                    anyhow::bail!("Torrent file with empty path");
+                }
                 let mut res_path = PathBuf::new();
                 for subpath in path {
-                    res_path = res_path.join(subpath.to_lossy_string());
+                    let BencodeValue::Bytes(_) = subpath else {
+                        anyhow::bail!("wrong torrent file structure");
+                    };
+                    let piece = subpath.to_lossy_string();
+                    if !is_plain_name(&piece) {
+                        anyhow::bail!("Invalid torrent file path piece {piece:?}");
+                    }
+                    res_path.push(piece);
                 }
                 let file = TorrentFile {
-                    length: len as u64,
-                    path: res_path.to_str().unwrap().to_owned(),
+                    length: *len as u64,
+                    path: res_path.to_string_lossy().into_owned(),
                 };
-                length += len as u64;
+                length += *len as u64;
                 res.push(file)
             }
             Some(res)
         } else {
             None
         };
-        let BencodeValue::Num(piece_length) = parsed_file["info"]["piece length"] else {
+        let BencodeValue::Num(piece_length) = info["piece length"] else {
             anyhow::bail!("Invalid torrent file structure");
         };
-        let BencodeValue::Bytes(ref byte_pieces) = parsed_file["info"]["pieces"] else {
-            println!("nooo");
+        let BencodeValue::Bytes(ref byte_pieces) = info["pieces"] else {
             anyhow::bail!("Invalid torrent file structure");
         };
         log!(LogLevel::Debug, "Parsed successfully");
@@ -126,37 +234,19 @@ impl Torrent {
         for i in 0..n {
             piece_hashes.push(byte_pieces[i * 20..(i + 1) * 20].to_vec());
         }
-        let torrent_info = TorrentInfo {
+        Ok(TorrentInfo {
             length,
             piece_length: piece_length as u64,
             piece_hashes,
-            name: parsed_file["info"]["name"].to_lossy_string(),
+            name,
             files,
-        };
-
-        let mut tracker_urls = None;
-
-        if let BencodeValue::List(ref be_trackers) = parsed_file["announce-list"] {
-            let mut trackers = Vec::new();
-            for tracker in be_trackers {
-                log!(LogLevel::Info, "{:?}", tracker[0].to_lossy_string());
-                trackers.push(tracker[0].to_lossy_string());
-            }
-            tracker_urls = Some(trackers);
-        }
-
-        Ok(Torrent {
-            tracker_url: parsed_file["announce"].to_lossy_string(),
-            tracker_urls,
-            info: torrent_info,
-            info_hash: Torrent::bencode_hash(&parsed_file["info"])?,
         })
     }
-    fn parse_torrent_file(path: &str) -> anyhow::Result<BencodeValue> {
+    fn read_torrent_file(path: &str) -> anyhow::Result<Vec<u8>> {
         let mut torrent_file = File::open(path)?;
         let mut bytes = Vec::new();
         torrent_file.read_to_end(&mut bytes)?;
-        Ok(BencodeValue::decode_bencoded_value(&bytes)?.0)
+        Ok(bytes)
     }
     fn bencode_hash(src: &BencodeValue) -> anyhow::Result<Vec<u8>> {
         let mut hasher = Sha1::new();
@@ -180,3 +270,94 @@ impl Torrent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_bytes(name: &str, files: Option<&[&[&str]]>) -> Vec<u8> {
+        let mut buf = b"d".to_vec();
+        if let Some(files) = files {
+            buf.extend_from_slice(b"5:filesl");
+            for path in files {
+                buf.extend_from_slice(b"d6:lengthi10e4:pathl");
+                for piece in *path {
+                    buf.extend_from_slice(format!("{}:{piece}", piece.len()).as_bytes());
+                }
+                buf.extend_from_slice(b"ee");
+            }
+            buf.extend_from_slice(b"e");
+        } else {
+            buf.extend_from_slice(b"6:lengthi10e");
+        }
+        buf.extend_from_slice(format!("4:name{}:{name}", name.len()).as_bytes());
+        buf.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+        buf.extend_from_slice(&[0; 20]);
+        buf.extend_from_slice(b"e");
+        buf
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        let torrent = Torrent::from_metadata(&info_bytes("movie.mkv", None), Vec::new()).unwrap();
+        assert_eq!(torrent.info.name, "movie.mkv");
+        assert_eq!(torrent.info.length, 10);
+
+        let files: &[&[&str]] = &[&["a", "b.txt"], &["c..d"]];
+        let torrent = Torrent::from_metadata(&info_bytes("folder", Some(files)), Vec::new()).unwrap();
+        let files = torrent.info.files.unwrap();
+        assert_eq!(Path::new(&files[0].path), Path::new("a").join("b.txt"));
+        assert_eq!(files[1].path, "c..d");
+        assert_eq!(torrent.info.length, 20);
+    }
+
+    #[test]
+    fn rejects_unsafe_names() {
+        for name in ["", ".", "..", "/etc", "a/b", "a\\b"] {
+            let res = Torrent::from_metadata(&info_bytes(name, None), Vec::new());
+            assert!(res.is_err(), "name {name:?} is accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_file_paths() {
+        let paths: [&[&str]; 6] = [&[], &[""], &[".."], &["a", "..", "b"], &["/etc", "passwd"], &["a/b"]];
+        for path in paths {
+            let res = Torrent::from_metadata(&info_bytes("folder", Some(&[path])), Vec::new());
+            assert!(res.is_err(), "path {path:?} is accepted");
+        }
+    }
+
+    fn torrent_bytes(trackers: &[u8]) -> Vec<u8> {
+        let mut buf = b"d".to_vec();
+        buf.extend_from_slice(trackers);
+        buf.extend_from_slice(b"4:info");
+        buf.extend_from_slice(&info_bytes("movie.mkv", None));
+        buf.extend_from_slice(b"e");
+        buf
+    }
+
+    #[test]
+    fn announce_list_tiers() {
+        let torrent = Torrent::from_bytes(&torrent_bytes(
+            b"8:announce17:http://a/announce13:announce-listll17:http://b/announcee\
+              l17:http://c/announce0:17:http://d/announceeleli1eee",
+        ))
+        .unwrap();
+        assert_eq!(torrent.tracker_url, "http://a/announce");
+        assert_eq!(torrent.tracker_tiers.len(), 2);
+        assert_eq!(torrent.tracker_tiers[0], vec!["http://b/announce"]);
+        let mut tier = torrent.tracker_tiers[1].clone();
+        tier.sort();
+        assert_eq!(tier, vec!["http://c/announce", "http://d/announce"]);
+    }
+
+    #[test]
+    fn announce_is_fallback_tier() {
+        let torrent =
+            Torrent::from_bytes(&torrent_bytes(b"8:announce17:http://a/announce")).unwrap();
+        assert_eq!(torrent.tracker_tiers, vec![vec!["http://a/announce"]]);
+        let torrent = Torrent::from_bytes(&torrent_bytes(b"")).unwrap();
+        assert!(torrent.tracker_tiers.is_empty());
+    }
+}
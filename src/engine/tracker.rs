@@ -1,20 +1,49 @@
+use std::collections::HashMap;
 use std::fmt::Write;
-use std::net::UdpSocket;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use rand::distributions::{Alphanumeric, DistString};
+use tokio::net::{lookup_host, UdpSocket};
 use tokio::sync::mpsc::Sender;
-use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
 use super::bencode::BencodeValue;
 use super::download::DataPiece;
+use super::listener::LISTEN_PORT;
 use super::logger::{log, LogLevel};
-use super::peers::Peer;
+use super::peers::{self, Peer};
 use super::torrent::Torrent;
 use super::DownloadEvents;
 
+// UDP tracker protocol (BEP 15) magic sent in connect request
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+// Requests are repeated if tracker doesn't answer in time
+const UDP_ATTEMPTS: usize = 3;
+const UDP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Announce event, numbers are used by UDP trackers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    // regular announce
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TrackerReq {
     pub info_hash: Vec<u8>,
@@ -23,13 +52,160 @@ pub struct TrackerReq {
     pub uploaded: u64,
     pub downloaded: u64,
     pub left: u64,
+    pub event: AnnounceEvent,
     compact: u8,
+    // shared between clones so order learned by any announce is kept
+    tiers: Arc<Mutex<Vec<Vec<String>>>>,
+    // "tracker id" each tracker sent, echoed in following announces
+    tracker_ids: Arc<Mutex<HashMap<String, String>>>,
 }
 
 #[derive(Debug)]
 pub struct TrackerResp {
     pub interval: i64,
-    pub peers: Vec<String>,
+    pub min_interval: Option<i64>,
+    pub peers: Vec<SocketAddr>,
+    pub warning: Option<String>,
+    pub tracker_id: Option<String>,
+    // seeders
+    pub complete: Option<u32>,
+    // leechers
+    pub incomplete: Option<u32>,
+}
+
+// Announce result of one tracker, shown in UI
+#[derive(Debug, Clone)]
+pub struct AnnounceInfo {
+    pub peers_n: usize,
+    pub warning: Option<String>,
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+}
+
+impl From<&TrackerResp> for AnnounceInfo {
+    fn from(resp: &TrackerResp) -> Self {
+        AnnounceInfo {
+            peers_n: resp.peers.len(),
+            warning: resp.warning.clone(),
+            complete: resp.complete,
+            incomplete: resp.incomplete,
+        }
+    }
+}
+
+// Swarm statistics from tracker scrape
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrapeInfo {
+    // seeders
+    pub complete: u32,
+    // leechers
+    pub incomplete: u32,
+    // times torrent was downloaded
+    pub downloaded: u32,
+}
+
+// Scrape url is made by replacing "announce" in the last path part with "scrape"
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let slash_i = announce_url.rfind('/')?;
+    let last = &announce_url[slash_i + 1..];
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{}scrape{}", &announce_url[..=slash_i], rest))
+}
+
+// Body of HTTP scrape response for single info hash
+fn parse_scrape(body: &[u8]) -> anyhow::Result<ScrapeInfo> {
+    let response = BencodeValue::decode_bencoded_value(body)?.0;
+    if let BencodeValue::Bytes(ref reason) = response["failure reason"] {
+        anyhow::bail!("{}", String::from_utf8_lossy(reason));
+    }
+    // only our info hash was requested, binary key can't be matched after decoding
+    let BencodeValue::Dict(ref files) = response["files"] else {
+        anyhow::bail!("Invalid scrape response structure");
+    };
+    let Some(stats) = files.values().next() else {
+        anyhow::bail!("Torrent is unknown to tracker");
+    };
+    let num = |key| match stats[key] {
+        BencodeValue::Num(n) => n.max(0) as u32,
+        _ => 0,
+    };
+    Ok(ScrapeInfo {
+        complete: num("complete"),
+        incomplete: num("incomplete"),
+        downloaded: num("downloaded"),
+    })
+}
+
+// Resolving host:port part of udp:// tracker url, socket of same family
+// is connected to it
+async fn udp_tracker_socket(tracker_url: &str) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+    let host = tracker_url.strip_prefix("udp://").unwrap_or(tracker_url);
+    let host = host.split('/').next().unwrap_or(host);
+    let Some(addr) = lookup_host(host).await?.next() else {
+        anyhow::bail!("Failed to resolve tracker {host}");
+    };
+    let socket = if addr.is_ipv6() {
+        UdpSocket::bind("[::]:0").await?
+    } else {
+        UdpSocket::bind("0.0.0.0:0").await?
+    };
+    socket.connect(addr).await?;
+    Ok((socket, addr))
+}
+
+// Non compact peers list: dictionaries with "ip" and "port"
+fn decode_peers_list(list: &[BencodeValue]) -> Vec<SocketAddr> {
+    let mut peers = Vec::new();
+    for peer in list {
+        let BencodeValue::Num(port @ 1..=65535) = peer["port"] else {
+            continue;
+        };
+        if let Ok(ip) = peer["ip"].to_lossy_string().parse::<std::net::IpAddr>() {
+            peers.push(SocketAddr::new(ip, port as u16));
+        }
+    }
+    peers
+}
+
+// Body of HTTP announce response, failure reason is returned as error
+fn parse_announce(body: &[u8]) -> anyhow::Result<TrackerResp> {
+    let response = BencodeValue::decode_bencoded_value(body)?.0;
+    if let BencodeValue::Bytes(ref reason) = response["failure reason"] {
+        anyhow::bail!("{}", String::from_utf8_lossy(reason));
+    }
+    let BencodeValue::Num(interval) = response["interval"] else {
+        anyhow::bail!("Invalid torrent response structure");
+    };
+    let text = |key| match response[key] {
+        BencodeValue::Bytes(ref bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    };
+    let num = |key| match response[key] {
+        BencodeValue::Num(n) => Some(n.max(0) as u32),
+        _ => None,
+    };
+    let min_interval = if let BencodeValue::Num(n) = response["min interval"] {
+        Some(n)
+    } else {
+        None
+    };
+    let mut peers = match response["peers"] {
+        BencodeValue::Bytes(ref peers_bytes) => peers::decode_compact_v4(peers_bytes),
+        BencodeValue::List(ref list) => decode_peers_list(list),
+        _ => Vec::new(),
+    };
+    if let BencodeValue::Bytes(ref peers_bytes) = response["peers6"] {
+        peers.extend(peers::decode_compact_v6(peers_bytes));
+    }
+    Ok(TrackerResp {
+        interval,
+        min_interval,
+        peers,
+        warning: text("warning message"),
+        tracker_id: text("tracker id"),
+        complete: num("complete"),
+        incomplete: num("incomplete"),
+    })
 }
 
 macro_rules! concat_slices {
@@ -44,92 +220,87 @@ macro_rules! concat_slices {
 
 impl TrackerReq {
     pub fn init(torrent: &Torrent, peer_id: String) -> Self {
+        let req = TrackerReq::new(torrent.info_hash.clone(), peer_id, torrent.info.length);
+        *req.tiers.lock().unwrap() = torrent.tracker_tiers.clone();
+        req
+    }
+
+    pub fn new(info_hash: Vec<u8>, peer_id: String, left: u64) -> Self {
         TrackerReq {
-            info_hash: torrent.info_hash.clone(),
+            info_hash,
             peer_id,
-            port: 6681,
+            port: LISTEN_PORT as u32,
             uploaded: 0,
             downloaded: 0,
-            left: torrent.info.length,
+            left,
+            event: AnnounceEvent::None,
             compact: 1,
+            tiers: Arc::new(Mutex::new(Vec::new())),
+            tracker_ids: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub async fn discover_peers(
-        &self,
-        torrent: Arc<Torrent>,
-        event_sender: Sender<DownloadEvents>,
-        data_sender: Sender<DataPiece>,
-    ) -> Vec<JoinHandle<()>> {
-        let mut handles = Vec::new();
-        let max_attempts = 5;
-        if let Some(trackers) = &torrent.tracker_urls {
-            for tracker in trackers {
-                handles.push(self.spawn_tracker_task(
-                    tracker,
-                    max_attempts,
-                    event_sender.clone(),
-                    data_sender.clone(),
-                ));
-            }
-        } else {
-            handles.push(self.spawn_tracker_task(
-                &torrent.tracker_url,
-                max_attempts,
-                event_sender,
-                data_sender,
-            ));
-        }
-        handles
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.tiers.lock().unwrap().clone()
     }
 
-    fn spawn_tracker_task(
+    // Trying tiers in order and trackers of tier one by one (BEP 12),
+    // tracker that answered is moved to the front of its tier.
+    // Result of every tried tracker is passed to report
+    pub async fn announce(
         &self,
-        tracker_url: &String,
-        max_attempts: usize,
-        event_sender: Sender<DownloadEvents>,
-        data_sender: Sender<DataPiece>,
-    ) -> JoinHandle<()> {
-        let req = (*self).clone();
-        let tracker_url = tracker_url.clone();
-        let peer_id = self.peer_id.clone();
-        let info_hash = self.info_hash.clone();
-        tokio::spawn(async move {
-            let mut attempts_n = 0;
-            let mut tracker_resp = None;
-            while attempts_n < max_attempts {
-                match req.clone().clone().send(&tracker_url).await {
+        mut report: impl FnMut(&String, Result<AnnounceInfo, String>),
+    ) -> anyhow::Result<TrackerResp> {
+        let tiers = self.tracker_tiers();
+        for (tier_i, tier) in tiers.iter().enumerate() {
+            for tracker in tier {
+                match self.send(tracker).await {
                     Ok(resp) => {
-                        tracker_resp = Some(resp);
-                        break;
+                        report(tracker, Ok(AnnounceInfo::from(&resp)));
+                        self.promote(tier_i, tracker);
+                        return Ok(resp);
                     }
-                    Err(_) => {
-                        log!(LogLevel::Info, "Failed tracker: {tracker_url}");
-                        attempts_n += 1;
+                    Err(e) => {
+                        log!(LogLevel::Info, "Failed tracker: {tracker} {e}");
+                        report(tracker, Err(e.to_string()));
                     }
                 }
             }
-            log!(LogLevel::Info, "Got response {:?}", tracker_resp);
-            if let Some(resp) = tracker_resp {
-                for peer in resp.peers {
-                    let data_sender = data_sender.clone();
-                    let event_sender = event_sender.clone();
-                    let peer_id = peer_id.clone();
-                    let info_hash = hex::encode(info_hash.clone());
-                    tokio::spawn(async move {
-                        if let Ok(peer) =
-                            Peer::new(&peer, data_sender.clone(), peer_id, info_hash, Duration::from_secs(2)).await
-                        {
-                            log!(LogLevel::Info, "ok peer {:?}", peer.peer_addr);
-                            event_sender
-                                .send(DownloadEvents::PeerAdd(peer, true))
-                                .await
-                                .unwrap();
-                        }
-                    });
-                }
+        }
+        anyhow::bail!("No tracker responded")
+    }
+
+    fn promote(&self, tier_i: usize, tracker: &String) {
+        let mut tiers = self.tiers.lock().unwrap();
+        if let Some(tier) = tiers.get_mut(tier_i) {
+            if let Some(pos) = tier.iter().position(|x| x == tracker) {
+                let tracker = tier.remove(pos);
+                tier.insert(0, tracker);
             }
-        })
+        }
+    }
+
+    // Connecting to peers from tracker response, connected ones are sent to worker
+    pub fn connect_peers(
+        &self,
+        peers: Vec<SocketAddr>,
+        event_sender: Sender<DownloadEvents>,
+        data_sender: Sender<DataPiece>,
+    ) {
+        for peer in peers {
+            let data_sender = data_sender.clone();
+            let event_sender = event_sender.clone();
+            let peer_id = self.peer_id.clone();
+            let info_hash = hex::encode(self.info_hash.clone());
+            tokio::spawn(async move {
+                if let Ok(peer) =
+                    Peer::new(peer, data_sender.clone(), peer_id, info_hash, Duration::from_secs(2)).await
+                {
+                    log!(LogLevel::Info, "ok peer {:?}", peer.peer_addr);
+                    let _ = event_sender.send(DownloadEvents::PeerAdd(peer, true)).await;
+                }
+            });
+        }
     }
 
     pub async fn send(&self, tracker_url: &String) -> anyhow::Result<TrackerResp> {
@@ -137,7 +308,7 @@ impl TrackerReq {
         if tracker_url.starts_with("udp://") {
             return self.send_udp(tracker_url).await;
         }
-        let params = &[
+        let mut params = vec![
             ("peer_id", self.peer_id.clone()),
             ("port", self.port.to_string()),
             ("uploaded", self.uploaded.to_string()),
@@ -145,6 +316,12 @@ impl TrackerReq {
             ("left", self.left.to_string()),
             ("compact", self.compact.to_string()),
         ];
+        if let Some(event) = self.event.as_str() {
+            params.push(("event", event.to_string()));
+        }
+        if let Some(tracker_id) = self.tracker_ids.lock().unwrap().get(tracker_url) {
+            params.push(("trackerid", tracker_id.clone()));
+        }
         log!(LogLevel::Debug, "Sending tracker request");
         let client = reqwest::Client::builder()
             .user_agent("my torrent")
@@ -161,7 +338,7 @@ impl TrackerReq {
                         s
                     })
                 ))
-                .query(params)
+                .query(&params)
                 .send(),
         )
         .await;
@@ -172,158 +349,463 @@ impl TrackerReq {
             }
             Ok(res) => {
                 let body = res?.bytes().await?;
-                let response = BencodeValue::decode_bencoded_value(&body)?.0;
-                let BencodeValue::Bytes(ref peers_bytes) = response["peers"] else {
-                    anyhow::bail!("Invalid torrent response structure");
-                };
-                let BencodeValue::Num(interval) = response["interval"] else {
-                    anyhow::bail!("Invalid torrent response structure");
-                };
-                log!(LogLevel::Debug, "Got valid response");
-                let peers_n = peers_bytes.len() / 6;
-                log!(LogLevel::Debug, "Found {peers_n} peer(s)");
-                let mut peers = Vec::new();
-                for i in 0..peers_n {
-                    peers.push(format!(
-                        "{}.{}.{}.{}:{}",
-                        peers_bytes[i * 6],
-                        peers_bytes[i * 6 + 1],
-                        peers_bytes[i * 6 + 2],
-                        peers_bytes[i * 6 + 3],
-                        u16::from_be_bytes([peers_bytes[i * 6 + 4], peers_bytes[i * 6 + 5]])
-                    ))
+                let resp = parse_announce(&body)?;
+                if let Some(ref warning) = resp.warning {
+                    log!(LogLevel::Info, "Tracker {tracker_url} warning: {warning}");
                 }
-                Ok(TrackerResp { interval, peers })
+                if let Some(ref tracker_id) = resp.tracker_id {
+                    self.tracker_ids
+                        .lock()
+                        .unwrap()
+                        .insert(tracker_url.clone(), tracker_id.clone());
+                }
+                log!(LogLevel::Debug, "Found {} peer(s)", resp.peers.len());
+                Ok(resp)
             }
         }
     }
 
     async fn send_udp(&self, tracker_url: &String) -> anyhow::Result<TrackerResp> {
-        const MAX_RETRIES: usize = 20;
-        let mut timeout_ms: u64 = 100; // Timeout duration in seconds
-        let mut retries = 0;
+        let action_connect: u32 = 0;
+        let action_announce: u32 = 1;
+        let action_error: u32 = 3;
+        let (socket, tracker_addr) = udp_tracker_socket(tracker_url).await?;
 
-        loop {
-            if retries >= MAX_RETRIES {
-                return Err(anyhow::anyhow!("Max retries exceeded"));
+        let mut buf = [0u8; 2048];
+        for _ in 0..UDP_ATTEMPTS {
+            let transaction_id: u32 = rand::random();
+            let connect_packet = concat_slices![
+                &UDP_PROTOCOL_ID.to_be_bytes(),
+                &action_connect.to_be_bytes(),
+                &transaction_id.to_be_bytes()
+            ];
+            socket.send(&connect_packet).await?;
+            let Ok(res) = timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await else {
+                continue;
+            };
+            let bytes_read = res?;
+            if bytes_read < 16
+                || buf[0..4] != action_connect.to_be_bytes()
+                || buf[4..8] != transaction_id.to_be_bytes()
+            {
+                anyhow::bail!("Invalid response received");
             }
+            let connection_id = &buf[8..16].to_vec();
 
-            let connection_id: u64 = 0x41727101980;
-            let action_connect: u32 = 0;
-            let action_announce: u32 = 1;
-            let transaction_id: u32 = rand::random();
+            let announce_packet = concat_slices![
+                connection_id,
+                &action_announce.to_be_bytes(),
+                &transaction_id.to_be_bytes(),
+                &self.info_hash,
+                self.peer_id.as_bytes(), // Peer ID
+                &self.downloaded.to_be_bytes(), // Downloaded
+                &self.left.to_be_bytes(), // Left
+                &self.uploaded.to_be_bytes(), // Uploaded
+                &(self.event as u32).to_be_bytes(), // Event
+                &0i32.to_be_bytes(),    // IP Address
+                &0i32.to_be_bytes(),    // Key
+                &(-1i32).to_be_bytes(), // Num Want
+                &(self.port as u16).to_be_bytes() // Port
+            ];
+            socket.send(&announce_packet).await?;
+            let Ok(res) = timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await else {
+                continue;
+            };
+            let bytes_read = res?;
+            if bytes_read < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+                anyhow::bail!("Invalid announce response received");
+            }
+            if buf[0..4] == action_error.to_be_bytes() {
+                anyhow::bail!("{}", String::from_utf8_lossy(&buf[8..bytes_read]));
+            }
+            if bytes_read < 20 || buf[0..4] != action_announce.to_be_bytes() {
+                anyhow::bail!("Invalid announce response received");
+            }
+            let num = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+            // peers have IPv6 addresses if tracker was reached over IPv6 (BEP 15)
+            let peers = if tracker_addr.is_ipv6() {
+                peers::decode_compact_v6(&buf[20..bytes_read])
+            } else {
+                peers::decode_compact_v4(&buf[20..bytes_read])
+            };
+            return Ok(TrackerResp {
+                interval: num(8) as i64,
+                min_interval: None,
+                peers,
+                warning: None,
+                tracker_id: None,
+                complete: Some(num(16)),
+                incomplete: Some(num(12)),
+            });
+        }
+        anyhow::bail!("Timeout error")
+    }
+
+    pub async fn scrape(&self, tracker_url: &String) -> anyhow::Result<ScrapeInfo> {
+        if tracker_url.starts_with("udp://") {
+            return self.scrape_udp(tracker_url).await;
+        }
+        let Some(url) = scrape_url(tracker_url) else {
+            anyhow::bail!("Tracker doesn't support scrape");
+        };
+        let client = reqwest::Client::builder()
+            .user_agent("my torrent")
+            .build()?;
+        let res = timeout(
+            Duration::from_secs(5),
+            client
+                .get(format!(
+                    "{}{}info_hash={}",
+                    url,
+                    if url.contains('?') { '&' } else { '?' },
+                    self.info_hash.iter().fold(String::new(), |mut s, b| {
+                        write!(s, "%{:02x}", b).unwrap();
+                        s
+                    })
+                ))
+                .send(),
+        )
+        .await;
+        let Ok(res) = res else {
+            anyhow::bail!("Timeout error");
+        };
+        let body = res?.bytes().await?;
+        parse_scrape(&body)
+    }
 
-            // Create UDP socket
-            let socket = UdpSocket::bind("0.0.0.0:0")?;
+    async fn scrape_udp(&self, tracker_url: &String) -> anyhow::Result<ScrapeInfo> {
+        let action_connect: u32 = 0;
+        let action_scrape: u32 = 2;
+        let (socket, _) = udp_tracker_socket(tracker_url).await?;
 
-            // Connect to tracker
-            socket.connect(tracker_url.strip_prefix("udp://").unwrap())?;
+        let mut buf = [0u8; 1024];
+        for _ in 0..UDP_ATTEMPTS {
+            let transaction_id: u32 = rand::random();
             let connect_packet = concat_slices![
-                &connection_id.to_be_bytes(),
+                &UDP_PROTOCOL_ID.to_be_bytes(),
                 &action_connect.to_be_bytes(),
                 &transaction_id.to_be_bytes()
             ];
+            socket.send(&connect_packet).await?;
+            let Ok(res) = timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await else {
+                continue;
+            };
+            let bytes_read = res?;
+            if bytes_read < 16
+                || buf[0..4] != action_connect.to_be_bytes()
+                || buf[4..8] != transaction_id.to_be_bytes()
+            {
+                anyhow::bail!("Invalid response received");
+            }
+            let connection_id = &buf[8..16].to_vec();
+
+            let scrape_packet = concat_slices![
+                connection_id,
+                &action_scrape.to_be_bytes(),
+                &transaction_id.to_be_bytes(),
+                &self.info_hash
+            ];
+            socket.send(&scrape_packet).await?;
+            let Ok(res) = timeout(UDP_TIMEOUT, socket.recv(&mut buf)).await else {
+                continue;
+            };
+            let bytes_read = res?;
+            if bytes_read < 20
+                || buf[0..4] != action_scrape.to_be_bytes()
+                || buf[4..8] != transaction_id.to_be_bytes()
+            {
+                anyhow::bail!("Invalid scrape response received");
+            }
+            let num = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+            return Ok(ScrapeInfo {
+                complete: num(8),
+                downloaded: num(12),
+                incomplete: num(16),
+            });
+        }
+        anyhow::bail!("Timeout error")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::net::TcpListener;
+
+    use super::super::http::{self, Response};
+    use super::*;
+
+    fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::from(ip), port))
+    }
+
+    #[test]
+    fn failure_reason_is_error() {
+        let err = parse_announce(b"d14:failure reason12:unknown hashe").unwrap_err();
+        assert_eq!(err.to_string(), "unknown hash");
+        assert!(parse_announce(b"d5:peers0:e").is_err());
+    }
+
+    #[test]
+    fn compact_response() {
+        let resp = parse_announce(
+            b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e\
+              5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50\
+              10:tracker id3:abc15:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(resp.min_interval, Some(60));
+        assert_eq!(resp.complete, Some(5));
+        assert_eq!(resp.incomplete, Some(3));
+        assert_eq!(resp.warning.as_deref(), Some("slow"));
+        assert_eq!(resp.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(resp.peers, vec![addr([127, 0, 0, 1], 6881), addr([10, 0, 0, 2], 80)]);
+    }
+
+    #[test]
+    fn minimal_response() {
+        let resp = parse_announce(b"d8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(resp.interval, 900);
+        assert_eq!(resp.min_interval, None);
+        assert_eq!(resp.complete, None);
+        assert_eq!(resp.incomplete, None);
+        assert_eq!(resp.warning, None);
+        assert_eq!(resp.tracker_id, None);
+        assert!(resp.peers.is_empty());
+    }
+
+    #[test]
+    fn non_compact_peers() {
+        // peers with bad address or port are skipped
+        let resp = parse_announce(
+            b"d8:intervali900e5:peersl\
+              d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+              d2:ip3:::14:porti80ee\
+              d2:ip3:bad4:porti1ee\
+              d2:ip8:10.0.0.24:porti0ee\
+              d2:ip8:10.0.0.34:porti70000ee\
+              ee",
+        )
+        .unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                addr([10, 0, 0, 1], 6881),
+                SocketAddr::from((Ipv6Addr::LOCALHOST, 80)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ipv6_peers() {
+        let resp = parse_announce(
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1\
+              6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                addr([127, 0, 0, 1], 6881),
+                SocketAddr::from((Ipv6Addr::LOCALHOST, 6881)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tracker_id_is_echoed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let received = queries.clone();
+        tokio::spawn(http::serve(listener, 0, move |req| {
+            let received = received.clone();
+            async move {
+                received.lock().unwrap().push(req.query);
+                Response::new(200, b"d8:intervali60e5:peers0:10:tracker id3:xyze".to_vec())
+            }
+        }));
+
+        let req = TrackerReq::new(vec![1; 20], "-RT0001-000000000000".to_string(), 10);
+        req.send(&url).await.unwrap();
+        req.send(&url).await.unwrap();
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries[0].get("trackerid"), None);
+        assert_eq!(queries[1]["trackerid"], "xyz");
+        assert_eq!(queries[1]["left"], "10");
+    }
+
+    #[tokio::test]
+    async fn udp_announce() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", tracker.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (n, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 16);
+            assert_eq!(buf[0..8], UDP_PROTOCOL_ID.to_be_bytes());
+            let tid = buf[12..16].to_vec();
+            let connect = concat_slices![&0u32.to_be_bytes(), &tid, &7u64.to_be_bytes()];
+            tracker.send_to(&connect, from).await.unwrap();
+
+            let (n, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 98);
+            assert_eq!(buf[0..8], 7u64.to_be_bytes());
+            assert_eq!(buf[8..12], 1u32.to_be_bytes());
+            assert_eq!(buf[16..36], [1; 20]);
+            let packet = buf[..n].to_vec();
+            let reply = concat_slices![
+                &1u32.to_be_bytes(),
+                &packet[12..16],
+                &900u32.to_be_bytes(),
+                &4u32.to_be_bytes(),
+                &9u32.to_be_bytes(),
+                b"\x7f\x00\x00\x01\x1a\xe1"
+            ];
+            tracker.send_to(&reply, from).await.unwrap();
+            packet
+        });
 
-            socket.send(&connect_packet)?;
+        let mut req = TrackerReq::new(vec![1; 20], "-RT0001-000000000000".to_string(), 10);
+        req.downloaded = 5;
+        req.uploaded = 3;
+        req.event = AnnounceEvent::Started;
+        let resp = req.send(&url).await.unwrap();
+        assert_eq!(resp.interval, 900);
+        assert_eq!(resp.incomplete, Some(4));
+        assert_eq!(resp.complete, Some(9));
+        assert_eq!(resp.peers, vec![addr([127, 0, 0, 1], 6881)]);
 
-            // Set read timeout
-            socket.set_read_timeout(Some(Duration::from_millis(timeout_ms)))?;
+        let packet = server.await.unwrap();
+        assert_eq!(packet[56..64], 5u64.to_be_bytes());
+        assert_eq!(packet[64..72], 10u64.to_be_bytes());
+        assert_eq!(packet[72..80], 3u64.to_be_bytes());
+        assert_eq!(packet[80..84], 2u32.to_be_bytes());
+    }
 
-            // Receive response
+    #[tokio::test]
+    async fn udp_tracker_error() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
             let mut buf = [0u8; 1024];
-            match socket.recv_from(&mut buf) {
-                Ok((bytes_read, _)) if bytes_read < 16 => {
-                    anyhow::bail!("Invalid response received");
-                }
-                Ok((bytes_read, _)) => {
-                    let received_transaction_id =
-                        u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                    let received_action = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-                    if received_transaction_id != transaction_id
-                        || received_action != action_connect
-                    {
-                        anyhow::bail!("Invalid response received");
-                    }
-                    let received_connection_id = u64::from_be_bytes([
-                        buf[8], buf[9], buf[10], buf[11], buf[12], buf[13], buf[14], buf[15],
-                    ]);
-
-                    log!(
-                        LogLevel::Info,
-                        "{} {:?}",
-                        received_connection_id,
-                        &self.info_hash
-                    );
-
-                    // Announce
-                    let announce_packet = concat_slices![
-                        &received_connection_id.to_be_bytes(),
-                        &action_announce.to_be_bytes(),
-                        &transaction_id.to_be_bytes(),
-                        &self.info_hash,
-                        &vec![0u8; 20],         // Peer ID
-                        &0i64.to_be_bytes(),    // Downloaded
-                        &0i64.to_be_bytes(),    // Left
-                        &0i64.to_be_bytes(),    // Uploaded
-                        &0i32.to_be_bytes(),    // Event
-                        &0i32.to_be_bytes(),    // IP Address
-                        &0i32.to_be_bytes(),    // Key
-                        &(-1i32).to_be_bytes(), // Num Want
-                        &6881u16.to_be_bytes()  // Port
-                    ];
-                    socket.send(&announce_packet)?;
-
-                    // Receive announce response
-                    match socket.recv_from(&mut buf) {
-                        Ok((bytes_read, _)) if bytes_read < 20 => {
-                            anyhow::bail!("Invalid announce response received");
-                        }
-                        Ok((bytes_read, _)) => {
-                            let received_transaction_id =
-                                u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
-                            let received_action =
-                                u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
-                            if received_transaction_id != transaction_id
-                                || received_action != action_announce
-                            {
-                                anyhow::bail!("Invalid announce response received");
-                            }
-                            let interval = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
-                            let mut peers = Vec::new();
-                            let mut offset = 20;
-                            while offset + 6 < bytes_read {
-                                let ip_bytes: [u8; 4] = [
-                                    buf[offset],
-                                    buf[offset + 1],
-                                    buf[offset + 2],
-                                    buf[offset + 3],
-                                ];
-                                let port = u16::from_be_bytes([buf[offset + 4], buf[offset + 5]]);
-                                let peer_addr = format!(
-                                    "{}.{}.{}.{}:{}",
-                                    ip_bytes[0], ip_bytes[1], ip_bytes[2], ip_bytes[3], port
-                                );
-                                peers.push(peer_addr);
-                                offset += 6;
-                            }
-                            return Ok(TrackerResp {
-                                interval: interval as i64,
-                                peers,
-                            });
-                        }
-                        Err(_) => {
-                            timeout_ms += 100;
-                            retries += 1;
-                            continue; // Retry
-                        }
-                    }
-                }
-                Err(_) => {
-                    timeout_ms += 100;
-                    retries += 1;
-                    continue; // Retry
-                }
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let connect = concat_slices![&0u32.to_be_bytes(), &buf[12..16], &7u64.to_be_bytes()];
+            tracker.send_to(&connect, from).await.unwrap();
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let reply = concat_slices![&3u32.to_be_bytes(), &buf[12..16], b"banned"];
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        let req = TrackerReq::new(vec![1; 20], "-RT0001-000000000000".to_string(), 10);
+        let err = req.send(&url).await.unwrap_err();
+        assert_eq!(err.to_string(), "banned");
+    }
+
+    #[test]
+    fn scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?key=1").as_deref(),
+            Some("http://example.com/x/scrape.php?key=1")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/x/announce/y"), None);
+    }
+
+    #[test]
+    fn scrape_response() {
+        let info = parse_scrape(
+            b"d5:filesd20:\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\
+              d8:completei5e10:downloadedi50e10:incompletei10eeee",
+        )
+        .unwrap();
+        let expected = ScrapeInfo {
+            complete: 5,
+            incomplete: 10,
+            downloaded: 50,
+        };
+        assert_eq!(info, expected);
+    }
+
+    #[test]
+    fn bad_scrape_responses() {
+        let err = parse_scrape(b"d14:failure reason7:privatee").unwrap_err();
+        assert_eq!(err.to_string(), "private");
+        let err = parse_scrape(b"d5:filesdee").unwrap_err();
+        assert_eq!(err.to_string(), "Torrent is unknown to tracker");
+        assert!(parse_scrape(b"d5:filesi1ee").is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_scrape() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let connect = concat_slices![&0u32.to_be_bytes(), &buf[12..16], &7u64.to_be_bytes()];
+            tracker.send_to(&connect, from).await.unwrap();
+            let (n, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 36);
+            assert_eq!(buf[8..12], 2u32.to_be_bytes());
+            assert_eq!(buf[16..36], [1; 20]);
+            let reply = concat_slices![
+                &2u32.to_be_bytes(),
+                &buf[12..16],
+                &5u32.to_be_bytes(),
+                &50u32.to_be_bytes(),
+                &10u32.to_be_bytes()
+            ];
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        let req = TrackerReq::new(vec![1; 20], "-RT0001-000000000000".to_string(), 10);
+        let info = req.scrape(&url).await.unwrap();
+        let expected = ScrapeInfo {
+            complete: 5,
+            incomplete: 10,
+            downloaded: 50,
+        };
+        assert_eq!(info, expected);
+    }
+
+    #[tokio::test]
+    async fn answered_tracker_is_promoted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(http::serve(listener, 0, |req| async move {
+            if req.path == "/announce" {
+                Response::new(200, b"d8:intervali60e5:peers0:e".to_vec())
+            } else {
+                Response::new(200, b"d14:failure reason4:gonee".to_vec())
             }
-        }
+        }));
+        let failing = good.replace("/announce", "/old/announce");
+        let dead = "udp://[::1]:0".to_string();
+
+        let req = TrackerReq::new(vec![1; 20], "-RT0001-000000000000".to_string(), 10);
+        *req.tiers.lock().unwrap() = vec![
+            vec![dead.clone()],
+            vec![failing.clone(), good.clone()],
+        ];
+        let mut reports = Vec::new();
+        req.announce(|url, res| reports.push((url.clone(), res.is_ok())))
+            .await
+            .unwrap();
+        let expected = vec![
+            (dead.clone(), false),
+            (failing.clone(), false),
+            (good.clone(), true),
+        ];
+        assert_eq!(reports, expected);
+        // clones share learned order
+        let clone = req.clone();
+        assert_eq!(clone.tracker_tiers(), vec![vec![dead], vec![good, failing]]);
     }
-}
\ No newline at end of file
+}
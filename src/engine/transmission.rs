@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use super::api;
+use super::http::{self, base64_decode, Request, Response};
+use super::listener::LISTEN_PORT;
+use super::logger::{log, LogLevel};
+use super::priorities::FilePriority;
+use super::session::{DownloadStatus, SessionHandle, TorrentDetails, TorrentStats};
+use super::settings::{self, Settings};
+use super::torrent::Torrent;
+
+// Transmission RPC for existing tools, at http://127.0.0.1:RPC_PORT/transmission/rpc
+// with basic auth, password is API token and user name is ignored
+pub const RPC_PORT: u16 = 9091;
+const RPC_PATH: &str = "/transmission/rpc";
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+// torrent-add can carry base64 metainfo, nothing else is big
+const MAX_REQUEST_BODY: usize = 8 * 1024 * 1024;
+
+// Torrent status codes of transmission
+const STATUS_STOPPED: u8 = 0;
+const STATUS_CHECK: u8 = 2;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED: u8 = 6;
+
+struct RpcState {
+    session: SessionHandle,
+    // CSRF protection, client has to repeat it from 409 reply
+    session_id: String,
+    // transmission clients address torrents by numbers, they stay same until restart
+    // and aren't reused after removal
+    ids: Mutex<HashMap<String, i64>>,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+pub fn spawn_rpc_server(session: SessionHandle) -> JoinHandle<()> {
+    let state = Arc::new(RpcState {
+        session,
+        session_id: api::new_token(),
+        ids: Mutex::new(HashMap::new()),
+    });
+    tokio::spawn(async move {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, RPC_PORT));
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to bind transmission RPC to {addr}: {e}");
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Serving transmission RPC on http://{addr}{RPC_PATH}");
+        http::serve(listener, MAX_REQUEST_BODY, move |req| {
+            let state = state.clone();
+            async move { handle_request(req, &state).await }
+        })
+        .await;
+    })
+}
+
+async fn handle_request(req: Request, state: &RpcState) -> Response {
+    if req.path != RPC_PATH {
+        return Response::text(404, "Not found");
+    }
+    if !authorized(&req) {
+        return Response::text(401, "Unauthorized")
+            .with_header("WWW-Authenticate", "Basic realm=\"Transmission\"");
+    }
+    if req.header("x-transmission-session-id") != Some(state.session_id.as_str()) {
+        return Response::text(409, "Invalid session id")
+            .with_header(SESSION_ID_HEADER, &state.session_id);
+    }
+    if req.method != "POST" {
+        return Response::text(405, "Only POST is supported");
+    }
+    let rpc: RpcRequest = match serde_json::from_slice(&req.body) {
+        Ok(rpc) => rpc,
+        Err(e) => return Response::text(400, &format!("Invalid request: {e}")),
+    };
+    log!(LogLevel::Debug, "Transmission RPC: {}", rpc.method);
+    let (result, arguments) = match state.call(&rpc.method, &rpc.arguments).await {
+        Ok(arguments) => ("success".to_string(), arguments),
+        Err(e) => (e.to_string(), json!({})),
+    };
+    let mut reply = json!({ "result": result, "arguments": arguments });
+    if let Some(tag) = rpc.tag {
+        reply["tag"] = tag;
+    }
+    Response::json(200, &reply).with_header(SESSION_ID_HEADER, &state.session_id)
+}
+
+fn authorized(req: &Request) -> bool {
+    let Some(encoded) = req
+        .header("authorization")
+        .and_then(|x| x.strip_prefix("Basic "))
+    else {
+        return false;
+    };
+    let Ok(credentials) = base64_decode(encoded).map(|x| String::from_utf8_lossy(&x).into_owned())
+    else {
+        return false;
+    };
+    credentials
+        .split_once(':')
+        .is_some_and(|(_, password)| api::token_matches(password))
+}
+
+impl RpcState {
+    async fn call(&self, method: &str, args: &Value) -> anyhow::Result<Value> {
+        match method {
+            "session-get" => Ok(session_get(&self.session_id)),
+            "session-stats" => self.session_stats().await,
+            "torrent-get" => self.torrent_get(args).await,
+            "torrent-add" => self.torrent_add(args).await,
+            "torrent-start" | "torrent-start-now" => {
+                for stats in self.select(args).await? {
+                    if let DownloadStatus::Paused | DownloadStatus::Error(_) = stats.status {
+                        self.session.resume(stats.hash).await?;
+                    }
+                }
+                Ok(json!({}))
+            }
+            "torrent-stop" => {
+                for stats in self.select(args).await? {
+                    self.session.pause(stats.hash).await?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-verify" => {
+                for stats in self.select(args).await? {
+                    self.session.verify(stats.hash).await?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-remove" => {
+                let delete_data = args["delete-local-data"].as_bool().unwrap_or(false);
+                for stats in self.select(args).await? {
+                    self.session.remove(stats.hash, delete_data).await?;
+                }
+                Ok(json!({}))
+            }
+            _ => anyhow::bail!("method name not recognized"),
+        }
+    }
+
+    fn id(&self, hash: &str) -> i64 {
+        let mut ids = self.ids.lock().unwrap();
+        let next = ids.values().max().copied().unwrap_or(0) + 1;
+        *ids.entry(hash.to_string()).or_insert(next)
+    }
+
+    async fn select(&self, args: &Value) -> anyhow::Result<Vec<TorrentStats>> {
+        let list = self.session.list().await?;
+        Ok(self.filter_ids(list, args))
+    }
+
+    // Torrents picked by "ids" argument: number, hash, list of them,
+    // "recently-active" or nothing for all torrents
+    fn filter_ids(&self, list: Vec<TorrentStats>, args: &Value) -> Vec<TorrentStats> {
+        let ids = match args.get("ids") {
+            None => return list,
+            Some(Value::String(x)) if x == "recently-active" => {
+                return list.into_iter().filter(is_active).collect();
+            }
+            Some(Value::Array(ids)) => ids.clone(),
+            Some(id) => vec![id.clone()],
+        };
+        list.into_iter()
+            .filter(|stats| {
+                let id = self.id(&stats.hash);
+                ids.iter().any(|x| match x {
+                    Value::Number(n) => n.as_i64() == Some(id),
+                    Value::String(hash) => hash.eq_ignore_ascii_case(&stats.hash),
+                    _ => false,
+                })
+            })
+            .collect()
+    }
+
+    async fn torrent_get(&self, args: &Value) -> anyhow::Result<Value> {
+        let fields: Vec<&str> = args["fields"]
+            .as_array()
+            .map(|x| x.iter().filter_map(|x| x.as_str()).collect())
+            .unwrap_or_default();
+        // files and trackers are only in details, fetched if requested
+        let needs_details = fields
+            .iter()
+            .any(|x| matches!(*x, "files" | "fileStats" | "priorities" | "wanted" | "trackers"));
+        let mut torrents = Vec::new();
+        for stats in self.select(args).await? {
+            let details = if needs_details {
+                Some(self.session.info(stats.hash.clone()).await?)
+            } else {
+                None
+            };
+            let id = self.id(&stats.hash);
+            let mut torrent = serde_json::Map::new();
+            for field in &fields {
+                if let Some(value) = torrent_field(field, id, &stats, details.as_ref()) {
+                    torrent.insert(field.to_string(), value);
+                }
+            }
+            torrents.push(Value::Object(torrent));
+        }
+        Ok(json!({ "torrents": torrents }))
+    }
+
+    // Torrent from "filename" (path, magnet or url) or base64 "metainfo"
+    async fn torrent_add(&self, args: &Value) -> anyhow::Result<Value> {
+        let dest = match args["download-dir"].as_str() {
+            Some(dir) => dir.to_string(),
+            None => settings::default_download_dir(),
+        };
+        let torrent = match (args["metainfo"].as_str(), args["filename"].as_str()) {
+            (Some(metainfo), _) => Torrent::from_bytes(&base64_decode(metainfo)?)?,
+            (None, Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {
+                let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+                Torrent::from_bytes(&bytes)?
+            }
+            (None, Some(filename)) if filename.starts_with("magnet:") => {
+                super::parse_magnet(filename, self.session.peer_id.clone()).await?
+            }
+            (None, Some(filename)) => super::parse_torrent(filename)?,
+            (None, None) => anyhow::bail!("no filename or metainfo specified"),
+        };
+        let hash = hex::encode(&torrent.info_hash);
+        let name = torrent.info.name.clone();
+        let duplicate = self.session.list().await?.iter().any(|x| x.hash == hash);
+        if !duplicate {
+            self.session.add(torrent, dest).await?;
+            if args["paused"].as_bool() == Some(true) {
+                self.session.pause(hash.clone()).await?;
+            }
+        }
+        let added = json!({ "id": self.id(&hash), "name": name, "hashString": hash });
+        Ok(if duplicate {
+            json!({ "torrent-duplicate": added })
+        } else {
+            json!({ "torrent-added": added })
+        })
+    }
+
+    async fn session_stats(&self) -> anyhow::Result<Value> {
+        let list = self.session.list().await?;
+        let active = list.iter().filter(|x| is_active(x)).count();
+        let uploaded: u64 = list.iter().map(|x| x.uploaded).sum();
+        let downloaded: u64 = list.iter().map(downloaded).sum();
+        let totals = json!({
+            "uploadedBytes": uploaded,
+            "downloadedBytes": downloaded,
+            "filesAdded": list.len(),
+            "sessionCount": 1,
+            "secondsActive": 0,
+        });
+        Ok(json!({
+            "activeTorrentCount": active,
+            "pausedTorrentCount": list.len() - active,
+            "torrentCount": list.len(),
+            "downloadSpeed": list.iter().map(|x| x.download_speed).sum::<u64>(),
+            "uploadSpeed": list.iter().map(|x| x.upload_speed).sum::<u64>(),
+            "cumulative-stats": totals,
+            "current-stats": totals,
+        }))
+    }
+}
+
+fn session_get(session_id: &str) -> Value {
+    let settings = Settings::get();
+    json!({
+        "version": "3.00 (compatible)",
+        "rpc-version": 17,
+        "rpc-version-minimum": 14,
+        "session-id": session_id,
+        "download-dir": settings::default_download_dir(),
+        "peer-port": LISTEN_PORT,
+        "speed-limit-down": settings.limits.download.unwrap_or(0),
+        "speed-limit-down-enabled": settings.limits.download.is_some(),
+        "speed-limit-up": settings.limits.upload.unwrap_or(0),
+        "speed-limit-up-enabled": settings.limits.upload.is_some(),
+        "alt-speed-down": settings.alt_limits.download.unwrap_or(0),
+        "alt-speed-up": settings.alt_limits.upload.unwrap_or(0),
+        "alt-speed-enabled": settings.alt_enabled,
+        "seedRatioLimit": settings.seed_ratio.unwrap_or(2.0),
+        "seedRatioLimited": settings.seed_ratio.is_some(),
+        "idle-seeding-limit": settings.seed_time.unwrap_or(30),
+        "idle-seeding-limit-enabled": settings.seed_time.is_some(),
+    })
+}
+
+fn is_active(stats: &TorrentStats) -> bool {
+    matches!(
+        stats.status,
+        DownloadStatus::Downloading | DownloadStatus::Seeding | DownloadStatus::Resuming
+    )
+}
+
+fn status_code(status: &DownloadStatus) -> u8 {
+    match status {
+        DownloadStatus::Resuming => STATUS_CHECK,
+        DownloadStatus::Downloading => STATUS_DOWNLOAD,
+        DownloadStatus::Seeding => STATUS_SEED,
+        DownloadStatus::Paused | DownloadStatus::Finished | DownloadStatus::Error(_) => STATUS_STOPPED,
+    }
+}
+
+fn percent_done(stats: &TorrentStats) -> f64 {
+    if stats.pieces_total == 0 {
+        return 0.0;
+    }
+    stats.pieces_done as f64 / stats.pieces_total as f64
+}
+
+// Pieces have same length except last one, so bytes are estimated from piece count
+fn downloaded(stats: &TorrentStats) -> u64 {
+    (stats.size as f64 * percent_done(stats)) as u64
+}
+
+fn torrent_field(field: &str, id: i64, stats: &TorrentStats, details: Option<&TorrentDetails>) -> Option<Value> {
+    let left = stats.size - downloaded(stats).min(stats.size);
+    let done = percent_done(stats);
+    let error = match stats.status {
+        DownloadStatus::Error(ref e) => e.clone(),
+        _ => String::new(),
+    };
+    Some(match field {
+        "id" => json!(id),
+        "name" => json!(stats.name),
+        "hashString" => json!(stats.hash),
+        "status" => json!(status_code(&stats.status)),
+        "totalSize" | "sizeWhenDone" => json!(stats.size),
+        "leftUntilDone" => json!(left),
+        "haveValid" | "downloadedEver" => json!(downloaded(stats)),
+        "percentDone" => json!(done),
+        "metadataPercentComplete" => json!(1.0),
+        "recheckProgress" => json!(0.0),
+        "isFinished" => json!(matches!(stats.status, DownloadStatus::Finished)),
+        "rateDownload" => json!(stats.download_speed),
+        "rateUpload" => json!(stats.upload_speed),
+        "uploadedEver" => json!(stats.total_uploaded),
+        "uploadRatio" => json!(stats.total_uploaded as f64 / stats.size.max(1) as f64),
+        "peersConnected" | "peersSendingToUs" => json!(stats.peers),
+        "peersGettingFromUs" => json!(stats.uploading_peers),
+        "eta" => match stats.download_speed {
+            0 => json!(-1),
+            speed => json!(left / speed),
+        },
+        // downloadDir is folder containing torrent data
+        "downloadDir" => json!(Path::new(&stats.save_dir)
+            .parent()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default()),
+        "error" => json!(if error.is_empty() { 0 } else { 3 }),
+        "errorString" => json!(error),
+        "queuePosition" => json!(id - 1),
+        "addedDate" | "doneDate" | "activityDate" => json!(0),
+        "files" => {
+            let files = &details?.files;
+            json!(files
+                .iter()
+                .map(|x| json!({
+                    "name": x.path,
+                    "length": x.length,
+                    "bytesCompleted": (x.length as f64 * done) as u64,
+                }))
+                .collect::<Vec<_>>())
+        }
+        "fileStats" => {
+            let files = &details?.files;
+            json!(files
+                .iter()
+                .map(|x| json!({
+                    "bytesCompleted": (x.length as f64 * done) as u64,
+                    "wanted": x.priority != FilePriority::Skip,
+                    "priority": priority_code(x.priority),
+                }))
+                .collect::<Vec<_>>())
+        }
+        "wanted" => json!(details?
+            .files
+            .iter()
+            .map(|x| x.priority != FilePriority::Skip)
+            .collect::<Vec<_>>()),
+        "priorities" => json!(details?
+            .files
+            .iter()
+            .map(|x| priority_code(x.priority))
+            .collect::<Vec<_>>()),
+        "trackers" => json!(details?
+            .trackers
+            .iter()
+            .enumerate()
+            .map(|(i, x)| json!({ "id": i, "announce": x.url, "tier": 0 }))
+            .collect::<Vec<_>>()),
+        _ => return None,
+    })
+}
+
+// Transmission priorities are -1, 0 and 1
+fn priority_code(priority: FilePriority) -> i8 {
+    match priority {
+        FilePriority::High => 1,
+        FilePriority::Low => -1,
+        FilePriority::Normal | FilePriority::Skip => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::http::Body;
+    use super::super::session::Session;
+    use super::super::settings::tests as test_settings;
+    use super::*;
+
+    fn state() -> RpcState {
+        RpcState {
+            session: Session::default().handle(),
+            session_id: "abc".to_string(),
+            ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn stats(hash: &str, status: DownloadStatus) -> TorrentStats {
+        TorrentStats {
+            hash: hash.to_string(),
+            name: format!("torrent {hash}"),
+            status,
+            size: 1000,
+            pieces_done: 1,
+            pieces_total: 4,
+            download_speed: 0,
+            upload_speed: 0,
+            uploaded: 0,
+            total_uploaded: 0,
+            peers: 0,
+            uploading_peers: 0,
+            save_dir: "/data/torrent".to_string(),
+        }
+    }
+
+    fn rpc_request(headers: &[(&str, &str)], body: &str) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: RPC_PATH.to_string(),
+            query: HashMap::new(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.as_bytes().to_vec(),
+            peer_addr: "127.0.0.1:50000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(status_code(&DownloadStatus::Resuming), STATUS_CHECK);
+        assert_eq!(status_code(&DownloadStatus::Downloading), STATUS_DOWNLOAD);
+        assert_eq!(status_code(&DownloadStatus::Seeding), STATUS_SEED);
+        for status in [
+            DownloadStatus::Paused,
+            DownloadStatus::Finished,
+            DownloadStatus::Error("disk full".to_string()),
+        ] {
+            assert_eq!(status_code(&status), STATUS_STOPPED);
+        }
+    }
+
+    #[tokio::test]
+    async fn ids_select_torrents() {
+        let state = state();
+        let list = vec![
+            stats("aa", DownloadStatus::Downloading),
+            stats("bb", DownloadStatus::Paused),
+            stats("cc", DownloadStatus::Seeding),
+        ];
+        let hashes = |args: Value| -> Vec<String> {
+            let selected = state.filter_ids(list.clone(), &args);
+            selected.into_iter().map(|x| x.hash).collect()
+        };
+        assert_eq!(hashes(json!({})), vec!["aa", "bb", "cc"]);
+        // numbers are given in order torrents are seen
+        assert_eq!(hashes(json!({ "ids": 2 })), vec!["bb"]);
+        assert_eq!(hashes(json!({ "ids": "CC" })), vec!["cc"]);
+        assert_eq!(hashes(json!({ "ids": [1, "cc", true] })), vec!["aa", "cc"]);
+        let active = hashes(json!({ "ids": "recently-active" }));
+        assert_eq!(active, vec!["aa", "cc"]);
+        assert!(hashes(json!({ "ids": 4 })).is_empty());
+    }
+
+    #[test]
+    fn upload_fields() {
+        let mut stats = stats("aa", DownloadStatus::Seeding);
+        stats.upload_speed = 2048;
+        stats.uploading_peers = 3;
+        stats.peers = 5;
+        let field = |name| torrent_field(name, 1, &stats, None);
+        assert_eq!(field("rateUpload"), Some(json!(2048)));
+        assert_eq!(field("peersGettingFromUs"), Some(json!(3)));
+        assert_eq!(field("peersConnected"), Some(json!(5)));
+        assert_eq!(field("status"), Some(json!(STATUS_SEED)));
+        assert_eq!(field("downloadDir"), Some(json!("/data")));
+        assert_eq!(field("files"), None);
+    }
+
+    #[tokio::test]
+    async fn session_id_handshake() {
+        test_settings::init();
+        let state = state();
+        // "user:test-token"
+        let auth = "Basic dXNlcjp0ZXN0LXRva2Vu";
+        let body = r#"{"method": "session-get", "tag": 7}"#;
+
+        let res = handle_request(rpc_request(&[], body), &state).await;
+        assert_eq!(res.status, 401);
+        let res = handle_request(rpc_request(&[("authorization", auth)], body), &state).await;
+        assert_eq!(res.status, 409);
+        let header = (SESSION_ID_HEADER.to_string(), "abc".to_string());
+        assert!(res.headers.contains(&header));
+
+        let headers = [
+            ("authorization", auth),
+            ("x-transmission-session-id", "abc"),
+        ];
+        let res = handle_request(rpc_request(&headers, body), &state).await;
+        assert_eq!(res.status, 200);
+        let Body::Bytes(body) = res.body else {
+            panic!("Streamed RPC response");
+        };
+        let reply: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply["result"], "success");
+        assert_eq!(reply["tag"], 7);
+        assert_eq!(reply["arguments"]["session-id"], "abc");
+    }
+}
@@ -1,4 +1,5 @@
 use super::get_readable_size;
+use crate::engine::priorities::{self, FilePriority};
 use crate::gui::files_tree::draw_tree;
 use crate::gui::MyApp;
 use egui::{FontFamily, FontId, TextFormat};
@@ -41,13 +42,13 @@ impl MyApp {
                                 let max_w = 11;
                                 let mut data = String::new();
                                 if let Some(i) = self.selected_row {
-                                    data = self.torrents[i].save_dir.clone();
+                                    data = self.session.torrents[i].save_dir.clone();
                                 }
                                 label!(ui, "Save path:", data, max_w);
 
                                 if let Some(i) = self.selected_row {
                                     data = get_readable_size(
-                                        self.torrents[i].torrent.info.length as usize,
+                                        self.session.torrents[i].torrent.info.length as usize,
                                         3,
                                     );
                                 }
@@ -56,7 +57,7 @@ impl MyApp {
 
                                 if let Some(i) = self.selected_row {
                                     data = get_readable_size(
-                                        self.torrents[i].torrent.info.piece_length as usize,
+                                        self.session.torrents[i].torrent.info.piece_length as usize,
                                         0,
                                     );
                                 }
@@ -64,7 +65,7 @@ impl MyApp {
                                 label!(ui, "Piece size:", data, max_w);
 
                                 if let Some(i) = self.selected_row {
-                                    data = self.torrents[i]
+                                    data = self.session.torrents[i]
                                         .torrent
                                         .info
                                         .piece_hashes
@@ -75,22 +76,65 @@ impl MyApp {
                                 label!(ui, "Pieces N:", data, max_w);
 
                                 if let Some(i) = self.selected_row {
-                                    data = hex::encode(&self.torrents[i].torrent.info_hash);
+                                    data = hex::encode(&self.session.torrents[i].torrent.info_hash);
                                 }
 
                                 label!(ui, "Info hash:", data, max_w);
                             });
 
+                            cols[0].label("Trackers");
+                            cols[0].group(|ui| {
+                                let Some(i) = self.selected_row else {
+                                    ui.label("");
+                                    return;
+                                };
+                                let max_w = 9;
+                                for tracker in &self.session.torrents[i].trackers {
+                                    ui.monospace(&tracker.url);
+                                    match &tracker.announce {
+                                        Some(Ok(info)) => {
+                                            let data = format!("ok, {} peer(s)", info.peers_n);
+                                            label!(ui, "Announce:", data, max_w);
+                                            if let Some(warning) = &info.warning {
+                                                label!(ui, "Warning:", warning, max_w);
+                                            }
+                                        }
+                                        Some(Err(e)) => {
+                                            label!(ui, "Announce:", format!("failed: {e}"), max_w);
+                                        }
+                                        None => {}
+                                    }
+                                    let data = match (&tracker.scrape, &tracker.announce) {
+                                        (Some(Ok(info)), _) => format!(
+                                            "seeders {}, leechers {}, completed {}",
+                                            info.complete, info.incomplete, info.downloaded
+                                        ),
+                                        // announce response can contain swarm counts too
+                                        (_, Some(Ok(info))) if info.complete.is_some() => format!(
+                                            "seeders {}, leechers {}",
+                                            info.complete.unwrap_or(0),
+                                            info.incomplete.unwrap_or(0)
+                                        ),
+                                        (Some(Err(e)), _) => format!("failed: {e}"),
+                                        _ => continue,
+                                    };
+                                    label!(ui, "Swarm:", data, max_w);
+                                }
+                                if self.session.torrents[i].trackers.is_empty() {
+                                    ui.label("");
+                                }
+                            });
+
                             cols[1].label("Downloading");
                             cols[1].group(|ui| {
                                 let max_w = 15;
                                 let mut data = String::new();
 
                                 if let Some(i) = self.selected_row {
-                                    let mut size = self.torrents[i].pieces_done as usize
-                                        * self.torrents[i].torrent.info.piece_length as usize;
-                                    if size > self.torrents[i].torrent.info.length as usize {
-                                        size = self.torrents[i].torrent.info.length as usize;
+                                    let mut size = self.session.torrents[i].pieces_done as usize
+                                        * self.session.torrents[i].torrent.info.piece_length as usize;
+                                    if size > self.session.torrents[i].torrent.info.length as usize {
+                                        size = self.session.torrents[i].torrent.info.length as usize;
                                     }
                                     data = get_readable_size(size, 1);
                                 }
@@ -98,14 +142,14 @@ impl MyApp {
                                 label!(ui, "Downloaded:", data, max_w);
 
                                 if let Some(i) = self.selected_row {
-                                    data = get_readable_size(self.torrents[i].uploaded as usize, 2)
+                                    data = get_readable_size(self.session.torrents[i].uploaded as usize, 2)
                                 }
                                 label!(ui, "Uploaded:", data, max_w);
 
                                 let mut data = 0;
 
                                 if let Some(i) = self.selected_row {
-                                    data = self.torrents[i].peers.len();
+                                    data = self.session.torrents[i].peers.len();
                                 }
                                 label!(ui, "Peers number:", data, max_w);
                             });
@@ -122,16 +166,31 @@ impl MyApp {
                                     .auto_shrink(false)
                                     .show(ui, |ui| {
                                         if let Some(i) = self.selected_row {
-                                            let torrent = &self.torrents[i].torrent;
+                                            let torrent = &self.session.torrents[i].torrent;
                                             if let Some(files) = &torrent.info.files {
-                                                draw_tree(
+                                                let file_priorities =
+                                                    priorities::torrent(&torrent.info_hash);
+                                                let mut values = file_priorities.get();
+                                                values.resize(files.len(), FilePriority::Normal);
+                                                let response = draw_tree(
                                                     &files
                                                         .iter()
                                                         .map(|x| x.path.as_str())
                                                         .collect(),
                                                     torrent.info.name.clone(),
                                                     ui,
-                                                )
+                                                    Some(&mut values),
+                                                );
+                                                if response.priorities_changed {
+                                                    file_priorities.set(values);
+                                                }
+                                                if let Some(file_i) = response.prioritize_ends {
+                                                    for range in
+                                                        priorities::file_ends(torrent, file_i)
+                                                    {
+                                                        file_priorities.add_urgent(range);
+                                                    }
+                                                }
                                             } else {
                                                 ui.label(&torrent.info.name);
                                             }
@@ -148,10 +207,10 @@ impl MyApp {
                                     .auto_shrink(false)
                                     .show(ui, |ui| {
                                         if let Some(i) = self.selected_row {
-                                            for peer in &self.torrents[i].peers {
-                                                ui.monospace(peer);
+                                            for peer in &self.session.torrents[i].peers {
+                                                ui.monospace(peer.to_string());
                                             }
-                                            if self.torrents[i].peers.is_empty() {
+                                            if self.session.torrents[i].peers.is_empty() {
                                                 ui.label("");
                                             }
                                         } else {
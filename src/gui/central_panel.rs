@@ -8,7 +8,7 @@ impl MyApp {
                 .min_scrolled_height(0.0)
                 .show(ui, |ui| {
                     ui.set_enabled(!self.import_opened);
-                    self.draw_table(ui, ctx);
+                    self.draw_table(ui);
                 });
         });
     }
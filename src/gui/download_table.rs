@@ -1,4 +1,6 @@
-use crate::gui::{get_readable_size, DownloadStatus, MyApp};
+use crate::engine::settings::Settings;
+use crate::engine::session::DownloadStatus;
+use crate::gui::{get_readable_size, MyApp};
 use eframe::egui::Ui;
 use egui::Color32;
 use egui_extras::{Column, TableBuilder};
@@ -6,7 +8,8 @@ use egui_extras::{Column, TableBuilder};
 use super::format_duration;
 
 impl MyApp {
-    pub fn draw_table(&mut self, ui: &mut Ui, ctx: &egui::Context) {
+    pub fn draw_table(&mut self, ui: &mut Ui) {
+        let settings = Settings::get();
         let mut table = TableBuilder::new(ui)
             .striped(true)
             .resizable(true)
@@ -18,6 +21,7 @@ impl MyApp {
             .column(Column::initial(100.0).at_least(40.0).clip(true))
             .column(Column::auto())
             .column(Column::auto())
+            .column(Column::auto())
             .column(Column::remainder().at_least(40.0))
             .min_scrolled_height(0.0);
 
@@ -45,41 +49,47 @@ impl MyApp {
                 header.col(|ui| {
                     ui.strong("Uploaded");
                 });
+                header.col(|ui| {
+                    ui.strong("Ratio");
+                });
             })
             .body(|body| {
                 {
-                    body.heterogeneous_rows((0..self.torrents.len()).map(|_| 16.0), |mut row| {
+                    body.heterogeneous_rows((0..self.session.torrents.len()).map(|_| 16.0), |mut row| {
                         let row_i = row.index();
                         if let Some(n) = self.selected_row {
                             row.set_selected(n == row_i);
                         }
 
                         row.col(|ui| {
-                            ui.label(&self.torrents[row_i].torrent.info.name);
+                            ui.label(&self.session.torrents[row_i].torrent.info.name);
                         });
                         row.col(|ui| {
                             let postfixed_size = get_readable_size(
-                                self.torrents[row_i].torrent.info.length as usize,
+                                self.session.torrents[row_i].torrent.info.length as usize,
                                 2,
                             );
                             ui.label(postfixed_size);
                         });
                         row.col(|ui| {
                             let progress_bar = {
-                                match self.torrents[row_i].status {
+                                match self.session.torrents[row_i].status {
                                     DownloadStatus::Downloading | DownloadStatus::Resuming => {
-                                        let progress = self.torrents[row_i].pieces_done as f32
-                                            / self.torrents[row_i].torrent.info.piece_hashes.len()
+                                        let progress = self.session.torrents[row_i].pieces_done as f32
+                                            / self.session.torrents[row_i].torrent.info.piece_hashes.len()
                                             as f32;
                                         egui::ProgressBar::new(progress)
                                             .text(format!("{:.2}%", progress * 100.0))
                                     }
+                                    DownloadStatus::Seeding => egui::ProgressBar::new(1.0)
+                                        .text("Seeding")
+                                        .fill(Color32::DARK_GREEN),
                                     DownloadStatus::Finished => {
                                         egui::ProgressBar::new(1.0).fill(Color32::GREEN)
                                     }
                                     _ => {
-                                        let progress = self.torrents[row_i].pieces_done as f32
-                                            / self.torrents[row_i].torrent.info.piece_hashes.len()
+                                        let progress = self.session.torrents[row_i].pieces_done as f32
+                                            / self.session.torrents[row_i].torrent.info.piece_hashes.len()
                                             as f32;
                                         egui::ProgressBar::new(progress)
                                             .text(format!("{:.2}%", progress * 100.0))
@@ -90,21 +100,21 @@ impl MyApp {
                             ui.add(progress_bar);
                         });
                         row.col(|ui| {
-                            let mut size = self.torrents[row_i].pieces_done as usize
-                                * self.torrents[row_i].torrent.info.piece_length as usize;
-                            if size > self.torrents[row_i].torrent.info.length as usize {
-                                size = self.torrents[row_i].torrent.info.length as usize;
+                            let mut size = self.session.torrents[row_i].pieces_done as usize
+                                * self.session.torrents[row_i].torrent.info.piece_length as usize;
+                            if size > self.session.torrents[row_i].torrent.info.length as usize {
+                                size = self.session.torrents[row_i].torrent.info.length as usize;
                             }
                             let size = get_readable_size(size, 2);
                             ui.label(size);
                         });
                         row.col(|ui| {
-                            if let DownloadStatus::Downloading = self.torrents[row_i].status {
-                                if let Some(speed) = self.torrents[row_i].download_speed {
+                            if let DownloadStatus::Downloading = self.session.torrents[row_i].status {
+                                if let Some(speed) = self.session.torrents[row_i].download_speed {
                                     if speed != 0 {
                                         ui.label(
                                             get_readable_size(
-                                                self.torrents[row_i].torrent.info.piece_length as usize
+                                                self.session.torrents[row_i].torrent.info.piece_length as usize
                                                     / (speed
                                                     as usize) * 1_000,
                                                 2,
@@ -121,10 +131,19 @@ impl MyApp {
                             }
                         });
                         row.col(|ui| {
-                            if let DownloadStatus::Downloading = self.torrents[row_i].status {
-                                if let Some(speed) = self.torrents[row_i].download_speed {
+                            if let DownloadStatus::Seeding = self.session.torrents[row_i].status {
+                                // time left till seeding target
+                                if let Some(minutes) = settings.seed_time {
+                                    let left =
+                                        (minutes * 60).saturating_sub(self.session.torrents[row_i].seeding_secs);
+                                    ui.label(format_duration(left));
+                                } else {
+                                    ui.label("∞");
+                                }
+                            } else if let DownloadStatus::Downloading = self.session.torrents[row_i].status {
+                                if let Some(speed) = self.session.torrents[row_i].download_speed {
                                     if speed != 0 {
-                                        let pieces_left = self.torrents[row_i].torrent.info.piece_hashes.len() - self.torrents[row_i].pieces_done as usize;
+                                        let pieces_left = self.session.torrents[row_i].torrent.info.piece_hashes.len() - self.session.torrents[row_i].pieces_done as usize;
                                         let left_secs = pieces_left as f64 / (1.0
                                             / (speed
                                             as f64) * 1_000.0);
@@ -140,7 +159,16 @@ impl MyApp {
                             }
                         });
                         row.col(|ui| {
-                            ui.label(get_readable_size(self.torrents[row_i].uploaded as usize, 2));
+                            ui.label(get_readable_size(self.session.torrents[row_i].uploaded as usize, 2));
+                        });
+                        row.col(|ui| {
+                            let ratio = self.session.torrents[row_i].total_uploaded as f64
+                                / self.session.torrents[row_i].torrent.info.length as f64;
+                            if let Some(target) = settings.seed_ratio {
+                                ui.label(format!("{ratio:.2}/{target:.2}"));
+                            } else {
+                                ui.label(format!("{ratio:.2}"));
+                            }
                         });
 
                         if row.response().clicked() {
@@ -154,7 +182,7 @@ impl MyApp {
                                 Some(row_i)
                             }
                         }
-                        if let DownloadStatus::Error(msg) = &self.torrents[row_i].status {
+                        if let DownloadStatus::Error(msg) = &self.session.torrents[row_i].status {
                             row.response().on_hover_text(msg);
                         }
 
@@ -162,8 +190,9 @@ impl MyApp {
                             // self.context_selected_row = Some(row_index);
 
                             let enabled = if let DownloadStatus::Finished
-                            | DownloadStatus::Downloading | DownloadStatus::Resuming =
-                                self.torrents[row_i].status
+                            | DownloadStatus::Downloading | DownloadStatus::Resuming
+                            | DownloadStatus::Seeding =
+                                self.session.torrents[row_i].status
                             {
                                 false
                             } else {
@@ -173,12 +202,12 @@ impl MyApp {
                                 .add_enabled(enabled, egui::Button::new("Resume"))
                                 .clicked()
                             {
-                                self.resume_torrent(row_i, ctx);
+                                self.session.resume_torrent(row_i);
                                 ui.close_menu();
                             };
 
                             let enabled = if let DownloadStatus::Finished | DownloadStatus::Paused =
-                                self.torrents[row_i].status
+                                self.session.torrents[row_i].status
                             {
                                 false
                             } else {
@@ -188,7 +217,7 @@ impl MyApp {
                                 .add_enabled(enabled, egui::Button::new("Pause"))
                                 .clicked()
                             {
-                                self.pause_torrent(row_i);
+                                self.session.pause_torrent(row_i);
                                 ui.close_menu();
                             };
 
@@ -1,17 +1,28 @@
 
-use egui::{CollapsingHeader, Ui};
+use egui::{CollapsingHeader, ComboBox, Ui};
+
+use crate::engine::priorities::FilePriority;
+
+// What user did with files in tree
+#[derive(Default)]
+pub struct TreeResponse {
+    pub priorities_changed: bool,
+    // file which head and tail should be downloaded first
+    pub prioritize_ends: Option<usize>,
+}
 
 #[derive(Clone, Default)]
 struct Tree {
     children: Vec<(Tree, String)>,
-    end_nodes: Vec<String>,
+    // file name and its index in torrent
+    end_nodes: Vec<(String, usize)>,
 }
 
 impl Tree {
     fn build_tree(paths: Vec<&str>) -> Self {
         let mut root = Tree::default();
 
-        for path in paths {
+        for (file_i, path) in paths.into_iter().enumerate() {
             let parts: Vec<&str> = path.split('/').collect();
             let mut current_node = &mut root;
 
@@ -28,7 +39,7 @@ impl Tree {
             }
 
             if let Some(file_name) = parts.last() {
-                current_node.end_nodes.push((*file_name).to_string());
+                current_node.end_nodes.push(((*file_name).to_string(), file_i));
             }
         }
 
@@ -37,27 +48,66 @@ impl Tree {
 }
 
 impl Tree {
-    fn draw(&mut self, ui: &mut Ui) {
-        self.ui_impl(ui, 0);
+    fn draw(
+        &mut self,
+        ui: &mut Ui,
+        priorities: &mut Option<&mut Vec<FilePriority>>,
+    ) -> TreeResponse {
+        let mut response = TreeResponse::default();
+        self.ui_impl(ui, 0, priorities, &mut response);
+        response
     }
-    fn ui_impl(&mut self, ui: &mut Ui, depth: usize) {
+    fn ui_impl(
+        &mut self,
+        ui: &mut Ui,
+        depth: usize,
+        priorities: &mut Option<&mut Vec<FilePriority>>,
+        response: &mut TreeResponse,
+    ) {
         for (child,  name) in &mut self.children {
             CollapsingHeader::new((*name).to_string())
                 .default_open(depth < 1)
-                .show(ui, |ui| child.ui_impl(ui, depth + 1));
+                .show(ui, |ui| child.ui_impl(ui, depth + 1, priorities, response));
         }
-        for name in &self.end_nodes {
-            ui.label(name);
+        for (name, file_i) in &self.end_nodes {
+            let Some(priorities) = priorities.as_mut() else {
+                ui.label(name);
+                continue;
+            };
+            ui.horizontal(|ui| {
+                let priority = &mut priorities[*file_i];
+                ComboBox::from_id_source(("file priority", *file_i))
+                    .width(70.0)
+                    .selected_text(priority.to_string())
+                    .show_ui(ui, |ui| {
+                        for value in FilePriority::ALL {
+                            response.priorities_changed |= ui
+                                .selectable_value(priority, value, value.to_string())
+                                .changed();
+                        }
+                    });
+                ui.label(name).context_menu(|ui| {
+                    if ui.button("Download head and tail first").clicked() {
+                        response.prioritize_ends = Some(*file_i);
+                        ui.close_menu();
+                    }
+                });
+            });
         }
-
     }
 }
 
-pub fn draw_tree(pathes: &Vec<&str>, root_name: String, ui: &mut Ui) {
+// Priorities are shown and can be changed if given, one per file
+pub fn draw_tree(
+    pathes: &Vec<&str>,
+    root_name: String,
+    ui: &mut Ui,
+    mut priorities: Option<&mut Vec<FilePriority>>,
+) -> TreeResponse {
     let tree: Tree = Tree::build_tree(pathes.to_vec());
     let mut tree = Tree {
         children: vec![(tree, root_name)],
         end_nodes: Vec::new()
     };
-    tree.draw(ui);
-}
\ No newline at end of file
+    tree.draw(ui, &mut priorities)
+}
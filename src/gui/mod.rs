@@ -6,29 +6,18 @@ mod top_panel;
 mod torrent_actions;
 mod torrent_import;
 
-use crate::engine::backup::Backup;
-use crate::engine::saver;
-use crate::engine::TorrentInfo;
-use crate::engine::{
-    download::{ChunksTask, PieceTask},
-    logger::{log, LogLevel},
-    torrent::Torrent,
-};
+use crate::engine::events::{EngineEvent, EngineEvents};
+use crate::engine::priorities::FilePriority;
+use crate::engine::session::Session;
+use crate::engine::torrent::Torrent;
 use egui::Visuals;
 
 use eframe::egui;
 use egui::Modifiers;
-use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::Duration;
-use std::time::Instant;
 
-use serde::{Deserialize, Serialize};
-use tokio::{
-    sync::broadcast::{Receiver, Sender},
-    task::JoinHandle,
-};
-
-const PIECES_TO_TIME_MEASURE: u8 = 5;
+use tokio::sync::oneshot;
 
 pub fn start_gui() -> anyhow::Result<()> {
     let icon = include_bytes!("../../folder-download.png");
@@ -51,115 +40,47 @@ pub fn start_gui() -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum DownloadStatus {
-    Resuming,
-    Downloading,
-    Paused,
-    Finished,
-    Error(String),
-}
+// Window is repainted when workers send events, so it shows them without delay
+struct Repaint(egui::Context);
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TorrentBackupInfo {
-    pub pieces_tasks: VecDeque<PieceTask>,
-    pub chunks_tasks: VecDeque<ChunksTask>,
-    pub torrent: Torrent,
-    pub save_path: String,
-    pub pieces_done: usize,
-    pub status: DownloadStatus,
-}
-
-#[derive(Clone, Debug)]
-pub struct UiHandle {
-    pub ui_sender: Sender<UiMsg>,
-    pub ctx: egui::Context,
-}
-
-impl UiHandle {
-    pub fn send_with_update(&self, msg: UiMsg) -> anyhow::Result<()> {
-        self.ui_sender.send(msg)?;
-        self.ctx.request_repaint();
-        Ok(())
+impl EngineEvents for Repaint {
+    fn on_event(&self, _info_hash: &[u8], _event: &EngineEvent) {
+        self.0.request_repaint();
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum UiMsg {
-    HashCheckFinished,
-
-    PeerDiscovered(String),
-
-    PeerDisconnect(String),
-
-    TorrentFinished,
-    PieceDone(u16),
-    ForceOff,
-
-    // String with error text
-    TorrentErr(String),
-
-    // Pieces downloaded in total
-    Pause(u16),
-
-    // Pieces done
-    Stop(u16),
-
-    // Uploaded bytes
-    DataUploaded(u64),
-}
-
-struct WorkerInfo {
-    handle: JoinHandle<()>,
-    sender: Sender<UiMsg>,
-    receiver: Receiver<UiMsg>,
-}
-
-pub struct TimeStamp {
-    pub time: Instant,
-    pub pieces_n: u32,
-}
-
-struct TorrentDownload {
-    status: DownloadStatus,
-    worker_info: Option<WorkerInfo>,
-    peers: Vec<String>,
-    torrent: Torrent,
-    pieces_done: u32,
-    last_timestamp: Option<TimeStamp>,
-    download_speed: Option<u16>,
-    save_dir: String,
-    uploaded: u32,
-}
-
 pub struct MyApp {
-    torrents: Vec<TorrentDownload>,
+    session: Session,
     selected_row: Option<usize>,
     user_msg: Option<(String, String)>,
     inited: bool,
     import_opened: bool,
     import_dest_dir: String,
     import_torrent: Option<Torrent>,
+    // chosen in import window, index is file index
+    import_priorities: Vec<FilePriority>,
+    import_magnet: String,
+    magnet_receiver: Option<oneshot::Receiver<anyhow::Result<Torrent>>>,
     torrent_to_delete: Option<usize>,
     zoom: f32,
-    peer_id: String,
     is_dark_theme: bool,
 }
 
 impl Default for MyApp {
     fn default() -> Self {
-        use rand::distributions::{Alphanumeric, DistString};
         Self {
-            torrents: Vec::new(),
+            session: Session::default(),
             selected_row: None,
             user_msg: None,
             inited: false,
             import_opened: false,
             import_dest_dir: String::new(),
             import_torrent: None,
+            import_priorities: Vec::new(),
+            import_magnet: String::new(),
+            magnet_receiver: None,
             torrent_to_delete: None,
             zoom: 1.0,
-            peer_id: Alphanumeric.sample_string(&mut rand::thread_rng(), 20),
             is_dark_theme: true,
         }
     }
@@ -183,7 +104,7 @@ impl eframe::App for MyApp {
             self.import_window(ctx);
         }
 
-        self.torrent_updates(ctx);
+        self.torrent_updates();
 
         self.show_message(ctx);
 
@@ -192,73 +113,15 @@ impl eframe::App for MyApp {
         self.cenral_panel(ctx);
     }
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
-        for q_torrent in &self.torrents {
-            match &q_torrent.status {
-                DownloadStatus::Downloading | DownloadStatus::Resuming => {
-                    if let Some(info) = &q_torrent.worker_info {
-                        info.sender
-                            .send(UiMsg::Stop(q_torrent.pieces_done as u16))
-                            .unwrap();
-                    }
-                }
-                DownloadStatus::Paused => {
-                    // Data is already backed up
-                }
-                status => {
-                    async_std::task::block_on(Backup::global().backup_torrent(TorrentBackupInfo {
-                        pieces_tasks: VecDeque::new(),
-                        chunks_tasks: VecDeque::new(),
-                        torrent: q_torrent.torrent.clone(),
-                        save_path: q_torrent.save_dir.clone(),
-                        pieces_done: q_torrent.pieces_done as usize,
-                        status: status.clone(),
-                    }))
-                    .unwrap();
-                }
-            }
-        }
-
-        let app_data = std::mem::take(self);
-        for q_torrent in app_data.torrents {
-            match q_torrent.status {
-                DownloadStatus::Downloading | DownloadStatus::Resuming => {
-                    if let Some(info) = q_torrent.worker_info {
-                        async_std::task::block_on(info.handle).unwrap();
-                    }
-                }
-                _ => {}
-            }
-        }
+        self.session.shutdown();
     }
 }
 
 impl MyApp {
     fn init(&mut self, ctx: &egui::Context) {
         self.inited = true;
-        saver::init_saver_globals();
-        Backup::init().expect("Saver does not work");
-        match async_std::task::block_on(Backup::global().load_config()) {
-            Ok(backups) => {
-                for backup in backups {
-                    self.torrents.push(TorrentDownload {
-                        peers: Vec::new(),
-                        status: backup.status.clone(),
-                        worker_info: None,
-                        torrent: backup.torrent.clone(),
-                        pieces_done: backup.pieces_done as u32,
-                        save_dir: backup.save_path.clone(),
-                        last_timestamp: None,
-                        download_speed: None,
-                        uploaded: 0,
-                    });
-                    log!(LogLevel::Info, "done: {}", backup.pieces_done);
-                    if let DownloadStatus::Downloading = backup.status {
-                        self.start_download(TorrentInfo::Backup(backup), ctx);
-                    }
-                }
-            }
-            Err(e) => log!(LogLevel::Error, "Failed to open backup file: {e}"),
-        }
+        self.session.subscribe(Arc::new(Repaint(ctx.clone())));
+        self.session.start();
         let ctx = ctx.clone();
         tokio::spawn(async move {
             loop {
@@ -300,7 +163,7 @@ impl MyApp {
     }
 }
 
-fn get_readable_size(bytes: usize, prec: usize) -> String {
+pub fn get_readable_size(bytes: usize, prec: usize) -> String {
     match bytes {
         0..=1023 => format!("{bytes}B"),
         1024..=1_048_575 => format!("{:.1$}KB", bytes as f64 / 1024.0, prec),
@@ -1,7 +1,12 @@
 use egui::Ui;
 
+use crate::engine::api;
 use crate::engine::parse_torrent;
-use crate::gui::{DownloadStatus, MyApp};
+use crate::engine::priorities;
+use crate::engine::ratelimit;
+use crate::engine::settings::{RateLimits, Settings};
+use crate::engine::session::DownloadStatus;
+use crate::gui::MyApp;
 
 impl MyApp {
     pub fn top_panel(&mut self, ctx: &egui::Context) {
@@ -19,6 +24,7 @@ impl MyApp {
                             let torrent = parse_torrent(path.to_str().unwrap());
                             if let Ok(torrent) = torrent {
                                 if self
+                                    .session
                                     .torrents
                                     .iter()
                                     .position(|x| x.torrent.info_hash == torrent.info_hash)
@@ -36,8 +42,14 @@ impl MyApp {
                             }
                         }
                     }
+                    if ui.button("Open magnet").clicked() {
+                        ui.close_menu();
+                        self.import_torrent = None;
+                        self.import_magnet.clear();
+                        self.import_opened = true;
+                    }
                     ui.menu_button("Edit", |ui| {
-                        self.torrent_actions(ui, ctx);
+                        self.torrent_actions(ui);
                     });
                     ui.menu_button("Appearance", |ui| {
                         if ui.button("Zoom In").clicked() {
@@ -52,59 +64,219 @@ impl MyApp {
                             self.is_dark_theme = !self.is_dark_theme;
                         }
                     });
+                    ui.menu_button("Settings", |ui| {
+                        self.settings_menu(ui);
+                    });
                 });
 
                 ui.separator();
                 ui.horizontal(|ui| {
-                    self.torrent_actions(ui, ctx);
+                    self.torrent_actions(ui);
                     ui.separator();
                     ui.horizontal(|ui| {
-                        ui.set_enabled(!self.torrents.is_empty());
+                        ui.set_enabled(!self.session.torrents.is_empty());
                         if ui.button("Pause All").clicked() {
                             let mut torrents_to_pause = Vec::new();
-                            for (i, entry) in self.torrents.iter().enumerate() {
-                                if let DownloadStatus::Downloading | DownloadStatus::Resuming = entry.status {
+                            for (i, entry) in self.session.torrents.iter().enumerate() {
+                                if let DownloadStatus::Downloading
+                                | DownloadStatus::Resuming
+                                | DownloadStatus::Seeding = entry.status
+                                {
                                     torrents_to_pause.push(i);
                                 }
                             }
                             for torrent_i in torrents_to_pause {
-                                self.pause_torrent(torrent_i);
+                                self.session.pause_torrent(torrent_i);
                             }
                         };
                         if ui.button("Resume All").clicked() {
                             let mut torrents_to_resume = Vec::new();
-                            for (i, entry) in self.torrents.iter().enumerate() {
+                            for (i, entry) in self.session.torrents.iter().enumerate() {
                                 if let DownloadStatus::Paused = entry.status {
                                     torrents_to_resume.push(i);
                                 }
                             }
                             for torrent_i in torrents_to_resume {
-                                self.resume_torrent(torrent_i, ctx);
+                                self.session.resume_torrent(torrent_i);
                             }
                         };
                     });
                 });
             });
     }
-    fn torrent_actions(&mut self, ui: &mut Ui, ctx: &egui::Context) {
+    fn torrent_actions(&mut self, ui: &mut Ui) {
         let enabled = self.selected_row.is_some();
         
         if ui.add_enabled(enabled, egui::Button::new("Pause")).clicked() {
-            if let DownloadStatus::Downloading | DownloadStatus::Resuming | DownloadStatus::Error(_) =
-                self.torrents[self.selected_row.unwrap()].status
+            if let DownloadStatus::Downloading
+            | DownloadStatus::Resuming
+            | DownloadStatus::Seeding
+            | DownloadStatus::Error(_) = self.session.torrents[self.selected_row.unwrap()].status
             {
-                self.pause_torrent(self.selected_row.unwrap());
+                self.session.pause_torrent(self.selected_row.unwrap());
             }
         }
         if ui.add_enabled(enabled, egui::Button::new("Resume")).clicked() {
             if let DownloadStatus::Paused | DownloadStatus::Error(_) =
-                self.torrents[self.selected_row.unwrap()].status
+                self.session.torrents[self.selected_row.unwrap()].status
             {
-                self.resume_torrent(self.selected_row.unwrap(), ctx);
+                self.session.resume_torrent(self.selected_row.unwrap());
             }
         }
         if ui.add_enabled(enabled, egui::Button::new("Delete")).clicked() {
             self.delete_torrent(self.selected_row.unwrap());
         }
+        let mut sequential = self.selected_row.is_some_and(|i| {
+            priorities::torrent(&self.session.torrents[i].torrent.info_hash).is_sequential()
+        });
+        let checkbox = egui::Checkbox::new(&mut sequential, "Sequential");
+        if ui.add_enabled(enabled, checkbox).changed() {
+            let i = self.selected_row.unwrap();
+            priorities::torrent(&self.session.torrents[i].torrent.info_hash).set_sequential(sequential);
+        }
+        ui.add_enabled_ui(enabled, |ui| {
+            ui.menu_button("Limits", |ui| {
+                if let Some(i) = self.selected_row {
+                    self.torrent_limits_menu(ui, i);
+                }
+            });
+        });
+    }
+
+    // Limits of selected torrent, global limits still apply on top of them
+    fn torrent_limits_menu(&mut self, ui: &mut Ui, i: usize) {
+        let limiter = ratelimit::torrent(&self.session.torrents[i].torrent.info_hash);
+        let mut limits = limiter.limits();
+        if limits_editor(ui, &mut limits) {
+            limiter.set(limits);
+        }
+    }
+
+    // Seeding stops when any of enabled targets is reached
+    fn settings_menu(&mut self, ui: &mut Ui) {
+        let mut settings = Settings::get();
+        let mut changed = false;
+
+        let mut ratio_on = settings.seed_ratio.is_some();
+        let mut ratio = settings.seed_ratio.unwrap_or(2.0);
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut ratio_on, "Seed until ratio").changed();
+            changed |= ui
+                .add_enabled(
+                    ratio_on,
+                    egui::DragValue::new(&mut ratio).speed(0.1).clamp_range(0.1..=100.0),
+                )
+                .changed();
+        });
+        settings.seed_ratio = ratio_on.then_some(ratio);
+
+        let mut time_on = settings.seed_time.is_some();
+        let mut time = settings.seed_time.unwrap_or(60);
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut time_on, "Seed for minutes").changed();
+            changed |= ui
+                .add_enabled(
+                    time_on,
+                    egui::DragValue::new(&mut time).clamp_range(1..=525_600),
+                )
+                .changed();
+        });
+        settings.seed_time = time_on.then_some(time);
+
+        ui.separator();
+        ui.label("Speed limits, KiB/s");
+        changed |= limits_editor(ui, &mut settings.limits);
+
+        ui.separator();
+        changed |= ui
+            .checkbox(&mut settings.alt_enabled, "Use alternative limits")
+            .changed();
+        changed |= limits_editor(ui, &mut settings.alt_limits);
+        let mut schedule_on = settings.alt_schedule.is_some();
+        let mut schedule = settings.alt_schedule.clone().unwrap_or_default();
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut schedule_on, "Scheduled from").changed();
+            ui.add_enabled_ui(schedule_on, |ui| {
+                changed |= ui
+                    .add(egui::DragValue::new(&mut schedule.from_hour).clamp_range(0..=23))
+                    .changed();
+                ui.label("to");
+                changed |= ui
+                    .add(egui::DragValue::new(&mut schedule.to_hour).clamp_range(0..=23))
+                    .changed();
+                ui.label("h");
+            });
+        });
+        settings.alt_schedule = schedule_on.then_some(schedule);
+
+        ui.separator();
+        let window = &mut settings.request_window;
+        ui.horizontal(|ui| {
+            ui.label("Requests per peer from");
+            changed |= ui
+                .add(egui::DragValue::new(&mut window.min).clamp_range(1..=window.max))
+                .changed();
+            ui.label("to");
+            changed |= ui
+                .add(egui::DragValue::new(&mut window.max).clamp_range(window.min..=2000))
+                .changed();
+        });
+        ui.horizontal(|ui| {
+            ui.label("Requests queued for seconds");
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut window.queue_secs)
+                        .speed(0.1)
+                        .clamp_range(0.5..=30.0),
+                )
+                .changed();
+        });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            let token = settings.api_token.clone().unwrap_or_default();
+            ui.label("API token");
+            ui.add(egui::TextEdit::singleline(&mut token.as_str()).desired_width(150.0));
+            if ui.button("Copy").clicked() {
+                ui.output_mut(|o| o.copied_text = token);
+            }
+            if ui.button("New").clicked() {
+                settings.api_token = Some(api::new_token());
+                changed = true;
+            }
+        });
+        changed |= ui
+            .checkbox(&mut settings.api_remote, "Allow API and web UI from network (after restart)")
+            .changed();
+
+        if changed {
+            Settings::set(settings);
+            ratelimit::apply_settings();
+        }
+    }
+}
+
+// Download and upload limits, returns true if user changed them
+fn limits_editor(ui: &mut Ui, limits: &mut RateLimits) -> bool {
+    let mut changed = false;
+    for (name, limit) in [
+        ("Download", &mut limits.download),
+        ("Upload", &mut limits.upload),
+    ] {
+        let mut on = limit.is_some();
+        let mut value = limit.unwrap_or(1024);
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut on, name).changed();
+            changed |= ui
+                .add_enabled(
+                    on,
+                    egui::DragValue::new(&mut value)
+                        .speed(16)
+                        .clamp_range(1..=1_048_576),
+                )
+                .changed();
+        });
+        *limit = on.then_some(value);
     }
+    changed
 }
@@ -1,8 +1,11 @@
+use crate::engine::parse_magnet;
+use crate::engine::priorities::{self, FilePriority};
 use crate::gui::MyApp;
-use crate::gui::TorrentInfo;
+use crate::engine::TorrentInfo;
 use egui::{ViewportBuilder, ViewportId};
 use std::path::Path;
 use egui::TextEdit;
+use tokio::sync::oneshot;
 
 use super::files_tree::draw_tree;
 use crate::gui::get_readable_size;
@@ -17,7 +20,10 @@ impl MyApp {
             |ctx, _| {
                 if ctx.input(|i| i.viewport().close_requested()) {
                     self.import_opened = false;
+                    self.magnet_receiver = None;
+                    self.import_priorities.clear();
                 }
+                self.poll_magnet();
                 egui::CentralPanel::default().show(ctx, |ui| {
                     ui.horizontal(|ui| {
                         ui.label("Save to:");
@@ -35,6 +41,12 @@ impl MyApp {
 
                     let dest_path = Path::new(&self.import_dest_dir);
 
+                    if self.import_torrent.is_none() {
+                        ui.separator();
+                        self.magnet_input(ui);
+                        return;
+                    }
+
                     // ui.add_space(5.0);
                     ui.separator();
                     // ui.add_space(5.0);
@@ -46,11 +58,19 @@ impl MyApp {
                         .show(ui, |ui| {
                             let torrent = self.import_torrent.as_ref().unwrap();
                             if let Some(files) = &torrent.info.files {
-                                draw_tree(
+                                self.import_priorities.resize(files.len(), FilePriority::Normal);
+                                let response = draw_tree(
                                     &files.iter().map(|x| x.path.as_str()).collect(),
                                     torrent.info.name.clone(),
                                     ui,
-                                )
+                                    Some(&mut self.import_priorities),
+                                );
+                                if let Some(file_i) = response.prioritize_ends {
+                                    let file_priorities = priorities::torrent(&torrent.info_hash);
+                                    for range in priorities::file_ends(torrent, file_i) {
+                                        file_priorities.add_urgent(range);
+                                    }
+                                }
                             } else {
                                 ui.label(&torrent.info.name);
                             }
@@ -64,6 +84,7 @@ impl MyApp {
                     //     });
                     // });
                     let mut start_download = false;
+                    let required_size = self.import_size();
 
                     // ui.with_layout(egui::Layout::left_to_right(egui::Align::BOTTOM), |ui| {
                         ui.horizontal(|ui| {
@@ -71,10 +92,7 @@ impl MyApp {
                                 ui.vertical(|ui| {
                                     ui.label(format!(
                                         "Required space: {}",
-                                        get_readable_size(
-                                            self.import_torrent.as_ref().unwrap().info.length as usize,
-                                            2
-                                        )
+                                        get_readable_size(required_size as usize, 2)
                                     ));
                                     let mut available_size = String::from("0");
                                     if dest_path.exists() {
@@ -100,6 +118,7 @@ impl MyApp {
                                 ui.set_enabled(button_enabled);
                                 if ui.button("Start").clicked() {
                                     self.import_opened = false;
+                                    self.import_magnet.clear();
     
                                     start_download = true;
                                 }
@@ -107,15 +126,98 @@ impl MyApp {
                         });
                     // });
                     if start_download {
-                        self.start_download(
+                        let info_hash = &self.import_torrent.as_ref().unwrap().info_hash;
+                        priorities::torrent(info_hash)
+                            .set(std::mem::take(&mut self.import_priorities));
+                        self.session.start_download(
                             TorrentInfo::Torrent(
                                 self.import_torrent.as_ref().unwrap().clone(),
                             ),
-                            ctx,
+                            &self.import_dest_dir,
                         );
                     }
                 });
             },
         );
     }
+
+    // Size of files that aren't skipped
+    fn import_size(&self) -> u64 {
+        let torrent = self.import_torrent.as_ref().unwrap();
+        let Some(ref files) = torrent.info.files else {
+            return torrent.info.length;
+        };
+        files
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.import_priorities.get(*i) != Some(&FilePriority::Skip))
+            .map(|(_, x)| x.length)
+            .sum()
+    }
+
+    fn magnet_input(&mut self, ui: &mut egui::Ui) {
+        let loading = self.magnet_receiver.is_some();
+        ui.horizontal(|ui| {
+            ui.label("Magnet:");
+            ui.add_enabled(
+                !loading,
+                TextEdit::singleline(&mut self.import_magnet)
+                    .hint_text("magnet:?xt=urn:btih:...")
+                    .desired_width(ui.available_width() / 1.5),
+            );
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Max), |ui| {
+                let enabled = !loading && !self.import_magnet.trim().is_empty();
+                if ui.add_enabled(enabled, egui::Button::new("Load")).clicked() {
+                    let (sender, receiver) = oneshot::channel();
+                    let uri = self.import_magnet.clone();
+                    let peer_id = self.session.peer_id.clone();
+                    tokio::spawn(async move {
+                        let _ = sender.send(parse_magnet(&uri, peer_id).await);
+                    });
+                    self.magnet_receiver = Some(receiver);
+                }
+            });
+        });
+        if loading {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Fetching torrent metadata from peers...");
+            });
+        }
+    }
+
+    fn poll_magnet(&mut self) {
+        let Some(receiver) = &mut self.magnet_receiver else {
+            return;
+        };
+        let res = match receiver.try_recv() {
+            Ok(res) => res,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => {
+                Err(anyhow::anyhow!("Metadata fetching was interrupted"))
+            }
+        };
+        self.magnet_receiver = None;
+        match res {
+            Ok(torrent) => {
+                if self
+                    .session
+                    .torrents
+                    .iter()
+                    .any(|x| x.torrent.info_hash == torrent.info_hash)
+                {
+                    self.import_opened = false;
+                    self.user_msg = Some((
+                        "Alert".to_string(),
+                        "This torrent is already imported".to_string(),
+                    ));
+                } else {
+                    self.import_torrent = Some(torrent);
+                }
+            }
+            Err(e) => {
+                self.user_msg = Some(("Magnet error".to_string(), e.to_string()));
+            }
+        }
+    }
 }
@@ -1,12 +1,29 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
+mod cli;
 mod engine;
 mod gui;
 use engine::logger;
+use engine::session::{self, Session};
 use crate::gui::start_gui;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     logger::Logger::init()?;
-    start_gui().unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|x| x.as_str()) {
+        // engine without window, controlled through control socket
+        Some("daemon") => {
+            let mut session = Session::default();
+            session.start();
+            session::run_headless(session).await;
+        }
+        Some(_) => {
+            if let Err(e) = cli::run(&args).await {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        None => start_gui().unwrap(),
+    }
     Ok(())
 }
\ No newline at end of file